@@ -0,0 +1,158 @@
+use regex::Regex;
+use std::sync::LazyLock;
+
+/// Simple but effective pattern for links with a scheme or a `www.` prefix.
+static URL_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?i)(?:https?://|www\.)[a-zA-Z0-9\-\.]+\.[a-zA-Z]{2,}(?:/[^\s]*)?").unwrap()
+});
+
+/// Closing wrappers paired with their opening counterpart.
+const WRAPPERS: &[(char, char)] = &[
+    (')', '('),
+    (']', '['),
+    ('}', '{'),
+    ('>', '<'),
+    ('»', '«'),
+    ('”', '“'),
+    ('’', '‘'),
+];
+
+/// Sentence punctuation that never ends a link in running text.
+const TRAILING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', '"', '\'', '…'];
+
+/// Returns the URLs found in free text, in order of appearance, with
+/// surrounding wrappers and punctuation trimmed (see [`trim_url`]). Markdown
+/// links pasted as plain text (`[text](url)`) yield the target, plus the
+/// text when it is a link itself.
+pub fn find_urls(text: &str) -> impl Iterator<Item = &str> {
+    URL_PATTERN
+        .find_iter(text)
+        .flat_map(|m| m.as_str().split("]("))
+        .map(trim_url)
+        .filter(|url| URL_PATTERN.find(url).is_some_and(|m| m.start() == 0))
+}
+
+/// The whole link around `range` (byte offsets of a detected URL in
+/// `text`). Formatting applied to part of a link splits Telegram's URL
+/// entity, so the entity alone may only cover a prefix or a fragment of it;
+/// this widens it to the surrounding whitespace-delimited token and trims
+/// the result like [`find_urls`].
+pub fn url_around(text: &str, range: std::ops::Range<usize>) -> &str {
+    let token_start = text[..range.start]
+        .rfind(char::is_whitespace)
+        .map(|i| i + text[i..].chars().next().map_or(1, char::len_utf8))
+        .unwrap_or(0);
+    let token_end = text[range.end..]
+        .find(char::is_whitespace)
+        .map(|i| range.end + i)
+        .unwrap_or(text.len());
+    let token = &text[token_start..token_end];
+    let start_in_token = range.start - token_start;
+    // Prefer the link with a scheme or `www.` covering the entity; a bare
+    // domain entity is only widened to the right.
+    find_urls(token)
+        .map(|url| {
+            let offset = url.as_ptr() as usize - token.as_ptr() as usize;
+            (offset, url)
+        })
+        .find(|(offset, url)| *offset <= start_in_token && start_in_token < offset + url.len())
+        .map(|(_, url)| url)
+        .unwrap_or_else(|| trim_url(&text[range.start..token_end]))
+}
+
+/// Strips what running text glues to the end of a link: sentence
+/// punctuation, and closing brackets or quotes without a matching opener
+/// inside the link. Balanced pairs stay, so
+/// `https://en.wikipedia.org/wiki/Rust_(language)` is left intact while
+/// `(https://example.com/?utm=x),` becomes `https://example.com/?utm=x`.
+pub fn trim_url(candidate: &str) -> &str {
+    let mut url = candidate;
+    while let Some(last) = url.chars().next_back() {
+        let unbalanced = WRAPPERS
+            .iter()
+            .find(|(close, _)| *close == last)
+            .is_some_and(|(close, open)| url.matches(*open).count() < url.matches(*close).count());
+        if unbalanced || TRAILING_PUNCTUATION.contains(&last) {
+            url = &url[..url.len() - last.len_utf8()];
+        } else {
+            break;
+        }
+    }
+    url
+}
+
+/// Whether `text` contains at least one URL.
+pub fn contains_url(text: &str) -> bool {
+    URL_PATTERN.is_match(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_urls_in_text() {
+        let found: Vec<&str> =
+            find_urls("see https://a.com/x?utm_source=y and www.b.org, not c.d").collect();
+        assert_eq!(found, vec!["https://a.com/x?utm_source=y", "www.b.org"]);
+    }
+
+    #[test]
+    fn test_trims_wrappers_and_punctuation() {
+        let found: Vec<&str> = find_urls(
+            "(https://example.com/?utm=x), [see www.a.org/p]. «https://b.com/x?y=1»! \
+             <https://c.com/>; https://en.wikipedia.org/wiki/Rust_(language)...",
+        )
+        .collect();
+        assert_eq!(
+            found,
+            vec![
+                "https://example.com/?utm=x",
+                "www.a.org/p",
+                "https://b.com/x?y=1",
+                "https://c.com/",
+                "https://en.wikipedia.org/wiki/Rust_(language)",
+            ]
+        );
+        assert_eq!(trim_url("https://a.com/?q=(x))"), "https://a.com/?q=(x)");
+        assert_eq!(trim_url("https://a.com/?q=1"), "https://a.com/?q=1");
+    }
+
+    #[test]
+    fn test_markdown_links() {
+        let found: Vec<&str> = find_urls(
+            "[click](https://a.com/?utm_source=x) and [https://b.com/](https://c.com/p?q=(1))",
+        )
+        .collect();
+        assert_eq!(
+            found,
+            vec![
+                "https://a.com/?utm_source=x",
+                "https://b.com/",
+                "https://c.com/p?q=(1)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_url_around_entities() {
+        let text = "look: (https://shop.com/item?utm=1&id=2), ok";
+        let start = text.find("https").unwrap();
+        // An entity cut short by bold text on `id=2`.
+        let end = text.find("&id").unwrap();
+        assert_eq!(
+            url_around(text, start..end),
+            "https://shop.com/item?utm=1&id=2"
+        );
+        // A fragment of the link, e.g. the bold part only.
+        let mid = text.find("id=2").unwrap();
+        assert_eq!(
+            url_around(text, mid..mid + 4),
+            "https://shop.com/item?utm=1&id=2"
+        );
+        // Bare domains are only widened to the right.
+        let text = "é shop.com/x?utm=1.";
+        let start = text.find("shop").unwrap();
+        assert_eq!(url_around(text, start..start + 8), "shop.com/x?utm=1");
+    }
+}
@@ -0,0 +1,13 @@
+//! Runtime-independent URL cleaning shared by the bot and the wasm package.
+//!
+//! Nothing in here does I/O: callers fetch the ClearURLs JSON themselves and
+//! hand it to [`Rules::from_json`].
+
+pub mod extract;
+pub mod models;
+pub mod redact;
+pub mod rules;
+
+pub use extract::{contains_url, find_urls, trim_url, url_around};
+pub use redact::redact_sensitive;
+pub use rules::{CleaningLevel, PassStats, ProviderError, Rules};
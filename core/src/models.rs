@@ -0,0 +1,389 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct UserConfig {
+    pub user_id: i64,
+    pub enabled: i32,
+    pub ai_enabled: i32,
+    pub mode: String, // "reply", "delete", "repost", "reaction", "dm" or "digest" (the last four groups only)
+    pub ignored_domains: String, // Comma-separated list
+    pub cleaned_count: i64,
+    pub language: String, // "en", "it", etc.
+    /// Dashboard colour scheme: "auto", "dark" or "light".
+    #[serde(default = "default_theme")]
+    pub theme: String,
+    /// Comma-separated dashboard widgets the user has hidden.
+    #[serde(default)]
+    pub hidden_widgets: String,
+    /// "conservative", "standard" or "strict" (see `rules::CleaningLevel`).
+    #[serde(default = "default_cleaning_level")]
+    pub cleaning_level: String,
+    /// Whether the public stats page at `/u/{public_token}` is served.
+    #[serde(default)]
+    pub public_stats: i32,
+    /// Kept when the page is switched off, so its link survives a toggle.
+    #[serde(default)]
+    pub public_token: Option<String>,
+    /// Whether cleaned links are kept in the history; the counter grows
+    /// either way.
+    #[serde(default = "default_log_history")]
+    pub log_history: i32,
+    /// Whether the user gets the weekly summary of their cleanings.
+    #[serde(default)]
+    pub digest_enabled: i32,
+}
+
+/// Reply modes a user can pick for themselves.
+pub const USER_MODES: &[&str] = &["reply", "delete", "repost", "reaction", "dm", "digest"];
+
+/// Modes a group or channel can use: the user modes, each sender's own
+/// (`default`), or `dry_run`, which only records what it finds.
+pub const CHAT_MODES: &[&str] = &[
+    "default", "reply", "delete", "repost", "reaction", "dm", "digest", "dry_run",
+];
+
+/// Dashboard colour schemes a user can pick.
+pub const THEMES: &[&str] = &["auto", "dark", "light"];
+
+/// Dashboard widgets that can be hidden.
+pub const DASHBOARD_WIDGETS: &[&str] = &[
+    "stats",
+    "chart",
+    "top_domains",
+    "history",
+    "chats",
+    "custom_rules",
+    "ai_usage",
+];
+
+fn default_theme() -> String {
+    "auto".to_string()
+}
+
+fn default_cleaning_level() -> String {
+    "standard".to_string()
+}
+
+fn default_log_history() -> i32 {
+    1
+}
+
+fn default_chat_cleaning_level() -> String {
+    "default".to_string()
+}
+
+fn default_chat_kind() -> String {
+    "group".to_string()
+}
+
+impl UserConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != 0
+    }
+    pub fn is_ai_enabled(&self) -> bool {
+        self.ai_enabled != 0
+    }
+    pub fn has_public_stats(&self) -> bool {
+        self.public_stats != 0
+    }
+    pub fn keeps_history(&self) -> bool {
+        self.log_history != 0
+    }
+    pub fn wants_digest(&self) -> bool {
+        self.digest_enabled != 0
+    }
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        Self {
+            user_id: 0,
+            enabled: 1,
+            ai_enabled: 0,
+            mode: "reply".to_string(),
+            ignored_domains: String::new(),
+            cleaned_count: 0,
+            language: "en".to_string(),
+            theme: default_theme(),
+            hidden_widgets: String::new(),
+            cleaning_level: default_cleaning_level(),
+            public_stats: 0,
+            public_token: None,
+            log_history: default_log_history(),
+            digest_enabled: 0,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ChatConfig {
+    pub chat_id: i64,
+    pub title: Option<String>,
+    pub enabled: i32,
+    pub added_by: i64,
+    pub mode: String, // "reply", "delete", "repost", "reaction", "dm", "digest", "dry_run" or "default"
+    #[serde(default)]
+    pub ai_enabled: i32,
+    /// A cleaning level, or "default" to use each sender's own.
+    #[serde(default = "default_chat_cleaning_level")]
+    pub cleaning_level: String,
+    /// "group" (groups and supergroups) or "channel".
+    #[serde(default = "default_chat_kind")]
+    pub kind: String,
+}
+
+/// A user's cleanings over a week, for their weekly summary.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeeklyStats {
+    pub cleaned: i64,
+    /// Most frequent providers with their counts.
+    pub top_providers: Vec<(String, i64)>,
+    /// Most cleaned domains with their counts.
+    pub top_domains: Vec<(String, i64)>,
+    /// The bot that cleaned for the user most recently.
+    pub bot_id: Option<i64>,
+}
+
+/// A chat with its activity, for the admin's chat list.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct ChatSummary {
+    pub chat_id: i64,
+    pub title: Option<String>,
+    pub enabled: i32,
+    /// The member who added the bot; 0 when unknown.
+    pub added_by: i64,
+    pub mode: String,
+    pub kind: String,
+    pub cleanings: i64,
+    /// Timestamp of the latest cleaning, if any.
+    pub last_activity: Option<i64>,
+}
+
+impl ChatConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != 0
+    }
+    pub fn is_ai_enabled(&self) -> bool {
+        self.ai_enabled != 0
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            chat_id: 0,
+            title: None,
+            enabled: 1,
+            added_by: 0,
+            mode: "default".to_string(),
+            ai_enabled: 0,
+            cleaning_level: default_chat_cleaning_level(),
+            kind: default_chat_kind(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct CustomRule {
+    pub id: i64,
+    pub user_id: i64,
+    pub pattern: String, // Regex or string to match in query params
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct CleanedLink {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: Option<i64>,
+    pub bot_id: Option<i64>,
+    pub original_url: String,
+    pub cleaned_url: String,
+    pub provider_name: Option<String>,
+    pub domain: Option<String>,
+    pub timestamp: i64,
+    pub deleted_at: Option<i64>,
+    pub cleaning_source: Option<String>, // "rules", "ai", "rules+ai" or "dry_run"
+    /// JSON array of the URLs a shortened link redirected through, from the
+    /// shared link to its destination; `None` when it was not expanded.
+    #[serde(default)]
+    pub redirect_chain: Option<String>,
+}
+
+/// Settings of a Fediverse account that mentions the Mastodon bot, keyed by
+/// its `user@instance` handle.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct FediverseAccount {
+    pub acct: String,
+    pub enabled: i32,
+    pub ignored_domains: String, // Comma-separated list
+    pub cleaned_count: i64,
+}
+
+impl FediverseAccount {
+    pub fn new(acct: &str) -> Self {
+        Self {
+            acct: acct.to_string(),
+            enabled: 1,
+            ignored_domains: String::new(),
+            cleaned_count: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled != 0
+    }
+}
+
+/// An admin-provided replacement for a built-in i18n string. `lang` is a
+/// locale code, or `*` for every locale.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct TranslationOverride {
+    pub lang: String,
+    pub key: String,
+    pub value: String,
+}
+
+/// Subscription tier deciding a user's limits (AI quota, custom rules,
+/// history retention).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Plan {
+    #[default]
+    Free,
+    Premium,
+    /// No limits at all; meant as the default plan of self-hosted instances.
+    Unlimited,
+}
+
+impl Plan {
+    pub const ALL: [Plan; 3] = [Plan::Free, Plan::Premium, Plan::Unlimited];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Free => "free",
+            Self::Premium => "premium",
+            Self::Unlimited => "unlimited",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|plan| plan.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// A plan granted to a user, optionally until `expires_at`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct Entitlement {
+    pub user_id: i64,
+    pub plan: String, // "free", "premium" or "unlimited"
+    pub expires_at: Option<i64>,
+    pub updated_at: i64,
+}
+
+/// A provider defined by the deployment admin, compiled into the engine next
+/// to the upstream ClearURLs providers for every user.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct GlobalProvider {
+    pub name: String,
+    /// The provider object in ClearURLs format, as JSON.
+    pub definition: String,
+    pub enabled: i32,
+    pub updated_at: i64,
+}
+
+/// Channel through which a configuration change was made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Bot,
+    Web,
+    System,
+}
+
+impl Transport {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Bot => "bot",
+            Self::Web => "web",
+            Self::System => "system",
+        }
+    }
+}
+
+/// Who performed a configuration change, recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Actor {
+    pub user_id: i64,
+    pub transport: Transport,
+}
+
+impl Actor {
+    pub fn bot(user_id: i64) -> Self {
+        Self {
+            user_id,
+            transport: Transport::Bot,
+        }
+    }
+
+    pub fn web(user_id: i64) -> Self {
+        Self {
+            user_id,
+            transport: Transport::Web,
+        }
+    }
+
+    pub fn system() -> Self {
+        Self {
+            user_id: 0,
+            transport: Transport::System,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+pub struct AuditEvent {
+    pub id: i64,
+    pub actor_id: i64,
+    pub transport: String,
+    pub action: String, // "user_config.save", "chat_config.save", "custom_rule.add", ...
+    pub target_id: i64, // user or chat the change applies to
+    pub details: Option<String>, // JSON snapshot of the new value
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configs_stored_before_newer_fields_get_their_defaults() {
+        let user: UserConfig = serde_json::from_value(serde_json::json!({
+            "user_id": 1, "enabled": 1, "ai_enabled": 0, "mode": "reply",
+            "ignored_domains": "", "cleaned_count": 3, "language": "it",
+        }))
+        .unwrap();
+        assert_eq!(user.theme, "auto");
+        assert_eq!(user.cleaning_level, "standard");
+        assert!(user.keeps_history());
+        assert!(!user.has_public_stats() && !user.wants_digest());
+
+        let chat: ChatConfig = serde_json::from_value(serde_json::json!({
+            "chat_id": -1, "title": null, "enabled": 1, "added_by": 0, "mode": "default",
+        }))
+        .unwrap();
+        assert_eq!(chat.cleaning_level, "default");
+        assert_eq!(chat.kind, "group");
+        assert!(chat.is_enabled() && !chat.is_ai_enabled());
+    }
+}
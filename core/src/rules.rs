@@ -0,0 +1,944 @@
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use url::Url;
+
+/// Small curated rule set compiled into the binary for offline use.
+const EMBEDDED_RULES: &str = include_str!("../data/embedded_rules.json");
+
+/// Rule passes applied before a URL is considered stable.
+const MAX_ITERATIONS: usize = 5;
+
+/// Common trackers stripped even when no provider rule covers them
+/// (e.g. Google Search `gs_lcrp`, `oq`, `client`, ...).
+const AGGRESSIVE_TRACKERS: &[&str] = &[
+    "gs_lcrp",
+    "oq",
+    "sourceid",
+    "client",
+    "bih",
+    "biw",
+    "ved",
+    "ei",
+    "iflsig",
+    "adgrpid",
+    "nw",
+    "matchtype",
+];
+
+/// Parameter name prefixes and suffixes that [`CleaningLevel::Strict`]
+/// treats as tracking even without a provider rule.
+const TRACKER_PREFIXES: &[&str] = &["utm_", "mc_", "pk_", "hsa_", "_hs", "mtm_", "trk"];
+const TRACKER_SUFFIXES: &[&str] = &["clid", "clkid", "_ref", "_source"];
+const TRACKER_NAMES: &[&str] = &[
+    "ref", "ref_src", "ref_url", "si", "igshid", "igsh", "feature", "share_id", "campaign",
+    "cmpid", "s_cid", "spm", "scm",
+];
+
+/// Hosts (and their subdomains) where [`CleaningLevel::Strict`] keeps only
+/// the query parameters known to change the page.
+const QUERY_ALLOWLIST: &[(&str, &[&str])] = &[
+    ("youtube.com", &["v", "t", "list", "index", "search_query"]),
+    ("youtu.be", &["t", "list"]),
+    ("google.com", &["q", "tbm", "start", "hl"]),
+    ("twitter.com", &[]),
+    ("x.com", &[]),
+    ("instagram.com", &[]),
+    ("tiktok.com", &[]),
+    ("linkedin.com", &[]),
+    ("open.spotify.com", &[]),
+];
+
+/// How far cleaning goes beyond the provider rules.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CleaningLevel {
+    /// Provider rules and the user's own rules only.
+    Conservative,
+    /// Also strips [`AGGRESSIVE_TRACKERS`] and truncates GitHub deep links.
+    #[default]
+    Standard,
+    /// Also strips parameters named like trackers, and everything but the
+    /// known functional parameters on allowlisted sites.
+    Strict,
+}
+
+impl CleaningLevel {
+    pub const ALL: [CleaningLevel; 3] = [
+        CleaningLevel::Conservative,
+        CleaningLevel::Standard,
+        CleaningLevel::Strict,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Conservative => "conservative",
+            Self::Standard => "standard",
+            Self::Strict => "strict",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(non_snake_case)]
+struct RawProvider {
+    #[serde(default)]
+    urlPattern: String,
+    #[serde(default)]
+    rules: Vec<String>,
+    #[serde(default)]
+    exceptions: Vec<String>,
+    #[serde(default)]
+    rawRules: Vec<String>,
+    #[serde(default)]
+    redirections: Vec<String>,
+    #[serde(default)]
+    referralMarketing: Vec<String>,
+    #[serde(default)]
+    forceRedirection: bool,
+    #[serde(default)]
+    pathRules: Vec<RawPathRule>,
+}
+
+/// A path segment to drop, e.g. `{"pattern": "^ref="}` for Amazon's
+/// `/ref=sr_1_1` or `{"pattern": "^share$", "trailing": true}`.
+#[derive(Debug, Deserialize)]
+struct RawPathRule {
+    pattern: String,
+    /// Only match the last segment of the path.
+    #[serde(default)]
+    trailing: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClearUrlsData {
+    providers: HashMap<String, RawProvider>,
+}
+
+#[derive(Clone)]
+struct CompiledProvider {
+    name: String,
+    url_pattern: Regex,
+    rules: Vec<Regex>,
+    exceptions: Vec<Regex>,
+    raw_rules: Vec<Regex>,
+    redirections: Vec<Regex>,
+    referral_marketing: Vec<Regex>,
+    path_rules: Vec<PathRule>,
+    _force_redirection: bool,
+}
+
+#[derive(Clone)]
+struct PathRule {
+    segment: Regex,
+    trailing: bool,
+}
+
+/// What one [`Rules::sanitize_with_stats`] run did.
+#[derive(Debug, Default, Clone)]
+pub struct PassStats {
+    /// Rule passes run; zero when the text was not a URL or was ignored.
+    pub passes: usize,
+    pub matched: Vec<String>,
+    /// Query parameter names stripped, including those of nested URLs.
+    pub removed_params: Vec<String>,
+}
+
+impl PassStats {
+    fn note_removed(&mut self, key: &str) {
+        if !self.removed_params.iter().any(|k| k == key) {
+            self.removed_params.push(key.to_string());
+        }
+    }
+}
+
+/// Why [`Rules::validate_provider`] rejected a definition.
+#[derive(Debug)]
+pub enum ProviderError {
+    Json(serde_json::Error),
+    MissingUrlPattern,
+    /// The pattern and the regex compiler's message.
+    InvalidPattern(String, String),
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "invalid provider: {}", e),
+            Self::MissingUrlPattern => write!(f, "urlPattern is required"),
+            Self::InvalidPattern(pattern, e) => write!(f, "invalid pattern {:?}: {}", pattern, e),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// A compiled ClearURLs rule set.
+#[derive(Clone, Default)]
+pub struct Rules {
+    providers: Vec<CompiledProvider>,
+    /// Hosts (and their subdomains) whose fragments are never touched.
+    fragment_exceptions: Vec<String>,
+    /// Providers whose `pathRules` are switched off.
+    disabled_path_rules: Vec<String>,
+}
+
+impl Rules {
+    /// Compiles the providers of a ClearURLs `data.min.json` document.
+    /// Providers or patterns that fail to compile are skipped.
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        let data: ClearUrlsData = serde_json::from_str(json)?;
+
+        let mut providers = Vec::new();
+        for (name, provider) in data.providers {
+            if provider.urlPattern.is_empty() {
+                continue;
+            }
+
+            let url_pattern = match Regex::new(&provider.urlPattern) {
+                Ok(r) => r,
+                Err(_) => continue,
+            };
+
+            let compile_list = |list: &[String]| -> Vec<Regex> {
+                list.iter().filter_map(|s| Regex::new(s).ok()).collect()
+            };
+
+            providers.push(CompiledProvider {
+                name,
+                url_pattern,
+                rules: compile_list(&provider.rules),
+                exceptions: compile_list(&provider.exceptions),
+                raw_rules: compile_list(&provider.rawRules),
+                redirections: compile_list(&provider.redirections),
+                referral_marketing: compile_list(&provider.referralMarketing),
+                path_rules: provider
+                    .pathRules
+                    .iter()
+                    .filter_map(|rule| {
+                        Some(PathRule {
+                            segment: Regex::new(&rule.pattern).ok()?,
+                            trailing: rule.trailing,
+                        })
+                    })
+                    .collect(),
+                _force_redirection: provider.forceRedirection,
+            });
+        }
+
+        sort_providers(&mut providers);
+        Ok(Self {
+            providers,
+            fragment_exceptions: Vec::new(),
+            disabled_path_rules: Vec::new(),
+        })
+    }
+
+    /// Checks a single provider definition (the value of one entry under
+    /// `providers`) without skipping anything: every pattern must compile.
+    /// Meant for hand-written providers, where a silently dropped rule would
+    /// go unnoticed.
+    pub fn validate_provider(definition: &serde_json::Value) -> Result<(), ProviderError> {
+        let provider = RawProvider::deserialize(definition).map_err(ProviderError::Json)?;
+        if provider.urlPattern.is_empty() {
+            return Err(ProviderError::MissingUrlPattern);
+        }
+        let patterns = std::iter::once(&provider.urlPattern)
+            .chain(&provider.rules)
+            .chain(&provider.exceptions)
+            .chain(&provider.rawRules)
+            .chain(&provider.redirections)
+            .chain(&provider.referralMarketing)
+            .chain(provider.pathRules.iter().map(|rule| &rule.pattern));
+        for pattern in patterns {
+            Regex::new(pattern)
+                .map_err(|e| ProviderError::InvalidPattern(pattern.clone(), e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Adds the providers of `other` (e.g. admin-defined supplements) to
+    /// this set. Both apply when a name exists in each.
+    pub fn extend(&mut self, other: &Rules) {
+        self.providers.extend(other.providers.iter().cloned());
+        sort_providers(&mut self.providers);
+    }
+
+    /// Switches off the path rules of these providers (by name).
+    pub fn set_disabled_path_rules(&mut self, providers: Vec<String>) {
+        self.disabled_path_rules = providers;
+    }
+
+    /// Leaves the fragment of URLs on these hosts alone, for single-page
+    /// apps that keep state in `#key=value` pairs.
+    pub fn set_fragment_exceptions(&mut self, hosts: Vec<String>) {
+        self.fragment_exceptions = hosts;
+    }
+
+    fn fragment_excepted(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.fragment_exceptions.iter().any(|d| {
+            host == d
+                || host
+                    .strip_suffix(d.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+
+    /// The embedded rule set: global trackers plus the most common sites
+    /// (Amazon, Google, YouTube, social networks). Far smaller than the full
+    /// ClearURLs list, but needs no download.
+    pub fn embedded() -> Self {
+        Self::from_json(EMBEDDED_RULES).unwrap_or_default()
+    }
+
+    /// Number of compiled providers.
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Cleans `text` and returns the cleaned URL with the provider credited
+    /// for it, or `None` when nothing changed. Parameters containing one of
+    /// `custom_params` are dropped first; hosts containing one of
+    /// `ignored_domains` are left alone.
+    pub fn sanitize(
+        &self,
+        text: &str,
+        custom_params: &[String],
+        ignored_domains: &[String],
+    ) -> Option<(String, String)> {
+        self.sanitize_with_stats(
+            text,
+            custom_params,
+            ignored_domains,
+            &mut PassStats::default(),
+        )
+    }
+
+    /// [`Rules::sanitize`], also reporting passes, matched providers and
+    /// removed parameters.
+    pub fn sanitize_with_stats(
+        &self,
+        text: &str,
+        custom_params: &[String],
+        ignored_domains: &[String],
+        stats: &mut PassStats,
+    ) -> Option<(String, String)> {
+        self.sanitize_at_level(
+            text,
+            custom_params,
+            ignored_domains,
+            CleaningLevel::default(),
+            stats,
+        )
+    }
+
+    /// [`Rules::sanitize_with_stats`] going as far as `level`: provider and
+    /// custom rules always run, the fallbacks only from the matching level.
+    pub fn sanitize_at_level(
+        &self,
+        text: &str,
+        custom_params: &[String],
+        ignored_domains: &[String],
+        level: CleaningLevel,
+        stats: &mut PassStats,
+    ) -> Option<(String, String)> {
+        let mut url_to_parse = text.to_string();
+        if !url_to_parse.contains("://") && !url_to_parse.starts_with("mailto:") {
+            url_to_parse = format!("http://{}", url_to_parse);
+        }
+
+        let mut url = Url::parse(&url_to_parse).ok()?;
+        if let Some(host) = url.host_str() {
+            if ignored_domains.iter().any(|d| host.contains(d)) {
+                tracing::debug!(host = %host, "URL host is in ignored domains");
+                return None;
+            }
+        }
+
+        let mut provider_name = String::from("Custom/Other");
+        let github_changed = level != CleaningLevel::Conservative && clean_github_url(&mut url);
+        if github_changed {
+            provider_name = "GitHub (Repo Root)".to_string();
+        }
+
+        // 1. Apply Custom User Rules FIRST
+        let custom_changed = retain_query(&mut url, |key| {
+            match custom_params.iter().find(|p| key.contains(p.as_str())) {
+                Some(pattern) => {
+                    tracing::debug!(param = %key, rule = %pattern, "Custom rule matched");
+                    stats.note_removed(key);
+                    false
+                }
+                None => true,
+            }
+        });
+
+        // 2. Identify Provider
+        if let Some(p) = self.providers.iter().find(|p| p.url_pattern.is_match(text)) {
+            provider_name = p.name.clone();
+            tracing::debug!(provider = %provider_name, "Provider identified");
+        }
+
+        // 3. Apply Extended Algorithm
+        let (mut changed, passes) = self.clean_url(&mut url, stats);
+        stats.passes = passes;
+
+        // 4. Aggressive Fallback for common trackers not in the ruleset
+        if level != CleaningLevel::Conservative
+            && retain_query(&mut url, |key| {
+                let keep = !AGGRESSIVE_TRACKERS.contains(&key);
+                if !keep {
+                    tracing::debug!(param = %key, "Aggressive tracker stripped");
+                    stats.note_removed(key);
+                }
+                keep
+            })
+        {
+            changed = true;
+        }
+
+        // 5. Strict: name heuristics, and allowlisted parameters only on
+        // the sites that have a list
+        if level == CleaningLevel::Strict {
+            let allowlist = url.host_str().and_then(query_allowlist);
+            if retain_query(&mut url, |key| {
+                let keep = match allowlist {
+                    Some(allowed) => allowed.contains(&key),
+                    None => !looks_like_tracker(key),
+                };
+                if !keep {
+                    tracing::debug!(param = %key, "Strict mode stripped parameter");
+                    stats.note_removed(key);
+                }
+                keep
+            }) {
+                changed = true;
+            }
+        }
+
+        if changed || custom_changed || github_changed {
+            return Some((url.to_string(), provider_name));
+        }
+        None
+    }
+
+    /// Applies the provider rules to `url` until it stops changing. Returns
+    /// whether the URL changed and how many rule passes ran; nested URLs
+    /// (parameter values, fragments) are cleaned recursively. Matched
+    /// providers and removed parameters are added to `stats`.
+    pub fn clean_url(&self, url: &mut Url, stats: &mut PassStats) -> (bool, usize) {
+        let mut changed = false;
+        let mut iterations = 0;
+        let mut passes = 0;
+
+        while iterations < MAX_ITERATIONS {
+            passes += 1;
+            let url_str = url.to_string();
+            let mut current_iteration_changed = false;
+
+            // 1. Match specific providers AND the global/generic one if it exists
+            for provider in self.providers.iter() {
+                // "generic" provider usually matches everything or has a catch-all pattern
+                if !(provider.url_pattern.is_match(&url_str) || provider.name == "generic") {
+                    continue;
+                }
+                if provider.exceptions.iter().any(|e| e.is_match(&url_str)) {
+                    continue;
+                }
+                if !stats.matched.contains(&provider.name) {
+                    stats.matched.push(provider.name.clone());
+                }
+
+                // Handle redirections
+                let redirected = provider.redirections.iter().find_map(|re| {
+                    let caps = re.captures(&url_str)?;
+                    Url::parse(caps.get(1)?.as_str()).ok()
+                });
+                if let Some(new_url) = redirected {
+                    *url = new_url;
+                    current_iteration_changed = true;
+                    changed = true;
+                    continue;
+                }
+
+                // Handle Query Parameters
+                if url.query().is_some() {
+                    let query_pairs: Vec<(String, String)> =
+                        url.query_pairs().into_owned().collect();
+                    let mut new_query = url::form_urlencoded::Serializer::new(String::new());
+                    let mut params_removed = false;
+                    let mut any_kept = false;
+
+                    for (key, mut value) in query_pairs {
+                        let keep = !provider.rules.iter().any(|r| r.is_match(&key))
+                            && !provider.referral_marketing.iter().any(|r| r.is_match(&key));
+
+                        if keep {
+                            // Recursive cleaning: check if value is a URL
+                            if value.starts_with("http") {
+                                if let Ok(mut inner_url) = Url::parse(&value) {
+                                    if self.clean_url(&mut inner_url, stats).0 {
+                                        value = inner_url.to_string();
+                                        changed = true;
+                                    }
+                                }
+                            }
+                            new_query.append_pair(&key, &value);
+                            any_kept = true;
+                        } else {
+                            stats.note_removed(&key);
+                            params_removed = true;
+                        }
+                    }
+
+                    if params_removed {
+                        changed = true;
+                        current_iteration_changed = true;
+                        if any_kept {
+                            url.set_query(Some(&new_query.finish()));
+                        } else {
+                            url.set_query(None);
+                        }
+                    }
+                }
+
+                // Handle Fragment (hash) - some tracking is after #
+                if !self.fragment_excepted(url) && clean_fragment(url, provider, stats) {
+                    changed = true;
+                    current_iteration_changed = true;
+                }
+
+                // Path segments
+                if !self.disabled_path_rules.contains(&provider.name)
+                    && clean_path(url, &provider.path_rules)
+                {
+                    changed = true;
+                    current_iteration_changed = true;
+                }
+
+                // Raw rules
+                let mut intermediate_url_str = url.to_string();
+                let mut raw_changed = false;
+                for raw in &provider.raw_rules {
+                    let new_str = raw.replace_all(&intermediate_url_str, "");
+                    if new_str != intermediate_url_str {
+                        intermediate_url_str = new_str.to_string();
+                        raw_changed = true;
+                    }
+                }
+
+                if raw_changed {
+                    if let Ok(new_url) = Url::parse(&intermediate_url_str) {
+                        *url = new_url;
+                        changed = true;
+                        current_iteration_changed = true;
+                    }
+                }
+            }
+
+            if !current_iteration_changed {
+                break;
+            }
+            iterations += 1;
+        }
+        (changed, passes)
+    }
+}
+
+/// Removes the provider's tracking keys from fragment parameters, either a
+/// bare `#utm_source=x&a=b` or the query of an SPA route such as
+/// `#/item?utm_source=x`. Other parameters keep their exact encoding and
+/// fragments that don't look like parameters (`#section`, `#/path`) are left
+/// alone. Returns whether anything was removed.
+fn clean_fragment(url: &mut Url, provider: &CompiledProvider, stats: &mut PassStats) -> bool {
+    let Some(fragment) = url.fragment() else {
+        return false;
+    };
+    let (route, params) = match fragment.split_once('?') {
+        Some((route, params)) => (Some(route), params),
+        None if fragment.split('&').all(|p| {
+            p.split_once('=')
+                .is_some_and(|(key, _)| !key.is_empty() && !key.contains('/'))
+        }) =>
+        {
+            (None, fragment)
+        }
+        None => return false,
+    };
+
+    let mut removed = false;
+    let kept: Vec<&str> = params
+        .split('&')
+        .filter(|pair| {
+            let raw_key = pair.split_once('=').map_or(*pair, |(key, _)| key);
+            let key = url::form_urlencoded::parse(raw_key.as_bytes())
+                .next()
+                .map(|(key, _)| key.into_owned())
+                .unwrap_or_default();
+            let tracking = !key.is_empty()
+                && (provider.rules.iter().any(|r| r.is_match(&key))
+                    || provider.referral_marketing.iter().any(|r| r.is_match(&key)));
+            if tracking {
+                stats.note_removed(&key);
+                removed = true;
+            }
+            !tracking
+        })
+        .collect();
+    if !removed {
+        return false;
+    }
+
+    let params = kept.join("&");
+    let fragment = match route {
+        Some(route) if params.is_empty() => route.to_string(),
+        Some(route) => format!("{}?{}", route, params),
+        None => params,
+    };
+    url.set_fragment((!fragment.is_empty()).then_some(fragment.as_str()));
+    true
+}
+
+/// Deterministic order, with the catch-all providers last so a site specific
+/// provider gets the credit when both match.
+fn sort_providers(providers: &mut [CompiledProvider]) {
+    providers.sort_by_key(|p| {
+        (
+            p.name == "globalRules" || p.name == "generic",
+            p.name.clone(),
+        )
+    });
+}
+
+/// Drops the path segments matching one of `rules`. Returns whether the
+/// path changed.
+fn clean_path(url: &mut Url, rules: &[PathRule]) -> bool {
+    if rules.is_empty() {
+        return false;
+    }
+    let Some(segments) = url.path_segments() else {
+        return false;
+    };
+    let segments: Vec<String> = segments.map(str::to_string).collect();
+    // A trailing slash yields an empty last segment; "trailing" rules look
+    // at the last real one.
+    let last = segments.iter().rposition(|s| !s.is_empty());
+    let mut removed = false;
+    let kept: Vec<&str> = segments
+        .iter()
+        .enumerate()
+        .filter(|(i, segment)| {
+            let drop = !segment.is_empty()
+                && rules
+                    .iter()
+                    .any(|r| (!r.trailing || Some(*i) == last) && r.segment.is_match(segment));
+            removed |= drop;
+            !drop
+        })
+        .map(|(_, segment)| segment.as_str())
+        .collect();
+    if removed {
+        url.set_path(&format!("/{}", kept.join("/")));
+    }
+    removed
+}
+
+/// Whether a parameter name looks like tracking without any rule for it.
+fn looks_like_tracker(key: &str) -> bool {
+    let key = key.to_ascii_lowercase();
+    TRACKER_NAMES.contains(&key.as_str())
+        || TRACKER_PREFIXES.iter().any(|p| key.starts_with(p))
+        || TRACKER_SUFFIXES.iter().any(|s| key.ends_with(s))
+}
+
+/// The parameters worth keeping on `host`, if it has an allowlist.
+fn query_allowlist(host: &str) -> Option<&'static [&'static str]> {
+    let host = host.trim_start_matches("www.");
+    QUERY_ALLOWLIST
+        .iter()
+        .find(|(domain, _)| {
+            host == *domain
+                || host
+                    .strip_suffix(domain)
+                    .is_some_and(|rest| rest.ends_with('.'))
+        })
+        .map(|(_, allowed)| *allowed)
+}
+
+/// Truncates GitHub deep links (e.g. `/owner/repo/blob/main/file.ext`) to
+/// the repository root.
+fn clean_github_url(url: &mut Url) -> bool {
+    if url.host_str() != Some("github.com") {
+        return false;
+    }
+    let path_segments: Vec<String> = url
+        .path_segments()
+        .map(|s| s.map(String::from).collect())
+        .unwrap_or_default();
+
+    if path_segments.len() > 2 {
+        let new_path = format!("/{}/{}", path_segments[0], path_segments[1]);
+        if url.path() != new_path {
+            url.set_path(&new_path);
+            url.set_query(None);
+            url.set_fragment(None);
+            return true;
+        }
+    }
+    false
+}
+
+/// Drops the query parameters whose key fails `keep`. Returns whether any
+/// parameter was removed.
+fn retain_query(url: &mut Url, mut keep: impl FnMut(&str) -> bool) -> bool {
+    if url.query().is_none() {
+        return false;
+    }
+    let query_pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+    let mut new_query = url::form_urlencoded::Serializer::new(String::new());
+    let mut removed = false;
+    let mut any_kept = false;
+
+    for (key, value) in query_pairs {
+        if keep(&key) {
+            new_query.append_pair(&key, &value);
+            any_kept = true;
+        } else {
+            removed = true;
+        }
+    }
+
+    if removed {
+        if any_kept {
+            url.set_query(Some(&new_query.finish()));
+        } else {
+            url.set_query(None);
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"{"providers":{"generic":{"urlPattern":".*","rules":["utm_.*"]}}}"#;
+
+    #[test]
+    fn test_from_json_skips_empty_patterns() {
+        let rules =
+            Rules::from_json(r#"{"providers":{"a":{"urlPattern":""},"b":{"urlPattern":"x"}}}"#)
+                .unwrap();
+        assert_eq!(rules.len(), 1);
+    }
+
+    #[test]
+    fn test_recursive_and_aggressive_cleaning() {
+        let rules = Rules::from_json(RULES).unwrap();
+        let mut stats = PassStats::default();
+        let (cleaned, _) = rules
+            .sanitize_with_stats(
+                "https://example.com/?next=https://a.com/?utm_source=x&utm_medium=y&ved=1&id=2",
+                &[],
+                &[],
+                &mut stats,
+            )
+            .unwrap();
+        assert_eq!(
+            cleaned,
+            "https://example.com/?next=https%3A%2F%2Fa.com%2F&id=2"
+        );
+        assert!(stats.passes >= 1);
+        assert!(stats.matched.contains(&"generic".to_string()));
+        assert_eq!(
+            stats.removed_params,
+            vec!["utm_source", "utm_medium", "ved"]
+        );
+    }
+
+    #[test]
+    fn test_fragment_cleaning() {
+        let mut rules = Rules::from_json(RULES).unwrap();
+        let clean = |rules: &Rules, url: &str| {
+            rules
+                .sanitize(url, &[], &[])
+                .map(|(cleaned, _)| cleaned)
+                .unwrap_or_else(|| url.to_string())
+        };
+        assert_eq!(
+            clean(&rules, "https://a.com/#utm_source=x&tab=2"),
+            "https://a.com/#tab=2"
+        );
+        assert_eq!(
+            clean(&rules, "https://a.com/#/item/1?utm_medium=x&q=a%20b"),
+            "https://a.com/#/item/1?q=a%20b"
+        );
+        assert_eq!(
+            clean(&rules, "https://a.com/#/item?utm_medium=x"),
+            "https://a.com/#/item"
+        );
+        // Not parameters, or no known tracking key: untouched.
+        for url in [
+            "https://a.com/#section",
+            "https://a.com/#/route/utm_source=x",
+            "https://a.com/#!/share=1",
+            "https://a.com/#access_token=abc&state=xyz",
+        ] {
+            assert_eq!(clean(&rules, url), url);
+        }
+
+        rules.set_fragment_exceptions(vec!["a.com".to_string()]);
+        let url = "https://app.a.com/#utm_source=x";
+        assert_eq!(clean(&rules, url), url);
+        assert_eq!(
+            clean(&rules, "https://nota.com/#utm_source=x"),
+            "https://nota.com/"
+        );
+    }
+
+    #[test]
+    fn test_path_rules() {
+        let mut rules = Rules::from_json(
+            r#"{"providers":{"shop":{"urlPattern":"shop\\.com","pathRules":[
+                {"pattern":"^ref="},
+                {"pattern":"^share$","trailing":true}
+            ]}}}"#,
+        )
+        .unwrap();
+        let clean = |rules: &Rules, url: &str| rules.sanitize(url, &[], &[]).map(|(c, _)| c);
+        assert_eq!(
+            clean(&rules, "https://shop.com/dp/1/ref=sr_1_1/share?x=1").as_deref(),
+            Some("https://shop.com/dp/1?x=1")
+        );
+        assert_eq!(
+            clean(&rules, "https://shop.com/share/item/").as_deref(),
+            None
+        );
+        assert_eq!(
+            clean(&rules, "https://shop.com/item/share/").as_deref(),
+            Some("https://shop.com/item/")
+        );
+
+        rules.set_disabled_path_rules(vec!["shop".to_string()]);
+        assert_eq!(clean(&rules, "https://shop.com/dp/1/ref=sr_1_1"), None);
+    }
+
+    #[test]
+    fn test_validate_and_extend_providers() {
+        let valid = serde_json::json!({ "urlPattern": "shop\\.com", "rules": ["^aff$"] });
+        assert!(Rules::validate_provider(&valid).is_ok());
+        for invalid in [
+            serde_json::json!({ "rules": ["^aff$"] }),
+            serde_json::json!({ "urlPattern": "shop", "rules": ["("] }),
+            serde_json::json!({ "urlPattern": "shop", "rules": "aff" }),
+        ] {
+            assert!(Rules::validate_provider(&invalid).is_err());
+        }
+
+        let mut rules = Rules::from_json(RULES).unwrap();
+        let url = "https://shop.com/item?aff=1&id=2";
+        assert!(rules.sanitize(url, &[], &[]).is_none());
+        let supplement =
+            Rules::from_json(&serde_json::json!({ "providers": { "shop": valid } }).to_string())
+                .unwrap();
+        rules.extend(&supplement);
+        assert_eq!(rules.len(), 2);
+        let (cleaned, provider) = rules.sanitize(url, &[], &[]).unwrap();
+        assert_eq!(cleaned, "https://shop.com/item?id=2");
+        assert_eq!(provider, "shop");
+    }
+
+    #[test]
+    fn test_embedded_rules() {
+        let rules = Rules::embedded();
+        assert!(!rules.is_empty());
+        let (cleaned, provider) = rules
+            .sanitize(
+                "https://www.amazon.it/dp/B0TEST/ref=sr_1_1?keywords=x&qid=1&utm_source=y",
+                &[],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(cleaned, "https://www.amazon.it/dp/B0TEST?keywords=x");
+        assert_eq!(provider, "amazon");
+    }
+
+    #[test]
+    fn test_custom_params_and_ignored_domains() {
+        let rules = Rules::default();
+        let custom = vec!["ref".to_string()];
+        let (cleaned, provider) = rules
+            .sanitize("https://shop.com/item?ref=abc&id=1", &custom, &[])
+            .unwrap();
+        assert_eq!(cleaned, "https://shop.com/item?id=1");
+        assert_eq!(provider, "Custom/Other");
+        assert!(rules
+            .sanitize(
+                "https://shop.com/item?ref=abc",
+                &custom,
+                &["shop.com".to_string()]
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn test_cleaning_levels() {
+        let rules = Rules::from_json(RULES).unwrap();
+        let clean = |url: &str, level| {
+            rules
+                .sanitize_at_level(url, &[], &[], level, &mut PassStats::default())
+                .map(|(cleaned, _)| cleaned)
+        };
+        let url = "https://example.com/?utm_source=x&ved=1&fbclid=2&id=3";
+        assert_eq!(
+            clean(url, CleaningLevel::Conservative).as_deref(),
+            Some("https://example.com/?ved=1&fbclid=2&id=3")
+        );
+        assert_eq!(
+            clean(url, CleaningLevel::Standard).as_deref(),
+            Some("https://example.com/?fbclid=2&id=3")
+        );
+        assert_eq!(
+            clean(url, CleaningLevel::Strict).as_deref(),
+            Some("https://example.com/?id=3")
+        );
+        assert_eq!(
+            clean(
+                "https://github.com/o/r/blob/main/a.rs",
+                CleaningLevel::Conservative
+            ),
+            None
+        );
+
+        // Allowlisted sites keep only their functional parameters.
+        assert_eq!(
+            clean(
+                "https://m.youtube.com/watch?v=abc&pp=xyz&t=42",
+                CleaningLevel::Strict
+            )
+            .as_deref(),
+            Some("https://m.youtube.com/watch?v=abc&t=42")
+        );
+        assert_eq!(
+            clean(
+                "https://notyoutube.com/watch?v=abc&pp=xyz",
+                CleaningLevel::Strict
+            ),
+            None
+        );
+        assert_eq!(CleaningLevel::parse(" Strict"), Some(CleaningLevel::Strict));
+        assert_eq!(CleaningLevel::parse("max"), None);
+    }
+}
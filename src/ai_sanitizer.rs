@@ -1,70 +1,714 @@
-use crate::config::Config;
+use crate::config::{Config, SharedConfig};
+use crate::db::Db;
 use anyhow::{anyhow, Result};
+use regex::Regex;
 use reqwest::Client;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
 use tracing::debug;
 
+/// Consecutive failed calls after which AI cleaning is paused.
+const BREAKER_THRESHOLD: u32 = 5;
+/// How long AI cleaning stays paused once the breaker opens.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+/// Base delay of the exponential backoff between retries.
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(250);
+/// Upper bound for a server-provided `Retry-After`.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Exponential backoff before retry number `retries + 1`. Saturates instead
+/// of overflowing for large `AI_MAX_RETRIES` values; the caller caps it at
+/// `MAX_RETRY_DELAY` anyway.
+fn backoff_delay(retries: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(retries.min(10)))
+}
+
+/// Results below this confidence are treated as "no change".
+const MIN_CONFIDENCE: f64 = 0.5;
+
+/// Hosts whose URLs carry no tracking worth an AI call.
+const KNOWN_CLEAN_HOSTS: &[&str] = &[
+    "wikipedia.org",
+    "wikimedia.org",
+    "docs.rs",
+    "crates.io",
+    "rust-lang.org",
+    "stackoverflow.com",
+    "archive.org",
+    "openstreetmap.org",
+];
+
+/// Functional query parameters that never identify a campaign or a user.
+const SAFE_PARAMS: &[&str] = &[
+    "q", "query", "search", "s", "id", "v", "p", "page", "lang", "hl", "t", "list", "index",
+    "sort", "order", "tab", "start", "limit", "offset", "format",
+];
+
+/// Bytes of a page read when looking for its metadata.
+const MAX_PAGE_BYTES: usize = 64 * 1024;
+/// Longest summary posted under a link.
+const MAX_SUMMARY_CHARS: usize = 160;
+/// Redirects followed when reading a page, each one checked again.
+const MAX_PAGE_REDIRECTS: usize = 5;
+
+static META_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"(?is)<meta\s[^>]*>"#).unwrap());
+static META_ATTR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?is)(name|property|content)\s*=\s*["']([^"']*)["']"#).unwrap());
+static TITLE_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?is)<title[^>]*>(.*?)</title>").unwrap());
+
+/// Extracts the title and description (plain or Open Graph) from an HTML head.
+fn extract_metadata(html: &str) -> Option<String> {
+    let mut title = TITLE_TAG
+        .captures(html)
+        .map(|c| c[1].trim().to_string())
+        .filter(|t| !t.is_empty());
+    let mut description = None;
+    for tag in META_TAG.find_iter(html) {
+        let mut key = None;
+        let mut content = None;
+        for attr in META_ATTR.captures_iter(tag.as_str()) {
+            match attr[1].to_lowercase().as_str() {
+                "content" => content = Some(attr[2].trim().to_string()),
+                _ => key = Some(attr[2].to_lowercase()),
+            }
+        }
+        match (key.as_deref(), content) {
+            (Some("og:title"), Some(c)) if title.is_none() => title = Some(c),
+            (Some("description") | Some("og:description"), Some(c)) if description.is_none() => {
+                description = Some(c)
+            }
+            _ => {}
+        }
+    }
+    match (title, description) {
+        (None, None) => None,
+        (title, description) => Some(format!(
+            "Title: {}\nDescription: {}",
+            title.unwrap_or_default(),
+            description.unwrap_or_default()
+        )),
+    }
+}
+
+/// Whether `ip` is reachable on the public internet: not loopback, private,
+/// shared (CGNAT), link-local, multicast or reserved, including IPv4
+/// addresses embedded in IPv6 ones.
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                || a >= 240
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(v4) = ip.to_ipv4_mapped() {
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            // NAT64 (64:ff9b::/96) reaches the IPv4 address in the last 32 bits.
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let v4 = Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32);
+                return is_public_ip(IpAddr::V4(v4));
+            }
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || (segments[0] & 0xfe00) == 0xfc00 // unique local, fc00::/7
+                || (segments[0] & 0xffc0) == 0xfe80 // link-local, fe80::/10
+                || (segments[0] & 0xffc0) == 0xfec0 // site-local, fec0::/10
+                || segments[0] == 0x2001 && segments[1] == 0x0db8) // documentation
+        }
+    }
+}
+
+/// Refuses URLs naming a local host or a non-public address. Domains also
+/// need their resolved addresses checked, see `resolve_public`.
+fn is_public_host(url: &url::Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.to_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost") && !domain.ends_with(".local")
+        }
+        Some(url::Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        None => false,
+    }
+}
+
+/// The address to connect to for `url`, provided its host and every
+/// address it resolves to are public. A domain resolving to a single
+/// private address is refused like a private literal.
+pub(crate) async fn resolve_public(url: &url::Url) -> Option<SocketAddr> {
+    if !matches!(url.scheme(), "http" | "https") || !is_public_host(url) {
+        return None;
+    }
+    let port = url.port_or_known_default()?;
+    let addrs: Vec<SocketAddr> = match url.host()? {
+        url::Host::Domain(domain) => tokio::net::lookup_host((domain, port))
+            .await
+            .ok()?
+            .collect(),
+        url::Host::Ipv4(ip) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        url::Host::Ipv6(ip) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+    };
+    let all_public = !addrs.is_empty() && addrs.iter().all(|addr| is_public_ip(addr.ip()));
+    all_public.then(|| addrs[0])
+}
+
+/// Structured answer requested from the model in JSON mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CleanResult {
+    pub cleaned_url: String,
+    #[serde(default)]
+    pub removed_params: Vec<String>,
+    #[serde(default)]
+    pub confidence: f64,
+}
+
+/// Phishing/scam likelihood of a destination, between 0 and 1.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RiskAssessment {
+    pub score: f64,
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// The JSON inside a model answer, without the Markdown code fence some
+/// models wrap it in even in JSON mode.
+fn strip_json_fence(content: &str) -> &str {
+    let content = content.trim();
+    content
+        .strip_prefix("```json")
+        .or_else(|| content.strip_prefix("```"))
+        .and_then(|c| c.strip_suffix("```"))
+        .unwrap_or(content)
+        .trim()
+}
+
+impl RiskAssessment {
+    /// Parses the model output, clamping the score into 0..=1.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut assessment: Self = serde_json::from_str(strip_json_fence(content))?;
+        assessment.score = assessment.score.clamp(0.0, 1.0);
+        Ok(assessment)
+    }
+}
+
+impl CleanResult {
+    /// Parses the model output, tolerating a surrounding Markdown code fence.
+    pub fn parse(content: &str) -> Result<Self> {
+        Ok(serde_json::from_str(strip_json_fence(content))?)
+    }
+
+    /// Rejects answers that are not a plain http(s) URL on the same host as
+    /// `original`, so explanations or redirects are never posted.
+    fn is_plausible_for(&self, original: &str) -> bool {
+        let (Ok(cleaned), Ok(original)) = (
+            url::Url::parse(&self.cleaned_url),
+            url::Url::parse(original),
+        ) else {
+            return false;
+        };
+        matches!(cleaned.scheme(), "http" | "https")
+            && cleaned.host_str() == original.host_str()
+            && self.confidence >= MIN_CONFIDENCE
+    }
+}
+
 #[derive(Clone)]
 pub struct AiEngine {
     client: Client,
-    api_key: Option<String>,
-    api_base: String,
-    model: String,
+    config: SharedConfig,
+    breaker: Arc<Mutex<Breaker>>,
+    usage: Option<Db>,
+}
+
+/// Circuit breaker skipping AI calls for a while after repeated failures, so
+/// a failing provider never delays bot replies.
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn is_open(&mut self) -> bool {
+        match self.open_until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                // Half-open: let the next call through, one more failure re-opens it
+                self.open_until = None;
+                self.consecutive_failures = BREAKER_THRESHOLD - 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn record(&mut self, success: bool) {
+        if success {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures += 1;
+        if self.consecutive_failures >= BREAKER_THRESHOLD {
+            tracing::warn!(
+                cooldown_secs = BREAKER_COOLDOWN.as_secs(),
+                "AI provider keeps failing, pausing AI cleaning"
+            );
+            self.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+        }
+    }
 }
 
 impl AiEngine {
-    pub fn new(config: &Config) -> Self {
+    /// The API key, base URL and model are read from `config` on every call,
+    /// so reloads take effect immediately.
+    pub fn new(config: SharedConfig) -> Self {
+        let proxy_url = config.load().proxy_url.clone();
+        let client = crate::http::client_builder(proxy_url.as_deref())
+            .and_then(|b| Ok(b.build()?))
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Invalid proxy for AI client, connecting directly");
+                Client::new()
+            });
         Self {
-            client: Client::new(),
-            api_key: config.ai_api_key.clone(),
-            api_base: config.ai_api_base.clone(),
-            model: config.ai_model.clone(),
+            client,
+            config,
+            breaker: Arc::new(Mutex::new(Breaker::default())),
+            usage: None,
         }
     }
 
-    pub async fn sanitize(&self, url: &str) -> Result<Option<String>> {
-        let api_key = match &self.api_key {
-            Some(key) => key,
-            None => return Ok(None),
+    /// Records per-user requests and tokens in `ai_usage` and enforces
+    /// `AI_DAILY_QUOTA`.
+    pub fn with_usage_tracking(mut self, db: Db) -> Self {
+        self.usage = Some(db);
+        self
+    }
+
+    /// Whether `user_id` still has AI requests left today. Lookup failures
+    /// allow the call rather than silently disabling AI.
+    async fn has_quota(&self, user_id: i64, config: &Config) -> bool {
+        let Some(db) = &self.usage else {
+            return true;
         };
+        let quota = crate::entitlements::user_limits(db, config, user_id)
+            .await
+            .ai_daily_quota;
+        if quota <= 0 {
+            return true;
+        }
+        match db.get_ai_usage_today(user_id).await {
+            Ok((requests, _)) => requests < quota,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read AI usage");
+                true
+            }
+        }
+    }
 
+    /// Sends a chat completion on behalf of `user_id` and returns the message
+    /// content. Each call is bounded by `AI_TIMEOUT` overall, retries 429/5xx
+    /// up to `AI_MAX_RETRIES` times with exponential backoff, and is skipped
+    /// while the breaker is open or the user is over their daily quota.
+    async fn complete(
+        &self,
+        user_id: i64,
+        messages: Value,
+        json_mode: bool,
+    ) -> Result<Option<String>> {
+        let config = self.config.load_full();
+        if !config.ai_available() {
+            return Ok(None);
+        }
+        let Some(api_key) = config.ai_api_key.clone() else {
+            return Ok(None);
+        };
+        if !self.has_quota(user_id, &config).await {
+            debug!(user_id, "AI daily quota exhausted, using rules only");
+            return Ok(None);
+        }
+        if self
+            .breaker
+            .lock()
+            .map(|mut b| b.is_open())
+            .unwrap_or(false)
+        {
+            debug!("AI circuit breaker open, skipping call");
+            return Ok(None);
+        }
+
+        let mut body = json!({
+            "model": config.ai_model,
+            "messages": messages,
+            "temperature": 0.0
+        });
+        if json_mode {
+            body["response_format"] = json!({"type": "json_object"});
+        }
+        let endpoint = format!("{}/chat/completions", config.ai_api_base);
+        let timeout = Duration::from_secs(config.ai_timeout_secs.max(1));
+
+        let started = Instant::now();
+        let attempt = async {
+            let mut retries = 0;
+            loop {
+                let response = self
+                    .client
+                    .post(&endpoint)
+                    .header("Authorization", format!("Bearer {}", api_key))
+                    .json(&body)
+                    .send()
+                    .await?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok::<Value, anyhow::Error>(response.json().await?);
+                }
+
+                let retryable = status.as_u16() == 429 || status.is_server_error();
+                if !retryable || retries >= config.ai_max_retries {
+                    let err = response.text().await.unwrap_or_default();
+                    return Err(anyhow!("AI API error ({}): {}", status, err));
+                }
+                let delay = response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok())
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| backoff_delay(retries))
+                    .min(MAX_RETRY_DELAY);
+                retries += 1;
+                debug!(status = %status, retries, delay_ms = delay.as_millis() as u64, "Retrying AI call");
+                tokio::time::sleep(delay).await;
+            }
+        };
+
+        let result = match tokio::time::timeout(timeout, attempt).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow!("AI call timed out after {}s", timeout.as_secs())),
+        };
+        if let Ok(mut breaker) = self.breaker.lock() {
+            breaker.record(result.is_ok());
+        }
+        let data = result?;
+
+        if let Some(db) = &self.usage {
+            let prompt_tokens = data["usage"]["prompt_tokens"].as_i64().unwrap_or(0);
+            let completion_tokens = data["usage"]["completion_tokens"].as_i64().unwrap_or(0);
+            let latency_ms = started.elapsed().as_millis() as i64;
+            if let Err(e) = db
+                .record_ai_usage(user_id, prompt_tokens, completion_tokens, latency_ms)
+                .await
+            {
+                tracing::warn!(error = %e, "Failed to record AI usage");
+            }
+        }
+
+        data["choices"][0]["message"]["content"]
+            .as_str()
+            .map(|content| Some(content.to_string()))
+            .ok_or_else(|| anyhow!("AI response has no message content"))
+    }
+
+    /// Cheap checks deciding whether an AI call could possibly find anything:
+    /// URLs without query or key=value fragment, on a known-clean host, or
+    /// carrying only functional parameters are skipped.
+    pub fn worth_asking(url: &str) -> bool {
+        let Ok(parsed) = url::Url::parse(url) else {
+            return false;
+        };
+        let host = parsed.host_str().unwrap_or_default().to_lowercase();
+        if KNOWN_CLEAN_HOSTS
+            .iter()
+            .any(|clean| host == *clean || host.ends_with(&format!(".{}", clean)))
+        {
+            return false;
+        }
+        let tracking_fragment = parsed.fragment().is_some_and(|f| f.contains('='));
+        let unknown_param = parsed
+            .query_pairs()
+            .any(|(key, _)| !SAFE_PARAMS.contains(&key.to_lowercase().as_str()));
+        tracking_fragment || unknown_param
+    }
+
+    /// Reads the start of the page at `url` and returns its metadata, if any.
+    /// Redirects are followed by hand so every hop is checked with
+    /// `resolve_public`, and each request connects to the address checked.
+    async fn fetch_metadata(&self, url: &str) -> Result<Option<String>> {
+        let proxy_url = self.config.load().proxy_url.clone();
+        let mut url = url::Url::parse(url)?;
+        let mut hops = 0;
+        let mut response = loop {
+            let Some(addr) = resolve_public(&url).await else {
+                debug!(url = %url, "Not fetching a non-public address");
+                return Ok(None);
+            };
+            let mut builder = crate::http::client_builder(proxy_url.as_deref())?
+                .redirect(reqwest::redirect::Policy::none())
+                .timeout(Duration::from_secs(5));
+            if let Some(url::Host::Domain(domain)) = url.host() {
+                builder = builder.resolve(domain, addr);
+            }
+            let response = builder.build()?.get(url.clone()).send().await?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|v| v.to_str().ok());
+            match location.and_then(|location| url.join(location).ok()) {
+                Some(next) if hops < MAX_PAGE_REDIRECTS => url = next,
+                _ => return Ok(None),
+            }
+            hops += 1;
+        };
+        let is_html = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("html"));
+        if !response.status().is_success() || !is_html {
+            return Ok(None);
+        }
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_PAGE_BYTES {
+                break;
+            }
+        }
+        Ok(extract_metadata(&String::from_utf8_lossy(&body)))
+    }
+
+    /// Produces a one-line description of the page behind `url` from its
+    /// title and description, or `None` when the page exposes neither.
+    pub async fn summarize(&self, user_id: i64, url: &str) -> Result<Option<String>> {
+        let Some(metadata) = self.fetch_metadata(url).await? else {
+            return Ok(None);
+        };
+        let content = self
+            .complete(
+                user_id,
+                json!([
+                    {"role": "system", "content": "You describe web pages in one short, neutral line. Never add links, markup or quotes."},
+                    {"role": "user", "content": format!("Describe this page in at most 120 characters.\n{}", metadata)}
+                ]),
+                false,
+            )
+            .await?;
+        Ok(content.and_then(|c| {
+            let line = c
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .trim_matches('"');
+            if line.is_empty() {
+                return None;
+            }
+            Some(line.chars().take(MAX_SUMMARY_CHARS).collect())
+        }))
+    }
+
+    /// Scores `url` for phishing or scam likelihood (look-alike domains,
+    /// credential harvesting, fake giveaways, ...).
+    pub async fn assess_risk(&self, user_id: i64, url: &str) -> Result<Option<RiskAssessment>> {
+        let content = self
+            .complete(
+                user_id,
+                json!([
+                    {"role": "system", "content": "You are a security analyst rating URLs for phishing and scam risk. Output only a JSON object."},
+                    {"role": "user", "content": format!(
+                        "Rate how likely this URL leads to phishing, credential harvesting or a scam. \
+                         Consider look-alike or misspelled brand domains, suspicious TLDs and login or payment lures. \
+                         Answer with a JSON object: {{\"score\": number between 0 and 1, \"reason\": short string}}.\n\
+                         URL: {}",
+                        url
+                    )}
+                ]),
+                true,
+            )
+            .await?;
+        content.map(|c| RiskAssessment::parse(&c)).transpose()
+    }
+
+    /// Asks the model for a cleaned version of `url`. Returns `None` when the
+    /// model finds nothing to remove or its answer fails validation.
+    pub async fn sanitize(&self, user_id: i64, url: &str) -> Result<Option<CleanResult>> {
+        if !Self::worth_asking(url) {
+            debug!("Skipping AI call, nothing left to clean in: {}", url);
+            return Ok(None);
+        }
         debug!("Requesting AI sanitization for: {}", url);
 
         let prompt = format!(
-            "You are a URL sanitizer. Remove all tracking parameters from the following URL. \n            Tracking parameters are things like utm_source, fbclid, gclid, etc., but also provider-specific ones. \n            Return ONLY the cleaned URL and nothing else. If the URL is already clean or no tracking is found, return the same URL. \n            URL: {}",
+            "Remove all tracking parameters from the following URL. \
+             Tracking parameters are things like utm_source, fbclid, gclid, etc., but also provider-specific ones. \
+             Answer with a JSON object: {{\"cleaned_url\": string, \"removed_params\": [string], \"confidence\": number between 0 and 1}}. \
+             If the URL is already clean, return it unchanged with an empty removed_params list.\n\
+             URL: {}",
             url
         );
 
-        let response = self.client.post(format!("{}/chat/completions", self.api_base))
-            .header("Authorization", format!("Bearer {}", api_key))
-            .json(&json!({
-                "model": self.model,
-                "messages": [
-                    {"role": "system", "content": "You are a specialized tool for cleaning URLs from tracking parameters. Output only the cleaned URL."},
+        let content = self
+            .complete(
+                user_id,
+                json!([
+                    {"role": "system", "content": "You are a specialized tool for cleaning URLs from tracking parameters. Output only a JSON object."},
                     {"role": "user", "content": prompt}
-                ],
-                "temperature": 0.0
-            }))
-            .send()
+                ]),
+                true,
+            )
             .await?;
+        let Some(content) = content else {
+            return Ok(None);
+        };
+        let result = CleanResult::parse(&content)?;
 
-        if !response.status().is_success() {
-            let err = response.text().await?;
-            return Err(anyhow!("AI API error: {}", err));
+        if !result.is_plausible_for(url) {
+            debug!(
+                confidence = result.confidence,
+                "Discarding implausible AI answer for: {}", url
+            );
+            return Ok(None);
+        }
+        if result.cleaned_url != url {
+            debug!(
+                removed = ?result.removed_params,
+                "AI cleaned URL: {} -> {}", url, result.cleaned_url
+            );
+            if let Some(db) = &self.usage {
+                if let Err(e) = db.record_ai_success(user_id).await {
+                    tracing::warn!(error = %e, "Failed to record AI usage");
+                }
+            }
+            return Ok(Some(result));
         }
 
-        let data: Value = response.json().await?;
-        let cleaned = data["choices"][0]["message"]["content"]
-            .as_str()
-            .map(|s| s.trim().to_string());
+        Ok(None)
+    }
+}
 
-        if let Some(cleaned_url) = cleaned {
-            if cleaned_url != url {
-                debug!("AI cleaned URL: {} -> {}", url, cleaned_url);
-                return Ok(Some(cleaned_url));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_answers_are_parsed_with_or_without_a_fence() {
+        let fenced = "```json\n{\"score\": 1.7, \"reason\": \"look-alike domain\"}\n```";
+        assert_eq!(
+            RiskAssessment::parse(fenced).unwrap(),
+            RiskAssessment {
+                score: 1.0,
+                reason: "look-alike domain".to_string()
             }
+        );
+        assert_eq!(RiskAssessment::parse("{\"score\": -1}").unwrap().score, 0.0);
+        assert!(RiskAssessment::parse("I think it is safe").is_err());
+
+        let plain = CleanResult::parse(" {\"cleaned_url\": \"https://a.com/\"} ").unwrap();
+        let fenced = CleanResult::parse("```\n{\"cleaned_url\": \"https://a.com/\"}\n```").unwrap();
+        assert_eq!(plain, fenced);
+        assert!(plain.removed_params.is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_without_overflowing() {
+        assert_eq!(backoff_delay(0), Duration::from_millis(250));
+        assert_eq!(backoff_delay(2), Duration::from_secs(1));
+        assert_eq!(backoff_delay(10), backoff_delay(u32::MAX));
+        assert!(backoff_delay(u32::MAX) > MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn ai_is_asked_only_about_possible_trackers() {
+        for url in [
+            "https://example.com/?ref=newsletter",
+            "https://example.com/page#utm_source=x",
+            "https://example.com/?q=rust&cid=42",
+        ] {
+            assert!(AiEngine::worth_asking(url), "{}", url);
+        }
+        for url in [
+            "https://wikipedia.org/wiki/Rust?ref=x",
+            "https://en.wikipedia.org/wiki/Rust?ref=x",
+            "https://example.com/?q=rust&page=2&LANG=en",
+            "https://example.com/page#section",
+            "https://example.com/",
+            "not a url",
+        ] {
+            assert!(!AiEngine::worth_asking(url), "{}", url);
         }
+    }
 
-        Ok(None)
+    #[test]
+    fn only_public_hosts_are_fetched() {
+        let public = |url: &str| is_public_host(&url::Url::parse(url).unwrap());
+        for url in [
+            "https://example.com/",
+            "http://93.184.216.34/",
+            "http://[2606:2800:220:1:248:1893:25c8:1946]/",
+        ] {
+            assert!(public(url), "{}", url);
+        }
+        for url in [
+            "http://localhost:8080/",
+            "http://admin.localhost/",
+            "http://printer.local/",
+            "http://127.0.0.1/",
+            "http://10.1.2.3/",
+            "http://172.16.0.1/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[fd12:3456::1]/",
+            "http://[fe80::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://[::ffff:a9fe:a9fe]/",
+            "http://[64:ff9b::a00:1]/",
+        ] {
+            assert!(!public(url), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolution_refuses_non_public_addresses() {
+        let resolve = |url: &str| {
+            let url = url::Url::parse(url).unwrap();
+            async move { resolve_public(&url).await }
+        };
+        assert_eq!(
+            resolve("http://93.184.216.34/").await,
+            Some("93.184.216.34:80".parse().unwrap())
+        );
+        assert_eq!(
+            resolve("https://[2606:2800:220:1:248:1893:25c8:1946]:8443/").await,
+            Some("[2606:2800:220:1:248:1893:25c8:1946]:8443".parse().unwrap())
+        );
+        for url in [
+            "ftp://93.184.216.34/",
+            "http://localhost/",
+            "http://127.0.0.1:3000/",
+            "http://[fc00::1]/",
+        ] {
+            assert_eq!(resolve(url).await, None, "{}", url);
+        }
     }
 }
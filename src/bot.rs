@@ -1,405 +1,5262 @@
-use crate::{ai_sanitizer::AiEngine, db::Db, i18n, sanitizer::RuleEngine};
-use regex::Regex;
+use crate::{
+    ai_sanitizer::AiEngine,
+    chat_admins::AdminCache,
+    config::{Config, SharedConfig, UpdateMode},
+    db::Db,
+    i18n,
+    messenger::{Messenger, SendOptions},
+    models::{Actor, Plan, TranslationOverride, CHAT_MODES},
+    rate_limit::RateLimiter,
+    sanitizer::RuleEngine,
+};
+use anyhow::Context;
+use clear_urls_core::CleaningLevel;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use teloxide::prelude::*;
-use teloxide::types::{MessageEntityKind, ParseMode, ReplyParameters};
+use teloxide::types::{
+    AllowedUpdate, BotCommand, CallbackQuery, ChatKind, ChatMemberUpdated, ChatPublic,
+    ChosenInlineResult, InlineKeyboardButton, InlineKeyboardMarkup, InlineQuery, InlineQueryResult,
+    InlineQueryResultArticle, InputMessageContent, InputMessageContentText, Me, MessageEntity,
+    MessageEntityKind, MessageId, MessageKind, PublicChatKind, ThreadId,
+};
+use teloxide::update_listeners::Polling;
+use teloxide::utils::command::BotCommands;
 use teloxide::utils::html;
+use teloxide::utils::render::Renderer;
+use tokio::sync::Semaphore;
 use whatlang::{detect, Lang};
 
+mod commands;
+
+/// Logs at info level under the `debug_trace` target when verbose tracing is
+/// enabled for the current user or chat, and at debug level otherwise.
+macro_rules! trace_if {
+    ($on:expr, $($arg:tt)+) => {
+        if $on {
+            tracing::info!(target: "clear_urls_bot::debug_trace", $($arg)+);
+        } else {
+            tracing::debug!($($arg)+);
+        }
+    };
+}
+
+/// Commands the bot answers. Their arguments are read from the rest of the
+/// message. Hidden ones are left out of the command menu, whose entries and
+/// descriptions come from each language's help text (see `command_menu`).
+#[derive(BotCommands, Clone, Copy, Debug, PartialEq)]
+#[command(rename_rule = "lowercase")]
+enum Command {
+    Start,
+    Help,
+    Clean,
+    Stats,
+    TopDomains,
+    ClearHistory,
+    #[command(hide)]
+    Undo,
+    AddRule,
+    Rules,
+    #[command(hide)]
+    DelRule,
+    #[command(hide)]
+    Reload,
+    #[command(hide)]
+    Debug,
+    #[command(hide)]
+    Usage,
+    #[command(hide)]
+    Translation,
+    #[command(hide)]
+    Plan,
+    #[command(hide)]
+    GlobalStats,
+    Export,
+    ForgetMe,
+    GroupStats,
+    GroupAi,
+    Level,
+    Mode,
+    Language,
+    Privacy,
+    Ignore,
+    #[command(hide)]
+    Unignore,
+    Whitelist,
+    #[command(hide)]
+    Unwhitelist,
+    Topic,
+    Settings,
+    Login,
+}
+
+impl Command {
+    /// Commands only the bot admin may use. From anyone else they are treated
+    /// as ordinary text.
+    fn is_admin_only(self) -> bool {
+        matches!(
+            self,
+            Command::Reload
+                | Command::Debug
+                | Command::Usage
+                | Command::Translation
+                | Command::Plan
+                | Command::GlobalStats
+        )
+    }
+}
+
+/// Update kinds requested from Telegram. `chat_member` is only delivered when
+/// asked for; it reports owners leaving their groups.
+const ALLOWED_UPDATES: &[AllowedUpdate] = &[
+    AllowedUpdate::Message,
+    AllowedUpdate::ChannelPost,
+    AllowedUpdate::MyChatMember,
+    AllowedUpdate::ChatMember,
+    AllowedUpdate::CallbackQuery,
+    AllowedUpdate::InlineQuery,
+    AllowedUpdate::ChosenInlineResult,
+];
+
+/// Most links offered as results of one inline query.
+const MAX_INLINE_RESULTS: usize = 5;
+
+/// Most domains one chat can ignore with /ignore.
+const MAX_CHAT_IGNORED_DOMAINS: usize = 100;
+
+/// Most members one chat can exempt with /whitelist.
+const MAX_CHAT_WHITELIST: usize = 100;
+
+/// Callback data prefix of the private /settings menu buttons, followed by
+/// the setting the button changes.
+const SETTINGS_PREFIX: &str = "settings:";
+
+/// `/start` payload of the deep link that opens a group's setup in private,
+/// followed by the chat id (`t.me/<bot>?start=group_setup_<chat_id>`).
+const GROUP_SETUP_PAYLOAD: &str = "group_setup_";
+
+/// Callback data prefix of the group setup buttons, followed by
+/// `<chat_id>:<setting>`.
+const GROUP_SETUP_PREFIX: &str = "group:";
+
+/// Callback data prefix of the "take over" button offered to administrators
+/// of a chat whose owner left, followed by the chat id.
+const CLAIM_CHAT_PREFIX: &str = "claim:";
+
+/// Callback data prefix of the button under a reaction mode hint, followed
+/// by the id of the message whose links it reveals.
+const SHOW_LINKS_PREFIX: &str = "show:";
+
+/// The reaction set in reaction mode. Telegram only accepts a fixed list of
+/// emoji as reactions, and 🧹 is not on it.
+const REACTION_EMOJI: &str = "👀";
+
+/// Returns the phishing warning reason for `url` when its score reaches the
+/// configured threshold, reusing assessments cached in the database.
+async fn risk_warning(
+    db: &Db,
+    ai: &AiEngine,
+    user_id: i64,
+    url: &str,
+    threshold: f64,
+) -> Option<String> {
+    let (score, reason) = match db.get_url_risk(url).await {
+        Ok(Some(cached)) => cached,
+        _ => {
+            let assessment = match ai.assess_risk(user_id, url).await {
+                Ok(Some(assessment)) => assessment,
+                Ok(None) => return None,
+                Err(e) => {
+                    tracing::debug!(error = %e, "Failed to score URL risk");
+                    return None;
+                }
+            };
+            if let Err(e) = db
+                .save_url_risk(url, assessment.score, &assessment.reason)
+                .await
+            {
+                tracing::warn!(error = %e, "Failed to cache URL risk");
+            }
+            (assessment.score, assessment.reason)
+        }
+    };
+    (score >= threshold).then_some(reason)
+}
+
+/// `/plan` reply: the user's effective plan and, for granted plans, when it
+/// expires.
+async fn plan_status(db: &Db, config: &Config, tr: &i18n::Translations, user_id: i64) -> String {
+    let plan = crate::entitlements::user_plan(db, config, user_id).await;
+    let mut text = tr
+        .plan_status
+        .replacen("{}", &user_id.to_string(), 1)
+        .replacen("{}", plan.as_str(), 1);
+    let expires_at = db
+        .get_entitlement(user_id)
+        .await
+        .ok()
+        .flatten()
+        .filter(|e| Plan::parse(&e.plan) == Some(plan))
+        .and_then(|e| e.expires_at);
+    if let Some(expires_at) = expires_at {
+        text.push_str(
+            &tr.plan_until
+                .replace("{}", &crate::db::format_day(expires_at / 86400)),
+        );
+    }
+    text
+}
+
+/// Whether `user_id` is the bot admin set by `ADMIN_ID`. Updates without a
+/// sender carry user id 0, which never matches, even when `ADMIN_ID` is unset.
+fn is_bot_admin(config: &Config, user_id: i64) -> bool {
+    user_id != 0 && user_id == config.admin_id
+}
+
+/// Whether `user_id` currently administers `chat_id`, from the cached
+/// `getChatAdministrators` list.
+async fn is_group_admin<M: Messenger>(
+    bot: &M,
+    admins: &AdminCache,
+    chat_id: ChatId,
+    user_id: i64,
+) -> Result<bool, teloxide::RequestError> {
+    admins
+        .is_admin(chat_id.0, user_id, || bot.chat_administrators(chat_id))
+        .await
+}
+
+/// Whether `user_id` may change a group's settings as its owner. A verified
+/// owner (set when an administrator added the bot) keeps the chat only while
+/// they still administer it. Chats without one, including those whose owner
+/// was guessed from the first link posted, go to the first chat
+/// administrator who asks.
+async fn owns_chat<M: Messenger>(
+    bot: &M,
+    admins: &AdminCache,
+    db: &Db,
+    chat_id: ChatId,
+    title: Option<&str>,
+    user_id: i64,
+) -> Result<bool, teloxide::RequestError> {
+    if user_id == 0 {
+        return Ok(false);
+    }
+    let owner = match db.get_chat_owner(chat_id.0).await {
+        Ok(owner) => owner,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read chat owner");
+            return Ok(false);
+        }
+    };
+    let is_admin = is_group_admin(bot, admins, chat_id, user_id).await?;
+    if let Some((owner_id, true)) = owner {
+        return Ok(owner_id == user_id && is_admin);
+    }
+    if !is_admin {
+        return Ok(false);
+    }
+    if let Err(e) = db
+        .set_chat_owner(chat_id.0, title, user_id, Actor::bot(user_id))
+        .await
+    {
+        tracing::error!(error = %e, "Failed to record chat owner");
+    }
+    Ok(true)
+}
+
+/// Whether `user_id` may change a group's settings (its mode, level and AI
+/// switch, and the setup that offers them): the bot admin or the group's
+/// owner, see `owns_chat`. A failed administrator lookup denies the change.
+async fn may_change_settings<M: Messenger>(
+    bot: &M,
+    admins: &AdminCache,
+    db: &Db,
+    config: &Config,
+    chat_id: ChatId,
+    title: Option<&str>,
+    user_id: i64,
+) -> bool {
+    if is_bot_admin(config, user_id) {
+        return true;
+    }
+    owns_chat(bot, admins, db, chat_id, title, user_id)
+        .await
+        .unwrap_or_else(|e| lookup_failed(chat_id, e))
+}
+
+/// Whether `user_id` may change a group's lists (ignored domains,
+/// whitelisted members and forum topics): the bot admin or any current chat
+/// administrator. A failed administrator lookup denies the change.
+async fn may_moderate<M: Messenger>(
+    bot: &M,
+    admins: &AdminCache,
+    config: &Config,
+    chat_id: ChatId,
+    user_id: i64,
+) -> bool {
+    if is_bot_admin(config, user_id) {
+        return true;
+    }
+    user_id != 0
+        && is_group_admin(bot, admins, chat_id, user_id)
+            .await
+            .unwrap_or_else(|e| lookup_failed(chat_id, e))
+}
+
+/// The outcome of a permission check whose administrator lookup failed: the
+/// change is denied.
+fn lookup_failed(chat_id: ChatId, e: teloxide::RequestError) -> bool {
+    tracing::warn!(error = %e, chat_id = %chat_id, "Failed to check chat administrator");
+    false
+}
+
+/// Converts a Telegram entity's UTF-16 offset and length into a byte range
+/// of `text`, or `None` when it does not fall on character boundaries.
+fn utf16_range(text: &str, offset: usize, length: usize) -> Option<std::ops::Range<usize>> {
+    let mut units = 0;
+    let (mut start, mut end) = (None, None);
+    for (byte, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if units == offset {
+            start = Some(byte);
+        }
+        if units == offset + length {
+            end = Some(byte);
+            break;
+        }
+        units += c.len_utf16();
+    }
+    Some(start?..end?)
+}
+
+/// Whether any of `entities` is a link.
+fn has_link_entity(entities: &[MessageEntity]) -> bool {
+    entities.iter().any(|entity| {
+        matches!(
+            entity.kind,
+            MessageEntityKind::Url | MessageEntityKind::TextLink { .. }
+        )
+    })
+}
+
+/// Where a message can carry links besides its own text: the part of the
+/// answered message it quotes (with that part's formatting), and the link
+/// previewed for a reply to a message in another chat.
+fn quoted_sources(msg: &Message) -> (Option<(&str, &[MessageEntity])>, Option<&str>) {
+    let quote = msg
+        .quote()
+        .map(|quote| (quote.text.as_str(), quote.entities.as_slice()));
+    let preview = match &msg.kind {
+        MessageKind::Common(common) => common
+            .external_reply
+            .as_ref()
+            .and_then(|reply| reply.link_preview_options.as_ref())
+            .and_then(|options| options.url.as_deref()),
+        _ => None,
+    };
+    (quote, preview)
+}
+
+/// Rewrites `text` with every original link replaced by its cleaned form,
+/// shifting the formatting `entities` (in UTF-16 units) to match and
+/// pointing text links at the cleaned URLs, so an edited post keeps its
+/// formatting. Links are located by their `Url` entities, or as whole links
+/// in the text when Telegram gave them none, and replaced right to left, so
+/// a link that is a prefix of another one never touches it.
+fn replace_links(
+    text: &str,
+    mut entities: Vec<MessageEntity>,
+    links: &[(&str, &str)],
+) -> (String, Vec<MessageEntity>) {
+    let cleaned_for = |url: &str| {
+        links
+            .iter()
+            .find(|(original, cleaned)| *original == url && original != cleaned)
+            .map(|(_, cleaned)| *cleaned)
+    };
+    for entity in &mut entities {
+        if let MessageEntityKind::TextLink { url } = &mut entity.kind {
+            if let Some(cleaned) = cleaned_for(url.as_str()).and_then(|c| c.parse().ok()) {
+                *url = cleaned;
+            }
+        }
+    }
+
+    let from_entities = entities
+        .iter()
+        .filter(|entity| entity.kind == MessageEntityKind::Url)
+        .filter_map(|entity| utf16_range(text, entity.offset, entity.length))
+        .map(|range| clear_urls_core::url_around(text, range));
+    let mut spans: Vec<(std::ops::Range<usize>, &str)> = Vec::new();
+    for url in from_entities.chain(clear_urls_core::find_urls(text)) {
+        let start = url.as_ptr() as usize - text.as_ptr() as usize;
+        let range = start..start + url.len();
+        let overlaps = spans
+            .iter()
+            .any(|(span, _)| span.start < range.end && range.start < span.end);
+        if let Some(cleaned) = cleaned_for(url).filter(|_| !overlaps) {
+            spans.push((range, cleaned));
+        }
+    }
+    spans.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+
+    let mut text = text.to_string();
+    for (range, cleaned) in spans {
+        let offset = text[..range.start].encode_utf16().count();
+        let old_end = offset + text[range.clone()].encode_utf16().count();
+        let new_end = offset + cleaned.encode_utf16().count();
+        // Positions after the link move with its end; positions inside it
+        // are kept within the cleaned link.
+        let moved = |position: usize| {
+            if position >= old_end {
+                position - old_end + new_end
+            } else {
+                position.min(new_end)
+            }
+        };
+        for entity in &mut entities {
+            let end = moved(entity.offset + entity.length);
+            entity.offset = moved(entity.offset);
+            entity.length = end - entity.offset;
+        }
+        text.replace_range(range, cleaned);
+    }
+    entities.retain(|entity| entity.length > 0);
+    (text, entities)
+}
+
 pub async fn run_bot(
     bot: Bot,
     db: Db,
     rules: RuleEngine,
     ai: AiEngine,
-    config: crate::config::Config,
+    config: SharedConfig,
     event_tx: tokio::sync::broadcast::Sender<serde_json::Value>,
+) -> anyhow::Result<()> {
+    // Each dispatcher resolves its own identity so several bots can share the
+    // process, the database and the rule engine.
+    let me = bot.get_me().await.context("Failed to fetch bot identity")?;
+    tracing::info!(bot = %me.username(), bot_id = %me.id, "Starting dispatcher");
+    if let Err(e) = register_commands(&bot).await {
+        tracing::warn!(error = %e, "Failed to register the command menu");
+    }
+
+    let (max_concurrent, queue_size) = {
+        let config = config.load();
+        (
+            config.max_concurrent_messages.max(1),
+            config.update_queue_size.max(1),
+        )
+    };
+    let limiter = MessageLimiter(Arc::new(Semaphore::new(max_concurrent)));
+    let handler = dptree::entry()
+        .branch(Update::filter_message().endpoint(handle_message_bounded))
+        .branch(Update::filter_channel_post().endpoint(handle_message_bounded))
+        .branch(Update::filter_my_chat_member().endpoint(handle_my_chat_member::<Bot>))
+        .branch(Update::filter_chat_member().endpoint(handle_chat_member::<Bot>))
+        .branch(Update::filter_callback_query().endpoint(handle_callback_query::<Bot>))
+        .branch(Update::filter_inline_query().endpoint(handle_inline_query::<Bot>))
+        .branch(Update::filter_chosen_inline_result().endpoint(handle_chosen_inline_result));
+    let webhook = {
+        let config = config.load();
+        (config.update_mode == UpdateMode::Webhook)
+            .then(|| (config.webhook_addr.clone(), config.webhook_url.clone()))
+    };
+
+    // Updates from one chat stay in order (teloxide's default distribution);
+    // different chats run concurrently, up to `max_concurrent` at a time.
+    let mut dispatcher = Dispatcher::builder(bot.clone(), handler)
+        .dependencies(dptree::deps![
+            db,
+            rules,
+            ai,
+            config,
+            event_tx,
+            me,
+            limiter,
+            ChatReplies::new(),
+            AdminCache::new()
+        ])
+        .worker_queue_size(queue_size)
+        .enable_ctrlc_handler()
+        .build();
+    let error_handler = LoggingErrorHandler::with_custom_text("An error from the update listener");
+    match webhook {
+        Some((addr, url)) => {
+            let url = url.context("WEBHOOK_URL is not set")?;
+            let listener = webhook_listener(&bot, &addr, &url).await?;
+            tracing::info!(addr = %addr, "Receiving updates through the webhook");
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
+        None => {
+            let listener = Polling::builder(bot)
+                .timeout(Duration::from_secs(10))
+                .allowed_updates(ALLOWED_UPDATES.to_vec())
+                .delete_webhook()
+                .await
+                .build();
+            dispatcher
+                .dispatch_with_listener(listener, error_handler)
+                .await;
+        }
+    }
+    Ok(())
+}
+
+/// Registers `url` as the bot's webhook and serves it on `addr`. teloxide's
+/// own `webhooks::axum` cannot pass `allowed_updates`, without which
+/// Telegram never sends `chat_member` updates.
+async fn webhook_listener(
+    bot: &Bot,
+    addr: &str,
+    url: &str,
+) -> anyhow::Result<impl teloxide::update_listeners::UpdateListener<Err = std::convert::Infallible>>
+{
+    use teloxide::update_listeners::webhooks;
+
+    let address: std::net::SocketAddr = addr.parse().context("Invalid WEBHOOK_ADDR")?;
+    let url = reqwest::Url::parse(url).context("Invalid WEBHOOK_URL")?;
+    let mut options = webhooks::Options::new(address, url.clone());
+    let secret = options.get_or_gen_secret_token().to_string();
+    bot.set_webhook(url)
+        .allowed_updates(ALLOWED_UPDATES.to_vec())
+        .secret_token(secret)
+        .await
+        .context("Failed to set the webhook")?;
+
+    let (listener, stopped, router) = webhooks::axum_no_setup(options);
+    let tcp = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to bind the webhook listener to {}", address))?;
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(tcp, router)
+            .with_graceful_shutdown(stopped)
+            .await
+        {
+            tracing::error!(error = %e, "Webhook server failed");
+        }
+    });
+    Ok(listener)
+}
+
+/// A duration in seconds as its two largest units, e.g. `2d 5h` or `12m`.
+fn format_age(secs: i64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs % 86400 / 3600, secs % 3600 / 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+/// The command menu for one language: the `/command - description` lines of
+/// its help text that name a visible [`Command`], in the help's order.
+fn command_menu(help_text: &str) -> Vec<BotCommand> {
+    let visible = Command::bot_commands();
+    help_text
+        .lines()
+        .filter_map(|line| {
+            let (command, description) = line.split_once(" - ")?;
+            visible
+                .iter()
+                .any(|c| c.command == command)
+                .then(|| BotCommand::new(command.trim_start_matches('/'), description))
+        })
+        .collect()
+}
+
+/// Sets the menu Telegram shows next to the message box, in every language
+/// the bot speaks. English is also the default for other languages.
+async fn register_commands(bot: &Bot) -> Result<(), teloxide::RequestError> {
+    for language in i18n::LANGUAGES {
+        let commands = command_menu(i18n::get_translations(language).help_text);
+        if *language == "en" {
+            bot.set_my_commands(commands.clone()).await?;
+        }
+        bot.set_my_commands(commands)
+            .language_code(*language)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Caps how many messages (and inline queries expanding links) a dispatcher
+/// processes at the same time.
+#[derive(Clone)]
+struct MessageLimiter(Arc<Semaphore>);
+
+/// Cleaned links recently answered in each group, with when, so a link that
+/// several members share in a row gets a single reply.
+#[derive(Clone)]
+struct RecentLinks(moka::future::Cache<(i64, String), i64>);
+
+impl RecentLinks {
+    fn new() -> Self {
+        Self(
+            moka::future::Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(Duration::from_secs(24 * 3600))
+                .build(),
+        )
+    }
+
+    /// Whether `url` was answered in `chat_id` less than `window` seconds
+    /// before `now`. Otherwise it is recorded as answered now.
+    async fn seen(&self, chat_id: i64, url: &str, now: i64, window: i64) -> bool {
+        let key = (chat_id, url.to_string());
+        if let Some(answered_at) = self.0.get(&key).await {
+            if now - answered_at < window {
+                return true;
+            }
+        }
+        self.0.insert(key, now).await;
+        false
+    }
+}
+
+/// The pages of a withheld reply and the buttons of the last one.
+type HiddenReply = (Vec<String>, Option<InlineKeyboardMarkup>);
+
+/// Replies withheld in reaction mode until someone presses "show cleaned
+/// links", per chat and message id.
+#[derive(Clone)]
+struct HiddenLinks(moka::future::Cache<(i64, i32), Arc<HiddenReply>>);
+
+impl HiddenLinks {
+    fn new() -> Self {
+        Self(
+            moka::future::Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(Duration::from_secs(48 * 3600))
+                .build(),
+        )
+    }
+}
+
+/// Caps the replies sent to each chat at `CHAT_REPLY_LIMIT` a minute. Links
+/// cleaned beyond it are held per forum topic, then sent together to their
+/// topic in a single message when the minute is over.
+///
+/// The budget is a fixed window rather than a token bucket: Telegram's own
+/// group limit is a per-minute count, and the `RateLimiter` window is a
+/// single Redis `INCR`, so replicas share it without a script. A burst
+/// straddling two windows can reach twice the limit, which the held message
+/// at the end of each window absorbs.
+#[derive(Clone)]
+struct FloodControl {
+    limiter: RateLimiter,
+    held: Arc<std::sync::Mutex<HashMap<HeldTopic, HeldLinks>>>,
+}
+
+/// Chat and forum topic links are held for.
+type HeldTopic = (i64, Option<ThreadId>);
+
+/// Links held for one topic: at most `MAX_HELD_LINKS` entries, and how
+/// many more were cleaned meanwhile.
+#[derive(Debug, Default)]
+struct HeldLinks {
+    entries: Vec<String>,
+    more: usize,
+}
+
+/// More than a held message can show anyway.
+const MAX_HELD_LINKS: usize = 100;
+
+impl FloodControl {
+    fn new() -> Self {
+        Self {
+            limiter: RateLimiter::new("chat_replies", Duration::from_secs(60)),
+            held: Arc::default(),
+        }
+    }
+
+    /// Holds `entries` for the topic `thread_id` of `chat_id`, counting
+    /// those past `MAX_HELD_LINKS` instead. Returns whether nothing was held
+    /// there yet, in which case the caller schedules the combined message.
+    fn hold(
+        &self,
+        chat_id: i64,
+        thread_id: Option<ThreadId>,
+        entries: impl IntoIterator<Item = String>,
+    ) -> bool {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        let pending = held.entry((chat_id, thread_id)).or_default();
+        let first = pending.entries.is_empty() && pending.more == 0;
+        for entry in entries {
+            if pending.entries.len() < MAX_HELD_LINKS {
+                pending.entries.push(entry);
+            } else {
+                pending.more += 1;
+            }
+        }
+        first
+    }
+
+    fn take(&self, chat_id: i64, thread_id: Option<ThreadId>) -> HeldLinks {
+        let mut held = self.held.lock().unwrap_or_else(|e| e.into_inner());
+        held.remove(&(chat_id, thread_id)).unwrap_or_default()
+    }
+}
+
+/// Sends the links held for the topic `thread_id` of `chat_id` after
+/// `delay`, as one message counting against the chat's reply limit.
+/// Entries that do not fit in it are summed up by `more`, a template for
+/// their count.
+async fn send_held_links<M: Messenger>(
+    bot: M,
+    flood: FloodControl,
+    chat_id: ChatId,
+    thread_id: Option<ThreadId>,
+    header: String,
+    more: &'static str,
+    delay: Duration,
 ) {
-    let handler = Update::filter_message().endpoint(handle_message);
+    tokio::time::sleep(delay).await;
+    let held = flood.take(chat_id.0, thread_id);
+    if held.entries.is_empty() {
+        return;
+    }
+    let trailer = |count: usize| more.replace("{}", &count.to_string());
+    // Room for the longest trailer the message could end with.
+    let budget = MAX_MESSAGE_LENGTH - trailer(held.entries.len() + held.more).len();
+    let mut text = header;
+    let mut shown = 0;
+    for entry in &held.entries {
+        if text.len() + entry.len() > budget {
+            break;
+        }
+        text.push_str(entry);
+        shown += 1;
+    }
+    let omitted = held.entries.len() - shown + held.more;
+    if omitted > 0 {
+        text.push_str(&trailer(omitted));
+    }
+
+    let now = crate::db::unix_now().unwrap_or(0);
+    flood.limiter.hit(&chat_id.0.to_string(), now).await;
+    let options = SendOptions {
+        thread_id,
+        disable_link_preview: true,
+        ..SendOptions::default()
+    };
+    if let Err(e) = bot.send_message(chat_id, text, options).await {
+        tracing::warn!(error = %e, chat_id = %chat_id, "Failed to send held links");
+    }
+}
+
+/// What the handlers keep about their replies in each chat.
+#[derive(Clone)]
+struct ChatReplies {
+    recent: RecentLinks,
+    hidden: HiddenLinks,
+    flood: FloodControl,
+}
+
+impl ChatReplies {
+    fn new() -> Self {
+        Self {
+            recent: RecentLinks::new(),
+            hidden: HiddenLinks::new(),
+            flood: FloodControl::new(),
+        }
+    }
+}
+
+/// Runs `handle_message` once a processing slot is free, within the
+/// `MESSAGE_TIMEOUT` deadline unless the message is a command.
+///
+/// The deadline cancels the handler wherever it is waiting. The one step
+/// that must not be cut in half, deleting a message and posting its cleaned
+/// replacement, runs as its own task (see `replace_message`) and finishes
+/// regardless. Commands such as `/export` or `/forgetme` always run to the
+/// end.
+#[allow(clippy::too_many_arguments)]
+async fn handle_message_bounded(
+    bot: Bot,
+    msg: Message,
+    db: Db,
+    rules: RuleEngine,
+    ai: AiEngine,
+    shared_config: SharedConfig,
+    event_tx: tokio::sync::broadcast::Sender<serde_json::Value>,
+    me: Me,
+    limiter: MessageLimiter,
+    replies: ChatReplies,
+    admins: AdminCache,
+) -> ResponseResult<()> {
+    let Ok(_permit) = limiter.0.acquire().await else {
+        return Ok(());
+    };
+    let timeout = Duration::from_secs(shared_config.load().message_timeout_secs.max(1));
+    let (chat_id, msg_id) = (msg.chat.id, msg.id);
+    let is_command = parse_command(&msg, &me).is_some();
+    let work = handle_message(
+        bot,
+        msg,
+        db,
+        rules,
+        ai,
+        shared_config,
+        event_tx,
+        me,
+        replies,
+        admins,
+    );
+    if is_command {
+        return work.await;
+    }
+    within_deadline(timeout, chat_id, msg_id, work).await
+}
+
+/// The bot command in `msg`, with its name as typed and its arguments.
+/// Channels get none, as they would be answered in public. In groups a
+/// command must name the bot, as other bots may share the command; one
+/// naming another bot fails to parse.
+fn parse_command<'a>(msg: &'a Message, me: &Me) -> Option<(Command, &'a str, &'a str)> {
+    let text = msg
+        .text()
+        .filter(|text| text.starts_with('/') && !msg.chat.is_channel())?;
+    let (head, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let (cmd, mentions_bot) = match head.split_once('@') {
+        Some((cmd, _)) => (cmd, true),
+        None => (head, false),
+    };
+    Command::parse(head, me.username())
+        .ok()
+        .filter(|_| mentions_bot || msg.chat.is_private())
+        .map(|command| (command, cmd, args.trim()))
+}
+
+/// Drops `work` once `timeout` has passed, logging the message it was for.
+async fn within_deadline(
+    timeout: Duration,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    work: impl std::future::Future<Output = ResponseResult<()>>,
+) -> ResponseResult<()> {
+    match tokio::time::timeout(timeout, work).await {
+        Ok(result) => result,
+        Err(_) => {
+            tracing::warn!(
+                chat_id = %chat_id,
+                msg_id = %msg_id,
+                timeout_secs = timeout.as_secs(),
+                "Message processing exceeded its deadline, dropped"
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Records the bot joining a group, or being promoted in one. The member who
+/// did it becomes the chat's owner if they administer the chat; otherwise the
+/// chat stays unowned until an administrator claims it. The first time the
+/// bot joins a group it posts a setup message with a button per mode.
+async fn handle_my_chat_member<M: Messenger>(
+    bot: M,
+    update: ChatMemberUpdated,
+    db: Db,
+    me: Me,
+    admins: AdminCache,
+) -> ResponseResult<()> {
+    let (old, new) = (&update.old_chat_member.kind, &update.new_chat_member.kind);
+    let joined = !old.is_present() && new.is_present();
+    let promoted = !old.is_privileged() && new.is_privileged();
+    if update.chat.is_private() || !(joined || promoted) {
+        return Ok(());
+    }
+    let chat_id = update.chat.id;
+    let actor_id = update.from.id.0 as i64;
+    let title = update.chat.title();
+    let owner = db.get_chat_owner(chat_id.0).await.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Failed to read chat owner");
+        None
+    });
+    // A promotion does not take the chat away from an owner already verified.
+    if !joined && matches!(owner, Some((_, true))) {
+        return Ok(());
+    }
+
+    // Fetches and caches the chat's administrators for later commands.
+    let is_admin = is_group_admin(&bot, &admins, chat_id, actor_id)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!(error = %e, chat_id = %chat_id, "Failed to check chat administrator");
+            false
+        });
+    let result = if is_admin {
+        db.set_chat_owner(chat_id.0, title, actor_id, Actor::bot(actor_id))
+            .await
+    } else if owner.is_none() {
+        let chat = crate::models::ChatConfig {
+            chat_id: chat_id.0,
+            title: title.map(str::to_string),
+            ..Default::default()
+        };
+        db.save_chat_config(&chat, Actor::bot(actor_id)).await
+    } else {
+        Ok(())
+    };
+    if let Err(e) = result {
+        tracing::error!(error = %e, chat_id = %chat_id, "Failed to record chat membership");
+    }
+    tracing::info!(chat_id = %chat_id, actor_id, is_admin, joined, "Bot membership changed");
+
+    if !joined || update.chat.is_channel() {
+        return Ok(());
+    }
+    match db.claim_chat_onboarding(chat_id.0).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(()),
+        Err(e) => {
+            tracing::error!(error = %e, chat_id = %chat_id, "Failed to claim chat onboarding");
+            return Ok(());
+        }
+    }
+    let language = match update
+        .from
+        .language_code
+        .as_deref()
+        .and_then(i18n::language_for_code)
+    {
+        Some(language) => language.to_string(),
+        None => db
+            .get_user_config(actor_id)
+            .await
+            .map(|c| c.language)
+            .unwrap_or_default(),
+    };
+    let tr = i18n::get_translations(&language);
+    let mode = db
+        .get_chat_config_or_default(chat_id.0)
+        .await
+        .map(|c| c.mode)
+        .unwrap_or_default();
+    let options = SendOptions {
+        keyboard: Some(onboarding_keyboard(chat_id, &mode, &me, &tr)),
+        ..SendOptions::default()
+    };
+    let text = tr
+        .onboarding
+        .replace("{}", &html::escape(title.unwrap_or(tr.unknown)));
+    if let Err(e) = bot.send_message(chat_id, text, options).await {
+        tracing::warn!(error = %e, chat_id = %chat_id, "Failed to post the setup message");
+    }
+    Ok(())
+}
+
+/// When a chat's owner leaves or stops being an administrator, the chat is
+/// left unowned and every other administrator who has started the bot is
+/// offered to take it over.
+async fn handle_chat_member<M: Messenger>(
+    bot: M,
+    update: ChatMemberUpdated,
+    db: Db,
+    admins: AdminCache,
+) -> ResponseResult<()> {
+    let member_id = update.new_chat_member.user.id.0 as i64;
+    if update.old_chat_member.kind.is_privileged() != update.new_chat_member.kind.is_privileged() {
+        admins.invalidate(update.chat.id.0).await;
+    }
+    if update.chat.is_private() || update.new_chat_member.kind.is_privileged() {
+        return Ok(());
+    }
+    let chat_id = update.chat.id;
+    match db.get_chat_owner(chat_id.0).await {
+        Ok(Some((owner_id, _))) if owner_id == member_id && owner_id != 0 => {}
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read chat owner");
+            return Ok(());
+        }
+    }
+    if let Err(e) = db.clear_chat_owner(chat_id.0, Actor::system()).await {
+        tracing::error!(error = %e, chat_id = %chat_id, "Failed to clear chat owner");
+        return Ok(());
+    }
+    tracing::info!(chat_id = %chat_id, owner_id = member_id, "Chat owner left, offering ownership");
+
+    let admins = match bot.chat_administrators(chat_id).await {
+        Ok(admins) => admins,
+        Err(e) => {
+            tracing::warn!(error = %e, chat_id = %chat_id, "Failed to list chat administrators");
+            return Ok(());
+        }
+    };
+    let title = html::escape(update.chat.title().unwrap_or_default());
+    for admin_id in admins.into_iter().filter(|id| *id != member_id) {
+        let language = db
+            .get_user_config(admin_id)
+            .await
+            .map(|c| c.language)
+            .unwrap_or_default();
+        let tr = i18n::get_translations(&language);
+        let button = InlineKeyboardButton::callback(
+            tr.ownership_accept,
+            format!("{}{}", CLAIM_CHAT_PREFIX, chat_id.0),
+        );
+        let options = SendOptions {
+            keyboard: Some(InlineKeyboardMarkup::new([[button]])),
+            ..SendOptions::default()
+        };
+        let text = tr.ownership_offer.replace("{}", &title);
+        // Administrators who never started the bot cannot be messaged.
+        if let Err(e) = bot.send_message(ChatId(admin_id), text, options).await {
+            tracing::debug!(error = %e, admin_id, "Failed to offer chat ownership");
+        }
+    }
+    Ok(())
+}
+
+/// Inline button presses: ownership offers, the private /settings menu and
+/// group setups.
+async fn handle_callback_query<M: Messenger>(
+    bot: M,
+    query: CallbackQuery,
+    db: Db,
+    shared_config: SharedConfig,
+    replies: ChatReplies,
+    admins: AdminCache,
+    me: Me,
+) -> ResponseResult<()> {
+    if let Some((chat_id, setting)) = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(GROUP_SETUP_PREFIX))
+        .and_then(|rest| rest.split_once(':'))
+        .and_then(|(id, setting)| Some((id.parse::<i64>().ok()?, setting)))
+    {
+        let config = shared_config.load_full();
+        return handle_group_setup_button(
+            &bot,
+            &query,
+            (chat_id, setting),
+            &db,
+            &config,
+            &admins,
+            &me,
+        )
+        .await;
+    }
+    if let Some(message_id) = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(SHOW_LINKS_PREFIX))
+        .and_then(|id| id.parse::<i32>().ok())
+    {
+        return handle_show_links(&bot, &query, message_id, &db, &replies.hidden).await;
+    }
+    if let Some(setting) = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(SETTINGS_PREFIX))
+    {
+        let config = shared_config.load_full();
+        return handle_settings_button(&bot, &query, setting, &db, &config).await;
+    }
+    let Some(chat_id) = query
+        .data
+        .as_deref()
+        .and_then(|data| data.strip_prefix(CLAIM_CHAT_PREFIX))
+        .and_then(|id| id.parse::<i64>().ok())
+    else {
+        return Ok(());
+    };
+    let user_id = query.from.id.0 as i64;
+    let language = db
+        .get_user_config(user_id)
+        .await
+        .map(|c| c.language)
+        .unwrap_or_default();
+    let tr = i18n::get_translations(&language);
+
+    // The first administrator to accept gets the chat.
+    let unowned = match db.get_chat_owner(chat_id).await {
+        Ok(Some((owner_id, verified))) => !verified || owner_id == user_id,
+        Ok(None) => false,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to read chat owner");
+            false
+        }
+    };
+    let is_admin = unowned
+        && bot
+            .is_chat_admin(ChatId(chat_id), user_id)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(error = %e, chat_id, "Failed to check chat administrator");
+                false
+            });
+    let text = if is_admin {
+        match db
+            .set_chat_owner(chat_id, None, user_id, Actor::bot(user_id))
+            .await
+        {
+            Ok(()) => tr.ownership_taken,
+            Err(e) => {
+                tracing::error!(error = %e, chat_id, "Failed to record chat owner");
+                tr.generic_error
+            }
+        }
+    } else {
+        tr.ownership_unavailable
+    };
+    bot.answer_callback(query.id, text.to_string()).await
+}
+
+/// The /settings menu: one button per setting showing its value. Pressing
+/// one toggles the setting or moves it to the next value.
+fn settings_keyboard(
+    user_config: &crate::models::UserConfig,
+    ai_available: bool,
+    tr: &i18n::Translations,
+) -> InlineKeyboardMarkup {
+    let on_off = |on: bool| if on { tr.setting_on } else { tr.setting_off };
+    let button = |label: String, setting: &str| {
+        vec![InlineKeyboardButton::callback(
+            label,
+            format!("{}{}", SETTINGS_PREFIX, setting),
+        )]
+    };
+    let mut rows = vec![button(
+        tr.setting_enabled
+            .replace("{}", on_off(user_config.is_enabled())),
+        "enabled",
+    )];
+    if ai_available {
+        rows.push(button(
+            tr.setting_ai
+                .replace("{}", on_off(user_config.is_ai_enabled())),
+            "ai",
+        ));
+    }
+    rows.push(button(
+        tr.setting_mode.replace("{}", &user_config.mode),
+        "mode",
+    ));
+    rows.push(button(
+        tr.setting_language.replace("{}", &user_config.language),
+        "language",
+    ));
+    rows.push(button(
+        tr.setting_digest
+            .replace("{}", on_off(user_config.wants_digest())),
+        "digest",
+    ));
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// The link that opens the setup of `chat_id` in a private chat with the bot.
+fn group_setup_url(me: &Me, chat_id: ChatId) -> String {
+    format!(
+        "https://t.me/{}?start={}{}",
+        me.username(),
+        GROUP_SETUP_PAYLOAD,
+        chat_id.0
+    )
+}
+
+/// The mode buttons of the message posted when the bot joins a group, two
+/// per row with the current one ticked, and a link to the full setup.
+fn onboarding_keyboard(
+    chat_id: ChatId,
+    mode: &str,
+    me: &Me,
+    tr: &i18n::Translations,
+) -> InlineKeyboardMarkup {
+    let mut rows: Vec<Vec<InlineKeyboardButton>> = CHAT_MODES
+        .chunks(2)
+        .map(|modes| {
+            modes
+                .iter()
+                .map(|m| {
+                    let label = if *m == mode {
+                        format!("✅ {}", m)
+                    } else {
+                        m.to_string()
+                    };
+                    let data = format!("{}{}:mode:{}", GROUP_SETUP_PREFIX, chat_id.0, m);
+                    InlineKeyboardButton::callback(label, data)
+                })
+                .collect()
+        })
+        .collect();
+    if let Ok(url) = group_setup_url(me, chat_id).parse() {
+        rows.push(vec![InlineKeyboardButton::url(tr.group_setup_open, url)]);
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// The private setup of a group: its mode, cleaning level and AI, one button
+/// each, showing the current value.
+fn group_setup_keyboard(
+    chat_config: &crate::models::ChatConfig,
+    ai_available: bool,
+    tr: &i18n::Translations,
+) -> InlineKeyboardMarkup {
+    let button = |label: String, setting: &str| {
+        vec![InlineKeyboardButton::callback(
+            label,
+            format!("{}{}:{}", GROUP_SETUP_PREFIX, chat_config.chat_id, setting),
+        )]
+    };
+    let mut rows = vec![
+        button(tr.setting_mode.replace("{}", &chat_config.mode), "mode"),
+        button(
+            tr.setting_level.replace("{}", &chat_config.cleaning_level),
+            "level",
+        ),
+    ];
+    if ai_available {
+        let ai = if chat_config.is_ai_enabled() {
+            tr.setting_on
+        } else {
+            tr.setting_off
+        };
+        rows.push(button(tr.setting_ai.replace("{}", ai), "ai"));
+    }
+    InlineKeyboardMarkup::new(rows)
+}
+
+/// The value after `current` in `values`, wrapping around; the first one
+/// when `current` is not among them.
+fn next_value(values: &[&str], current: &str) -> String {
+    let next = values
+        .iter()
+        .position(|value| *value == current)
+        .map_or(0, |i| (i + 1) % values.len());
+    values[next].to_string()
+}
+
+/// Applies a group setup button, if the user who pressed it still owns the
+/// group, then redraws the setup with the new values. `mode:<mode>` buttons
+/// come from the message posted when the bot joined, which is redrawn as
+/// such.
+async fn handle_group_setup_button<M: Messenger>(
+    bot: &M,
+    query: &CallbackQuery,
+    (chat_id, setting): (i64, &str),
+    db: &Db,
+    config: &Config,
+    admins: &AdminCache,
+    me: &Me,
+) -> ResponseResult<()> {
+    let user_id = query.from.id.0 as i64;
+    let language = db
+        .get_user_config(user_id)
+        .await
+        .map(|c| c.language)
+        .unwrap_or_default();
+    let tr = i18n::get_translations(&language);
+    let mut chat_config = match db.get_chat_config(chat_id).await {
+        Ok(Some(chat_config)) => chat_config,
+        Ok(None) => return bot.answer_callback(query.id.clone(), String::new()).await,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to fetch chat config");
+            return bot
+                .answer_callback(query.id.clone(), tr.generic_error.to_string())
+                .await;
+        }
+    };
+    let title = chat_config.title.clone();
+    if !may_change_settings(
+        bot,
+        admins,
+        db,
+        config,
+        ChatId(chat_id),
+        title.as_deref(),
+        user_id,
+    )
+    .await
+    {
+        return bot
+            .answer_callback(query.id.clone(), tr.owner_only.to_string())
+            .await;
+    }
+    let picked_mode = setting
+        .strip_prefix("mode:")
+        .filter(|mode| CHAT_MODES.contains(mode));
+    match setting {
+        "mode" => chat_config.mode = next_value(CHAT_MODES, &chat_config.mode),
+        _ if picked_mode.is_some() => {
+            chat_config.mode = picked_mode.unwrap_or_default().to_string()
+        }
+        "level" => {
+            let levels: Vec<&str> = std::iter::once("default")
+                .chain(CleaningLevel::ALL.iter().map(|l| l.as_str()))
+                .collect();
+            chat_config.cleaning_level = next_value(&levels, &chat_config.cleaning_level);
+        }
+        "ai" if config.ai_available() => {
+            chat_config.ai_enabled = i32::from(!chat_config.is_ai_enabled())
+        }
+        _ => return bot.answer_callback(query.id.clone(), String::new()).await,
+    }
+    if let Err(e) = db.save_chat_config(&chat_config, Actor::bot(user_id)).await {
+        tracing::error!(error = %e, "Failed to save chat config");
+        return bot
+            .answer_callback(query.id.clone(), tr.generic_error.to_string())
+            .await;
+    }
+    tracing::info!(
+        chat_id,
+        user_id,
+        setting,
+        "Group setting changed from the setup"
+    );
+
+    if let Some(message) = &query.message {
+        let title = html::escape(title.as_deref().unwrap_or(tr.unknown));
+        let (text, keyboard) = if picked_mode.is_some() {
+            let keyboard = onboarding_keyboard(ChatId(chat_id), &chat_config.mode, me, &tr);
+            (tr.onboarding.replace("{}", &title), keyboard)
+        } else {
+            let keyboard = group_setup_keyboard(&chat_config, config.ai_available(), &tr);
+            (tr.group_setup.replace("{}", &title), keyboard)
+        };
+        if let Err(e) = bot
+            .edit_text(message.chat().id, message.id(), text, keyboard)
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to redraw the group setup");
+        }
+    }
+    bot.answer_callback(query.id.clone(), tr.settings_saved.to_string())
+        .await
+}
+
+/// Applies a /settings menu button for the user who pressed it, then redraws
+/// the menu with the new values (and language).
+async fn handle_settings_button<M: Messenger>(
+    bot: &M,
+    query: &CallbackQuery,
+    setting: &str,
+    db: &Db,
+    config: &Config,
+) -> ResponseResult<()> {
+    let user_id = query.from.id.0 as i64;
+    let mut user_config = match db.get_user_config(user_id).await {
+        Ok(user_config) => user_config,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to fetch user config");
+            let tr = i18n::get_translations("en");
+            return bot
+                .answer_callback(query.id.clone(), tr.generic_error.to_string())
+                .await;
+        }
+    };
+    user_config.user_id = user_id;
+    match setting {
+        "enabled" => user_config.enabled = i32::from(!user_config.is_enabled()),
+        "ai" if config.ai_available() => {
+            user_config.ai_enabled = i32::from(!user_config.is_ai_enabled())
+        }
+        "mode" => user_config.mode = next_value(crate::models::USER_MODES, &user_config.mode),
+        "language" => user_config.language = next_value(i18n::LANGUAGES, &user_config.language),
+        "digest" => user_config.digest_enabled = i32::from(!user_config.wants_digest()),
+        _ => return bot.answer_callback(query.id.clone(), String::new()).await,
+    }
+    let tr = i18n::get_translations(&user_config.language);
+    if let Err(e) = db.save_user_config(&user_config, Actor::bot(user_id)).await {
+        tracing::error!(error = %e, "Failed to save user config");
+        return bot
+            .answer_callback(query.id.clone(), tr.generic_error.to_string())
+            .await;
+    }
+    tracing::info!(user_id, setting, "Setting changed from the menu");
+
+    if let Some(message) = &query.message {
+        let keyboard = settings_keyboard(&user_config, config.ai_available(), &tr);
+        let edit = bot.edit_text(
+            message.chat().id,
+            message.id(),
+            tr.settings_menu.to_string(),
+            keyboard,
+        );
+        if let Err(e) = edit.await {
+            tracing::warn!(error = %e, "Failed to redraw the settings menu");
+        }
+    }
+    bot.answer_callback(query.id.clone(), tr.settings_saved.to_string())
+        .await
+}
+
+/// Replaces a reaction mode hint with the cleaned links it stands for, for
+/// whoever pressed its button first.
+async fn handle_show_links<M: Messenger>(
+    bot: &M,
+    query: &CallbackQuery,
+    message_id: i32,
+    db: &Db,
+    hidden: &HiddenLinks,
+) -> ResponseResult<()> {
+    let Some(message) = &query.message else {
+        return Ok(());
+    };
+    let chat_id = message.chat().id;
+    let Some(reply) = hidden.0.remove(&(chat_id.0, message_id)).await else {
+        let language = db
+            .get_user_config(query.from.id.0 as i64)
+            .await
+            .map(|c| c.language)
+            .unwrap_or_default();
+        let tr = i18n::get_translations(&language);
+        return bot
+            .answer_callback(query.id.clone(), tr.links_expired.to_string())
+            .await;
+    };
+    let (pages, keyboard) = &*reply;
+    let last = pages.len().saturating_sub(1);
+    for (i, page) in pages.iter().enumerate() {
+        let keyboard = if i == last { keyboard.clone() } else { None };
+        if i == 0 {
+            let keyboard = keyboard.unwrap_or_default();
+            bot.edit_text(chat_id, message.id(), page.clone(), keyboard)
+                .await?;
+        } else {
+            let options = SendOptions {
+                reply_to: Some(message.id()),
+                disable_link_preview: true,
+                keyboard,
+                ..SendOptions::default()
+            };
+            bot.send_message(chat_id, page.clone(), options).await?;
+        }
+    }
+    bot.answer_callback(query.id.clone(), String::new()).await
+}
+
+/// A link typed after the bot's username, with the cleaned URL and provider
+/// when the rules changed it.
+struct InlineLink {
+    original: String,
+    chain: Vec<String>,
+    cleaned: Option<(String, String)>,
+}
+
+/// Longest an inline query spends expanding short links. Telegram sends a
+/// query on every keystroke and drops late answers, so past this the rule-only
+/// result is shown.
+const INLINE_BUDGET: Duration = Duration::from_secs(3);
+
+/// Cleans the links of an inline query like a private message of `user_id`
+/// would be: expansion (when `expand` is set, within `INLINE_BUDGET`), rules,
+/// custom rules, ignored domains and the user's level. AI is left out, as
+/// Telegram sends a query on every keystroke.
+async fn clean_inline_links(
+    db: &Db,
+    rules: &RuleEngine,
+    config: &Config,
+    user_id: i64,
+    user_config: &crate::models::UserConfig,
+    query: &str,
+    expand: bool,
+) -> Vec<InlineLink> {
+    let budget = expand
+        .then(|| Budget::new(INLINE_BUDGET.min(Duration::from_secs(config.message_budget_secs))));
+    let custom_rules = db.get_custom_rules(user_id).await.unwrap_or_default();
+    let ignored_domains: Vec<String> = user_config
+        .ignored_domains
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+    let level = CleaningLevel::parse(&user_config.cleaning_level).unwrap_or_default();
+
+    let mut candidates: Vec<String> = Vec::new();
+    for url in clear_urls_core::find_urls(query) {
+        if !candidates.iter().any(|c| c == url) {
+            candidates.push(url.to_string());
+        }
+    }
+    let mut links = Vec::new();
+    for original in candidates.into_iter().take(MAX_INLINE_RESULTS) {
+        let chain = match &budget {
+            Some(budget) => budget
+                .run(rules.expand_url_chain(&original), "expand")
+                .await
+                .unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let expanded = chain.last().cloned().unwrap_or_else(|| original.clone());
+        let cleaned = rules.sanitize(&expanded, &custom_rules, &ignored_domains, level);
+        links.push(InlineLink {
+            original,
+            chain,
+            cleaned,
+        });
+    }
+    links
+}
+
+/// `@bot <text>` in any chat: one result per link in the text, which sends
+/// the cleaned URL. Nothing is recorded until a result is chosen.
+async fn handle_inline_query<M: Messenger>(
+    bot: M,
+    query: InlineQuery,
+    db: Db,
+    rules: RuleEngine,
+    shared_config: SharedConfig,
+    limiter: MessageLimiter,
+) -> ResponseResult<()> {
+    let config = shared_config.load_full();
+    let user_id = query.from.id.0 as i64;
+    let user_config = db.get_user_config(user_id).await.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Failed to fetch user config, using default");
+        crate::models::UserConfig::default()
+    });
+    let lang_code = match query.from.language_code.as_deref() {
+        Some(l) => i18n::language_for_code(l).unwrap_or(&user_config.language),
+        None => &user_config.language,
+    };
+    let tr = i18n::get_translations(lang_code);
+
+    // Queries take a message slot to expand links; when none is free they
+    // are answered with the rule-only result rather than queued.
+    let permit = limiter.0.try_acquire().ok();
+    let links = clean_inline_links(
+        &db,
+        &rules,
+        &config,
+        user_id,
+        &user_config,
+        &query.query,
+        permit.is_some(),
+    )
+    .await;
+    drop(permit);
+    let results = links
+        .into_iter()
+        .enumerate()
+        .map(|(index, link)| {
+            let (url, description) = match link.cleaned {
+                Some((cleaned, provider)) => (cleaned, tr.inline_cleaned.replace("{}", &provider)),
+                None => (link.original, tr.inline_already_clean.to_string()),
+            };
+            let content = InputMessageContent::Text(InputMessageContentText::new(url.clone()));
+            InlineQueryResult::Article(
+                InlineQueryResultArticle::new(index.to_string(), url, content)
+                    .description(description),
+            )
+        })
+        .collect();
+    bot.answer_inline(query.id, results).await
+}
+
+/// Records the cleaning behind an inline result once the user sends it, like
+/// a cleaned message (without a chat). Telegram only reports chosen results
+/// when inline feedback is enabled with @BotFather's `/setinlinefeedback`.
+async fn handle_chosen_inline_result(
+    result: ChosenInlineResult,
+    db: Db,
+    rules: RuleEngine,
+    shared_config: SharedConfig,
+    event_tx: tokio::sync::broadcast::Sender<serde_json::Value>,
+    me: Me,
+) -> ResponseResult<()> {
+    let Ok(index) = result.result_id.parse::<usize>() else {
+        return Ok(());
+    };
+    let config = shared_config.load_full();
+    let user_id = result.from.id.0 as i64;
+    let user_config = match db.get_user_config(user_id).await {
+        Ok(user_config) => user_config,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to fetch user config");
+            return Ok(());
+        }
+    };
+    // Expansions are cached, so this finds the links the query showed.
+    let links = clean_inline_links(
+        &db,
+        &rules,
+        &config,
+        user_id,
+        &user_config,
+        &result.query,
+        true,
+    )
+    .await;
+    let Some(InlineLink {
+        original,
+        chain,
+        cleaned: Some((cleaned, provider)),
+    }) = links.into_iter().nth(index)
+    else {
+        return Ok(());
+    };
+
+    let _ = db.increment_cleaned_count(user_id, 1).await;
+    let cleaning = (
+        original.as_str(),
+        cleaned.as_str(),
+        provider.as_str(),
+        "rules",
+    );
+    let keep_history = user_config.keeps_history();
+    log_cleaning(
+        &db,
+        &event_tx,
+        &me,
+        user_id,
+        keep_history,
+        None,
+        cleaning,
+        &chain,
+    )
+    .await;
+    tracing::info!(user_id, provider = %provider, "Inline result sent");
+    Ok(())
+}
+
+/// A cleaned link as logged: original URL, cleaned URL, provider and
+/// cleaning source (`rules`, `ai`, `rules+ai`, or `dry_run` for a link
+/// found in a `dry_run` chat).
+type Cleaning<'a> = (&'a str, &'a str, &'a str, &'a str);
+
+/// Logs a cleaned link to the user's history, with the redirects followed
+/// to reach it, unless they turned the history off (`/privacy`), and
+/// publishes it on the live event stream.
+#[allow(clippy::too_many_arguments)]
+async fn log_cleaning(
+    db: &Db,
+    event_tx: &tokio::sync::broadcast::Sender<serde_json::Value>,
+    me: &Me,
+    user_id: i64,
+    keep_history: bool,
+    chat_id: Option<i64>,
+    (original, cleaned, provider, source): Cleaning<'_>,
+    chain: &[String],
+) {
+    if keep_history {
+        let _ = db
+            .log_redirected_link(
+                user_id,
+                chat_id,
+                Some(me.id.0 as i64),
+                original,
+                cleaned,
+                provider,
+                source,
+                chain,
+            )
+            .await;
+    }
+    let _ = event_tx.send(serde_json::json!({
+        "user_id": user_id,
+        "chat_id": chat_id,
+        "bot_id": me.id.0,
+        "original_url": original,
+        "cleaned_url": cleaned,
+        "provider_name": provider,
+        "cleaning_source": source,
+        "redirect_chain": (chain.len() > 1).then_some(chain),
+        "timestamp": crate::db::unix_now().unwrap_or(0)
+    }));
+}
+
+/// Time left for the optional network steps (shortlink expansion, AI) of one
+/// message. Once it runs out those steps are skipped, so the reply still goes
+/// out with the rule-only result.
+struct Budget {
+    deadline: tokio::time::Instant,
+}
+
+impl Budget {
+    fn new(duration: Duration) -> Self {
+        Self {
+            deadline: tokio::time::Instant::now() + duration,
+        }
+    }
+
+    /// Runs `step`, or gives up with `None` when the budget runs out first.
+    async fn run<F: std::future::Future>(&self, step: F, name: &str) -> Option<F::Output> {
+        let result = tokio::time::timeout_at(self.deadline, step).await.ok();
+        if result.is_none() {
+            tracing::warn!(step = name, "Message time budget exhausted, skipping step");
+        }
+        result
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    skip(bot, db, rules, ai, shared_config, event_tx, me, replies, admins),
+    fields(chat_id = %msg.chat.id, user_id)
+)]
+async fn handle_message<M: Messenger + Clone + 'static>(
+    bot: M,
+    msg: Message,
+    db: Db,
+    rules: RuleEngine,
+    ai: AiEngine,
+    shared_config: SharedConfig,
+    event_tx: tokio::sync::broadcast::Sender<serde_json::Value>,
+    me: Me,
+    replies: ChatReplies,
+    admins: AdminCache,
+) -> ResponseResult<()> {
+    let config = shared_config.load_full();
+    let budget = Budget::new(Duration::from_secs(config.message_budget_secs));
+    tracing::info!(chat_id = %msg.chat.id, msg_id = %msg.id, "Processing incoming message");
+    let chat_id = msg.chat.id;
+    // Channel posts have no sender: the owner's settings apply to them and
+    // their cleanings count as the owner's.
+    let user_id = match &msg.from {
+        Some(user) => user.id.0 as i64,
+        None if msg.chat.is_channel() => db
+            .get_chat_owner(chat_id.0)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to read chat owner");
+                None
+            })
+            .map_or(0, |(owner_id, _)| owner_id),
+        None => 0,
+    };
+    tracing::Span::current().record("user_id", user_id);
+    let debug = db.is_debug_target(user_id).await || db.is_debug_target(chat_id.0).await;
+
+    let mut user_config = db.get_user_config(user_id).await.unwrap_or_else(|e| {
+        tracing::error!(error = %e, "Failed to fetch user config, using default");
+        crate::models::UserConfig::default()
+    });
+
+    let telegram_lang = msg
+        .from
+        .as_ref()
+        .and_then(|u| u.language_code.as_deref())
+        .and_then(i18n::language_for_code);
+
+    // A user writing to the bot for the first time starts in the language
+    // of their Telegram app rather than in English.
+    if let Some(lang) = telegram_lang.filter(|l| *l != user_config.language) {
+        if msg.chat.is_private() && user_id != 0 {
+            match db.init_user_language(user_id, lang).await {
+                Ok(true) => user_config.language = lang.to_string(),
+                Ok(false) => {}
+                Err(e) => tracing::warn!(error = %e, "Failed to store the user's language"),
+            }
+        }
+    }
+
+    // 1. Detect URLs early
+    let (text, entities) = if let Some(t) = msg.text() {
+        (t, msg.entities())
+    } else if let Some(c) = msg.caption() {
+        (c, msg.caption_entities())
+    } else {
+        ("", None)
+    };
+
+    // Detect language
+    let detected_lang = if !text.is_empty() {
+        detect(text).map(|info| info.lang())
+    } else {
+        None
+    };
+
+    let lang_code = match (detected_lang, telegram_lang) {
+        (Some(Lang::Ita), _) => "it",
+        (Some(Lang::Eng), _) => "en",
+        (_, Some(l)) => l,
+        _ => &user_config.language,
+    };
+
+    let tr = i18n::get_translations(lang_code);
+
+    let mut has_urls = entities.is_some_and(has_link_entity);
+
+    // Manual fallback detection for schemeless URLs or cases where Telegram detection fails
+    if !has_urls && clear_urls_core::contains_url(text) {
+        has_urls = true;
+        trace_if!(debug, "URL detected via manual regex fallback");
+    }
+
+    // A quote or the preview of a reply from another chat can bring links
+    // the text itself does not have.
+    let (quote, preview_url) = quoted_sources(&msg);
+    if !has_urls
+        && (preview_url.is_some()
+            || quote.is_some_and(|(text, entities)| {
+                has_link_entity(entities) || clear_urls_core::contains_url(text)
+            }))
+    {
+        has_urls = true;
+        trace_if!(debug, "URL found in a quote or a reply preview");
+    }
+
+    // Handle Commands
+    if let Some((command, cmd, args)) = parse_command(&msg, &me) {
+        if let Err(e) = db.record_usage(cmd).await {
+            tracing::warn!(error = %e, "Failed to record command usage");
+        }
+        // The bot admin's commands read as plain text from anyone else.
+        if !command.is_admin_only() || is_bot_admin(&config, user_id) {
+            let cx = commands::CommandContext {
+                bot: &bot,
+                msg: &msg,
+                db: &db,
+                rules: &rules,
+                shared_config: &shared_config,
+                config: &config,
+                event_tx: &event_tx,
+                me: &me,
+                admins: &admins,
+                budget: &budget,
+                tr: &tr,
+                user_config: &user_config,
+                user_id,
+                chat_id,
+                is_private: msg.chat.is_private(),
+                args,
+            };
+            return commands::run(&cx, command).await;
+        }
+    }
+
+    // Persist/Update chat info
+    let is_group_context = msg.chat.is_group() || msg.chat.is_supergroup() || msg.chat.is_channel();
+    let mut chat_config = db
+        .get_chat_config_or_default(chat_id.0)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed to fetch chat config, using default");
+            crate::models::ChatConfig::default()
+        });
+
+    if is_group_context {
+        let title = msg.chat.title().map(|s| s.to_string());
+        let chat_config_db = db.get_chat_config(chat_id.0).await.unwrap_or(None);
+        let chat_exists = chat_config_db.is_some();
+
+        let kind = if msg.chat.is_channel() {
+            "channel"
+        } else {
+            "group"
+        };
+
+        // Only save if it's new or title changed. Ownership comes from the
+        // `my_chat_member` update, not from whoever posts first.
+        if !chat_exists || chat_config.title != title || chat_config.kind != kind {
+            chat_config.title = title.clone();
+            chat_config.kind = kind.to_string();
+            let _ = db.save_chat_config(&chat_config, Actor::bot(user_id)).await;
+        }
+
+        // Only a chat administrator is told the bot is active, and becomes
+        // the owner of a chat whose `my_chat_member` update was missed.
+        if !chat_exists
+            && has_urls
+            && owns_chat(&bot, &admins, &db, chat_id, msg.chat.title(), user_id)
+                .await
+                .unwrap_or(false)
+        {
+            let notify_text = tr.group_activated.replace(
+                "{}",
+                &html::escape(&title.unwrap_or_else(|| tr.unknown.to_string())),
+            );
+            let _ = bot
+                .send_message(ChatId(user_id), notify_text, SendOptions::default())
+                .await;
+        }
+    }
+
+    if !has_urls {
+        return Ok(());
+    }
+
+    // Logic: In groups, only check if the group enabled the bot.
+    // In private, check if the user enabled the bot.
+    let is_enabled = if is_group_context {
+        chat_config.is_enabled()
+    } else {
+        user_config.is_enabled()
+    };
+
+    if !is_enabled {
+        tracing::info!(is_group_context, chat_id = %chat_id, "Bot is disabled for this context (skipping)");
+        return Ok(());
+    }
+
+    // In forums, administrators choose with /topic which topics are cleaned.
+    if let Some(thread_id) = forum_topic(&msg) {
+        let topic_enabled = db
+            .is_topic_enabled(chat_id.0, thread_id)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to read topic setting");
+                true
+            });
+        if !topic_enabled {
+            trace_if!(debug, chat_id = %chat_id, thread_id, "Cleaning is off in this topic (skipping)");
+            return Ok(());
+        }
+    }
+
+    // Members a group's administrators exempted with /whitelist, such as
+    // other bots, keep their links as they are.
+    if let Some(sender) = msg.from.as_ref().filter(|_| is_group_context) {
+        let whitelisted = db
+            .is_chat_whitelisted(chat_id.0, user_id, sender.username.as_deref())
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to check chat whitelist");
+                false
+            });
+        if whitelisted {
+            trace_if!(debug, chat_id = %chat_id, "Sender is whitelisted in this chat (skipping)");
+            return Ok(());
+        }
+    }
+
+    // Groups and channels skip the domains their administrators listed with
+    // /ignore; a sender's own list only applies in private.
+    let ignored_domains: Vec<String> = if is_group_context {
+        db.get_chat_ignored_domains(chat_id.0)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::error!(error = %e, "Failed to fetch chat ignored domains");
+                Vec::new()
+            })
+    } else {
+        user_config
+            .ignored_domains
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect()
+    };
+
+    // AI follows the same rule: the group's setting in groups, the user's in private.
+    let ai_enabled = config.ai_available()
+        && if is_group_context {
+            chat_config.is_ai_enabled()
+        } else {
+            user_config.is_ai_enabled()
+        };
+
+    // A group's level applies to everyone unless left at "default".
+    let cleaning_level = match chat_config.cleaning_level.as_str() {
+        "default" | "" => CleaningLevel::parse(&user_config.cleaning_level),
+        level => CleaningLevel::parse(level),
+    }
+    .unwrap_or_default();
+
+    let custom_rules = db.get_custom_rules(user_id).await.unwrap_or_default();
+    let mut cleaned_urls = Vec::new();
+
+    let mode = match chat_config.mode.as_str() {
+        // Channel posts have no author to answer or message privately, so
+        // they are edited in place unless the channel asks for a reply.
+        m @ ("reply" | "digest" | "dry_run") if msg.chat.is_channel() => m.to_string(),
+        _ if msg.chat.is_channel() => "edit".to_string(),
+        "default" | "" => user_config.mode.clone(),
+        m => m.to_string(),
+    };
+    // Dry run lets administrators see in the dashboard what the bot would
+    // clean before it answers or deletes anything. It spends no AI quota
+    // and counts nothing.
+    let dry_run = mode == "dry_run" && is_group_context;
+    let ai_enabled = ai_enabled && !dry_run;
+    // Links from a quote or a reply preview are not in the message, so the
+    // modes that delete, repost or edit it leave them out.
+    let (quote, preview_url) = match mode.as_str() {
+        "reply" | "dm" | "reaction" | "dry_run" => (quote, preview_url),
+        _ => (None, None),
+    };
+
+    let mut url_candidates = Vec::new();
+
+    // The message text first, then the part of another message it quotes.
+    let sources = std::iter::once((text, entities.unwrap_or_default())).chain(quote);
+    for (text, entities) in sources {
+        // 1. Get URLs from Telegram Entities
+        for entity in entities {
+            let url_str = match &entity.kind {
+                MessageEntityKind::Url => {
+                    let Some(range) = utf16_range(text, entity.offset, entity.length) else {
+                        continue;
+                    };
+                    // Reassembles links whose entity was split by formatting.
+                    clear_urls_core::url_around(text, range).to_string()
+                }
+                MessageEntityKind::TextLink { url } => url.to_string(),
+                _ => continue,
+            };
+            if !url_candidates.contains(&url_str) {
+                trace_if!(debug, url = %rules.redact_sensitive(&url_str), "Found URL via Telegram entity");
+                url_candidates.push(url_str);
+            }
+        }
+
+        // 2. Supplement with Regex Detection
+        for url_str in clear_urls_core::find_urls(text) {
+            let url_str = url_str.to_string();
+            if !url_candidates.contains(&url_str) {
+                trace_if!(debug, url = %rules.redact_sensitive(&url_str), "Found URL via Regex fallback");
+                url_candidates.push(url_str);
+            }
+        }
+    }
+
+    // A reply to a message in another chat only shows its link preview.
+    if let Some(url_str) = preview_url.map(str::to_string) {
+        if !url_candidates.contains(&url_str) {
+            trace_if!(debug, url = %rules.redact_sensitive(&url_str), "Found URL via reply preview");
+            url_candidates.push(url_str);
+        }
+    }
+
+    if url_candidates.is_empty() {
+        trace_if!(debug, "No URL candidates found in message");
+        return Ok(());
+    }
+
+    // 3. Process candidates
+    let mut redirects = HashMap::new();
+    for url_str in url_candidates {
+        // 1. Expand shortened URLs first
+        let chain = budget
+            .run(rules.expand_url_chain(&url_str), "expand")
+            .await
+            .unwrap_or_default();
+        let expanded_url = chain.last().cloned().unwrap_or_else(|| url_str.clone());
+        if chain.len() > 1 {
+            redirects.insert(url_str.clone(), chain);
+        }
+        if expanded_url != url_str {
+            trace_if!(
+                debug,
+                from = %rules.redact_sensitive(&url_str),
+                to = %rules.redact_sensitive(&expanded_url),
+                "Expanded shortened URL"
+            );
+        }
+        let original_url_str = url_str.clone();
+        let mut current_url = expanded_url;
+
+        // 2. Sanitization
+        if let Some((cleaned, provider)) = rules.sanitize(
+            &current_url,
+            &custom_rules,
+            &ignored_domains,
+            cleaning_level,
+        ) {
+            current_url = cleaned;
+            tracing::info!(provider = %provider, "URL sanitized by engine");
+
+            if ai_enabled {
+                if let Some(Ok(Some(ai_cleaned))) =
+                    budget.run(ai.sanitize(user_id, &current_url), "ai").await
+                {
+                    current_url = ai_cleaned.cleaned_url;
+                    let provider_name = format!("AI ({})", provider);
+                    cleaned_urls.push((original_url_str, current_url, provider_name, "rules+ai"));
+                    continue;
+                }
+            }
+
+            tracing::info!(
+                original = %rules.redact_sensitive(&original_url_str),
+                cleaned = %current_url,
+                provider = %provider,
+                "URL sanitized by engine"
+            );
+            cleaned_urls.push((original_url_str, current_url, provider, "rules"));
+        } else {
+            trace_if!(
+                debug,
+                url = %rules.redact_sensitive(&current_url),
+                custom_rules = custom_rules.len(),
+                ignored_domains = ignored_domains.len(),
+                "URL was already clean"
+            );
+            if ai_enabled {
+                if let Some(Ok(Some(ai_cleaned))) =
+                    budget.run(ai.sanitize(user_id, &current_url), "ai").await
+                {
+                    tracing::info!("URL sanitized by AI fallback");
+                    cleaned_urls.push((
+                        original_url_str,
+                        ai_cleaned.cleaned_url,
+                        "AI (Deep Scan)".to_string(),
+                        "ai",
+                    ));
+                }
+            }
+        }
+    }
+
+    if cleaned_urls.is_empty() {
+        tracing::info!("Processing finished: no URLs required cleaning");
+        return Ok(());
+    }
+
+    // A channel without a recorded owner has no one to credit: its posts
+    // are cleaned but neither counted nor logged.
+    let credited = user_id != 0;
+    if credited && !dry_run {
+        let _ = db
+            .increment_cleaned_count(user_id, cleaned_urls.len() as i64)
+            .await;
+    }
+    if credited {
+        for (orig, clean, prov, source) in &cleaned_urls {
+            let chain = redirects.get(orig).map(Vec::as_slice).unwrap_or_default();
+            let source = if dry_run { "dry_run" } else { *source };
+            let cleaning = (orig.as_str(), clean.as_str(), prov.as_str(), source);
+            log_cleaning(
+                &db,
+                &event_tx,
+                &me,
+                user_id,
+                user_config.keeps_history(),
+                Some(chat_id.0),
+                cleaning,
+                chain,
+            )
+            .await;
+        }
+    }
+
+    trace_if!(
+        debug,
+        mode = %mode,
+        chat_mode = %chat_config.mode,
+        user_mode = %user_config.mode,
+        cleaned = cleaned_urls.len(),
+        "Resolved reply mode"
+    );
+    if let Err(e) = db.record_usage(&format!("mode:{}", mode)).await {
+        tracing::warn!(error = %e, "Failed to record mode usage");
+    }
+    // Digest mode only counts; the daily summary comes from `digest`.
+    if mode == "digest" && is_group_context {
+        return Ok(());
+    }
+    if dry_run {
+        trace_if!(
+            debug,
+            cleaned = cleaned_urls.len(),
+            "Dry run, not answering"
+        );
+        return Ok(());
+    }
+    // Without the right to edit the post, the channel gets a reply instead.
+    if mode == "edit" {
+        let links: Vec<(&str, &str)> = cleaned_urls
+            .iter()
+            .map(|(original, cleaned, _, _)| (original.as_str(), cleaned.as_str()))
+            .collect();
+        let (text, entities) = replace_links(text, entities.unwrap_or_default().to_vec(), &links);
+        let caption = msg.text().is_none();
+        match bot
+            .edit_message(chat_id, msg.id, text, entities, caption)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => {
+                tracing::warn!(error = %e, chat_id = %chat_id, "Failed to edit channel post, replying")
+            }
+        }
+    }
+    // A link shared again shortly after is counted above but not answered
+    // twice. Delete and repost modes still replace the message.
+    let repeat_window = config.repeat_window_secs as i64;
+    let replaces_message = matches!(mode.as_str(), "delete" | "repost");
+    if is_group_context && !replaces_message && repeat_window > 0 {
+        let now = crate::db::unix_now().unwrap_or(0);
+        let mut fresh = Vec::with_capacity(cleaned_urls.len());
+        for link in cleaned_urls {
+            if !replies
+                .recent
+                .seen(chat_id.0, &link.1, now, repeat_window)
+                .await
+            {
+                fresh.push(link);
+            }
+        }
+        cleaned_urls = fresh;
+        if cleaned_urls.is_empty() {
+            trace_if!(debug, "Every link was answered recently, not replying");
+            return Ok(());
+        }
+    }
+
+    let mut warnings = vec![None; cleaned_urls.len()];
+    if ai_enabled && config.ai_phishing_check {
+        let checks = cleaned_urls.iter().map(|(_, cleaned, _, _)| {
+            let check = risk_warning(&db, &ai, user_id, cleaned, config.ai_phishing_threshold);
+            budget.run(check, "phishing check")
+        });
+        warnings = futures::future::join_all(checks)
+            .await
+            .into_iter()
+            .map(Option::flatten)
+            .collect();
+    }
+    let warning_prefix = |i: usize| -> String {
+        warnings[i]
+            .as_ref()
+            .map(|reason| tr.risk_warning.replace("{}", &html::escape(reason)))
+            .unwrap_or_default()
+    };
+
+    let user_name = msg
+        .from
+        .as_ref()
+        .map(|u| u.first_name.clone())
+        .or_else(|| msg.chat.title().map(str::to_string))
+        .unwrap_or_else(|| "User".into());
+    let bullet_list = || {
+        cleaned_urls
+            .iter()
+            .enumerate()
+            .map(|(i, (_, cleaned, _, _))| {
+                format!(
+                    "• {}<a href=\"{}\">{}</a>\n",
+                    warning_prefix(i),
+                    html::escape(cleaned),
+                    html::escape(cleaned)
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // DM mode posts nothing in the group: the author gets the links in
+    // private, or a short pointer to the bot if they never started it.
+    if mode == "dm" && is_group_context {
+        let title = msg.chat.title().unwrap_or(tr.unknown);
+        let header = tr.cleaned_from.replace("{}", &html::escape(title));
+        let mut delivered = false;
+        for page in paginate(header, bullet_list(), MAX_MESSAGE_LENGTH) {
+            if user_id == 0 {
+                break;
+            }
+            match bot
+                .send_message(ChatId(user_id), page, SendOptions::default())
+                .await
+            {
+                Ok(()) => delivered = true,
+                Err(e) => {
+                    tracing::info!(error = %e, "Could not DM cleaned links");
+                    break;
+                }
+            }
+        }
+        if !delivered {
+            let start_url = format!("https://t.me/{}", me.username());
+            let text = tr
+                .dm_start_bot
+                .replacen("{}", &html::escape(&user_name), 1)
+                .replacen("{}", &html::escape(&start_url), 1);
+            let options = SendOptions {
+                reply_to: Some(msg.id),
+                thread_id: msg.thread_id,
+                disable_link_preview: true,
+                ..SendOptions::default()
+            };
+            bot.send_message(chat_id, text, options).await?;
+        }
+        return Ok(());
+    }
+
+    let link_buttons = || {
+        link_keyboard(
+            cleaned_urls
+                .iter()
+                .map(|(_, cleaned, _, _)| cleaned.as_str()),
+            config.instant_view_rhash.as_deref(),
+            &tr,
+        )
+    };
+
+    // Past the chat's reply budget, links wait for the next minute and go
+    // out together.
+    let reply_limit = config.chat_reply_limit;
+    if is_group_context && reply_limit > 0 {
+        let now = crate::db::unix_now().unwrap_or(0);
+        let flood = &replies.flood;
+        if flood.limiter.hit(&chat_id.0.to_string(), now).await > reply_limit {
+            let entries = cleaned_urls
+                .iter()
+                .enumerate()
+                .map(|(i, (_, cleaned, _, _))| {
+                    format!(
+                        "• {}: {}<a href=\"{}\">{}</a>\n",
+                        html::escape(&user_name),
+                        warning_prefix(i),
+                        html::escape(cleaned),
+                        html::escape(cleaned)
+                    )
+                })
+                .collect::<Vec<_>>();
+            trace_if!(
+                debug,
+                held = entries.len(),
+                "Chat over its reply limit, holding links"
+            );
+            let thread_id = msg.thread_id.filter(|_| msg.is_topic_message);
+            if flood.hold(chat_id.0, thread_id, entries) {
+                let delay = Duration::from_secs(flood.limiter.retry_after(now));
+                tokio::spawn(send_held_links(
+                    bot.clone(),
+                    flood.clone(),
+                    chat_id,
+                    thread_id,
+                    tr.held_links.to_string(),
+                    tr.held_links_more,
+                    delay,
+                ));
+            }
+            return Ok(());
+        }
+    }
+
+    // Reaction mode only marks the message and puts the links behind a
+    // button, so busy groups are not filled with replies.
+    if mode == "reaction" && is_group_context {
+        if let Err(e) = bot
+            .set_reaction(chat_id, msg.id, REACTION_EMOJI.to_string())
+            .await
+        {
+            tracing::warn!(error = %e, chat_id = %chat_id, "Failed to react to message");
+        }
+        let header = tr.cleaned_for.replace("{}", &html::escape(&user_name));
+        let pages = paginate(header, bullet_list(), MAX_MESSAGE_LENGTH);
+        replies
+            .hidden
+            .0
+            .insert((chat_id.0, msg.id.0), Arc::new((pages, link_buttons())))
+            .await;
+        let button = InlineKeyboardButton::callback(
+            tr.show_cleaned,
+            format!("{}{}", SHOW_LINKS_PREFIX, msg.id.0),
+        );
+        let options = SendOptions {
+            reply_to: Some(msg.id),
+            thread_id: msg.thread_id,
+            keyboard: Some(InlineKeyboardMarkup::new([[button]])),
+            ..SendOptions::default()
+        };
+        let text = tr
+            .reaction_hint
+            .replace("{}", &cleaned_urls.len().to_string());
+        bot.send_message(chat_id, text, options).await?;
+        return Ok(());
+    }
+
+    // Repost mode sends the whole text again with the links replaced in
+    // place, so the conversation reads as before. Captions (the media cannot
+    // be re-sent) and texts that would no longer fit are handled like delete
+    // mode.
+    let mut mode = mode;
+    if mode == "repost" && is_group_context {
+        let header = tr.reposted_for.replace("{}", &html::escape(&user_name));
+        let repost = msg.text().and_then(|_| {
+            let links: Vec<(&str, &str)> = cleaned_urls
+                .iter()
+                .map(|(original, cleaned, _, _)| (original.as_str(), cleaned.as_str()))
+                .collect();
+            let (text, entities) =
+                replace_links(text, entities.unwrap_or_default().to_vec(), &links);
+            let length = header.encode_utf16().count() + text.encode_utf16().count();
+            (length <= MAX_MESSAGE_LENGTH)
+                .then(|| format!("{}{}", header, Renderer::new(&text, &entities).as_html()))
+        });
+        match repost {
+            Some(repost) => {
+                let options = SendOptions {
+                    reply_to: msg.reply_to_message().map(|reply| reply.id),
+                    thread_id: msg.thread_id,
+                    keyboard: link_buttons(),
+                    ..SendOptions::default()
+                };
+                if replace_message(&bot, chat_id, msg.id, vec![(repost, options)]).await? {
+                    return Ok(());
+                }
+            }
+            None => mode = "delete".to_string(),
+        }
+    }
+
+    if mode == "delete" {
+        let header = tr.cleaned_for.replace("{}", &html::escape(&user_name));
+        let pages = paginate(header, bullet_list(), MAX_MESSAGE_LENGTH);
+        let last = pages.len().saturating_sub(1);
+        let pages = pages
+            .into_iter()
+            .enumerate()
+            .map(|(i, page)| {
+                let options = SendOptions {
+                    thread_id: msg.thread_id,
+                    keyboard: if i == last { link_buttons() } else { None },
+                    ..SendOptions::default()
+                };
+                (page, options)
+            })
+            .collect();
+        if replace_message(&bot, chat_id, msg.id, pages).await? {
+            return Ok(());
+        }
+    }
+
+    let mut response = if is_group_context {
+        tr.cleaned_for.replace("{}", &html::escape(&user_name))
+    } else {
+        String::from(tr.cleaned_links)
+    };
+
+    if !response.ends_with('\n') {
+        response.push('\n');
+    }
+
+    const MAX_SUMMARIES: usize = 3;
+
+    // Destination summaries, private chats only to keep groups quiet
+    let mut summaries = vec![None; cleaned_urls.len()];
+    if !is_group_context && ai_enabled && config.ai_summaries {
+        for (i, (_, cleaned, _, _)) in cleaned_urls.iter().take(MAX_SUMMARIES).enumerate() {
+            match budget.run(ai.summarize(user_id, cleaned), "summary").await {
+                Some(Ok(summary)) => summaries[i] = summary,
+                Some(Err(e)) => tracing::debug!(error = %e, "Failed to summarize destination"),
+                None => break,
+            }
+        }
+    }
+    let summary_line = |i: usize| -> String {
+        summaries[i]
+            .as_ref()
+            .map(|s| format!("\n<i>{}</i>", html::escape(s)))
+            .unwrap_or_default()
+    };
+    // Collapsed by default so the hops don't crowd the cleaned link.
+    let redirect_section = |original: &str| -> String {
+        let Some(chain) = redirects.get(original).filter(|_| config.show_redirects) else {
+            return String::new();
+        };
+        let hops: Vec<String> = chain.iter().map(|url| html::escape(url)).collect();
+        format!(
+            "\n<blockquote expandable>{}\n{}</blockquote>",
+            tr.redirect_chain,
+            hops.join("\n→ ")
+        )
+    };
+
+    let single = cleaned_urls.len() == 1;
+    let entries = cleaned_urls
+        .iter()
+        .enumerate()
+        .map(|(i, (original, cleaned, _, _))| {
+            let escaped_url = html::escape(cleaned.trim());
+            let entry = format!(
+                "{}<a href=\"{}\">{}</a>{}{}",
+                warning_prefix(i),
+                escaped_url,
+                escaped_url,
+                summary_line(i),
+                redirect_section(original)
+            );
+            if single {
+                entry
+            } else {
+                format!("• {}\n", entry)
+            }
+        });
+    let pages = paginate(response, entries, MAX_MESSAGE_LENGTH);
+
+    tracing::info!(chat_id = %chat_id, pages = pages.len(), "Sending cleaned URLs reply");
+
+    // Every page answers the original message, in its topic if any.
+    let options = SendOptions {
+        reply_to: Some(msg.id),
+        // Support for Supergroup topics/threads
+        thread_id: msg.thread_id,
+        disable_link_preview: true, // Keep groups clean by default
+        ..SendOptions::default()
+    };
+
+    // Buttons go under the last page, after every link they open.
+    let last = pages.len().saturating_sub(1);
+    for (i, page) in pages.into_iter().enumerate() {
+        let options = SendOptions {
+            keyboard: if i == last { link_buttons() } else { None },
+            ..options.clone()
+        };
+        if let Err(e) = bot.send_message(chat_id, page, options).await {
+            tracing::error!(chat_id = %chat_id, error = %e, "Failed to send cleaned URLs reply");
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Deletes `msg_id` and posts `pages` in its place; `Ok(false)` when the
+/// message could not be deleted and nothing was posted. The pair runs as
+/// its own task, so the message deadline cannot stop it after the delete
+/// and leave the chat without the cleaned link. If the task fails, the
+/// result says whether the delete had gone through.
+async fn replace_message<M: Messenger + Clone + 'static>(
+    bot: &M,
+    chat_id: ChatId,
+    msg_id: MessageId,
+    pages: Vec<(String, SendOptions)>,
+) -> ResponseResult<bool> {
+    let bot = bot.clone();
+    let deleted = Arc::new(AtomicBool::new(false));
+    let task = tokio::spawn({
+        let deleted = deleted.clone();
+        async move {
+            if bot.delete_message(chat_id, msg_id).await.is_err() {
+                return Ok(false);
+            }
+            deleted.store(true, Ordering::Relaxed);
+            for (text, options) in pages {
+                bot.send_message(chat_id, text, options).await?;
+            }
+            Ok(true)
+        }
+    });
+    task.await.unwrap_or_else(|e| {
+        tracing::warn!(chat_id = %chat_id, error = %e, "Message replacement task failed");
+        Ok(deleted.load(Ordering::Relaxed))
+    })
+}
+
+/// The host named by a /ignore argument (`example.com`, `www.example.com`
+/// or a whole link), lowercased and without `www.`.
+fn normalize_domain(arg: &str) -> Option<String> {
+    let arg = arg.trim().to_lowercase();
+    if arg.is_empty() {
+        return None;
+    }
+    let url = if arg.contains("://") {
+        arg
+    } else {
+        format!("http://{}", arg)
+    };
+    let url = url::Url::parse(&url).ok()?;
+    let host = url.host_str()?;
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    (host.contains('.') && !host.starts_with('.')).then(|| host.to_string())
+}
+
+/// The forum topic `msg` was posted in, 0 for General, whose messages carry
+/// no thread id; None outside forums.
+fn forum_topic(msg: &Message) -> Option<i64> {
+    let is_forum = matches!(
+        &msg.chat.kind,
+        ChatKind::Public(ChatPublic {
+            kind: PublicChatKind::Supergroup(group),
+            ..
+        }) if group.is_forum
+    );
+    let thread_id = msg
+        .thread_id
+        .filter(|_| msg.is_topic_message)
+        .map_or(0, |thread| thread.0 .0 as i64);
+    is_forum.then_some(thread_id)
+}
+
+/// The member named by a /whitelist command, as stored in
+/// `chat_user_whitelist`: the author of the replied message, a mention of a
+/// member without a username, or a user id, or else a lowercase `@user`
+/// name, since the Bot API cannot look usernames up.
+fn whitelist_member(msg: &Message, arg: &str) -> Option<String> {
+    let arg = arg.trim();
+    if arg.is_empty() {
+        // In forums a plain message replies to the topic's first message.
+        let topic_root = msg.thread_id.map(|thread| thread.0);
+        return msg
+            .reply_to_message()
+            .filter(|reply| Some(reply.id) != topic_root)
+            .and_then(|reply| reply.from.as_ref())
+            .map(|user| user.id.0.to_string());
+    }
+    let mentioned = msg.entities().and_then(|entities| {
+        entities.iter().find_map(|entity| match &entity.kind {
+            MessageEntityKind::TextMention { user } => Some(user.id.0.to_string()),
+            _ => None,
+        })
+    });
+    if mentioned.is_some() {
+        return mentioned;
+    }
+    if let Ok(id) = arg.parse::<i64>() {
+        return (id > 0).then(|| id.to_string());
+    }
+    let name = arg.strip_prefix('@')?.to_lowercase();
+    let valid = (4..=32).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    valid.then_some(name)
+}
+
+/// How a whitelisted member is shown: `@user`, or the id of a member added
+/// without one.
+fn member_label(member: &str) -> String {
+    if member.parse::<i64>().is_ok() {
+        format!("<code>{}</code>", member)
+    } else {
+        format!("@{}", html::escape(member))
+    }
+}
+
+/// Query parameters of `original` that `cleaned` no longer has, in order.
+fn removed_params(original: &str, cleaned: &str) -> Vec<String> {
+    let (Ok(original), Ok(cleaned)) = (url::Url::parse(original), url::Url::parse(cleaned)) else {
+        return Vec::new();
+    };
+    let kept: Vec<_> = cleaned.query_pairs().map(|(name, _)| name).collect();
+    let mut removed: Vec<String> = Vec::new();
+    for (name, _) in original.query_pairs() {
+        if !kept.contains(&name) && !removed.iter().any(|r| *r == name) {
+            removed.push(name.into_owned());
+        }
+    }
+    removed
+}
+
+/// Links under one reply that get buttons; further links get none.
+const MAX_LINK_BUTTONS: usize = 5;
+/// Telegram caps inline queries, so longer links cannot be shared this way.
+const MAX_INLINE_QUERY_LENGTH: usize = 256;
+
+/// One row per cleaned link: Share, which opens the chat picker with the
+/// bot's inline mode pre-filled with the link, and Instant View when a
+/// template is configured. Labels are numbered when there are several links.
+fn link_keyboard<'a>(
+    urls: impl ExactSizeIterator<Item = &'a str>,
+    rhash: Option<&str>,
+    tr: &i18n::Translations,
+) -> Option<InlineKeyboardMarkup> {
+    let numbered = urls.len() > 1;
+    let rows: Vec<Vec<InlineKeyboardButton>> = urls
+        .take(MAX_LINK_BUTTONS)
+        .enumerate()
+        .map(|(i, url)| {
+            let label = |text: &str| {
+                if numbered {
+                    format!("{} {}", text, i + 1)
+                } else {
+                    text.to_string()
+                }
+            };
+            let url = url.trim();
+            let instant_view = rhash
+                .and_then(|rhash| crate::instant_view::link(url, rhash))
+                .map(|link| InlineKeyboardButton::url(label(tr.instant_view), link));
+            let share = (url.chars().count() <= MAX_INLINE_QUERY_LENGTH)
+                .then(|| InlineKeyboardButton::switch_inline_query(label(tr.share), url));
+            instant_view.into_iter().chain(share).collect()
+        })
+        .filter(|row: &Vec<_>| !row.is_empty())
+        .collect();
+    (!rows.is_empty()).then(|| InlineKeyboardMarkup::new(rows))
+}
+
+/// Leaves a buffer below Telegram's 4096 character limit.
+const MAX_MESSAGE_LENGTH: usize = 4000;
+
+/// Splits a reply into messages of at most `max` bytes without cutting an
+/// entry: `header` opens the first one and every later message continues
+/// the list. An entry longer than `max` on its own still gets its own
+/// message rather than being dropped.
+fn paginate(header: String, entries: impl IntoIterator<Item = String>, max: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = header;
+    for entry in entries {
+        if !page.is_empty() && page.len() + entry.len() > max {
+            pages.push(std::mem::take(&mut page));
+        }
+        page.push_str(&entry);
+    }
+    if !page.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::messenger::MockMessenger;
+    use crate::models::{ChatConfig, UserConfig};
+    use teloxide::types::MessageId;
+
+    const USER_ID: i64 = 4242;
+    const GROUP_ID: i64 = -1001234;
+
+    struct Harness {
+        db: Db,
+        rules: RuleEngine,
+        ai: AiEngine,
+        config: SharedConfig,
+        events: tokio::sync::broadcast::Sender<serde_json::Value>,
+        me: Me,
+        replies: ChatReplies,
+        admins: AdminCache,
+    }
+
+    impl Harness {
+        async fn new(name: &str) -> Self {
+            let db = Db::test(name).await;
+            let mut config = Config::load(None);
+            config.ai_api_key = None;
+            let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+            let me = serde_json::from_value(serde_json::json!({
+                "id": 1, "is_bot": true, "first_name": "Clear", "username": "clear_urls_bot",
+                "can_join_groups": true, "can_read_all_group_messages": true,
+                "supports_inline_queries": false, "has_main_web_app": false,
+            }))
+            .unwrap();
+            Self {
+                db,
+                rules: RuleEngine::new_lazy("").with_embedded_rules(),
+                ai: AiEngine::new(config.clone()),
+                config,
+                events: tokio::sync::broadcast::channel(16).0,
+                me,
+                replies: ChatReplies::new(),
+                admins: AdminCache::new(),
+            }
+        }
+
+        async fn handle(&self, messenger: &MockMessenger, msg: Message) {
+            handle_message(
+                messenger.clone(),
+                msg,
+                self.db.clone(),
+                self.rules.clone(),
+                self.ai.clone(),
+                self.config.clone(),
+                self.events.clone(),
+                self.me.clone(),
+                self.replies.clone(),
+                self.admins.clone(),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    fn message(chat: serde_json::Value, text: &str) -> Message {
+        serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": chat,
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+            "text": text,
+        }))
+        .unwrap()
+    }
+
+    fn private_message(text: &str) -> Message {
+        message(
+            serde_json::json!({ "id": USER_ID, "type": "private", "first_name": "Ada" }),
+            text,
+        )
+    }
+
+    fn group_message(text: &str) -> Message {
+        message(
+            serde_json::json!({ "id": GROUP_ID, "type": "supergroup", "title": "Links" }),
+            text,
+        )
+    }
+
+    async fn save_group(h: &Harness, mode: &str) {
+        let chat = ChatConfig {
+            chat_id: GROUP_ID,
+            title: Some("Links".into()),
+            added_by: USER_ID,
+            mode: mode.into(),
+            ..Default::default()
+        };
+        h.db.save_chat_config(&chat, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn replies_with_cleaned_link() {
+        let h = Harness::new("reply").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            private_message("look https://example.com/page?utm_source=x&id=1"),
+        )
+        .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (chat_id, text, options) = &sent[0];
+        assert_eq!(*chat_id, ChatId(USER_ID));
+        assert!(text.contains("https://example.com/page?id=1"));
+        assert!(!text.contains("utm_source"));
+        assert_eq!(options.reply_to, Some(MessageId(7)));
+        assert!(options.disable_link_preview);
+        assert!(messenger.deleted.lock().unwrap().is_empty());
+        assert_eq!(
+            h.db.get_user_config(USER_ID).await.unwrap().cleaned_count,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn reassembles_links_split_by_formatting() {
+        let h = Harness::new("split").await;
+        let messenger = MockMessenger::default();
+        // Bold `id=1` cut the URL entity short; on its own the fragment
+        // would be cleaned as a second, wrong link.
+        let text = "é (https://example.com/page?utm_source=x&id=1).";
+        let mut msg = private_message(text);
+        msg.kind = serde_json::from_value(serde_json::json!({
+            "text": text,
+            "entities": [
+                { "type": "url", "offset": 3, "length": 38 },
+                { "type": "bold", "offset": 41, "length": 4 },
+            ],
+        }))
+        .unwrap();
+        h.handle(&messenger, msg).await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("https://example.com/page?id=1"));
+        assert_eq!(
+            h.db.get_user_config(USER_ID).await.unwrap().cleaned_count,
+            1
+        );
+        assert_eq!(utf16_range("é😀ab", 1, 3), Some(2..7));
+        assert_eq!(utf16_range("😀", 1, 1), None);
+    }
+
+    #[test]
+    fn paginates_without_splitting_entries() {
+        let entries = || ["aaaa\n", "bbbb\n", "cccc\n"].map(String::from);
+        assert_eq!(
+            paginate("H\n".into(), entries(), 100),
+            ["H\naaaa\nbbbb\ncccc\n"]
+        );
+        assert_eq!(
+            paginate("H\n".into(), entries(), 12),
+            ["H\naaaa\nbbbb\n", "cccc\n"]
+        );
+        assert_eq!(
+            paginate(String::new(), entries(), 3),
+            ["aaaa\n", "bbbb\n", "cccc\n"]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_replies_are_split_not_truncated() {
+        let h = Harness::new("paginate").await;
+        let messenger = MockMessenger::default();
+        let links: Vec<String> = (0..60)
+            .map(|i| format!("https://example.com/{}/{}?utm_source=x", "p".repeat(60), i))
+            .collect();
+        h.handle(&messenger, private_message(&links.join(" ")))
+            .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert!(sent.len() > 1);
+        for (_, text, options) in &sent {
+            assert!(text.len() <= MAX_MESSAGE_LENGTH);
+            assert_eq!(options.reply_to, Some(MessageId(7)));
+        }
+        let all: String = sent.iter().map(|(_, text, _)| text.as_str()).collect();
+        for i in 0..60 {
+            assert!(all.contains(&format!("{}/{}\"", "p".repeat(60), i)));
+        }
+        assert!(!all.contains("truncated"));
+    }
+
+    #[tokio::test]
+    async fn settings_link_is_sent_privately_to_the_owner() {
+        let h = Harness::new("settings").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.dashboard_url = Some("https://dash.example".into());
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+
+        // Nobody owns the chat yet, and the member is no administrator.
+        h.handle(&messenger, group_message("/settings@clear_urls_bot"))
+            .await;
+        save_group(&h, "default").await;
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        h.admins.invalidate(GROUP_ID).await;
+        h.handle(&messenger, group_message("/settings@clear_urls_bot"))
+            .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0].0, ChatId(GROUP_ID));
+        assert!(!sent[0].1.contains("https://dash.example"));
+        let (dm_chat, dm_text, _) = &sent[1];
+        assert_eq!(*dm_chat, ChatId(USER_ID));
+        let prefix = format!("https://dash.example/chats/{}?token=", GROUP_ID);
+        let start = dm_text.find(&prefix).unwrap() + prefix.len();
+        let token = &dm_text[start..dm_text[start..].find('"').unwrap() + start];
+        let bot_token = h.config.load().bot_token.clone();
+        assert_eq!(
+            crate::links::verify_chat_link(&bot_token, token, crate::db::unix_now().unwrap()),
+            Some((GROUP_ID, USER_ID))
+        );
+        assert_eq!(sent[2].0, ChatId(GROUP_ID));
+    }
+
+    #[tokio::test]
+    async fn login_links_work_once() {
+        let h = Harness::new("login").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.dashboard_url = Some("https://dash.example".into());
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, group_message("/login@clear_urls_bot"))
+            .await;
+        h.handle(&messenger, private_message("/login")).await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        assert!(!sent[0].1.contains("https://dash.example"));
+        let prefix = "https://dash.example/login?token=";
+        let start = sent[1].1.find(prefix).unwrap() + prefix.len();
+        let token = &sent[1].1[start..start + 64];
+        assert_eq!(
+            h.db.consume_login_token(token).await.unwrap(),
+            Some(USER_ID)
+        );
+        assert_eq!(h.db.consume_login_token(token).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn export_is_sent_as_a_document_in_private_only() {
+        let h = Harness::new("export").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, group_message("/export@clear_urls_bot"))
+            .await;
+        assert!(messenger.documents.lock().unwrap().is_empty());
+        assert_eq!(messenger.sent.lock().unwrap().len(), 1);
+
+        h.handle(&messenger, private_message("/export")).await;
+        let documents = messenger.documents.lock().unwrap().clone();
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].0, ChatId(USER_ID));
+        assert_eq!(documents[0].1, format!("clear_urls_{}.zip", USER_ID));
+        assert!(documents[0].2.starts_with(b"PK"));
+    }
+
+    #[tokio::test]
+    async fn budget_skips_steps_once_exhausted() {
+        let budget = Budget::new(Duration::from_millis(20));
+        assert_eq!(budget.run(async { 1 }, "fast").await, Some(1));
+        let hung = budget.run(std::future::pending::<()>(), "hung").await;
+        assert_eq!(hung, None);
+        // Whatever is left after the deadline is skipped outright.
+        let late = budget.run(tokio::time::sleep(Duration::from_millis(1)), "late");
+        assert_eq!(late.await, None);
+    }
+
+    #[tokio::test]
+    async fn stays_quiet_for_clean_links() {
+        let h = Harness::new("clean").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("https://example.com/page?id=1"))
+            .await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cleans_links_from_quotes_and_reply_previews() {
+        let h = Harness::new("quotes").await;
+        let messenger = MockMessenger::default();
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": { "id": USER_ID, "type": "private", "first_name": "Ada" },
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+            "text": "this one?",
+            "quote": { "text": "read https://example.com/a?utm_source=x", "position": 0 },
+            "external_reply": {
+                "origin": {
+                    "type": "channel",
+                    "date": 1_700_000_000,
+                    "chat": { "id": -100, "type": "channel", "title": "News" },
+                    "message_id": 3,
+                },
+                "chat": { "id": -100, "type": "channel", "title": "News" },
+                "message_id": 3,
+                // teloxide cannot parse an external reply without media.
+                "photo": [{ "file_id": "a", "file_unique_id": "b", "width": 1, "height": 1 }],
+                "link_preview_options": { "url": "https://example.com/b?fbclid=1" },
+            },
+        }))
+        .unwrap();
+        h.handle(&messenger, msg).await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("https://example.com/a"));
+        assert!(sent[0].1.contains("https://example.com/b"));
+        assert!(!sent[0].1.contains("utm_source"));
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn quoted_links_leave_the_reply_alone_in_delete_and_repost_modes() {
+        for mode in ["delete", "repost", "reply"] {
+            let h = Harness::new(&format!("quote_{}", mode)).await;
+            save_group(&h, mode).await;
+            let messenger = MockMessenger::default();
+            let mut msg = serde_json::to_value(group_message("agreed")).unwrap();
+            msg["quote"] = serde_json::json!({
+                "text": "read https://example.com/a?utm_source=x",
+                "position": 0,
+            });
+            h.handle(&messenger, serde_json::from_value(msg).unwrap())
+                .await;
+
+            assert!(messenger.deleted.lock().unwrap().is_empty(), "{}", mode);
+            let sent = messenger.sent.lock().unwrap().clone();
+            if mode == "reply" {
+                assert_eq!(sent.len(), 1);
+                assert!(sent[0].1.contains("https://example.com/a"));
+            } else {
+                assert!(sent.is_empty(), "{}", mode);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_mode_replaces_the_message() {
+        let h = Harness::new("delete").await;
+        save_group(&h, "delete").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        assert_eq!(
+            *messenger.deleted.lock().unwrap(),
+            vec![(ChatId(GROUP_ID), MessageId(7))]
+        );
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (chat_id, text, options) = &sent[0];
+        assert_eq!(*chat_id, ChatId(GROUP_ID));
+        assert!(text.contains("Ada"));
+        assert!(text.contains("https://example.com/?q=1"));
+        // The original is gone, so there is nothing to reply to.
+        assert_eq!(options.reply_to, None);
+    }
+
+    #[tokio::test]
+    async fn repost_mode_keeps_the_text_and_its_formatting() {
+        let h = Harness::new("repost").await;
+        save_group(&h, "repost").await;
+        let messenger = MockMessenger::default();
+        let chat = serde_json::json!({ "id": GROUP_ID, "type": "supergroup", "title": "Links" });
+        let msg: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": chat,
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada <3" },
+            "text": "Look: https://example.com/?utm_source=x&q=1 <wow>",
+            "entities": [{ "type": "bold", "offset": 0, "length": 4 }],
+            "reply_to_message": {
+                "message_id": 5,
+                "date": 1_700_000_000,
+                "chat": chat,
+                "text": "any links?",
+            },
+        }))
+        .unwrap();
+        h.handle(&messenger, msg).await;
+
+        assert_eq!(
+            *messenger.deleted.lock().unwrap(),
+            vec![(ChatId(GROUP_ID), MessageId(7))]
+        );
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (_, text, options) = &sent[0];
+        assert_eq!(
+            text,
+            "<b>Ada &lt;3</b> wrote:\n<b>Look</b>: https://example.com/?q=1 &lt;wow&gt;"
+        );
+        // The repost answers what the original answered.
+        assert_eq!(options.reply_to, Some(MessageId(5)));
+        assert!(options.keyboard.is_some());
+
+        // Captions cannot be re-sent without their media: listed as in delete mode.
+        let caption: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 8,
+            "date": 1_700_000_000,
+            "chat": chat,
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+            "photo": [{ "file_id": "a", "file_unique_id": "b", "width": 1, "height": 1 }],
+            "caption": "https://example.com/?utm_source=y&q=2",
+        }))
+        .unwrap();
+        h.handle(&messenger, caption).await;
+        assert_eq!(messenger.deleted.lock().unwrap().len(), 2);
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert!(sent[1].1.contains("Link(s) cleaned for Ada"));
+    }
+
+    #[tokio::test]
+    async fn reaction_mode_reveals_links_on_request() {
+        let h = Harness::new("reaction").await;
+        save_group(&h, "reaction").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        assert_eq!(
+            *messenger.reactions.lock().unwrap(),
+            vec![(ChatId(GROUP_ID), MessageId(7), REACTION_EMOJI.to_string())]
+        );
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (_, text, options) = &sent[0];
+        assert!(!text.contains("example.com"));
+        assert_eq!(options.reply_to, Some(MessageId(7)));
+        let button = &options.keyboard.as_ref().unwrap().inline_keyboard[0][0];
+        assert_eq!(button.text, "Show cleaned links");
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
+
+        let press = || {
+            let mut hint = group_message("🧹");
+            hint.id = MessageId(8);
+            let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": "q1",
+                "from": { "id": 99, "is_bot": false, "first_name": "Bob" },
+                "chat_instance": "1",
+                "data": format!("{}7", SHOW_LINKS_PREFIX),
+                "message": serde_json::to_value(hint).unwrap(),
+            }))
+            .unwrap();
+            handle_callback_query(
+                messenger.clone(),
+                query,
+                h.db.clone(),
+                h.config.clone(),
+                h.replies.clone(),
+                h.admins.clone(),
+                h.me.clone(),
+            )
+        };
+        press().await.unwrap();
+        press().await.unwrap();
+
+        let edited = messenger.edited_texts.lock().unwrap().clone();
+        assert_eq!(edited.len(), 1);
+        let (chat, message_id, text, _) = &edited[0];
+        assert_eq!((*chat, *message_id), (ChatId(GROUP_ID), MessageId(8)));
+        assert!(text.contains("https://example.com/?q=1"));
+        let answers = messenger.answered.lock().unwrap().clone();
+        assert_eq!(answers[1].1, "These links are no longer available.");
+    }
+
+    #[tokio::test]
+    async fn cleaning_level_follows_the_user_unless_the_group_sets_one() {
+        let h = Harness::new("levels").await;
+        let messenger = MockMessenger::default();
+        let link = "https://example.com/?data_source=feed&q=1";
+        h.handle(&messenger, private_message(link)).await;
+        h.handle(&messenger, private_message("/level strict")).await;
+        h.handle(&messenger, private_message(link)).await;
+        assert_eq!(
+            h.db.get_user_config(USER_ID).await.unwrap().cleaning_level,
+            "strict"
+        );
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
+
+        save_group(&h, "reply").await;
+        h.handle(&messenger, group_message(link)).await;
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 2);
+        // Only the owner may pin the group to a level.
+        h.handle(&messenger, group_message("/level conservative"))
+            .await;
+        let mut chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.cleaning_level, "default");
+        chat.cleaning_level = "conservative".into();
+        h.db.save_chat_config(&chat, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        h.handle(&messenger, group_message(link)).await;
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn dm_mode_sends_links_privately() {
+        let h = Harness::new("dm").await;
+        save_group(&h, "dm").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        assert!(messenger.deleted.lock().unwrap().is_empty());
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (chat_id, text, _) = &sent[0];
+        assert_eq!(*chat_id, ChatId(USER_ID));
+        assert!(text.contains("Links"));
+        assert!(text.contains("https://example.com/?q=1"));
+    }
+
+    #[tokio::test]
+    async fn dm_mode_points_unreachable_users_to_the_bot() {
+        let h = Harness::new("dm-fallback").await;
+        save_group(&h, "dm").await;
+        let messenger = MockMessenger::default();
+        messenger.unreachable.lock().unwrap().push(ChatId(USER_ID));
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let (chat_id, text, options) = &sent[0];
+        assert_eq!(*chat_id, ChatId(GROUP_ID));
+        assert!(text.contains("https://t.me/clear_urls_bot"));
+        assert!(!text.contains("https://example.com"));
+        assert_eq!(options.reply_to, Some(MessageId(7)));
+    }
+
+    #[tokio::test]
+    async fn digest_mode_only_records_the_cleaning() {
+        let h = Harness::new("digest").await;
+        save_group(&h, "digest").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        assert!(messenger.sent.lock().unwrap().is_empty());
+        let (total, _, _) = h.db.get_chat_digest(GROUP_ID, 0).await.unwrap();
+        assert_eq!(total, 1);
+    }
+
+    #[tokio::test]
+    async fn dry_run_records_links_without_posting() {
+        let h = Harness::new("dry_run").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        h.handle(&messenger, group_message("/mode@clear_urls_bot dry_run"))
+            .await;
+        assert_eq!(
+            h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap().mode,
+            "dry_run"
+        );
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("<b>dry_run</b>"));
+        assert!(messenger.deleted.lock().unwrap().is_empty());
+        let detections = h.db.get_chat_detections(GROUP_ID, 10).await.unwrap();
+        assert_eq!(detections.len(), 1);
+        assert_eq!(detections[0].cleaned_url, "https://example.com/?q=1");
+    }
+
+    #[tokio::test]
+    async fn dry_run_links_stay_out_of_stats() {
+        let h = Harness::new("dry_run_stats").await;
+        save_group(&h, "dry_run").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+        h.handle(&messenger, group_message("/groupstats@clear_urls_bot"))
+            .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert!(sent[0]
+            .1
+            .ends_with("Links cleaned in this chat: <b>0</b>\nMembers protected: <b>0</b>"));
+        let weekly = h.db.get_weekly_stats(USER_ID, 0, 5).await.unwrap();
+        assert_eq!(weekly.cleaned, 0);
+        assert!(weekly.top_providers.is_empty());
+        let history = h.db.get_chat_history(GROUP_ID, 10).await.unwrap();
+        assert!(history.is_empty());
+        let detections = h.db.get_chat_detections(GROUP_ID, 10).await.unwrap();
+        assert_eq!(detections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dry_run_skips_private_senders() {
+        let h = Harness::new("dry_run_private").await;
+        save_group(&h, "dry_run").await;
+        let user = UserConfig {
+            user_id: USER_ID,
+            log_history: 0,
+            ..Default::default()
+        };
+        h.db.save_user_config(&user, Actor::system()).await.unwrap();
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        assert!(messenger.sent.lock().unwrap().is_empty());
+        let history = h.db.get_chat_history(GROUP_ID, 10).await.unwrap();
+        assert!(history.is_empty());
+        let user = h.db.get_user_config(USER_ID).await.unwrap();
+        assert_eq!(user.cleaned_count, 0);
+        assert_eq!(h.db.get_global_stats().await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn repeated_links_are_answered_once() {
+        let h = Harness::new("repeats").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        for text in [
+            "https://example.com/?utm_medium=social&q=1",
+            "again https://example.com/?q=1&utm_source=x",
+            "https://example.com/?utm_medium=social&q=2",
+        ] {
+            h.handle(&messenger, group_message(text)).await;
+        }
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        assert!(sent[1].1.contains("https://example.com/?q=2"));
+        let (total, _, _) = h.db.get_chat_digest(GROUP_ID, 0).await.unwrap();
+        assert_eq!(total, 3);
+    }
+
+    #[tokio::test]
+    async fn global_stats_count_cleanings_for_good() {
+        let h = Harness::new("global_stats").await;
+        let messenger = MockMessenger::default();
+        for text in [
+            "https://example.com/?utm_medium=social&q=1 https://example.com/?utm_source=x",
+            "https://example.com/?utm_medium=social&q=2",
+            "nothing to clean here",
+        ] {
+            h.handle(&messenger, private_message(text)).await;
+        }
+        h.db.increment_cleaned_count(99, 4).await.unwrap();
+        assert_eq!(h.db.get_global_stats().await.unwrap(), (7, 2));
+
+        // Erasing an account does not take its cleanings back.
+        h.db.delete_all_user_data(USER_ID, Actor::system())
+            .await
+            .unwrap();
+        h.db.increment_cleaned_count(99, 1).await.unwrap();
+        assert_eq!(h.db.get_global_stats().await.unwrap(), (8, 2));
+    }
+
+    #[tokio::test]
+    async fn replies_offer_share_and_instant_view_buttons() {
+        let h = Harness::new("instant_view").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+
+        let mut config = (*h.config.load_full()).clone();
+        config.instant_view_rhash = Some("abc123".into());
+        h.config.store(Arc::new(config));
+        let text = "https://example.com/?utm_medium=social&q=2 https://example.org/?utm_source=x";
+        h.handle(&messenger, group_message(text)).await;
+
+        use teloxide::types::InlineKeyboardButtonKind;
+        let sent = messenger.sent.lock().unwrap().clone();
+        // Without a template, a single link only gets Share.
+        let keyboard = sent[0].2.keyboard.clone().unwrap();
+        assert_eq!(keyboard.inline_keyboard.len(), 1);
+        assert_eq!(keyboard.inline_keyboard[0].len(), 1);
+        let share = &keyboard.inline_keyboard[0][0];
+        assert_eq!(share.text, "↗️ Share");
+        assert_eq!(
+            share.kind,
+            InlineKeyboardButtonKind::SwitchInlineQuery("https://example.com/?q=1".into())
+        );
+
+        let keyboard = sent[1].2.keyboard.clone().unwrap();
+        assert_eq!(keyboard.inline_keyboard.len(), 2);
+        let row = &keyboard.inline_keyboard[1];
+        assert_eq!(row[0].text, "📖 Instant View 2");
+        assert_eq!(row[1].text, "↗️ Share 2");
+        let InlineKeyboardButtonKind::Url(url) = &row[0].kind else {
+            panic!("expected a URL button");
+        };
+        assert_eq!(
+            url.as_str(),
+            "https://t.me/iv?url=https%3A%2F%2Fexample.org%2F&rhash=abc123"
+        );
+    }
+
+    #[tokio::test]
+    async fn inline_queries_clean_and_log_chosen_results() {
+        let h = Harness::new("inline").await;
+        let messenger = MockMessenger::default();
+        let from = serde_json::json!({
+            "id": USER_ID, "is_bot": false, "first_name": "Ada", "language_code": "en"
+        });
+        let text = "https://example.com/?utm_source=x&q=1 and https://example.org/";
+        let query = serde_json::from_value(serde_json::json!({
+            "id": "q1", "from": from, "query": text, "offset": "",
+        }))
+        .unwrap();
+        handle_inline_query(
+            messenger.clone(),
+            query,
+            h.db.clone(),
+            h.rules.clone(),
+            h.config.clone(),
+            MessageLimiter(Arc::new(Semaphore::new(1))),
+        )
+        .await
+        .unwrap();
+
+        let answers = messenger.inline_answers.lock().unwrap().clone();
+        let results: Vec<_> = answers[0]
+            .1
+            .iter()
+            .map(|result| match result {
+                InlineQueryResult::Article(article) => {
+                    (article.title.clone(), article.description.clone().unwrap())
+                }
+                _ => panic!("expected an article"),
+            })
+            .collect();
+        assert_eq!(
+            results,
+            [
+                (
+                    "https://example.com/?q=1".to_string(),
+                    "🧹 Tracking removed (globalRules)".to_string()
+                ),
+                (
+                    "https://example.org/".to_string(),
+                    "✅ Link already clean".to_string()
+                ),
+            ]
+        );
+        // Showing results records nothing.
+        assert!(h.db.get_history(USER_ID, 10).await.unwrap().is_empty());
+
+        for result_id in ["1", "0"] {
+            let chosen = serde_json::from_value(serde_json::json!({
+                "result_id": result_id, "from": from, "query": text,
+            }))
+            .unwrap();
+            handle_chosen_inline_result(
+                chosen,
+                h.db.clone(),
+                h.rules.clone(),
+                h.config.clone(),
+                h.events.clone(),
+                h.me.clone(),
+            )
+            .await
+            .unwrap();
+        }
+        let history = h.db.get_history(USER_ID, 10).await.unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].cleaned_url, "https://example.com/?q=1");
+        assert_eq!(history[0].chat_id, None);
+        assert_eq!(
+            h.db.get_user_config(USER_ID).await.unwrap().cleaned_count,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn busy_inline_queries_get_the_rule_only_result() {
+        let h = Harness::new("inline-busy").await;
+        let messenger = MockMessenger::default();
+        let query = serde_json::from_value(serde_json::json!({
+            "id": "q1",
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+            "query": "https://example.com/?utm_source=x&q=1",
+            "offset": "",
+        }))
+        .unwrap();
+        // Every message slot is taken.
+        handle_inline_query(
+            messenger.clone(),
+            query,
+            h.db.clone(),
+            h.rules.clone(),
+            h.config.clone(),
+            MessageLimiter(Arc::new(Semaphore::new(0))),
+        )
+        .await
+        .unwrap();
+
+        let answers = messenger.inline_answers.lock().unwrap().clone();
+        let InlineQueryResult::Article(article) = &answers[0].1[0] else {
+            panic!("expected an article");
+        };
+        assert_eq!(article.title, "https://example.com/?q=1");
+    }
+
+    #[tokio::test]
+    async fn clean_command_ignores_the_enabled_flag_and_ignored_domains() {
+        let h = Harness::new("clean_command").await;
+        let mut user_config = h.db.get_user_config(USER_ID).await.unwrap();
+        user_config.user_id = USER_ID;
+        user_config.enabled = 0;
+        user_config.ignored_domains = "example.com".into();
+        h.db.save_user_config(&user_config, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        let link = "https://example.com/?utm_source=x&q=1&utm_medium=y";
+        h.handle(&messenger, private_message(link)).await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
+
+        h.handle(&messenger, private_message(&format!("/clean {}", link)))
+            .await;
+        h.handle(
+            &messenger,
+            private_message("/clean https://example.com/?q=1"),
+        )
+        .await;
+        h.handle(&messenger, private_message("/clean")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(
+            sent[0].1,
+            "🧹 Cleaned with the <b>globalRules</b> rules:\n<code>https://example.com/?q=1</code>\n\
+             Removed parameters: <code>utm_source</code>, <code>utm_medium</code>"
+        );
+        assert_eq!(sent[0].2.reply_to, Some(MessageId(7)));
+        assert!(sent[0].2.keyboard.is_some());
+        assert!(sent[1].1.starts_with("✅"));
+        assert!(sent[2].1.starts_with("Usage:"));
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn chat_administrators_manage_ignored_domains() {
+        let h = Harness::new("chat_ignore").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("/ignore@clear_urls_bot https://www.Example.com/x"),
+        )
+        .await;
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        h.admins.invalidate(GROUP_ID).await;
+        h.handle(
+            &messenger,
+            group_message("/ignore@clear_urls_bot https://www.Example.com/x"),
+        )
+        .await;
+        assert_eq!(
+            h.db.get_chat_ignored_domains(GROUP_ID).await.unwrap(),
+            ["example.com"]
+        );
+        h.handle(
+            &messenger,
+            group_message("https://shop.example.com/?utm_source=x"),
+        )
+        .await;
+        h.handle(
+            &messenger,
+            group_message("/unignore@clear_urls_bot example.com"),
+        )
+        .await;
+        h.handle(
+            &messenger,
+            group_message("https://shop.example.com/?utm_source=y"),
+        )
+        .await;
+
+        let sent: Vec<String> = messenger
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text, _)| text.clone())
+            .collect();
+        assert_eq!(sent.len(), 4);
+        assert_eq!(sent[0], "Only the chat's administrators can do this.");
+        assert!(sent[1].contains("<b>example.com</b> are no longer cleaned"));
+        assert!(sent[2].contains("are cleaned again"));
+        assert!(sent[3].contains("https://shop.example.com/"));
+    }
+
+    #[tokio::test]
+    async fn custom_rules_are_capped_and_can_be_removed() {
+        let h = Harness::new("custom_rules").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.max_custom_rules = 2;
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+        for command in [
+            "/addrule ref",
+            "/addrule tag",
+            "/addrule ref",
+            "/addrule more",
+            "/delrule ref",
+            "/delrule ref",
+            "/addrule more",
+        ] {
+            h.handle(&messenger, private_message(command)).await;
+        }
+
+        let sent: Vec<String> = messenger
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text, _)| text.clone())
+            .collect();
+        let tr = i18n::get_translations("en");
+        assert_eq!(sent[2], tr.rule_duplicate);
+        assert_eq!(sent[3], tr.rule_limit.replace("{}", "2"));
+        assert_eq!(sent[4], tr.rule_removed.replace("{}", "ref"));
+        assert_eq!(sent[5], tr.rule_not_found.replace("{}", "ref"));
+        assert_eq!(sent[6], tr.rule_added.replace("{}", "more"));
+        let mut patterns: Vec<String> =
+            h.db.get_custom_rules(USER_ID)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|rule| rule.pattern)
+                .collect();
+        patterns.sort();
+        assert_eq!(patterns, ["more", "tag"]);
+        let audit = h.db.get_audit_log(Some(USER_ID), 10).await.unwrap();
+        assert!(audit.iter().any(|e| e.action == "custom_rule.remove"
+            && e.details.as_deref().is_some_and(|d| d.contains("\"ref\""))));
+    }
+
+    #[tokio::test]
+    async fn whitelisted_members_keep_their_links() {
+        let h = Harness::new("chat_whitelist").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        let from = |id: i64, username: &str, text: &str| {
+            let mut msg = serde_json::to_value(group_message(text)).unwrap();
+            msg["from"] = serde_json::json!({
+                "id": id, "is_bot": true, "first_name": "Feed", "username": username,
+            });
+            msg
+        };
+        h.handle(
+            &messenger,
+            group_message("/whitelist@clear_urls_bot @Feed_Bot"),
+        )
+        .await;
+        let mut reply = serde_json::to_value(group_message("/whitelist@clear_urls_bot")).unwrap();
+        reply["reply_to_message"] = from(77, "news_bot", "hi");
+        h.handle(&messenger, serde_json::from_value(reply).unwrap())
+            .await;
+        assert_eq!(
+            h.db.get_chat_whitelist(GROUP_ID).await.unwrap(),
+            ["77", "feed_bot"]
+        );
+        for (id, username) in [(99, "feed_bot"), (77, "renamed_bot")] {
+            let msg = from(id, username, "https://example.com/?utm_source=x");
+            h.handle(&messenger, serde_json::from_value(msg).unwrap())
+                .await;
+        }
+        h.handle(
+            &messenger,
+            group_message("/unwhitelist@clear_urls_bot @feed_bot"),
+        )
+        .await;
+        let msg = from(99, "feed_bot", "https://example.com/?utm_source=y");
+        h.handle(&messenger, serde_json::from_value(msg).unwrap())
+            .await;
+
+        let sent: Vec<String> = messenger
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text, _)| text.clone())
+            .collect();
+        assert_eq!(sent.len(), 4);
+        assert!(sent[0].contains("Links from @feed_bot are no longer cleaned"));
+        assert!(sent[1].contains("Links from <code>77</code>"));
+        assert!(sent[2].contains("@feed_bot are cleaned again"));
+        assert!(sent[3].contains("https://example.com/"));
+        assert!(!sent[3].contains("utm_source"));
+    }
+
+    #[tokio::test]
+    async fn forum_topics_can_be_turned_on_or_off() {
+        let h = Harness::new("forum_topics").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        let in_topic = |thread_id: Option<i32>, text: &str| {
+            let mut msg = serde_json::to_value(group_message(text)).unwrap();
+            msg["chat"]["is_forum"] = true.into();
+            if let Some(thread_id) = thread_id {
+                msg["is_topic_message"] = true.into();
+                msg["message_thread_id"] = thread_id.into();
+            }
+            serde_json::from_value::<Message>(msg).unwrap()
+        };
+        h.handle(&messenger, in_topic(Some(5), "/topic@clear_urls_bot off"))
+            .await;
+        let link = |i: usize| format!("https://example.com/{}?utm_source=x", i);
+        for (i, thread_id) in [None, Some(5), Some(6)].into_iter().enumerate() {
+            h.handle(&messenger, in_topic(thread_id, &link(i))).await;
+        }
+        h.handle(&messenger, in_topic(Some(6), "/topic@clear_urls_bot on"))
+            .await;
+        for (i, thread_id) in [None, Some(5), Some(6)].into_iter().enumerate() {
+            h.handle(&messenger, in_topic(thread_id, &link(i + 3)))
+                .await;
+        }
+        h.handle(&messenger, group_message("/topic@clear_urls_bot on"))
+            .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        let summary: Vec<(Option<i32>, bool)> = sent
+            .iter()
+            .map(|(_, text, options)| {
+                (
+                    options.thread_id.map(|thread| thread.0 .0),
+                    text.contains("https://example.com/"),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Some(5), false),
+                (None, true),
+                (Some(6), true),
+                (Some(6), false),
+                (Some(6), true),
+                (None, false),
+            ]
+        );
+        assert!(sent[0].1.contains("no longer cleaned"));
+        assert_eq!(sent[5].1, "This command only works in groups with topics.");
+    }
+
+    #[test]
+    fn whitelist_arguments_name_one_member() {
+        let msg = group_message("/whitelist");
+        assert_eq!(whitelist_member(&msg, ""), None);
+        assert_eq!(whitelist_member(&msg, "@Some_Bot"), Some("some_bot".into()));
+        assert_eq!(whitelist_member(&msg, "12345"), Some("12345".into()));
+        assert_eq!(whitelist_member(&msg, "-12345"), None);
+        assert_eq!(whitelist_member(&msg, "@no spaces"), None);
+        assert_eq!(whitelist_member(&msg, "plain"), None);
+    }
+
+    #[tokio::test]
+    async fn busy_chats_get_held_links_in_one_message() {
+        let h = Harness::new("flood").await;
+        save_group(&h, "reply").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.chat_reply_limit = 1;
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+        for i in 1..=3 {
+            let link = format!("https://example.com/{}?utm_source=x", i);
+            h.handle(&messenger, group_message(&link)).await;
+        }
+        assert_eq!(messenger.sent.lock().unwrap().len(), 1);
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 3);
+
+        send_held_links(
+            messenger.clone(),
+            h.replies.flood.clone(),
+            ChatId(GROUP_ID),
+            None,
+            i18n::get_translations("en").held_links.to_string(),
+            i18n::get_translations("en").held_links_more,
+            Duration::ZERO,
+        )
+        .await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        let (chat_id, text, _) = &sent[1];
+        assert_eq!(*chat_id, ChatId(GROUP_ID));
+        assert!(text.starts_with("<b>Links cleaned in the last minute:</b>"));
+        assert!(text.contains("• Ada: <a href=\"https://example.com/2\">"));
+        assert!(text.contains("https://example.com/3"));
+        assert!(h.replies.flood.take(GROUP_ID, None).entries.is_empty());
+    }
+
+    #[tokio::test]
+    async fn held_links_are_capped_to_one_message() {
+        let flood = FloodControl::new();
+        let entries = (0..150)
+            .map(|i| format!("• Ada: https://example.com/{}/{}\n", i, "x".repeat(150)))
+            .collect::<Vec<_>>();
+        assert!(flood.hold(GROUP_ID, None, entries[..120].to_vec()));
+        assert!(!flood.hold(GROUP_ID, None, entries[120..].to_vec()));
+
+        let messenger = MockMessenger::default();
+        let tr = i18n::get_translations("en");
+        send_held_links(
+            messenger.clone(),
+            flood.clone(),
+            ChatId(GROUP_ID),
+            None,
+            tr.held_links.to_string(),
+            tr.held_links_more,
+            Duration::ZERO,
+        )
+        .await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        let text = &sent[0].1;
+        assert!(text.len() <= MAX_MESSAGE_LENGTH);
+        let shown = text.matches("• Ada:").count();
+        assert!(shown > 0 && shown < MAX_HELD_LINKS);
+        assert!(text.ends_with(&format!("…and {} more\n", 150 - shown)));
+        // The held message used one reply of the chat's budget.
+        let now = crate::db::unix_now().unwrap();
+        assert_eq!(flood.limiter.hit(&GROUP_ID.to_string(), now).await, 2);
+    }
 
-    Dispatcher::builder(bot, handler)
-        .dependencies(dptree::deps![db, rules, ai, config, event_tx])
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
+    #[tokio::test]
+    async fn cached_risk_scores_warn_from_the_threshold() {
+        let h = Harness::new("risk_threshold").await;
+        let phishing = "https://paypa1.example/login";
+        h.db.save_url_risk(phishing, 0.8, "look-alike domain")
+            .await
+            .unwrap();
+        h.db.save_url_risk("https://shop.example/", 0.2, "ordinary shop")
+            .await
+            .unwrap();
+        let warn =
+            |url: &'static str, threshold: f64| risk_warning(&h.db, &h.ai, USER_ID, url, threshold);
+        assert_eq!(
+            warn(phishing, 0.7).await.as_deref(),
+            Some("look-alike domain")
+        );
+        assert_eq!(
+            warn(phishing, 0.8).await.as_deref(),
+            Some("look-alike domain")
+        );
+        assert_eq!(warn(phishing, 0.9).await, None);
+        assert_eq!(warn("https://shop.example/", 0.7).await, None);
+        // Unscored links get no warning while the AI is not configured.
+        assert_eq!(warn("https://unknown.example/", 0.0).await, None);
+    }
+
+    #[tokio::test]
+    async fn held_links_go_back_to_their_forum_topic() {
+        let h = Harness::new("flood_topics").await;
+        save_group(&h, "reply").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.chat_reply_limit = 1;
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+        let in_topic = |thread_id: i32, text: &str| {
+            let mut msg = serde_json::to_value(group_message(text)).unwrap();
+            msg["chat"]["is_forum"] = true.into();
+            msg["is_topic_message"] = true.into();
+            msg["message_thread_id"] = thread_id.into();
+            serde_json::from_value::<Message>(msg).unwrap()
+        };
+        for (i, thread_id) in [5, 5, 6].into_iter().enumerate() {
+            let link = format!("https://example.com/{}?utm_source=x", i);
+            h.handle(&messenger, in_topic(thread_id, &link)).await;
+        }
+        assert_eq!(messenger.sent.lock().unwrap().len(), 1);
+
+        let topic = |id: i32| Some(ThreadId(MessageId(id)));
+        for thread_id in [topic(5), topic(6)] {
+            send_held_links(
+                messenger.clone(),
+                h.replies.flood.clone(),
+                ChatId(GROUP_ID),
+                thread_id,
+                i18n::get_translations("en").held_links.to_string(),
+                i18n::get_translations("en").held_links_more,
+                Duration::ZERO,
+            )
+            .await;
+        }
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[1].2.thread_id, topic(5));
+        assert!(sent[1].1.contains("https://example.com/1"));
+        assert_eq!(sent[2].2.thread_id, topic(6));
+        assert!(sent[2].1.contains("https://example.com/2"));
+        assert!(!sent[2].1.contains("https://example.com/1"));
+    }
+
+    #[tokio::test]
+    async fn mode_command_switches_the_group_mode() {
+        let h = Harness::new("mode_command").await;
+        save_group(&h, "reply").await;
+        const OWNER_ID: i64 = 77;
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), OWNER_ID, Actor::bot(OWNER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, group_message("/mode@clear_urls_bot repost"))
+            .await;
+        // Administering the group is not enough while another admin owns it.
+        messenger
+            .chat_admins
+            .lock()
+            .unwrap()
+            .extend([USER_ID, OWNER_ID]);
+        h.admins.invalidate(GROUP_ID).await;
+        h.handle(&messenger, group_message("/mode@clear_urls_bot repost"))
+            .await;
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), USER_ID, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        h.handle(&messenger, group_message("/mode@clear_urls_bot sideways"))
+            .await;
+        h.handle(&messenger, group_message("/mode@clear_urls_bot repost"))
+            .await;
+        h.handle(&messenger, private_message("/mode reply")).await;
+
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.mode, "repost");
+        let sent: Vec<String> = messenger
+            .sent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text, _)| text.clone())
+            .collect();
+        let tr = i18n::get_translations("en");
+        assert_eq!(sent[0], tr.owner_only);
+        assert_eq!(sent[1], tr.owner_only);
+        assert!(sent[2].starts_with("Current group mode: <b>reply</b>"));
+        assert_eq!(sent[3], "✅ Group mode set to <b>repost</b>.");
+        assert_eq!(sent[4], tr.group_only);
+    }
+
+    #[tokio::test]
+    async fn new_users_start_in_their_telegram_language() {
+        let h = Harness::new("language").await;
+        let messenger = MockMessenger::default();
+        let italian = |text: &str| {
+            let mut msg = private_message(text);
+            msg.from.as_mut().unwrap().language_code = Some("it-IT".into());
+            msg
+        };
+        let language = || async { h.db.get_user_config(USER_ID).await.unwrap().language };
+
+        h.handle(&messenger, group_message("/help@clear_urls_bot"))
+            .await;
+        assert_eq!(language().await, "en");
+        h.handle(&messenger, italian("/help")).await;
+        assert_eq!(language().await, "it");
+
+        h.handle(&messenger, italian("/language en")).await;
+        h.handle(&messenger, italian("/start")).await;
+        assert_eq!(language().await, "en");
+        h.handle(&messenger, private_message("/language fr")).await;
+        assert_eq!(language().await, "en");
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent[2].1, "✅ Language set to <b>en</b>.");
+        assert_eq!(
+            sent[4].1,
+            "Current language: <b>en</b>\n\nUsage: <code>/language en|it</code>"
+        );
+    }
+
+    #[tokio::test]
+    async fn privacy_mode_cleans_without_keeping_history() {
+        let h = Harness::new("privacy").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/privacy on")).await;
+        h.handle(
+            &messenger,
+            private_message("https://example.com/a?utm_source=x"),
+        )
         .await;
-}
 
-#[tracing::instrument(
-    skip(bot, db, rules, ai, config, event_tx),
-    fields(chat_id = %msg.chat.id, user_id)
-)]
-async fn handle_message(
-    bot: Bot,
-    msg: Message,
-    db: Db,
-    rules: RuleEngine,
-    ai: AiEngine,
-    config: crate::config::Config,
-    event_tx: tokio::sync::broadcast::Sender<serde_json::Value>,
-) -> ResponseResult<()> {
-    tracing::info!(chat_id = %msg.chat.id, msg_id = %msg.id, "Processing incoming message");
-    let chat_id = msg.chat.id;
-    let user_id = msg.from.as_ref().map(|u| u.id.0 as i64).unwrap_or(0);
-    tracing::Span::current().record("user_id", user_id);
+        let user_config = h.db.get_user_config(USER_ID).await.unwrap();
+        assert!(!user_config.keeps_history());
+        assert_eq!(user_config.cleaned_count, 1);
+        assert!(h.db.get_history(USER_ID, 10).await.unwrap().is_empty());
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent[0].1, i18n::get_translations("en").privacy_on);
+        assert!(sent[1].1.contains("https://example.com/a"));
 
-    let user_config = db.get_user_config(user_id).await.unwrap_or_else(|e| {
-        tracing::error!(error = %e, "Failed to fetch user config, using default");
-        crate::models::UserConfig::default()
-    });
+        h.handle(&messenger, private_message("/privacy off")).await;
+        h.handle(
+            &messenger,
+            private_message("https://example.com/b?utm_source=x"),
+        )
+        .await;
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
+    }
 
-    // 1. Detect URLs early
-    let (text, entities) = if let Some(t) = msg.text() {
-        (t, msg.entities())
-    } else if let Some(c) = msg.caption() {
-        (c, msg.caption_entities())
-    } else {
-        ("", None)
-    };
+    #[tokio::test]
+    async fn cleaning_a_message_is_measured() {
+        let h = Harness::new("metrics").await;
+        // Other tests clean links concurrently, so only growth is checked.
+        let sample = |name: &str| -> f64 {
+            crate::metrics::render()
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+                .unwrap_or(0.0)
+        };
+        let passes = sample("clear_urls_sanitize_iterations_count");
+        let matches = sample("clear_urls_provider_matches_total{provider=\"globalRules\"}");
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            private_message("https://example.com/?utm_source=x"),
+        )
+        .await;
+        assert!(sample("clear_urls_sanitize_iterations_count") > passes);
+        assert!(sample("clear_urls_provider_matches_total{provider=\"globalRules\"}") > matches);
+    }
 
-    // Detect language
-    let detected_lang = if !text.is_empty() {
-        detect(text).map(|info| info.lang())
-    } else {
-        None
-    };
+    #[tokio::test]
+    async fn commands_and_reply_modes_are_counted_for_usage() {
+        let h = Harness::new("usage_counts").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/help")).await;
+        h.handle(&messenger, private_message("/help")).await;
+        h.handle(
+            &messenger,
+            private_message("https://example.com/?utm_source=x"),
+        )
+        .await;
+        // Commands naming another bot are not counted.
+        save_group(&h, "reply").await;
+        h.handle(&messenger, group_message("/help@other_bot")).await;
 
-    let telegram_lang = msg.from.as_ref().and_then(|u| u.language_code.as_deref());
+        let mut config = (*h.config.load_full()).clone();
+        config.admin_id = USER_ID;
+        h.config.store(Arc::new(config));
+        messenger.sent.lock().unwrap().clear();
+        h.handle(&messenger, private_message("/usage")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(
+            sent[0].1,
+            "<b>Usage in the last 30 days</b> 📈\n\n<code>/help</code> — 2\n\
+             <code>/usage</code> — 1\n<code>mode:reply</code> — 1\n\nAI: 0 requests, 0 tokens"
+        );
+    }
 
-    let lang_code = match (detected_lang, telegram_lang) {
-        (Some(Lang::Ita), _) => "it",
-        (Some(Lang::Eng), _) => "en",
-        (_, Some(l)) if l.starts_with("it") => "it",
-        (_, Some(l)) if l.starts_with("en") => "en",
-        _ => &user_config.language,
-    };
+    #[tokio::test]
+    async fn usage_reports_ai_totals_in_the_admin_language() {
+        let h = Harness::new("usage_ai").await;
+        h.db.record_ai_usage(9, 120, 30, 400).await.unwrap();
+        h.db.record_ai_usage(9, 50, 0, 200).await.unwrap();
+        let user = UserConfig {
+            user_id: USER_ID,
+            language: "it".into(),
+            ..Default::default()
+        };
+        h.db.save_user_config(&user, Actor::system()).await.unwrap();
+        let mut config = (*h.config.load_full()).clone();
+        config.admin_id = USER_ID;
+        h.config.store(Arc::new(config));
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/usage")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert!(sent[0].1.ends_with("\nIA: 2 richieste, 200 token"));
+    }
 
-    let tr = i18n::get_translations(lang_code);
+    #[tokio::test]
+    async fn debug_toggles_verbose_tracing_for_the_admin_only() {
+        let h = Harness::new("debug_toggle").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/debug 99")).await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
+        assert!(!h.db.is_debug_target(99).await);
 
-    let mut has_urls = entities
-        .as_ref()
-        .map(|e| {
-            e.iter().any(|entity| {
-                matches!(
-                    entity.kind,
-                    MessageEntityKind::Url | MessageEntityKind::TextLink { .. }
-                )
-            })
-        })
-        .unwrap_or(false);
+        let mut config = (*h.config.load_full()).clone();
+        config.admin_id = USER_ID;
+        h.config.store(Arc::new(config));
+        h.handle(&messenger, private_message("/debug 99")).await;
+        assert!(h.db.is_debug_target(99).await);
+        h.handle(&messenger, private_message("/debug")).await;
+        h.handle(&messenger, private_message("/debug 99 off")).await;
+        assert!(!h.db.is_debug_target(99).await);
 
-    // Manual fallback detection for schemeless URLs or cases where Telegram detection fails
-    if !has_urls {
-        // Simple but effective regex for detection
-        let url_pattern = r"(?i)(?:https?://|www\.)[a-zA-Z0-9\-\.]+\.[a-zA-Z]{2,}(?:/[^\s]*)?";
-        if let Ok(re) = Regex::new(url_pattern) {
-            if re.is_match(text) {
-                has_urls = true;
-                tracing::debug!("URL detected via manual regex fallback");
-            }
+        let sent = messenger.sent.lock().unwrap().clone();
+        let texts: Vec<&str> = sent.iter().map(|(_, text, _)| text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "🔍 Verbose tracing enabled for <code>99</code>.",
+                "Usage: <code>/debug &lt;id&gt; [off]</code>\nActive: 99",
+                "Verbose tracing disabled for <code>99</code>.",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn global_stats_are_for_the_admin_only() {
+        let h = Harness::new("global_stats_admin").await;
+        h.db.increment_cleaned_count(USER_ID, 3).await.unwrap();
+        for provider in ["amazon", "amazon", "google"] {
+            h.db.log_cleaned_link(
+                USER_ID,
+                None,
+                None,
+                "a",
+                "https://a.com/",
+                provider,
+                "rules",
+            )
+            .await
+            .unwrap();
         }
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/globalstats")).await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
+
+        let mut config = Config::load(None);
+        config.admin_id = USER_ID;
+        h.config.store(Arc::new(config));
+        h.handle(&messenger, private_message("/globalstats")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        let text = &sent[0].1;
+        assert!(text.contains("Links cleaned: <b>3</b>\nUsers: <b>1</b>"));
+        assert!(text.contains("Rules updated: <b>never (built-in rules)</b>"));
+        assert!(text
+            .ends_with("1. <code>amazon</code> — 2 (1 👤)\n2. <code>google</code> — 1 (1 👤)\n"));
+        assert_eq!(format_age(93_784), "1d 2h");
+        assert_eq!(format_age(3_720), "1h 2m");
     }
 
-    // Handle Commands
-    if let Some(text_val) = msg.text() {
-        if text_val.starts_with('/') {
-            let cmd_parts: Vec<&str> = text_val.split('@').collect();
-            let cmd = cmd_parts[0];
-            let is_private = msg.chat.is_private();
-            let bot_username = config.bot_username.to_lowercase();
-
-            let is_targeted = if cmd_parts.len() > 1 {
-                cmd_parts[1].to_lowercase().starts_with(&bot_username)
-            } else {
-                is_private
-            };
+    #[tokio::test]
+    async fn demoted_owners_lose_group_settings() {
+        let h = Harness::new("demoted_owner").await;
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), USER_ID, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        h.handle(&messenger, group_message("/level@clear_urls_bot strict"))
+            .await;
+        let level = || async {
+            h.db.get_chat_config(GROUP_ID)
+                .await
+                .unwrap()
+                .unwrap()
+                .cleaning_level
+        };
+        assert_eq!(level().await, "strict");
 
-            if is_targeted {
-                match cmd {
-                    "/start" => {
-                        tracing::info!("Handling /start command for user {}", user_id);
-                        let welcome_text = tr.welcome.replace("{}", &user_id.to_string());
-                        bot.send_message(chat_id, welcome_text)
-                            .parse_mode(ParseMode::Html)
-                            .await?;
-                        return Ok(());
-                    }
-                    "/help" => {
-                        bot.send_message(chat_id, tr.help_text)
-                            .parse_mode(ParseMode::Html)
-                            .await?;
-                        return Ok(());
-                    }
-                    "/stats" => {
-                        let stats_text = tr
-                            .stats_text
-                            .replace("{}", &user_config.cleaned_count.to_string());
-                        bot.send_message(chat_id, stats_text)
-                            .parse_mode(ParseMode::Html)
-                            .await?;
-                        return Ok(());
-                    }
-                    _ => {}
-                }
-            }
-        }
+        // Still the verified owner in the database, but no longer an admin.
+        messenger.chat_admins.lock().unwrap().clear();
+        h.admins.invalidate(GROUP_ID).await;
+        h.handle(&messenger, group_message("/level@clear_urls_bot standard"))
+            .await;
+        assert_eq!(level().await, "strict");
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((USER_ID, true))
+        );
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(
+            sent.last().unwrap().1,
+            i18n::get_translations("en").owner_only
+        );
     }
 
-    // Persist/Update chat info
-    let is_group_context = msg.chat.is_group() || msg.chat.is_supergroup() || msg.chat.is_channel();
-    let mut chat_config = db
-        .get_chat_config_or_default(chat_id.0)
-        .await
-        .unwrap_or_else(|e| {
-            tracing::error!(error = %e, "Failed to fetch chat config, using default");
-            crate::models::ChatConfig::default()
-        });
+    #[tokio::test]
+    async fn group_setup_deep_link_opens_the_setup_in_private() {
+        let h = Harness::new("group_setup").await;
+        save_group(&h, "reply").await;
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), USER_ID, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
 
-    if is_group_context {
-        let title = msg.chat.title().map(|s| s.to_string());
-        let chat_config_db = db.get_chat_config(chat_id.0).await.unwrap_or(None);
-        let chat_exists = chat_config_db.is_some();
+        // Without a dashboard, group /settings links to the setup.
+        h.handle(&messenger, group_message("/settings@clear_urls_bot"))
+            .await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        let button =
+            serde_json::to_value(&sent[0].2.keyboard.as_ref().unwrap().inline_keyboard[0][0])
+                .unwrap();
+        let deep_link = format!("https://t.me/clear_urls_bot?start=group_setup_{}", GROUP_ID);
+        assert_eq!(button["url"], deep_link);
 
-        // Only save if it's new or title changed
-        if !chat_exists || chat_config.title != title {
-            chat_config.title = title.clone();
-            if !chat_exists {
-                chat_config.added_by = user_id;
-            }
-            let _ = db.save_chat_config(&chat_config).await;
-        }
+        let mut stranger = private_message(&format!("/start group_setup_{}", GROUP_ID));
+        stranger.from.as_mut().unwrap().id = UserId(99);
+        h.handle(&messenger, stranger).await;
+        h.handle(
+            &messenger,
+            private_message(&format!("/start group_setup_{}", GROUP_ID)),
+        )
+        .await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent[1].1, i18n::get_translations("en").owner_only);
+        assert!(sent[2].1.contains("<b>Links</b>"));
+        let labels: Vec<String> = sent[2]
+            .2
+            .keyboard
+            .clone()
+            .unwrap()
+            .inline_keyboard
+            .iter()
+            .map(|row| row[0].text.clone())
+            .collect();
+        assert_eq!(labels, ["💬 Mode: reply", "🧽 Level: default"]);
 
-        if !chat_exists && user_id != 0 && has_urls {
-            let notify_text = tr.group_activated.replace(
-                "{}",
-                &html::escape(&title.unwrap_or_else(|| tr.unknown.to_string())),
-            );
-            let _ = bot
-                .send_message(ChatId(user_id), notify_text)
-                .parse_mode(ParseMode::Html)
-                .await;
+        let press = |setting: &str| {
+            let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": "q1",
+                "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+                "chat_instance": "1",
+                "data": format!("{}{}:{}", GROUP_SETUP_PREFIX, GROUP_ID, setting),
+                "message": serde_json::to_value(private_message("setup")).unwrap(),
+            }))
+            .unwrap();
+            handle_callback_query(
+                messenger.clone(),
+                query,
+                h.db.clone(),
+                h.config.clone(),
+                h.replies.clone(),
+                h.admins.clone(),
+                h.me.clone(),
+            )
+        };
+        press("mode").await.unwrap();
+        press("level").await.unwrap();
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(
+            (chat.mode.as_str(), chat.cleaning_level.as_str()),
+            ("delete", "conservative")
+        );
+        let edited = messenger.edited_texts.lock().unwrap().clone();
+        assert_eq!(
+            edited[1].3.inline_keyboard[1][0].text,
+            "🧽 Level: conservative"
+        );
+
+        // Demoted owners can no longer change anything.
+        messenger.chat_admins.lock().unwrap().clear();
+        h.admins.invalidate(GROUP_ID).await;
+        press("mode").await.unwrap();
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.mode, "delete");
+    }
+
+    #[tokio::test]
+    async fn settings_menu_toggles_values_in_place() {
+        let h = Harness::new("settings_menu").await;
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/settings")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        let labels: Vec<String> = sent[0]
+            .2
+            .keyboard
+            .clone()
+            .unwrap()
+            .inline_keyboard
+            .iter()
+            .map(|row| row[0].text.clone())
+            .collect();
+        // AI is not configured, so it has no button.
+        assert_eq!(
+            labels,
+            [
+                "🧹 Cleaning: on",
+                "💬 Mode: reply",
+                "🌐 Language: en",
+                "📬 Weekly summary: off"
+            ]
+        );
+
+        let press = |setting: &str| {
+            let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": "q1",
+                "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+                "chat_instance": "1",
+                "data": format!("{}{}", SETTINGS_PREFIX, setting),
+                "message": serde_json::to_value(private_message("menu")).unwrap(),
+            }))
+            .unwrap();
+            handle_callback_query(
+                messenger.clone(),
+                query,
+                h.db.clone(),
+                h.config.clone(),
+                h.replies.clone(),
+                h.admins.clone(),
+                h.me.clone(),
+            )
+        };
+        press("enabled").await.unwrap();
+        press("mode").await.unwrap();
+        press("language").await.unwrap();
+        press("ai").await.unwrap();
+        press("digest").await.unwrap();
+
+        let user_config = h.db.get_user_config(USER_ID).await.unwrap();
+        assert!(!user_config.is_enabled());
+        assert_eq!(user_config.mode, "delete");
+        assert_eq!(user_config.language, "it");
+        assert!(!user_config.is_ai_enabled());
+        assert!(user_config.wants_digest());
+        let edited = messenger.edited_texts.lock().unwrap().clone();
+        assert_eq!(edited.len(), 4);
+        let (chat, message_id, text, keyboard) = &edited[2];
+        assert_eq!((*chat, *message_id), (ChatId(USER_ID), MessageId(7)));
+        assert!(text.contains("Le tue impostazioni"));
+        assert_eq!(keyboard.inline_keyboard[0][0].text, "🧹 Pulizia: disattiva");
+        let answers = messenger.answered.lock().unwrap().clone();
+        assert_eq!(answers[2].1, "✅ Salvato");
+        assert_eq!(answers[3].1, "");
+    }
+
+    #[test]
+    fn command_menu_lists_every_visible_command() {
+        let visible = Command::bot_commands().len();
+        for language in i18n::LANGUAGES {
+            let menu = command_menu(i18n::get_translations(language).help_text);
+            assert_eq!(menu.len(), visible, "{}", language);
         }
+        let menu = command_menu(i18n::get_translations("it").help_text);
+        assert_eq!(menu[0].command, "start");
+        assert_eq!(menu[0].description, "Inizia e ricevi il link al dashboard");
+        assert_eq!(
+            Command::parse("/topdomains@Clear_URLs_bot", "clear_urls_bot").ok(),
+            Some(Command::TopDomains)
+        );
+        assert!(Command::parse("/stats@other_bot", "clear_urls_bot").is_err());
     }
 
-    if !has_urls {
-        return Ok(());
+    #[test]
+    fn replacing_links_keeps_formatting() {
+        let text = "🔥 https://a.com/?utm_source=x&id=1 read";
+        let bold_url = MessageEntity::bold(3, 32);
+        let italic = MessageEntity::italic(36, 4);
+        let link = MessageEntity::text_link("https://b.com/?fbclid=1".parse().unwrap(), 36, 4);
+        let (text, entities) = replace_links(
+            text,
+            vec![bold_url, italic, link],
+            &[
+                ("https://a.com/?utm_source=x&id=1", "https://a.com/?id=1"),
+                ("https://b.com/?fbclid=1", "https://b.com/"),
+            ],
+        );
+        assert_eq!(text, "🔥 https://a.com/?id=1 read");
+        assert_eq!(
+            entities,
+            [
+                MessageEntity::bold(3, 19),
+                MessageEntity::italic(23, 4),
+                MessageEntity::text_link("https://b.com/".parse().unwrap(), 23, 4),
+            ]
+        );
     }
 
-    // Logic: In groups, only check if the group enabled the bot.
-    // In private, check if the user enabled the bot.
-    let is_enabled = if is_group_context {
-        chat_config.is_enabled()
-    } else {
-        user_config.is_enabled()
-    };
+    #[test]
+    fn replacing_links_leaves_longer_links_sharing_a_prefix() {
+        let url = |offset, length| MessageEntity::new(MessageEntityKind::Url, offset, length);
+        let text = "https://x.com/p?utm_source=a and https://x.com/p?utm_source=a&id=5 ok";
+        let first = url(0, 28);
+        let second = url(33, 33);
+        // Starts inside the second link and runs past it: its start stays
+        // within the cleaned link.
+        let bold = MessageEntity::bold(50, 19);
+        let (text, entities) = replace_links(
+            text,
+            vec![first, second, bold],
+            &[
+                ("https://x.com/p?utm_source=a", "https://x.com/p"),
+                ("https://x.com/p?utm_source=a&id=5", "https://x.com/p?id=5"),
+            ],
+        );
+        assert_eq!(text, "https://x.com/p and https://x.com/p?id=5 ok");
+        assert_eq!(
+            entities,
+            [url(0, 15), url(20, 20), MessageEntity::bold(37, 6)]
+        );
+    }
 
-    if !is_enabled {
-        tracing::info!(is_group_context, chat_id = %chat_id, "Bot is disabled for this context (skipping)");
-        return Ok(());
+    fn channel_post(text: &str) -> Message {
+        let chat = serde_json::json!({ "id": GROUP_ID, "type": "channel", "title": "News" });
+        serde_json::from_value(serde_json::json!({
+            "message_id": 7,
+            "date": 1_700_000_000,
+            "chat": chat,
+            "sender_chat": chat,
+            "text": text,
+        }))
+        .unwrap()
     }
 
-    let ignored_domains: Vec<String> = user_config
-        .ignored_domains
-        .split(',')
-        .map(|s| s.trim().to_lowercase())
-        .filter(|s| !s.is_empty())
-        .collect();
+    #[tokio::test]
+    async fn channel_posts_are_edited_for_their_owner() {
+        let h = Harness::new("channel").await;
+        h.db.set_chat_owner(GROUP_ID, Some("News"), USER_ID, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            channel_post("New post: https://example.com/?utm_source=x&q=1"),
+        )
+        .await;
 
-    let custom_rules = db.get_custom_rules(user_id).await.unwrap_or_default();
-    let mut cleaned_urls = Vec::new();
+        let edited = messenger.edited.lock().unwrap().clone();
+        assert_eq!(edited.len(), 1);
+        assert_eq!(edited[0].2, "New post: https://example.com/?q=1");
+        assert!(messenger.sent.lock().unwrap().is_empty());
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.kind, "channel");
 
-    let mut url_candidates = Vec::new();
+        // Without edit rights the cleaned link is posted as a reply.
+        let messenger = MockMessenger {
+            fail_delete: true,
+            ..MockMessenger::default()
+        };
+        h.handle(
+            &messenger,
+            channel_post("https://example.org/?utm_source=x"),
+        )
+        .await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].1.contains("https://example.org/"));
+        assert_eq!(sent[0].2.reply_to, Some(MessageId(7)));
+    }
 
-    // 1. Get URLs from Telegram Entities
-    if let Some(ents) = entities {
-        let utf16: Vec<u16> = text.encode_utf16().collect();
-        for entity in ents {
-            let url_str = match &entity.kind {
-                MessageEntityKind::Url => {
-                    let start = entity.offset;
-                    let end = start + entity.length;
-                    if end > utf16.len() {
+    #[tokio::test]
+    async fn unowned_channel_posts_are_not_counted() {
+        let h = Harness::new("channel_unowned").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            channel_post("New post: https://example.com/?utm_source=x&q=1"),
+        )
+        .await;
+
+        let edited = messenger.edited.lock().unwrap().clone();
+        assert_eq!(edited.len(), 1);
+        assert_eq!(edited[0].2, "New post: https://example.com/?q=1");
+        let history = h.db.get_chat_history(GROUP_ID, 10).await.unwrap();
+        assert!(history.is_empty());
+        assert_eq!(h.db.get_user_config(0).await.unwrap().cleaned_count, 0);
+        assert_eq!(h.db.get_global_stats().await.unwrap(), (0, 0));
+    }
+
+    /// Serves `/s` -> `/hop` -> `/final?utm_source=x&q=1` on a local port.
+    /// A public address for the shortener, which expansion refuses to
+    /// follow to a local one.
+    const SHORTENER: &str = "http://93.184.216.34";
+
+    /// A proxy answering for `SHORTENER`: `/s` redirects to `/hop`, which
+    /// redirects to a tracked link. Returns the proxy URL.
+    async fn redirect_server() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let target = format!("{}/final?utm_source=x&q=1", SHORTENER);
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = [0u8; 1024];
+                let read = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..read]);
+                let path = request
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|url| url.strip_prefix(SHORTENER));
+                let response = match path {
+                    Some("/s") => "HTTP/1.1 301 Moved\r\nLocation: /hop\r\n".to_string(),
+                    Some("/hop") => format!("HTTP/1.1 302 Found\r\nLocation: {}\r\n", target),
+                    _ => "HTTP/1.1 200 OK\r\n".to_string(),
+                };
+                let _ = stream
+                    .write_all(format!("{}Content-Length: 0\r\n\r\n", response).as_bytes())
+                    .await;
+            }
+        });
+        proxy
+    }
+
+    #[cfg(feature = "ai")]
+    /// An OpenAI-compatible API on a local port that answers every
+    /// completion with `cleaned_url`. Returns its base URL.
+    async fn ai_server(cleaned_url: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            while let Ok((mut stream, _)) = listener.accept().await {
+                // Reads the headers and the body before answering.
+                let mut request = Vec::new();
+                let mut chunk = [0u8; 4096];
+                while let Ok(read @ 1..) = stream.read(&mut chunk).await {
+                    request.extend_from_slice(&chunk[..read]);
+                    let text = String::from_utf8_lossy(&request);
+                    let Some((head, body)) = text.split_once("\r\n\r\n") else {
                         continue;
+                    };
+                    let length = head
+                        .lines()
+                        .find_map(|line| {
+                            line.to_lowercase()
+                                .strip_prefix("content-length:")?
+                                .trim()
+                                .parse()
+                                .ok()
+                        })
+                        .unwrap_or(0);
+                    if body.len() >= length {
+                        break;
                     }
-                    String::from_utf16_lossy(&utf16[start..end])
                 }
-                MessageEntityKind::TextLink { url } => url.to_string(),
-                _ => continue,
-            };
-            if !url_candidates.contains(&url_str) {
-                tracing::debug!(url = %url_str, "Found URL via Telegram entity");
-                url_candidates.push(url_str);
+                let content = serde_json::json!({
+                    "cleaned_url": cleaned_url,
+                    "removed_params": ["xyz"],
+                    "confidence": 0.9,
+                });
+                let body = serde_json::json!({
+                    "choices": [{ "message": { "content": content.to_string() } }],
+                    "usage": { "prompt_tokens": 10, "completion_tokens": 5 },
+                })
+                .to_string();
+                let _ = stream
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                            body.len(),
+                            body
+                        )
+                        .as_bytes(),
+                    )
+                    .await;
             }
-        }
+        });
+        base
     }
 
-    // 2. Supplement with Regex Detection
-    let url_pattern = r"(?i)(?:https?://|www\.)[a-zA-Z0-9\-\.]+\.[a-zA-Z]{2,}(?:/[^\s]*)?";
-    if let Ok(re) = Regex::new(url_pattern) {
-        for mat in re.find_iter(text) {
-            let url_str = mat.as_str().to_string();
-            if !url_candidates.contains(&url_str) {
-                tracing::debug!(url = %url_str, "Found URL via Regex fallback");
-                url_candidates.push(url_str);
-            }
-        }
+    #[cfg(feature = "ai")]
+    /// Turns AI on in `h`'s configuration, against `ai_server`.
+    async fn enable_ai(h: &Harness, cleaned_url: &'static str) {
+        let mut config = (*h.config.load_full()).clone();
+        config.ai_api_key = Some("test".into());
+        config.ai_api_base = ai_server(cleaned_url).await;
+        h.config.store(Arc::new(config));
     }
 
-    if url_candidates.is_empty() {
-        tracing::debug!("No URL candidates found in message");
-        return Ok(());
+    #[cfg(not(feature = "ai"))]
+    #[tokio::test]
+    async fn ai_is_off_without_the_ai_feature() {
+        let h = Harness::new("no_ai_feature").await;
+        let mut config = (*h.config.load_full()).clone();
+        config.ai_api_key = Some("test".into());
+        // Nothing listens here; a call would fail rather than be skipped.
+        config.ai_api_base = "http://127.0.0.1:9".into();
+        assert!(!config.ai_available());
+        h.config.store(Arc::new(config));
+        let user = UserConfig {
+            user_id: USER_ID,
+            ai_enabled: 1,
+            ..Default::default()
+        };
+        h.db.save_user_config(&user, Actor::system()).await.unwrap();
+        assert!(h
+            .ai
+            .sanitize(USER_ID, "https://example.com/page?xyz=1")
+            .await
+            .unwrap()
+            .is_none());
+
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, private_message("/stats")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert!(!sent[0].1.contains("AI"), "{}", sent[0].1);
     }
 
-    // 3. Process candidates
-    for url_str in url_candidates {
-        // 1. Expand shortened URLs first
-        let expanded_url = rules.expand_url(&url_str).await;
-        let original_url_str = url_str.clone();
-        let mut current_url = expanded_url;
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn group_ai_follows_the_group_setting() {
+        let h = Harness::new("group_ai").await;
+        enable_ai(&h, "https://example.com/page").await;
+        save_group(&h, "reply").await;
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), USER_ID, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        // Rules leave `xyz` alone; only the AI removes it.
+        let link = "https://example.com/page?xyz=1";
 
-        // 2. Sanitization
-        if let Some((cleaned, provider)) =
-            rules.sanitize(&current_url, &custom_rules, &ignored_domains)
-        {
-            current_url = cleaned;
-            tracing::info!(provider = %provider, "URL sanitized by engine");
+        h.handle(&messenger, group_message(link)).await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
 
-            if user_config.is_ai_enabled() && config.ai_api_key.is_some() {
-                if let Ok(Some(ai_cleaned)) = ai.sanitize(&current_url).await {
-                    current_url = ai_cleaned;
-                    let provider_name = format!("AI ({})", provider);
-                    cleaned_urls.push((original_url_str, current_url, provider_name));
-                    continue;
-                }
-            }
+        h.handle(&messenger, group_message("/groupai@clear_urls_bot on"))
+            .await;
+        assert!(h
+            .db
+            .get_chat_config(GROUP_ID)
+            .await
+            .unwrap()
+            .unwrap()
+            .is_ai_enabled());
+        h.handle(&messenger, group_message(link)).await;
+        // The sender's own setting does not apply in the group.
+        assert!(!h.db.get_user_config(USER_ID).await.unwrap().is_ai_enabled());
+        h.handle(&messenger, private_message(link)).await;
 
-            tracing::info!(
-                original = %rules.redact_sensitive(&original_url_str),
-                cleaned = %current_url,
-                provider = %provider,
-                "URL sanitized by engine"
-            );
-            cleaned_urls.push((original_url_str, current_url, provider));
-        } else {
-            tracing::debug!(url = %rules.redact_sensitive(&current_url), "URL was already clean");
-            if user_config.is_ai_enabled() && config.ai_api_key.is_some() {
-                if let Ok(Some(ai_cleaned)) = ai.sanitize(&current_url).await {
-                    tracing::info!("URL sanitized by AI fallback");
-                    cleaned_urls.push((original_url_str, ai_cleaned, "AI (Deep Scan)".to_string()));
-                }
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].1, "🤖 AI Deep Scan enabled for this group.");
+        assert!(
+            sent[1].1.contains("https://example.com/page\""),
+            "{}",
+            sent[1].1
+        );
+    }
+
+    #[cfg(feature = "ai")]
+    #[tokio::test]
+    async fn ai_cleanings_are_tagged_and_counted_in_stats() {
+        let mut h = Harness::new("ai_stats").await;
+        enable_ai(&h, "https://example.com/page").await;
+        h.ai = AiEngine::new(h.config.clone()).with_usage_tracking(h.db.clone());
+        let user = UserConfig {
+            user_id: USER_ID,
+            ai_enabled: 1,
+            ..Default::default()
+        };
+        h.db.save_user_config(&user, Actor::system()).await.unwrap();
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            private_message("https://example.com/page?xyz=1"),
+        )
+        .await;
+        h.handle(
+            &messenger,
+            private_message("https://example.com/?utm_source=x"),
+        )
+        .await;
+
+        let history = h.db.get_history(USER_ID, 10).await.unwrap();
+        let mut sources: Vec<_> = history
+            .iter()
+            .map(|link| link.cleaning_source.as_deref())
+            .collect();
+        sources.sort();
+        assert_eq!(sources, [Some("ai"), Some("rules")]);
+
+        h.handle(&messenger, private_message("/stats")).await;
+        let sent = messenger.sent.lock().unwrap().clone();
+        let stats = &sent.last().unwrap().1;
+        assert!(
+            stats.contains("\nAI-assisted cleanings: <b>1</b> · 100% success · "),
+            "{}",
+            stats
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_chain_is_logged_and_shown() {
+        let mut h = Harness::new("redirects").await;
+        let proxy = redirect_server().await;
+        h.rules = RuleEngine::new_lazy("")
+            .with_embedded_rules()
+            .with_proxy(Some(&proxy));
+        let base = SHORTENER;
+        let mut config = (*h.config.load_full()).clone();
+        config.shorteners = vec![base.trim_start_matches("http://").to_string()];
+        config.show_redirects = true;
+        h.rules.reconfigure(&config);
+        h.config.store(Arc::new(config));
+        // The text pattern wants a domain, so mark the link like Telegram does.
+        let link = format!("{}/s", base);
+        let mut msg = serde_json::to_value(private_message(&link)).unwrap();
+        msg["entities"] = serde_json::json!([{ "type": "url", "offset": 0, "length": link.len() }]);
+        let messenger = MockMessenger::default();
+        h.handle(&messenger, serde_json::from_value(msg).unwrap())
+            .await;
+
+        let chain = [
+            format!("{}/s", base),
+            format!("{}/hop", base),
+            format!("{}/final?utm_source=x&q=1", base),
+        ];
+        let history = h.db.get_history(USER_ID, 10).await.unwrap();
+        assert_eq!(history[0].cleaned_url, format!("{}/final?q=1", base));
+        let logged: Vec<String> =
+            serde_json::from_str(history[0].redirect_chain.as_deref().unwrap()).unwrap();
+        assert_eq!(logged, chain);
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        let expected = format!(
+            "<blockquote expandable>{}\n{}</blockquote>",
+            i18n::get_translations("en").redirect_chain,
+            chain.map(|url| html::escape(&url)).join("\n→ ")
+        );
+        assert!(sent[0].1.contains(&expected), "{}", sent[0].1);
+    }
+
+    #[tokio::test]
+    async fn timed_out_message_is_not_deleted() {
+        let mut h = Harness::new("deadline-delete").await;
+        save_group(&h, "delete").await;
+        // A proxy that accepts the expansion request and never answers.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
             }
-        }
+        });
+        h.rules = RuleEngine::new_lazy("")
+            .with_embedded_rules()
+            .with_proxy(Some(&proxy));
+        let mut config = (*h.config.load_full()).clone();
+        config.shorteners = vec![SHORTENER.trim_start_matches("http://").to_string()];
+        config.message_budget_secs = 60;
+        h.rules.reconfigure(&config);
+        h.config.store(Arc::new(config));
+        let link = format!("{}/s?utm_source=x", SHORTENER);
+        let mut msg = serde_json::to_value(group_message(&link)).unwrap();
+        msg["entities"] = serde_json::json!([{ "type": "url", "offset": 0, "length": link.len() }]);
+        let msg: Message = serde_json::from_value(msg).unwrap();
+        let messenger = MockMessenger::default();
+        let work = handle_message(
+            messenger.clone(),
+            msg.clone(),
+            h.db.clone(),
+            h.rules.clone(),
+            h.ai.clone(),
+            h.config.clone(),
+            h.events.clone(),
+            h.me.clone(),
+            h.replies.clone(),
+            h.admins.clone(),
+        );
+        within_deadline(Duration::from_secs(1), msg.chat.id, msg.id, work)
+            .await
+            .unwrap();
+
+        assert!(messenger.deleted.lock().unwrap().is_empty());
+        assert!(messenger.sent.lock().unwrap().is_empty());
     }
 
-    if cleaned_urls.is_empty() {
-        tracing::info!("Processing finished: no URLs required cleaning");
-        return Ok(());
+    #[tokio::test]
+    async fn only_commands_for_the_bot_are_parsed() {
+        let h = Harness::new("parse_command").await;
+        let parsed = |msg: Message| {
+            parse_command(&msg, &h.me).map(|(_, cmd, args)| (cmd.to_string(), args.to_string()))
+        };
+        assert_eq!(
+            parsed(private_message("/export now")),
+            Some(("/export".into(), "now".into()))
+        );
+        assert_eq!(
+            parsed(group_message("/export@clear_urls_bot")),
+            Some(("/export".into(), "".into()))
+        );
+        assert_eq!(parsed(group_message("/export")), None);
+        assert_eq!(parsed(group_message("/export@other_bot")), None);
+        assert_eq!(parsed(channel_post("/export@clear_urls_bot")), None);
+        assert_eq!(parsed(private_message("https://example.com/")), None);
     }
 
-    let _ = db
-        .increment_cleaned_count(user_id, cleaned_urls.len() as i64)
+    #[tokio::test]
+    async fn delete_mode_falls_back_to_reply() {
+        let h = Harness::new("delete-fallback").await;
+        save_group(&h, "delete").await;
+        let messenger = MockMessenger {
+            fail_delete: true,
+            ..Default::default()
+        };
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
         .await;
-    for (orig, clean, prov) in &cleaned_urls {
-        let _ = db.log_cleaned_link(user_id, orig, clean, prov).await;
 
-        let _ = event_tx.send(serde_json::json!({
-            "user_id": user_id,
-            "original_url": orig,
-            "cleaned_url": clean,
-            "provider_name": prov,
-            "timestamp": std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .map(|d| d.as_secs())
-                .unwrap_or(0)
-        }));
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].2.reply_to, Some(MessageId(7)));
+        assert!(sent[0].1.contains("https://example.com/?q=1"));
     }
 
-    let mode = match chat_config.mode.as_str() {
-        "default" | "" => user_config.mode.clone(),
-        m => m.to_string(),
-    };
+    #[tokio::test]
+    async fn forgetme_erases_the_user_only_once_confirmed() {
+        let h = Harness::new("forgetme").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            private_message("https://example.com/page?utm_source=x"),
+        )
+        .await;
+        save_group(&h, "reply").await;
 
-    if mode == "delete" && bot.delete_message(chat_id, msg.id).await.is_ok() {
-        let user_name = msg
-            .from
-            .as_ref()
-            .map(|u| u.first_name.clone())
-            .unwrap_or_else(|| "User".into());
-        let mut response = tr.cleaned_for.replace("{}", &html::escape(&user_name));
-        for (_, cleaned, _) in &cleaned_urls {
-            response.push_str(&format!(
-                "• <a href=\"{}\">{}</a>\n",
-                html::escape(cleaned),
-                html::escape(cleaned)
-            ));
-        }
-        bot.send_message(chat_id, response)
-            .parse_mode(ParseMode::Html)
-            .await?;
-        return Ok(());
-    }
+        h.handle(&messenger, private_message("/forgetme")).await;
+        assert_eq!(h.db.get_history(USER_ID, 10).await.unwrap().len(), 1);
 
-    let mut response = if is_group_context {
-        let user_name = msg
-            .from
-            .as_ref()
-            .map(|u| u.first_name.clone())
-            .unwrap_or_else(|| "User".into());
-        tr.cleaned_for.replace("{}", &html::escape(&user_name))
-    } else {
-        String::from(tr.cleaned_links)
-    };
+        h.handle(&messenger, private_message("/forgetme confirm"))
+            .await;
+        assert!(h.db.get_history(USER_ID, 10).await.unwrap().is_empty());
+        assert_eq!(
+            h.db.get_user_config(USER_ID).await.unwrap().cleaned_count,
+            0
+        );
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.added_by, 0);
+        assert_eq!(messenger.sent.lock().unwrap().len(), 3);
+    }
 
-    if !response.ends_with('\n') {
-        response.push('\n');
+    #[tokio::test]
+    async fn disabled_group_is_ignored() {
+        let h = Harness::new("disabled").await;
+        let chat = ChatConfig {
+            chat_id: GROUP_ID,
+            title: Some("Links".into()),
+            enabled: 0,
+            ..Default::default()
+        };
+        h.db.save_chat_config(&chat, Actor::bot(USER_ID))
+            .await
+            .unwrap();
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
+        assert!(messenger.sent.lock().unwrap().is_empty());
+        assert!(messenger.deleted.lock().unwrap().is_empty());
     }
 
-    const MAX_MESSAGE_LENGTH: usize = 4000; // Leave a buffer for Telegram's 4096 limit
+    #[tokio::test]
+    async fn new_group_notifies_the_member_who_posted() {
+        let h = Harness::new("activation").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
 
-    if cleaned_urls.len() == 1 {
-        let clean = cleaned_urls[0].1.trim();
-        let escaped_url = html::escape(clean);
-        let link_entry = format!("<a href=\"{}\">{}</a>", escaped_url, escaped_url);
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0, ChatId(USER_ID));
+        assert_eq!(sent[1].0, ChatId(GROUP_ID));
+        assert_eq!(sent[1].2.reply_to, Some(MessageId(7)));
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((USER_ID, true))
+        );
+    }
 
-        if response.len() + link_entry.len() < MAX_MESSAGE_LENGTH {
-            response.push_str(&link_entry);
-        }
-    } else {
-        for (_, cleaned, _) in &cleaned_urls {
-            let clean = cleaned.trim();
-            let escaped_url = html::escape(clean);
-            let link_entry = format!("• <a href=\"{}\">{}</a>\n", escaped_url, escaped_url);
+    #[tokio::test]
+    async fn first_poster_does_not_own_the_group() {
+        let h = Harness::new("first-poster").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/?utm_medium=social&q=1"),
+        )
+        .await;
 
-            if response.len() + link_entry.len() > MAX_MESSAGE_LENGTH {
-                response.push_str("... (truncated)");
-                break;
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(GROUP_ID));
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((0, false))
+        );
+    }
+
+    /// `actor` changing `member_id`'s status in the group.
+    fn membership(
+        actor: i64,
+        member_id: i64,
+        old_status: &str,
+        new_status: &str,
+    ) -> ChatMemberUpdated {
+        let member = |status: &str| {
+            let mut member = serde_json::json!({
+                "user": { "id": member_id, "is_bot": member_id == 1, "first_name": "Clear" },
+                "status": status,
+            });
+            if status == "administrator" {
+                for right in [
+                    "can_be_edited",
+                    "is_anonymous",
+                    "can_manage_chat",
+                    "can_delete_messages",
+                    "can_manage_video_chats",
+                    "can_restrict_members",
+                    "can_promote_members",
+                    "can_change_info",
+                    "can_invite_users",
+                    "can_post_stories",
+                    "can_edit_stories",
+                    "can_delete_stories",
+                ] {
+                    member[right] = serde_json::json!(false);
+                }
             }
-            response.push_str(&link_entry);
-        }
+            member
+        };
+        serde_json::from_value(serde_json::json!({
+            "chat": { "id": GROUP_ID, "type": "supergroup", "title": "Links" },
+            "from": { "id": actor, "is_bot": false, "first_name": "Ada" },
+            "date": 1_700_000_000,
+            "old_chat_member": member(old_status),
+            "new_chat_member": member(new_status),
+        }))
+        .unwrap()
     }
 
-    tracing::info!(chat_id = %chat_id, "Sending cleaned URLs reply");
+    #[tokio::test]
+    async fn owner_comes_from_the_administrator_who_added_the_bot() {
+        let h = Harness::new("membership").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        let update = |actor, old, new| {
+            handle_my_chat_member(
+                messenger.clone(),
+                membership(actor, 1, old, new),
+                h.db.clone(),
+                h.me.clone(),
+                h.admins.clone(),
+            )
+        };
 
-    use teloxide::types::LinkPreviewOptions;
-    let mut request = bot
-        .send_message(chat_id, response)
-        .reply_parameters(ReplyParameters::new(msg.id))
-        .parse_mode(ParseMode::Html)
-        .link_preview_options(LinkPreviewOptions {
-            is_disabled: true, // Keep groups clean by default
-            url: None,
-            prefer_small_media: false,
-            prefer_large_media: false,
-            show_above_text: false,
-        });
+        // Added by a plain member: recorded, but nobody owns it.
+        update(77, "left", "member").await.unwrap();
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((0, false))
+        );
+        // Promoted by an administrator, who becomes the owner.
+        update(USER_ID, "member", "administrator").await.unwrap();
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((USER_ID, true))
+        );
+        // Re-added by a plain member: the verified owner stays.
+        update(77, "left", "member").await.unwrap();
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((USER_ID, true))
+        );
 
-    // Support for Supergroup topics/threads
-    if let Some(thread_id) = msg.thread_id {
-        request = request.message_thread_id(thread_id);
+        // The setup message went out once, on the first join.
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(GROUP_ID));
+        assert!(sent[0].1.contains("Thanks for adding me to <b>Links</b>"));
+        let keyboard = sent[0].2.keyboard.clone().unwrap().inline_keyboard;
+        assert_eq!(keyboard[0][0].text, "✅ default");
+        assert_eq!(keyboard.len(), 5);
+
+        let press = |user_id: i64| {
+            let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": "q1",
+                "from": { "id": user_id, "is_bot": false, "first_name": "Ada" },
+                "chat_instance": "1",
+                "data": format!("{}{}:mode:delete", GROUP_SETUP_PREFIX, GROUP_ID),
+                "message": serde_json::to_value(group_message("setup")).unwrap(),
+            }))
+            .unwrap();
+            handle_callback_query(
+                messenger.clone(),
+                query,
+                h.db.clone(),
+                h.config.clone(),
+                h.replies.clone(),
+                h.admins.clone(),
+                h.me.clone(),
+            )
+        };
+        press(77).await.unwrap();
+        let mode = || async { h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap().mode };
+        assert_eq!(mode().await, "default");
+        press(USER_ID).await.unwrap();
+        assert_eq!(mode().await, "delete");
+        let edited = messenger.edited_texts.lock().unwrap().clone();
+        assert!(edited[0].2.contains("Thanks for adding me"));
+        assert_eq!(edited[0].3.inline_keyboard[1][0].text, "✅ delete");
     }
 
-    if let Err(e) = request.await {
-        tracing::error!(chat_id = %chat_id, error = %e, "Failed to send cleaned URLs reply");
-        return Err(e);
+    #[tokio::test]
+    async fn setup_message_follows_the_adders_language() {
+        let h = Harness::new("onboarding").await;
+        let messenger = MockMessenger::default();
+        messenger.chat_admins.lock().unwrap().push(USER_ID);
+        let join = |chat_type: &str, chat_id: i64| {
+            let mut update =
+                serde_json::to_value(membership(USER_ID, 1, "left", "administrator")).unwrap();
+            update["from"]["language_code"] = serde_json::json!("it-IT");
+            update["chat"]["type"] = serde_json::json!(chat_type);
+            update["chat"]["id"] = serde_json::json!(chat_id);
+            handle_my_chat_member(
+                messenger.clone(),
+                serde_json::from_value(update).unwrap(),
+                h.db.clone(),
+                h.me.clone(),
+                h.admins.clone(),
+            )
+        };
+
+        // Channels get no setup message.
+        join("channel", -1009999).await.unwrap();
+        assert!(messenger.sent.lock().unwrap().is_empty());
+
+        join("supergroup", GROUP_ID).await.unwrap();
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0]
+            .1
+            .contains("Grazie per avermi aggiunto a <b>Links</b>"));
+        let keyboard = sent[0].2.keyboard.clone().unwrap().inline_keyboard;
+        let reaction = keyboard
+            .iter()
+            .flatten()
+            .find(|button| button.text.ends_with("reaction"))
+            .unwrap();
+        let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) = &reaction.kind else {
+            panic!("not a callback button");
+        };
+
+        let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+            "id": "q1",
+            "from": { "id": USER_ID, "is_bot": false, "first_name": "Ada" },
+            "chat_instance": "1",
+            "data": data,
+            "message": serde_json::to_value(group_message("setup")).unwrap(),
+        }))
+        .unwrap();
+        handle_callback_query(
+            messenger.clone(),
+            query,
+            h.db.clone(),
+            h.config.clone(),
+            h.replies.clone(),
+            h.admins.clone(),
+            h.me.clone(),
+        )
+        .await
+        .unwrap();
+        let chat = h.db.get_chat_config(GROUP_ID).await.unwrap().unwrap();
+        assert_eq!(chat.mode, "reaction");
+        assert_eq!(messenger.answered.lock().unwrap().len(), 1);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn departed_owner_is_replaced_by_the_first_admin_to_accept() {
+        let h = Harness::new("ownership").await;
+        let messenger = MockMessenger::default();
+        messenger
+            .chat_admins
+            .lock()
+            .unwrap()
+            .extend([USER_ID, 88, 99]);
+        h.db.set_chat_owner(GROUP_ID, Some("Links"), 77, Actor::system())
+            .await
+            .unwrap();
+
+        // Somebody else leaving changes nothing.
+        let left = |member_id| {
+            handle_chat_member(
+                messenger.clone(),
+                membership(member_id, member_id, "administrator", "left"),
+                h.db.clone(),
+                h.admins.clone(),
+            )
+        };
+        left(55).await.unwrap();
+        assert!(messenger.sent.lock().unwrap().is_empty());
+
+        messenger.chat_admins.lock().unwrap().retain(|id| *id != 99);
+        left(77).await.unwrap();
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((0, false))
+        );
+        let sent = messenger.sent.lock().unwrap().clone();
+        let offered: Vec<ChatId> = sent.iter().map(|(chat, _, _)| *chat).collect();
+        assert_eq!(offered, [ChatId(USER_ID), ChatId(88)]);
+        let keyboard = sent[0].2.keyboard.clone().unwrap();
+        let teloxide::types::InlineKeyboardButtonKind::CallbackData(data) =
+            &keyboard.inline_keyboard[0][0].kind
+        else {
+            panic!("expected a callback button");
+        };
+
+        let press = |user_id: i64| {
+            let query: CallbackQuery = serde_json::from_value(serde_json::json!({
+                "id": format!("q{}", user_id),
+                "from": { "id": user_id, "is_bot": false, "first_name": "Ada" },
+                "chat_instance": "1",
+                "data": data,
+            }))
+            .unwrap();
+            handle_callback_query(
+                messenger.clone(),
+                query,
+                h.db.clone(),
+                h.config.clone(),
+                h.replies.clone(),
+                h.admins.clone(),
+                h.me.clone(),
+            )
+        };
+        // No longer an administrator.
+        press(99).await.unwrap();
+        press(88).await.unwrap();
+        press(USER_ID).await.unwrap();
+        assert_eq!(
+            h.db.get_chat_owner(GROUP_ID).await.unwrap(),
+            Some((88, true))
+        );
+        let tr = i18n::get_translations("en");
+        let answers: Vec<String> = messenger
+            .answered
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, text)| text.clone())
+            .collect();
+        assert_eq!(
+            answers,
+            [
+                tr.ownership_unavailable,
+                tr.ownership_taken,
+                tr.ownership_unavailable
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn group_stats_count_the_links_cleaned_in_the_chat() {
+        let h = Harness::new("group_stats").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/a?utm_source=x"),
+        )
+        .await;
+        h.handle(
+            &messenger,
+            private_message("https://example.com/b?utm_source=x"),
+        )
+        .await;
+        h.handle(&messenger, group_message("/groupstats@clear_urls_bot"))
+            .await;
+        h.handle(&messenger, private_message("/groupstats")).await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent[2].0, ChatId(GROUP_ID));
+        assert!(sent[2]
+            .1
+            .ends_with("Links cleaned in this chat: <b>1</b>\nMembers protected: <b>1</b>"));
+        assert_eq!(sent[3].1, i18n::get_translations("en").group_only);
+    }
+
+    #[tokio::test]
+    async fn top_domains_group_hosts_without_www() {
+        let h = Harness::new("top_domains").await;
+        let messenger = MockMessenger::default();
+        for link in [
+            "https://www.Example.com/a?utm_source=x",
+            "https://example.com/b?utm_source=x",
+            "https://news.site/c?fbclid=y",
+        ] {
+            h.handle(&messenger, private_message(link)).await;
+        }
+        h.handle(&messenger, private_message("/topdomains")).await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        let text = &sent.last().unwrap().1;
+        assert!(text.contains("Distinct domains cleaned: <b>2</b>"));
+        assert!(text.ends_with("1. <code>example.com</code> — 2\n2. <code>news.site</code> — 1\n"));
+    }
+
+    #[tokio::test]
+    async fn each_bot_answers_its_own_commands_and_tags_history() {
+        let mut h = Harness::new("two_bots").await;
+        save_group(&h, "reply").await;
+        let messenger = MockMessenger::default();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/a?utm_source=x"),
+        )
+        .await;
+        h.me = serde_json::from_value(serde_json::json!({
+            "id": 2, "is_bot": true, "first_name": "Clear EU", "username": "clear_urls_eu_bot",
+            "can_join_groups": true, "can_read_all_group_messages": true,
+            "supports_inline_queries": false, "has_main_web_app": false,
+        }))
+        .unwrap();
+        h.handle(
+            &messenger,
+            group_message("https://example.com/b?utm_source=x"),
+        )
+        .await;
+        h.handle(&messenger, group_message("/groupstats@clear_urls_bot"))
+            .await;
+        h.handle(&messenger, group_message("/groupstats@clear_urls_eu_bot"))
+            .await;
+
+        let sent = messenger.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 3);
+        assert!(sent[2].1.contains("Links cleaned in this chat: <b>2</b>"));
+        let mut bots: Vec<_> =
+            h.db.get_history(USER_ID, 10)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|link| link.bot_id)
+                .collect();
+        bots.sort();
+        assert_eq!(bots, [Some(1), Some(2)]);
+    }
 }
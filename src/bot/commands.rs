@@ -0,0 +1,1400 @@
+//! The bot's commands, one handler each. `handle_message` parses the command
+//! and hands it to `run` with the message it came in.
+
+use super::*;
+use crate::models::UserConfig;
+
+/// What a command handler needs from the message it answers.
+pub(super) struct CommandContext<'a, M> {
+    pub(super) bot: &'a M,
+    pub(super) msg: &'a Message,
+    pub(super) db: &'a Db,
+    pub(super) rules: &'a RuleEngine,
+    pub(super) shared_config: &'a SharedConfig,
+    pub(super) config: &'a Config,
+    pub(super) event_tx: &'a tokio::sync::broadcast::Sender<serde_json::Value>,
+    pub(super) me: &'a Me,
+    pub(super) admins: &'a AdminCache,
+    pub(super) budget: &'a Budget,
+    pub(super) tr: &'a i18n::Translations,
+    pub(super) user_config: &'a UserConfig,
+    pub(super) user_id: i64,
+    pub(super) chat_id: ChatId,
+    pub(super) is_private: bool,
+    /// The text after the command, trimmed.
+    pub(super) args: &'a str,
+}
+
+/// Answers `command`.
+pub(super) async fn run<M: Messenger>(
+    cx: &CommandContext<'_, M>,
+    command: Command,
+) -> ResponseResult<()> {
+    match command {
+        Command::Start => start(cx).await,
+        Command::Help => help(cx).await,
+        Command::Stats => stats(cx).await,
+        Command::TopDomains => top_domains(cx).await,
+        Command::ClearHistory => clear_history(cx).await,
+        Command::Undo => undo(cx).await,
+        Command::AddRule => add_rule(cx).await,
+        Command::Rules => list_rules(cx).await,
+        Command::DelRule => del_rule(cx).await,
+        Command::Reload => reload(cx).await,
+        Command::Debug => debug(cx).await,
+        Command::Usage => usage(cx).await,
+        Command::GlobalStats => global_stats(cx).await,
+        Command::Translation => translation(cx).await,
+        Command::Plan => plan(cx).await,
+        Command::GroupAi => group_ai(cx).await,
+        Command::Clean => clean(cx).await,
+        Command::Level => level(cx).await,
+        Command::Mode => mode(cx).await,
+        Command::Language => language(cx).await,
+        Command::Privacy => privacy(cx).await,
+        Command::Ignore | Command::Unignore => ignore(cx, command).await,
+        Command::Whitelist | Command::Unwhitelist => whitelist(cx, command).await,
+        Command::Topic => topic(cx).await,
+        Command::Settings => settings(cx).await,
+        Command::Login => login(cx).await,
+        Command::Export => export(cx).await,
+        Command::ForgetMe => forget_me(cx).await,
+        Command::GroupStats => group_stats(cx).await,
+    }
+}
+
+/// `/start`: the welcome message, or a group's setup when opened from the
+/// group's deep link.
+async fn start<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    tracing::info!("Handling /start command for user {}", user_id);
+    // Deep links from a group open its setup here.
+    let setup_chat = args
+        .strip_prefix(GROUP_SETUP_PAYLOAD)
+        .and_then(|id| id.parse::<i64>().ok())
+        .filter(|id| *id < 0 && is_private);
+    if let Some(group_id) = setup_chat {
+        let chat_config = db.get_chat_config(group_id).await.unwrap_or_else(|e| {
+            tracing::error!(error = %e, "Failed to fetch chat config");
+            None
+        });
+        let title = chat_config.as_ref().and_then(|c| c.title.clone());
+        let allowed = chat_config.is_some()
+            && may_change_settings(
+                bot,
+                admins,
+                db,
+                config,
+                ChatId(group_id),
+                title.as_deref(),
+                user_id,
+            )
+            .await;
+        let (text, options) = match chat_config.filter(|_| allowed) {
+            Some(chat_config) => (
+                tr.group_setup
+                    .replace("{}", &html::escape(title.as_deref().unwrap_or(tr.unknown))),
+                SendOptions {
+                    keyboard: Some(group_setup_keyboard(
+                        &chat_config,
+                        config.ai_available(),
+                        tr,
+                    )),
+                    ..SendOptions::default()
+                },
+            ),
+            None => (tr.owner_only.to_string(), SendOptions::default()),
+        };
+        bot.send_message(chat_id, text, options).await?;
+        return Ok(());
+    }
+    let welcome_text = tr.welcome.replace("{}", &user_id.to_string());
+    bot.send_message(chat_id, welcome_text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/help`.
+async fn help<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot, tr, chat_id, ..
+    } = *cx;
+    bot.send_message(chat_id, tr.help_text.to_string(), SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/stats`: the user's cleaned links and, with AI on, today's AI usage.
+async fn stats<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        ..
+    } = *cx;
+    let mut stats_text = tr
+        .stats_text
+        .replace("{}", &user_config.cleaned_count.to_string());
+    if user_config.is_ai_enabled() && config.ai_available() {
+        let (requests, tokens) = db.get_ai_usage_today(user_id).await.unwrap_or((0, 0));
+        let limits = crate::entitlements::user_limits(db, config, user_id).await;
+        let quota = if limits.ai_daily_quota > 0 {
+            limits.ai_daily_quota.to_string()
+        } else {
+            "∞".to_string()
+        };
+        stats_text.push_str(
+            &tr.ai_usage_text
+                .replacen("{}", &requests.to_string(), 1)
+                .replacen("{}", &quota, 1)
+                .replacen("{}", &tokens.to_string(), 1),
+        );
+        let (assisted, total_requests, successes, avg_latency) =
+            db.get_ai_stats(user_id).await.unwrap_or((0, 0, 0, 0));
+        if total_requests > 0 {
+            stats_text.push_str(
+                &tr.ai_stats_text
+                    .replacen("{}", &assisted.to_string(), 1)
+                    .replacen("{}", &(successes * 100 / total_requests).to_string(), 1)
+                    .replacen("{}", &avg_latency.to_string(), 1),
+            );
+        }
+    }
+    bot.send_message(chat_id, stats_text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/topdomains`: the domains the user cleaned most.
+async fn top_domains<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        ..
+    } = *cx;
+    let distinct = db.count_distinct_domains(user_id).await.unwrap_or(0);
+    let top = db.get_top_domains(user_id, 5).await.unwrap_or_default();
+    let mut text = tr.top_domains_text.replace("{}", &distinct.to_string());
+    if top.is_empty() {
+        text.push_str(tr.no_history);
+    }
+    for (i, (domain, count)) in top.iter().enumerate() {
+        text.push_str(&format!(
+            "{}. <code>{}</code> — {}\n",
+            i + 1,
+            html::escape(domain),
+            count
+        ));
+    }
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/clearhistory`: hides the history, which `/undo` can bring back for a
+/// while.
+async fn clear_history<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        ..
+    } = *cx;
+    let text = match db.clear_history(user_id).await {
+        Ok(_) => tr.history_cleared.replace(
+            "{}",
+            &(crate::db::HISTORY_UNDO_WINDOW_SECS / 60).to_string(),
+        ),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to clear history");
+            tr.generic_error.to_string()
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/undo`: restores a history cleared within the undo window.
+async fn undo<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        ..
+    } = *cx;
+    let text = match db.undo_clear_history(user_id).await {
+        Ok(0) => tr.nothing_to_undo,
+        Ok(_) => tr.history_restored,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to restore history");
+            tr.generic_error
+        }
+    };
+    bot.send_message(chat_id, text.to_string(), SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/addrule <pattern>`.
+async fn add_rule<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let limits = crate::entitlements::user_limits(db, config, user_id).await;
+    let text = match db
+        .add_custom_rule(user_id, args, limits.max_custom_rules, Actor::bot(user_id))
+        .await
+    {
+        Ok(()) => tr.rule_added.replace("{}", &html::escape(args)),
+        Err(e) => match e.downcast_ref::<crate::db::CustomRuleError>() {
+            Some(crate::db::CustomRuleError::EmptyPattern) => tr.rule_usage.to_string(),
+            Some(crate::db::CustomRuleError::Duplicate) => tr.rule_duplicate.to_string(),
+            Some(crate::db::CustomRuleError::LimitReached(max)) => {
+                tr.rule_limit.replace("{}", &max.to_string())
+            }
+            None => {
+                tracing::error!(error = %e, "Failed to add custom rule");
+                tr.generic_error.to_string()
+            }
+        },
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/rules`: the user's custom rules.
+async fn list_rules<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        ..
+    } = *cx;
+    let rules = db.get_custom_rules(user_id).await.unwrap_or_default();
+    let mut text = String::from(tr.rules_title);
+    if rules.is_empty() {
+        text.push_str(tr.rule_usage);
+    }
+    for rule in &rules {
+        text.push_str(&format!("• <code>{}</code>\n", html::escape(&rule.pattern)));
+    }
+    if !rules.is_empty() {
+        text.push('\n');
+        text.push_str(tr.rule_remove_usage);
+    }
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/delrule <pattern>`.
+async fn del_rule<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let text = if args.is_empty() {
+        tr.rule_remove_usage.to_string()
+    } else {
+        let rule = db
+            .get_custom_rules(user_id)
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .find(|rule| rule.pattern == args);
+        let removed = match rule {
+            Some(rule) => {
+                db.delete_custom_rule(user_id, rule.id, Actor::bot(user_id))
+                    .await
+            }
+            None => Ok(false),
+        };
+        match removed {
+            Ok(true) => tr.rule_removed.replace("{}", &html::escape(args)),
+            Ok(false) => tr.rule_not_found.replace("{}", &html::escape(args)),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to delete custom rule");
+                tr.generic_error.to_string()
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/reload`: re-reads the configuration (bot admin).
+async fn reload<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        rules,
+        shared_config,
+        tr,
+        chat_id,
+        ..
+    } = *cx;
+    let text = match crate::reload::reload_config(shared_config, rules).await {
+        Ok(()) => tr.config_reloaded.to_string(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to reload configuration");
+            tr.config_reload_failed
+                .replace("{}", &html::escape(&format!("{:#}", e)))
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/debug [id] [off]`: verbose tracing for a user or chat (bot admin).
+async fn debug<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let mut parts = args.split_whitespace();
+    let target = parts.next().and_then(|id| id.parse::<i64>().ok());
+    let enable = parts.next() != Some("off");
+    let text = match target {
+        Some(target) => match db
+            .set_debug_target(target, enable, Actor::bot(user_id))
+            .await
+        {
+            Ok(()) if enable => tr.debug_enabled.replace("{}", &target.to_string()),
+            Ok(()) => tr.debug_disabled.replace("{}", &target.to_string()),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to toggle debug tracing");
+                tr.generic_error.to_string()
+            }
+        },
+        None => {
+            let active = db.get_debug_targets().await.unwrap_or_default();
+            let active = if active.is_empty() {
+                "-".to_string()
+            } else {
+                active
+                    .iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            tr.debug_usage.replace("{}", &active)
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/usage`: command and AI usage over 30 days (bot admin).
+async fn usage<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        chat_id,
+        ..
+    } = *cx;
+    let usage = db.get_usage_stats(30).await.unwrap_or_default();
+    let mut text = String::from(tr.usage_title);
+    if usage.is_empty() {
+        text.push('-');
+    }
+    for (name, count) in &usage {
+        text.push_str(&format!(
+            "<code>{}</code> — {}\n",
+            html::escape(name),
+            count
+        ));
+    }
+    let (requests, tokens) = db.get_ai_usage_totals(30).await.unwrap_or((0, 0));
+    text.push_str(
+        &tr.usage_ai
+            .replacen("{}", &requests.to_string(), 1)
+            .replacen("{}", &tokens.to_string(), 1),
+    );
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/globalstats`: totals and the provider leaderboard (bot admin).
+async fn global_stats<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        rules,
+        tr,
+        chat_id,
+        ..
+    } = *cx;
+    let (cleaned, users) = db.get_global_stats().await.unwrap_or((0, 0));
+    let rules_age = match rules.refreshed_at() {
+        Some(at) => tr
+            .rules_age
+            .replace("{}", &format_age(crate::db::unix_now().unwrap_or(at) - at)),
+        None => tr.rules_embedded.to_string(),
+    };
+    let mut text = tr
+        .global_stats_text
+        .replacen("{}", &cleaned.to_string(), 1)
+        .replacen("{}", &users.to_string(), 1)
+        .replacen("{}", &rules.provider_count().to_string(), 1)
+        .replacen("{}", &rules_age, 1);
+    let today = crate::db::unix_now().unwrap_or(0) / 86400;
+    let providers = db
+        .get_provider_leaderboard(today - 29, today, 10)
+        .await
+        .unwrap_or_default();
+    if providers.is_empty() {
+        text.push('-');
+    }
+    for (i, (provider, count, users)) in providers.iter().enumerate() {
+        text.push_str(&format!(
+            "{}. <code>{}</code> — {} ({} 👤)\n",
+            i + 1,
+            html::escape(provider),
+            count,
+            users
+        ));
+    }
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/translation <lang> <key> [value]`: overrides a translated string
+/// (bot admin).
+async fn translation<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let mut parts = args.splitn(3, char::is_whitespace);
+    let lang = parts.next().unwrap_or("").to_lowercase();
+    let key = parts.next().unwrap_or("");
+    let value = parts.next().unwrap_or("").trim();
+    let text = if lang.is_empty() || key.is_empty() {
+        let overrides = db.get_translation_overrides().await.unwrap_or_default();
+        let mut text = String::from(tr.translation_usage);
+        if overrides.is_empty() {
+            text.push('-');
+        }
+        for o in &overrides {
+            text.push_str(&format!(
+                "<code>{} {}</code> — {}\n",
+                html::escape(&o.lang),
+                html::escape(&o.key),
+                html::escape(&o.value)
+            ));
+        }
+        text
+    } else if !(lang == "*" || i18n::LANGUAGES.contains(&lang.as_str()))
+        || !i18n::KEYS.contains(&key)
+    {
+        tr.translation_invalid
+            .replace("{}", &i18n::LANGUAGES.join(", "))
+    } else {
+        let actor = Actor::bot(user_id);
+        let result = if value.is_empty() {
+            db.delete_translation_override(&lang, key, actor)
+                .await
+                .map(|_| tr.translation_removed)
+        } else {
+            let entry = TranslationOverride {
+                lang: lang.clone(),
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+            db.set_translation_override(&entry, actor)
+                .await
+                .map(|()| tr.translation_saved)
+        };
+        match result {
+            Ok(done) => {
+                if let Err(e) = i18n::load_overrides(db).await {
+                    tracing::error!(error = %e, "Failed to reload translation overrides");
+                }
+                done.replace("{}", &html::escape(key))
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to save translation override");
+                tr.generic_error.to_string()
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/plan <user> [plan] [days]`: shows or grants a plan (bot admin).
+async fn plan<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let mut parts = args.split_whitespace();
+    let target = parts.next().and_then(|id| id.parse::<i64>().ok());
+    let plan = parts.next().map(Plan::parse);
+    let days = parts
+        .next()
+        .map(|d| d.parse::<i64>().ok().filter(|d| *d > 0));
+    let text = match (target, plan, days) {
+        (Some(target), None, None) => plan_status(db, config, tr, target).await,
+        (Some(target), Some(Some(plan)), None | Some(Some(_))) => {
+            let expires_at = days
+                .flatten()
+                .map(|days| crate::db::unix_now().unwrap_or(0) + days * 86400);
+            match db
+                .set_entitlement(target, plan, expires_at, Actor::bot(user_id))
+                .await
+            {
+                Ok(()) => plan_status(db, config, tr, target).await,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to set entitlement");
+                    tr.generic_error.to_string()
+                }
+            }
+        }
+        _ => tr.plan_usage.to_string(),
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/groupai on|off`: AI cleaning in a group (owner).
+async fn group_ai<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    if is_private {
+        bot.send_message(chat_id, tr.group_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    let is_owner =
+        may_change_settings(bot, admins, db, config, chat_id, msg.chat.title(), user_id).await;
+    let mut chat_config = db
+        .get_chat_config_or_default(chat_id.0)
+        .await
+        .unwrap_or_default();
+    let text = if !is_owner {
+        tr.owner_only.to_string()
+    } else {
+        match args {
+            "on" | "off" => {
+                chat_config.chat_id = chat_id.0;
+                chat_config.title = msg.chat.title().map(|s| s.to_string());
+                chat_config.ai_enabled = (args == "on") as i32;
+                match db.save_chat_config(&chat_config, Actor::bot(user_id)).await {
+                    Ok(()) if args == "on" => tr.group_ai_enabled.to_string(),
+                    Ok(()) => tr.group_ai_disabled.to_string(),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to save chat AI setting");
+                        tr.generic_error.to_string()
+                    }
+                }
+            }
+            _ => tr.group_ai_usage.to_string(),
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/clean <url>`: cleans one link on demand.
+async fn clean<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        rules,
+        config,
+        event_tx,
+        me,
+        budget,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    let Some(url) = clear_urls_core::find_urls(args).next() else {
+        bot.send_message(chat_id, tr.clean_usage.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    };
+    let url = url.to_string();
+    // Asked for explicitly, so neither the enabled flag nor
+    // ignored domains apply. The group's level still does.
+    let chat_level = if is_private {
+        None
+    } else {
+        db.get_chat_config(chat_id.0)
+            .await
+            .unwrap_or(None)
+            .map(|c| c.cleaning_level)
+    };
+    let level = match chat_level.as_deref() {
+        None | Some("default" | "") => CleaningLevel::parse(&user_config.cleaning_level),
+        Some(level) => CleaningLevel::parse(level),
+    }
+    .unwrap_or_default();
+    let custom_rules = db.get_custom_rules(user_id).await.unwrap_or_default();
+    let chain = budget
+        .run(rules.expand_url_chain(&url), "expand")
+        .await
+        .unwrap_or_default();
+    let expanded = chain.last().cloned().unwrap_or_else(|| url.clone());
+    let mut options = SendOptions {
+        reply_to: Some(msg.id),
+        thread_id: msg.thread_id,
+        disable_link_preview: true,
+        ..SendOptions::default()
+    };
+    let text = match rules.sanitize(&expanded, &custom_rules, &[], level) {
+        Some((cleaned, provider)) => {
+            let _ = db.increment_cleaned_count(user_id, 1).await;
+            let cleaning = (url.as_str(), cleaned.as_str(), provider.as_str(), "rules");
+            log_cleaning(
+                db,
+                event_tx,
+                me,
+                user_id,
+                user_config.keeps_history(),
+                Some(chat_id.0),
+                cleaning,
+                &chain,
+            )
+            .await;
+            let mut text = tr
+                .clean_result
+                .replacen("{}", &html::escape(&provider), 1)
+                .replacen("{}", &html::escape(&cleaned), 1);
+            let removed: Vec<String> = removed_params(&expanded, &cleaned)
+                .iter()
+                .map(|param| format!("<code>{}</code>", html::escape(param)))
+                .collect();
+            if !removed.is_empty() {
+                text.push_str(&tr.clean_removed.replace("{}", &removed.join(", ")));
+            }
+            options.keyboard = link_keyboard(
+                std::iter::once(cleaned.as_str()),
+                config.instant_view_rhash.as_deref(),
+                tr,
+            );
+            text
+        }
+        None => tr.clean_nothing.to_string(),
+    };
+    bot.send_message(chat_id, text, options).await?;
+    Ok(())
+}
+
+/// `/level`: the user's cleaning level in private, the group's (owner) in
+/// groups.
+async fn level<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        admins,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    let level = match args {
+        "default" if !is_private => Some("default"),
+        _ => CleaningLevel::parse(args).map(|l| l.as_str()),
+    };
+    let text = if is_private {
+        let mut user_config = user_config.clone();
+        match level {
+            Some(level) => {
+                user_config.user_id = user_id;
+                user_config.cleaning_level = level.to_string();
+                match db.save_user_config(&user_config, Actor::bot(user_id)).await {
+                    Ok(()) => tr.level_set.replace("{}", level),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to save cleaning level");
+                        tr.generic_error.to_string()
+                    }
+                }
+            }
+            None => tr.level_usage.replace("{}", &user_config.cleaning_level),
+        }
+    } else {
+        let mut chat_config = db
+            .get_chat_config_or_default(chat_id.0)
+            .await
+            .unwrap_or_default();
+        match level {
+            None => tr.level_usage.replace("{}", &chat_config.cleaning_level),
+            Some(_)
+                if !may_change_settings(
+                    bot,
+                    admins,
+                    db,
+                    config,
+                    chat_id,
+                    msg.chat.title(),
+                    user_id,
+                )
+                .await =>
+            {
+                tr.owner_only.to_string()
+            }
+            Some(level) => {
+                chat_config.chat_id = chat_id.0;
+                chat_config.title = msg.chat.title().map(|s| s.to_string());
+                chat_config.cleaning_level = level.to_string();
+                match db.save_chat_config(&chat_config, Actor::bot(user_id)).await {
+                    Ok(()) => tr.level_set.replace("{}", level),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to save chat cleaning level");
+                        tr.generic_error.to_string()
+                    }
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/mode`: how a group's links are answered.
+async fn mode<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    if is_private {
+        bot.send_message(chat_id, tr.group_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    let mut chat_config = db
+        .get_chat_config_or_default(chat_id.0)
+        .await
+        .unwrap_or_default();
+    let mode = CHAT_MODES.contains(&args).then_some(args);
+    let text = match mode {
+        None => tr.mode_usage.replace("{}", &chat_config.mode),
+        Some(_)
+            if !may_change_settings(
+                bot,
+                admins,
+                db,
+                config,
+                chat_id,
+                msg.chat.title(),
+                user_id,
+            )
+            .await =>
+        {
+            tr.owner_only.to_string()
+        }
+        Some(mode) => {
+            chat_config.chat_id = chat_id.0;
+            chat_config.title = msg.chat.title().map(|s| s.to_string());
+            chat_config.mode = mode.to_string();
+            match db.save_chat_config(&chat_config, Actor::bot(user_id)).await {
+                Ok(()) => tr.mode_set.replace("{}", mode),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to save chat mode");
+                    tr.generic_error.to_string()
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/language <code>`.
+async fn language<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let language = i18n::LANGUAGES
+        .iter()
+        .find(|l| **l == args)
+        .filter(|_| user_id != 0);
+    let text = match language {
+        None => tr
+            .language_usage
+            .replacen("{}", &user_config.language, 1)
+            .replacen("{}", &i18n::LANGUAGES.join("|"), 1),
+        Some(language) => {
+            let mut user_config = user_config.clone();
+            user_config.user_id = user_id;
+            user_config.language = language.to_string();
+            match db.save_user_config(&user_config, Actor::bot(user_id)).await {
+                Ok(()) => i18n::get_translations(language)
+                    .language_set
+                    .replace("{}", language),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to save language");
+                    tr.generic_error.to_string()
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/privacy on|off`: whether cleanings are kept in the history.
+async fn privacy<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let mut user_config = user_config.clone();
+    let log_history = match args {
+        "on" => Some(0),
+        "off" => Some(1),
+        _ => None,
+    };
+    if let Some(log_history) = log_history.filter(|_| user_id != 0) {
+        user_config.user_id = user_id;
+        user_config.log_history = log_history;
+        if let Err(e) = db.save_user_config(&user_config, Actor::bot(user_id)).await {
+            tracing::error!(error = %e, "Failed to save history setting");
+            bot.send_message(
+                chat_id,
+                tr.generic_error.to_string(),
+                SendOptions::default(),
+            )
+            .await?;
+            return Ok(());
+        }
+    }
+    let text = if user_config.keeps_history() {
+        tr.privacy_off
+    } else {
+        tr.privacy_on
+    };
+    bot.send_message(chat_id, text.to_string(), SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/ignore` and `/unignore <domain>`: domains a group leaves alone.
+async fn ignore<M: Messenger>(cx: &CommandContext<'_, M>, command: Command) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    if is_private {
+        bot.send_message(chat_id, tr.group_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    let domains = db
+        .get_chat_ignored_domains(chat_id.0)
+        .await
+        .unwrap_or_default();
+    let text = match normalize_domain(args) {
+        None => {
+            let listed: Vec<String> = domains
+                .iter()
+                .map(|domain| format!("<code>{}</code>", html::escape(domain)))
+                .collect();
+            let listed = if listed.is_empty() {
+                "—".to_string()
+            } else {
+                listed.join(", ")
+            };
+            tr.ignore_usage.replace("{}", &listed)
+        }
+        Some(_) if !may_moderate(bot, admins, config, chat_id, user_id).await => {
+            tr.chat_admins_only.to_string()
+        }
+        Some(domain)
+            if command == Command::Ignore
+                && domains.len() >= MAX_CHAT_IGNORED_DOMAINS
+                && !domains.contains(&domain) =>
+        {
+            tr.ignore_limit
+                .replace("{}", &MAX_CHAT_IGNORED_DOMAINS.to_string())
+        }
+        Some(domain) => {
+            let actor = Actor::bot(user_id);
+            let changed = if command == Command::Ignore {
+                db.add_chat_ignored_domain(chat_id.0, &domain, actor)
+                    .await
+                    .map(|_| tr.ignore_added)
+            } else {
+                db.remove_chat_ignored_domain(chat_id.0, &domain, actor)
+                    .await
+                    .map(|removed| {
+                        if removed {
+                            tr.unignore_removed
+                        } else {
+                            tr.unignore_missing
+                        }
+                    })
+            };
+            match changed {
+                Ok(text) => text.replace("{}", &html::escape(&domain)),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to update chat ignored domains");
+                    tr.generic_error.to_string()
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/whitelist` and `/unwhitelist <member>`: members a group leaves alone.
+async fn whitelist<M: Messenger>(
+    cx: &CommandContext<'_, M>,
+    command: Command,
+) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    if is_private {
+        bot.send_message(chat_id, tr.group_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    let members = db.get_chat_whitelist(chat_id.0).await.unwrap_or_default();
+    let text = match whitelist_member(msg, args) {
+        None => {
+            let listed: Vec<String> = members.iter().map(|member| member_label(member)).collect();
+            let listed = if listed.is_empty() {
+                "—".to_string()
+            } else {
+                listed.join(", ")
+            };
+            tr.whitelist_usage.replace("{}", &listed)
+        }
+        Some(_) if !may_moderate(bot, admins, config, chat_id, user_id).await => {
+            tr.chat_admins_only.to_string()
+        }
+        Some(member)
+            if command == Command::Whitelist
+                && members.len() >= MAX_CHAT_WHITELIST
+                && !members.contains(&member) =>
+        {
+            tr.whitelist_limit
+                .replace("{}", &MAX_CHAT_WHITELIST.to_string())
+        }
+        Some(member) => {
+            let actor = Actor::bot(user_id);
+            let changed = if command == Command::Whitelist {
+                db.add_chat_whitelisted_member(chat_id.0, &member, actor)
+                    .await
+                    .map(|_| tr.whitelist_added)
+            } else {
+                db.remove_chat_whitelisted_member(chat_id.0, &member, actor)
+                    .await
+                    .map(|removed| {
+                        if removed {
+                            tr.unwhitelist_removed
+                        } else {
+                            tr.unwhitelist_missing
+                        }
+                    })
+            };
+            match changed {
+                Ok(text) => text.replace("{}", &member_label(&member)),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to update chat whitelist");
+                    tr.generic_error.to_string()
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/topic on|off`: cleaning in the current forum topic.
+async fn topic<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        admins,
+        tr,
+        user_id,
+        chat_id,
+        args,
+        ..
+    } = *cx;
+    let Some(thread_id) = forum_topic(msg) else {
+        bot.send_message(chat_id, tr.forum_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    };
+    // Answered in the topic itself, not in General.
+    let options = SendOptions {
+        thread_id: msg.thread_id.filter(|_| msg.is_topic_message),
+        ..SendOptions::default()
+    };
+    let enabled = match args {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+    let text = match enabled {
+        None => {
+            let on = db
+                .is_topic_enabled(chat_id.0, thread_id)
+                .await
+                .unwrap_or(true);
+            tr.topic_usage
+                .replace("{}", if on { tr.setting_on } else { tr.setting_off })
+        }
+        Some(enabled) => {
+            if !may_moderate(bot, admins, config, chat_id, user_id).await {
+                tr.chat_admins_only.to_string()
+            } else {
+                match db
+                    .set_topic_enabled(chat_id.0, thread_id, enabled, Actor::bot(user_id))
+                    .await
+                {
+                    Ok(()) if enabled => tr.topic_on.to_string(),
+                    Ok(()) => tr.topic_off.to_string(),
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to save topic setting");
+                        tr.generic_error.to_string()
+                    }
+                }
+            }
+        }
+    };
+    bot.send_message(chat_id, text, options).await?;
+    Ok(())
+}
+
+/// `/settings`: the settings menu in private, a link to the group's setup
+/// (owner) in groups.
+async fn settings<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        msg,
+        db,
+        config,
+        me,
+        admins,
+        tr,
+        user_config,
+        user_id,
+        chat_id,
+        is_private,
+        ..
+    } = *cx;
+    // In private, the user's own settings as buttons. The
+    // menu follows the configured language, which it sets.
+    if is_private {
+        let tr = i18n::get_translations(&user_config.language);
+        let options = SendOptions {
+            keyboard: Some(settings_keyboard(user_config, config.ai_available(), &tr)),
+            ..SendOptions::default()
+        };
+        bot.send_message(chat_id, tr.settings_menu.to_string(), options)
+            .await?;
+        return Ok(());
+    }
+    let is_owner =
+        may_change_settings(bot, admins, db, config, chat_id, msg.chat.title(), user_id).await;
+    let text = if !is_owner {
+        tr.owner_only.to_string()
+    } else if let Some(dashboard_url) = &config.dashboard_url {
+        let expires_at = crate::db::unix_now().unwrap_or(0) + crate::links::CHAT_LINK_TTL_SECS;
+        let token =
+            crate::links::chat_link_token(&config.bot_token, chat_id.0, user_id, expires_at);
+        let url = crate::links::chat_settings_url(dashboard_url, chat_id.0, &token);
+        let title = msg.chat.title().unwrap_or(tr.unknown);
+        let link_text = tr
+            .settings_link
+            .replacen("{}", &html::escape(title), 1)
+            .replacen("{}", &html::escape(&url), 1);
+        // The link signs the user in, so it only goes out privately.
+        match bot
+            .send_message(ChatId(user_id), link_text, SendOptions::default())
+            .await
+        {
+            Ok(()) => tr.settings_link_sent.to_string(),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to send settings link");
+                tr.settings_dm_failed.to_string()
+            }
+        }
+    } else {
+        // Without a dashboard, the group is set up in private.
+        let options = group_setup_url(me, chat_id)
+            .parse()
+            .map(|url| SendOptions {
+                keyboard: Some(InlineKeyboardMarkup::new([[InlineKeyboardButton::url(
+                    tr.group_setup_open,
+                    url,
+                )]])),
+                ..SendOptions::default()
+            })
+            .unwrap_or_default();
+        bot.send_message(chat_id, tr.group_setup_prompt.to_string(), options)
+            .await?;
+        return Ok(());
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/login`: a one-time dashboard login link.
+async fn login<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        config,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        ..
+    } = *cx;
+    let text = if !is_private {
+        tr.private_only.to_string()
+    } else if let Some(dashboard_url) = &config.dashboard_url {
+        let token = crate::links::random_token();
+        match db
+            .create_login_token(&token, user_id, crate::links::LOGIN_LINK_TTL)
+            .await
+        {
+            Ok(()) => tr.login_link.replace(
+                "{}",
+                &html::escape(&crate::links::login_url(dashboard_url, &token)),
+            ),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to create login link");
+                tr.generic_error.to_string()
+            }
+        }
+    } else {
+        tr.dashboard_unavailable.to_string()
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/export`: the user's data as an archive.
+async fn export<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        ..
+    } = *cx;
+    if !is_private {
+        bot.send_message(chat_id, tr.private_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    match crate::exporter::export_user_data(db, user_id).await {
+        Ok(archive) => {
+            bot.send_document(
+                chat_id,
+                crate::exporter::archive_name(user_id),
+                archive,
+                tr.export_caption.to_string(),
+            )
+            .await?;
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to export user data");
+            bot.send_message(
+                chat_id,
+                tr.generic_error.to_string(),
+                SendOptions::default(),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// `/forgetme confirm`: erases the user's data.
+async fn forget_me<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        user_id,
+        chat_id,
+        is_private,
+        args,
+        ..
+    } = *cx;
+    let text = if !is_private {
+        tr.private_only.to_string()
+    } else if args != "confirm" {
+        tr.forgetme_confirm.to_string()
+    } else {
+        match db.delete_all_user_data(user_id, Actor::bot(user_id)).await {
+            Ok(()) => tr.forgetme_done.to_string(),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to delete user data");
+                tr.generic_error.to_string()
+            }
+        }
+    };
+    bot.send_message(chat_id, text, SendOptions::default())
+        .await?;
+    Ok(())
+}
+
+/// `/groupstats`: links cleaned in the group.
+async fn group_stats<M: Messenger>(cx: &CommandContext<'_, M>) -> ResponseResult<()> {
+    let CommandContext {
+        bot,
+        db,
+        tr,
+        chat_id,
+        is_private,
+        ..
+    } = *cx;
+    if is_private {
+        bot.send_message(chat_id, tr.group_only.to_string(), SendOptions::default())
+            .await?;
+        return Ok(());
+    }
+    let (total, members) = db.get_chat_stats(chat_id.0).await.unwrap_or((0, 0));
+    let stats_text = tr
+        .group_stats_text
+        .replacen("{}", &total.to_string(), 1)
+        .replacen("{}", &members.to_string(), 1);
+    bot.send_message(chat_id, stats_text, SendOptions::default())
+        .await?;
+    Ok(())
+}
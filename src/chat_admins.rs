@@ -0,0 +1,89 @@
+//! Who administers each chat, from `getChatAdministrators`, cached for a few
+//! minutes. Group settings commands and the dashboard's chat pages check it
+//! on every use, so an owner who left or was demoted loses access even though
+//! `chat_configs.added_by` still names them.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a fetched administrator list is trusted.
+const ADMINS_TTL: Duration = Duration::from_secs(300);
+
+/// Human administrators per chat id, creator first.
+#[derive(Clone)]
+pub struct AdminCache(moka::future::Cache<i64, Arc<Vec<i64>>>);
+
+impl AdminCache {
+    pub fn new() -> Self {
+        Self(
+            moka::future::Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(ADMINS_TTL)
+                .build(),
+        )
+    }
+
+    /// Whether `user_id` administers `chat_id`, calling `fetch` for the list
+    /// when it is not cached. Failed fetches are not cached.
+    pub async fn is_admin<F, Fut, E>(&self, chat_id: i64, user_id: i64, fetch: F) -> Result<bool, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<i64>, E>>,
+    {
+        if user_id == 0 {
+            return Ok(false);
+        }
+        let admins = match self.0.get(&chat_id).await {
+            Some(admins) => admins,
+            None => {
+                let admins = Arc::new(fetch().await?);
+                self.0.insert(chat_id, admins.clone()).await;
+                admins
+            }
+        };
+        Ok(admins.contains(&user_id))
+    }
+
+    /// Forgets the list of `chat_id`, after a member's rights changed.
+    pub async fn invalidate(&self, chat_id: i64) {
+        self.0.invalidate(&chat_id).await;
+    }
+}
+
+impl Default for AdminCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn fetches_once_until_invalidated() {
+        let cache = AdminCache::new();
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, ()>(vec![1, 2])
+        };
+        assert_eq!(cache.is_admin(-10, 1, fetch).await, Ok(true));
+        assert_eq!(cache.is_admin(-10, 3, fetch).await, Ok(false));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            cache.is_admin(-10, 1, || async { Err("down") }).await,
+            Ok(true)
+        );
+        cache.invalidate(-10).await;
+        assert_eq!(
+            cache.is_admin(-10, 1, || async { Err("down") }).await,
+            Err("down")
+        );
+        assert_eq!(cache.is_admin(-10, 0, fetch).await, Ok(false));
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    }
+}
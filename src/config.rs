@@ -1,67 +1,624 @@
+use crate::models::Plan;
+use anyhow::Context;
+use arc_swap::ArcSwap;
 use dotenvy::dotenv;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Configuration shared with handlers and background tasks. Hot-reloadable
+/// settings are swapped in place by `reload::reload_config`.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+/// Deployment profile selected by `APP_ENV`, providing defaults for settings
+/// that differ between environments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Development,
+    Staging,
+    Production,
+}
+
+impl Profile {
+    pub fn parse(value: &str) -> Self {
+        match value.trim().to_lowercase().as_str() {
+            "production" | "prod" => Self::Production,
+            "staging" | "stage" => Self::Staging,
+            _ => Self::Development,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Development => "development",
+            Self::Staging => "staging",
+            Self::Production => "production",
+        }
+    }
+
+    /// Only development has a default database; a deployed profile without
+    /// `DATABASE_URL` is a misconfiguration, not a local Postgres.
+    fn default_database_url(&self) -> Option<&'static str> {
+        match self {
+            Self::Development => Some("sqlite:bot.db"),
+            Self::Staging | Self::Production => None,
+        }
+    }
+
+    fn default_update_mode(&self) -> UpdateMode {
+        match self {
+            Self::Development => UpdateMode::Polling,
+            Self::Staging | Self::Production => UpdateMode::Webhook,
+        }
+    }
+
+    fn default_json_logs(&self) -> bool {
+        !matches!(self, Self::Development)
+    }
+
+    pub fn default_log_filter(&self) -> &'static str {
+        match self {
+            Self::Development => "clear_urls_bot=debug,teloxide=info,axum=info",
+            Self::Staging | Self::Production => "clear_urls_bot=info,teloxide=info,axum=info",
+        }
+    }
+}
+
+/// How Telegram delivers updates, selected by `UPDATE_MODE`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMode {
+    /// Long polling; needs no public address.
+    Polling,
+    /// Telegram posts updates to `WEBHOOK_URL`, served on `WEBHOOK_ADDR`.
+    Webhook,
+}
+
+impl UpdateMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "polling" => Some(Self::Polling),
+            "webhook" => Some(Self::Webhook),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Config {
+    pub profile: Profile,
+    pub update_mode: UpdateMode,
+    /// Public HTTPS URL Telegram posts updates to in webhook mode.
+    pub webhook_url: Option<String>,
+    /// Address the webhook listener binds to.
+    pub webhook_addr: String,
+    /// Emit JSON logs instead of pretty console output.
+    pub json_logs: bool,
     pub bot_token: String,
+    /// Additional bots served by the same process (regional clones, ...).
+    pub extra_bot_tokens: Vec<String>,
     pub bot_username: String,
     pub database_url: String,
+    pub database_url_ro: Option<String>,
     pub server_addr: String,
+    /// Public base URL of the dashboard, used for links sent by the bot.
+    pub dashboard_url: Option<String>,
+    /// Networks (CIDR or single addresses) allowed to reach `/admin`; empty
+    /// allows everyone.
+    pub admin_ip_allowlist: Vec<String>,
+    /// Hex public keys of other instances whose profile bundles may carry
+    /// counters, history and chats over (see `profile`).
+    pub trusted_profile_keys: Vec<String>,
+    /// Take the client address from `X-Forwarded-For` (set when the server
+    /// sits behind a reverse proxy).
+    pub trust_proxy_headers: bool,
+    /// Requests per minute each client address may make to the anonymous
+    /// `/clean` endpoint; 0 turns the endpoint off.
+    pub public_clean_rate_limit: i64,
     pub admin_id: i64,
     pub clearurls_source: String,
     pub ai_api_key: Option<String>,
     pub ai_api_base: String,
     pub ai_model: String,
+    /// Overall budget for one AI call, retries included.
+    pub ai_timeout_secs: u64,
+    pub ai_max_retries: u32,
+    /// AI requests allowed per user per day; 0 disables the quota.
+    pub ai_daily_quota: i64,
+    /// Add an AI one-line summary of the destination under links in private chats.
+    pub ai_summaries: bool,
+    /// Score cleaned links for phishing and warn above `ai_phishing_threshold`.
+    pub ai_phishing_check: bool,
+    pub ai_phishing_threshold: f64,
+    pub max_custom_rules: i64,
+    /// Plan of users without an entitlement; `unlimited` for self-hosting.
+    pub default_plan: Plan,
+    pub premium_ai_daily_quota: i64,
+    pub premium_max_custom_rules: i64,
+    /// Days of history kept for free users; 0 keeps it forever.
+    pub history_retention_days: i64,
+    /// Messages one dispatcher processes at the same time.
+    pub max_concurrent_messages: usize,
+    /// Updates buffered per chat worker before polling waits.
+    pub update_queue_size: usize,
+    /// Hard limit for handling one message; it is dropped when exceeded,
+    /// unless its cleaned replacement is already being posted.
+    pub message_timeout_secs: u64,
+    /// Time allowed for expansion and AI steps of one message before the
+    /// rule-only result is sent.
+    pub message_budget_secs: u64,
+    /// Days between a dashboard account-deletion request and the actual
+    /// erasure; signing in again cancels it.
+    pub account_deletion_grace_days: i64,
+    /// Seconds during which a link already answered in a group is not
+    /// answered again; 0 answers every time.
+    pub repeat_window_secs: u64,
+    /// Replies per minute the bot sends to one group; links cleaned beyond
+    /// it are sent together when the minute is over. 0 means no limit.
+    pub chat_reply_limit: i64,
+    /// UTC hour at which chats in `digest` mode get their daily summary, and
+    /// opted-in users their weekly one on Mondays.
+    pub digest_hour: u32,
+    pub redis_url: Option<String>,
+    pub rules_refresh_interval_secs: u64,
+    /// Cron expressions from `SCHEDULE_<JOB>`, by job name; jobs without
+    /// one keep their default schedule (see `scheduler::schedule_for`).
+    pub schedules: BTreeMap<String, String>,
+    pub shorteners: Vec<String>,
+    /// List the redirects a shortened link went through, collapsed, under
+    /// the reply.
+    pub show_redirects: bool,
+    /// Instant View template hash; when set, replies get a button opening
+    /// the cleaned link in Telegram's reader view.
+    pub instant_view_rhash: Option<String>,
+    /// Hosts whose URL fragments are never cleaned.
+    pub fragment_exceptions: Vec<String>,
+    /// Providers whose path-segment rules are switched off.
+    pub disabled_path_rules: Vec<String>,
+    /// Proxy for every outbound request (Telegram, rules, expansion, AI).
+    pub proxy_url: Option<String>,
+    /// Bot API server, for a self-hosted `telegram-bot-api`.
+    pub telegram_api_url: String,
+    /// File the configuration was loaded from, re-read on reload.
+    pub config_path: Option<PathBuf>,
+    /// OTLP/HTTP collector base URL; traces and metrics are exported when set.
+    pub otlp_endpoint: Option<String>,
+    /// Extra headers for the OTLP exporter (API keys, tenant ids, ...).
+    pub otlp_headers: HashMap<String, String>,
+    /// Mastodon instance base URL; the mention bot runs when this and
+    /// `mastodon_token` are set.
+    pub mastodon_instance: Option<String>,
+    pub mastodon_token: Option<String>,
+    pub mastodon_poll_interval_secs: u64,
+    /// Language of the mention bot's replies.
+    pub mastodon_language: String,
+    /// Push-monitor URL (healthchecks.io, Uptime Kuma, ...) pinged while the
+    /// process is healthy.
+    pub heartbeat_url: Option<String>,
+    pub heartbeat_interval_secs: u64,
+}
+
+/// Settings read from an optional TOML/YAML file. Keys are the lowercase
+/// names of the matching environment variables (`teloxide_token`, `ai_model`,
+/// ...), and environment variables always take precedence over the file.
+/// A `[profiles.<name>]` table overrides top-level keys for that profile.
+#[derive(Default)]
+struct Settings {
+    file: HashMap<String, serde_json::Value>,
+}
+
+impl Settings {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let raw = std::fs::read_to_string(path)?;
+        let file = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&raw)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&raw)?,
+            _ => anyhow::bail!("unsupported config format (expected .toml, .yaml or .yml)"),
+        };
+        Ok(Self { file })
+    }
+
+    /// Merges the `[profiles.<profile>]` table over the top-level keys.
+    fn apply_profile(&mut self, profile: Profile) {
+        let overrides = self
+            .file
+            .get("profiles")
+            .and_then(|p| p.get(profile.as_str()))
+            .and_then(|p| p.as_object())
+            .cloned();
+        if let Some(overrides) = overrides {
+            self.file.extend(overrides);
+        }
+    }
+
+    fn get(&self, env_key: &str) -> Option<String> {
+        if let Ok(value) = env::var(env_key) {
+            return Some(value);
+        }
+        match self.file.get(&env_key.to_lowercase())? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    }
+
+    /// Reads a list given either as a file array or a comma-separated string.
+    fn get_list(&self, env_key: &str) -> Option<Vec<String>> {
+        let items: Vec<String> = match env::var(env_key) {
+            Ok(value) => value.split(',').map(|s| s.trim().to_string()).collect(),
+            Err(_) => match self.file.get(&env_key.to_lowercase())? {
+                serde_json::Value::Array(values) => values
+                    .iter()
+                    .filter_map(|v| v.as_str().map(|s| s.trim().to_string()))
+                    .collect(),
+                serde_json::Value::String(value) => {
+                    value.split(',').map(|s| s.trim().to_string()).collect()
+                }
+                _ => return None,
+            },
+        };
+        Some(items.into_iter().filter(|s| !s.is_empty()).collect())
+    }
 }
 
 impl Config {
     pub fn from_env() -> Self {
+        Self::load(None)
+    }
+
+    /// Loads the configuration from `config_path` (or `CONFIG_PATH`), with
+    /// environment variables and `.env` layered on top. Exits at startup
+    /// when it cannot be loaded; see `try_load`.
+    pub fn load(config_path: Option<PathBuf>) -> Self {
+        Self::try_load(config_path).unwrap_or_else(|e| panic!("FATAL: {:#}", e))
+    }
+
+    /// Like `load`, but returns an error instead of exiting, so a reload
+    /// with a broken file keeps the running configuration.
+    pub fn try_load(config_path: Option<PathBuf>) -> anyhow::Result<Self> {
         dotenv().ok();
 
-        let bot_token = env::var("TELOXIDE_TOKEN").expect("TELOXIDE_TOKEN must be set");
-        let mut bot_username = env::var("BOT_USERNAME").expect("BOT_USERNAME must be set");
+        let config_path = config_path.or_else(|| env::var("CONFIG_PATH").ok().map(PathBuf::from));
+        let mut settings = match &config_path {
+            Some(path) => Settings::load(path)
+                .with_context(|| format!("cannot read config file {}", path.display()))?,
+            None => Settings::default(),
+        };
+
+        let profile = Profile::parse(&settings.get("APP_ENV").unwrap_or_default());
+        settings.apply_profile(profile);
+        let json_logs = match settings.get("LOG_FORMAT") {
+            Some(format) => format.eq_ignore_ascii_case("json"),
+            None => profile.default_json_logs(),
+        };
+
+        // Token and username are only required to run the bot; `validate`
+        // rejects them when missing.
+        let bot_token = settings.get("TELOXIDE_TOKEN").unwrap_or_default();
+        let extra_bot_tokens = settings.get_list("EXTRA_BOT_TOKENS").unwrap_or_default();
+        let mut bot_username = settings.get("BOT_USERNAME").unwrap_or_default();
         if bot_username.starts_with('@') {
             bot_username = bot_username[1..].to_string();
         }
-        let database_url = env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:bot.db".to_string());
-        let port = env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-        let server_addr = env::var("SERVER_ADDR").unwrap_or_else(|_| format!("0.0.0.0:{}", port));
+        let database_url = settings
+            .get("DATABASE_URL")
+            .filter(|v| !v.is_empty())
+            .or_else(|| profile.default_database_url().map(str::to_string))
+            .with_context(|| {
+                format!(
+                    "DATABASE_URL must be set in the {} profile",
+                    profile.as_str()
+                )
+            })?;
+        let update_mode = match settings.get("UPDATE_MODE").filter(|v| !v.is_empty()) {
+            Some(mode) => UpdateMode::parse(&mode).with_context(|| {
+                format!("UPDATE_MODE must be polling or webhook, not {:?}", mode)
+            })?,
+            None => profile.default_update_mode(),
+        };
+        let webhook_url = settings.get("WEBHOOK_URL").filter(|v| !v.is_empty());
+        let webhook_addr = settings
+            .get("WEBHOOK_ADDR")
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "0.0.0.0:8443".to_string());
+        let database_url_ro = settings.get("DATABASE_URL_RO").filter(|v| !v.is_empty());
+        let port = settings.get("PORT").unwrap_or_else(|| "3000".to_string());
+        let server_addr = settings
+            .get("SERVER_ADDR")
+            .unwrap_or_else(|| format!("0.0.0.0:{}", port));
+        let dashboard_url = settings
+            .get("DASHBOARD_URL")
+            .filter(|v| !v.is_empty())
+            .map(|v| v.trim_end_matches('/').to_string());
+        let admin_ip_allowlist = settings.get_list("ADMIN_IP_ALLOWLIST").unwrap_or_default();
+        let trusted_profile_keys = settings
+            .get_list("TRUSTED_PROFILE_KEYS")
+            .unwrap_or_default();
+        let trust_proxy_headers = settings
+            .get("TRUST_PROXY_HEADERS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let public_clean_rate_limit = settings
+            .get("PUBLIC_CLEAN_RATE_LIMIT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
 
-        let admin_id = env::var("ADMIN_ID")
-            .unwrap_or_else(|_| "0".to_string())
+        let admin_id = settings
+            .get("ADMIN_ID")
+            .unwrap_or_else(|| "0".to_string())
             .parse()
             .unwrap_or(0);
 
-        let clearurls_source = env::var("CLEARURLS_SOURCE").unwrap_or_else(|_| {
+        let clearurls_source = settings.get("CLEARURLS_SOURCE").unwrap_or_else(|| {
             "https://raw.githubusercontent.com/ClearURLs/Rules/refs/heads/master/data.min.json"
                 .to_string()
         });
 
+        let ai_api_key = settings.get("AI_API_KEY");
+        let ai_api_base = settings
+            .get("AI_API_BASE")
+            .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
+        let ai_model = settings
+            .get("AI_MODEL")
+            .unwrap_or_else(|| "gpt-3.5-turbo".to_string());
+
+        let ai_timeout_secs = settings
+            .get("AI_TIMEOUT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(10);
+        let ai_max_retries = settings
+            .get("AI_MAX_RETRIES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(2);
+
+        let ai_daily_quota = settings
+            .get("AI_DAILY_QUOTA")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(50);
+
+        let ai_summaries = settings
+            .get("AI_SUMMARIES")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let ai_phishing_check = settings
+            .get("AI_PHISHING_CHECK")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let ai_phishing_threshold = settings
+            .get("AI_PHISHING_THRESHOLD")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0.7);
+
+        let max_custom_rules = settings
+            .get("MAX_CUSTOM_RULES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(50);
+
+        let default_plan = settings
+            .get("DEFAULT_PLAN")
+            .and_then(|v| Plan::parse(&v))
+            .unwrap_or_default();
+        let premium_ai_daily_quota = settings
+            .get("PREMIUM_AI_DAILY_QUOTA")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(500);
+        let premium_max_custom_rules = settings
+            .get("PREMIUM_MAX_CUSTOM_RULES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(500);
+        let history_retention_days = settings
+            .get("HISTORY_RETENTION_DAYS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let max_concurrent_messages = settings
+            .get("MAX_CONCURRENT_MESSAGES")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(16);
+        let update_queue_size = settings
+            .get("UPDATE_QUEUE_SIZE")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(64);
+        let message_timeout_secs = settings
+            .get("MESSAGE_TIMEOUT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
+        let message_budget_secs = settings
+            .get("MESSAGE_BUDGET")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(15);
+        let account_deletion_grace_days = settings
+            .get("ACCOUNT_DELETION_GRACE_DAYS")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(7);
+        let repeat_window_secs = settings
+            .get("REPEAT_WINDOW")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(600);
+        let chat_reply_limit = settings
+            .get("CHAT_REPLY_LIMIT")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(20);
+        let digest_hour = settings
+            .get("DIGEST_HOUR")
+            .and_then(|v| v.parse().ok())
+            .filter(|hour| *hour < 24)
+            .unwrap_or(20);
+
+        let redis_url = settings.get("REDIS_URL").filter(|v| !v.is_empty());
+
+        let rules_refresh_interval_secs = settings
+            .get("RULES_REFRESH_INTERVAL")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(86400);
+        let schedules = crate::scheduler::JOBS
+            .iter()
+            .filter_map(|job| {
+                let expression = settings.get(&crate::scheduler::schedule_key(job))?;
+                (!expression.trim().is_empty()).then(|| (job.to_string(), expression))
+            })
+            .collect();
+        let shorteners = settings.get_list("SHORTENERS").unwrap_or_else(|| {
+            crate::sanitizer::DEFAULT_SHORTENERS
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        });
+        let show_redirects = settings
+            .get("SHOW_REDIRECTS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+        let instant_view_rhash = settings.get("INSTANT_VIEW_RHASH").filter(|v| !v.is_empty());
+        let fragment_exceptions = settings.get_list("FRAGMENT_EXCEPTIONS").unwrap_or_else(|| {
+            crate::sanitizer::DEFAULT_FRAGMENT_EXCEPTIONS
+                .iter()
+                .map(|s| s.to_string())
+                .collect()
+        });
+        let disabled_path_rules = settings.get_list("DISABLED_PATH_RULES").unwrap_or_default();
+
+        let proxy_url = settings.get("PROXY_URL").filter(|v| !v.is_empty());
+        let telegram_api_url = settings
+            .get("TELEGRAM_API_URL")
+            .filter(|v| !v.is_empty())
+            .map(|v| v.trim_end_matches('/').to_string())
+            .unwrap_or_else(|| "https://api.telegram.org".to_string());
+
+        let mastodon_instance = settings
+            .get("MASTODON_INSTANCE")
+            .filter(|v| !v.is_empty())
+            .map(|v| v.trim_end_matches('/').to_string());
+        let mastodon_token = settings.get("MASTODON_TOKEN").filter(|v| !v.is_empty());
+        let mastodon_poll_interval_secs = settings
+            .get("MASTODON_POLL_INTERVAL")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(30);
+        let mastodon_language = settings
+            .get("MASTODON_LANGUAGE")
+            .filter(|v| !v.is_empty())
+            .unwrap_or_else(|| "en".to_string());
 
-        let ai_api_key = env::var("AI_API_KEY").ok();
-        let ai_api_base =
-            env::var("AI_API_BASE").unwrap_or_else(|_| "https://api.openai.com/v1".to_string());
-        let ai_model = env::var("AI_MODEL").unwrap_or_else(|_| "gpt-3.5-turbo".to_string());
+        let heartbeat_url = settings.get("HEARTBEAT_URL").filter(|v| !v.is_empty());
+        let heartbeat_interval_secs = settings
+            .get("HEARTBEAT_INTERVAL")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(60);
 
-        Self {
+        let otlp_endpoint = settings
+            .get("OTEL_EXPORTER_OTLP_ENDPOINT")
+            .filter(|v| !v.is_empty());
+        let otlp_headers = settings
+            .get_list("OTEL_EXPORTER_OTLP_HEADERS")
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                Some((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+
+        Ok(Self {
+            profile,
+            update_mode,
+            webhook_url,
+            webhook_addr,
+            json_logs,
             bot_token,
+            extra_bot_tokens,
             bot_username,
             database_url,
+            database_url_ro,
             server_addr,
+            dashboard_url,
+            admin_ip_allowlist,
+            trusted_profile_keys,
+            trust_proxy_headers,
+            public_clean_rate_limit,
             admin_id,
             clearurls_source,
             ai_api_key,
             ai_api_base,
             ai_model,
-        }
+            ai_timeout_secs,
+            ai_max_retries,
+            ai_daily_quota,
+            ai_summaries,
+            ai_phishing_check,
+            ai_phishing_threshold,
+            max_custom_rules,
+            default_plan,
+            premium_ai_daily_quota,
+            premium_max_custom_rules,
+            history_retention_days,
+            max_concurrent_messages,
+            update_queue_size,
+            message_timeout_secs,
+            message_budget_secs,
+            account_deletion_grace_days,
+            repeat_window_secs,
+            chat_reply_limit,
+            digest_hour,
+            redis_url,
+            rules_refresh_interval_secs,
+            schedules,
+            shorteners,
+            show_redirects,
+            instant_view_rhash,
+            fragment_exceptions,
+            disabled_path_rules,
+            proxy_url,
+            telegram_api_url,
+            config_path,
+            otlp_endpoint,
+            otlp_headers,
+            mastodon_instance,
+            mastodon_token,
+            mastodon_poll_interval_secs,
+            mastodon_language,
+            heartbeat_url,
+            heartbeat_interval_secs,
+        })
+    }
+
+    /// Whether AI calls can be made: the `ai` feature is compiled in and an
+    /// API key is configured.
+    pub fn ai_available(&self) -> bool {
+        cfg!(feature = "ai") && self.ai_api_key.is_some()
     }
 
     pub fn validate(&self) {
-        if self.bot_token.is_empty() || !self.bot_token.contains(':') {
-            panic!("FATAL: TELOXIDE_TOKEN non è valido o è vuoto. Controlla il file .env");
+        if cfg!(feature = "bot") {
+            if self.bot_token.is_empty() || !self.bot_token.contains(':') {
+                panic!("FATAL: TELOXIDE_TOKEN non è valido o è vuoto. Controlla il file .env");
+            }
+            if self.extra_bot_tokens.iter().any(|t| !t.contains(':')) {
+                panic!("FATAL: EXTRA_BOT_TOKENS contiene un token non valido");
+            }
+            if self.bot_username.is_empty() {
+                panic!("FATAL: BOT_USERNAME deve essere configurato");
+            }
+            if self.update_mode == UpdateMode::Webhook {
+                if self.webhook_url.is_none() {
+                    panic!(
+                        "FATAL: WEBHOOK_URL must be set in webhook mode (the default in the {} profile); set UPDATE_MODE=polling otherwise",
+                        self.profile.as_str()
+                    );
+                }
+                if !self.extra_bot_tokens.is_empty() {
+                    panic!("FATAL: EXTRA_BOT_TOKENS needs UPDATE_MODE=polling");
+                }
+            }
         }
-        if self.bot_username.is_empty() {
-            panic!("FATAL: BOT_USERNAME deve essere configurato");
+
+        for (job, expression) in &self.schedules {
+            if let Err(e) = crate::scheduler::CronSchedule::parse(expression) {
+                panic!(
+                    "FATAL: {} is not a valid cron expression: {}",
+                    crate::scheduler::schedule_key(job),
+                    e
+                );
+            }
         }
 
         // Render Reserved Ports check
@@ -76,3 +633,84 @@ impl Config {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, body: &str) -> anyhow::Result<Config> {
+        load_file(&format!("{}.toml", name), body)
+    }
+
+    fn load_file(name: &str, body: &str) -> anyhow::Result<Config> {
+        let path = env::temp_dir().join(format!("config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, body).unwrap();
+        let config = Config::try_load(Some(path.clone()));
+        let _ = std::fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn profiles_pick_environment_defaults() {
+        let development = load("development", "").unwrap();
+        assert_eq!(development.profile, Profile::Development);
+        assert_eq!(development.database_url, "sqlite:bot.db");
+        assert_eq!(development.update_mode, UpdateMode::Polling);
+        assert!(!development.json_logs);
+
+        let production = load(
+            "production",
+            "app_env = \"production\"\n\
+             [profiles.production]\n\
+             database_url = \"postgres://db/clear_urls_bot\"\n",
+        )
+        .unwrap();
+        assert_eq!(production.profile, Profile::Production);
+        assert_eq!(production.database_url, "postgres://db/clear_urls_bot");
+        assert_eq!(production.update_mode, UpdateMode::Webhook);
+        assert!(production.json_logs);
+
+        let polling = load(
+            "polling",
+            "app_env = \"staging\"\ndatabase_url = \"postgres://db\"\nupdate_mode = \"polling\"\n",
+        )
+        .unwrap();
+        assert_eq!(polling.update_mode, UpdateMode::Polling);
+    }
+
+    #[test]
+    fn yaml_files_accept_lists_and_numbers() {
+        let config = load_file(
+            "lists.yaml",
+            "admin_id: 42\n\
+             shorteners:\n  - bit.ly\n  - ' t.co '\n\
+             disabled_path_rules: amazon, ebay\n",
+        )
+        .unwrap();
+        assert_eq!(config.admin_id, 42);
+        assert_eq!(config.shorteners, ["bit.ly", "t.co"]);
+        assert_eq!(config.disabled_path_rules, ["amazon", "ebay"]);
+
+        let error = load_file("settings.json", "{}").err().unwrap();
+        assert!(format!("{:#}", error)
+            .ends_with("unsupported config format (expected .toml, .yaml or .yml)"));
+    }
+
+    #[test]
+    fn deployed_profiles_need_a_database() {
+        let error = load("no-database", "app_env = \"staging\"\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "DATABASE_URL must be set in the staging profile"
+        );
+        let error = load(
+            "bad-mode",
+            "database_url = \"sqlite::memory:\"\nupdate_mode = \"push\"\n",
+        )
+        .err()
+        .unwrap();
+        assert!(error.to_string().starts_with("UPDATE_MODE must be"));
+    }
+}
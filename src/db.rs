@@ -1,14 +1,98 @@
-use crate::models::{ChatConfig, UserConfig};
+use crate::models::{
+    Actor, AuditEvent, ChatConfig, ChatSummary, CustomRule, Entitlement, FediverseAccount,
+    GlobalProvider, Plan, TranslationOverride, UserConfig, WeeklyStats,
+};
+use crate::shared_cache::SharedCache;
 use anyhow::Result;
-use sqlx::{any::AnyPoolOptions, Any, Pool};
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use moka::future::Cache;
+use sqlx::{any::AnyPoolOptions, Any, AnyConnection, Pool};
+use std::time::Duration;
+
+/// Reasons a custom rule can be rejected, meant to be shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CustomRuleError {
+    EmptyPattern,
+    Duplicate,
+    LimitReached(i64),
+}
+
+impl std::fmt::Display for CustomRuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EmptyPattern => write!(f, "The rule pattern cannot be empty"),
+            Self::Duplicate => write!(f, "This rule already exists"),
+            Self::LimitReached(max) => write!(f, "You can have at most {} custom rules", max),
+        }
+    }
+}
+
+impl std::error::Error for CustomRuleError {}
+
+/// How long a phishing assessment is reused before the URL is scored again.
+const URL_RISK_TTL_SECS: i64 = 7 * 86400;
+
+/// How long a cleared history stays restorable before it is purged.
+pub const HISTORY_UNDO_WINDOW_SECS: i64 = 300;
+
+/// How long configs and rules are served from memory before being re-read.
+/// Every save path invalidates its entry, so this only bounds staleness for
+/// writes made by other processes.
+const CONFIG_CACHE_TTL: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct Db {
     pub pool: Pool<Any>,
+    /// Pool used for heavy read-only queries (history, statistics). Points to
+    /// the read replica when one is configured, otherwise to `pool`.
+    pub read_pool: Pool<Any>,
+    user_cache: Cache<i64, UserConfig>,
+    chat_cache: Cache<i64, Option<ChatConfig>>,
+    rules_cache: Cache<i64, Vec<CustomRule>>,
+    debug_cache: Cache<i64, bool>,
+    shared: SharedCache,
+    /// File of a `Db::test` database, removed with the last clone.
+    #[cfg(test)]
+    test_file: Option<std::sync::Arc<TestFile>>,
+}
+
+#[cfg(test)]
+struct TestFile(std::path::PathBuf);
+
+#[cfg(test)]
+impl Drop for TestFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+impl Db {
+    /// A fresh SQLite database in the temp directory. `name` must be unique
+    /// among the tests, which run concurrently.
+    pub(crate) async fn test(name: &str) -> Self {
+        let path = Self::test_path(name);
+        let _ = std::fs::remove_file(&path);
+        let mut db = Self::new(&format!("sqlite:{}?mode=rwc", path.display()))
+            .await
+            .unwrap();
+        db.test_file = Some(std::sync::Arc::new(TestFile(path)));
+        db
+    }
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("clear-urls-bot-{}-{}.db", name, std::process::id()))
+    }
 }
 
 impl Db {
     pub async fn new(database_url: &str) -> Result<Self> {
+        Self::with_replica(database_url, None).await
+    }
+
+    /// Connects to the primary database and, if given, a read-only replica.
+    pub async fn with_replica(database_url: &str, read_only_url: Option<&str>) -> Result<Self> {
         sqlx::any::install_default_drivers();
 
         let pool = AnyPoolOptions::new()
@@ -16,11 +100,56 @@ impl Db {
             .connect(database_url)
             .await?;
 
-        let db = Self { pool };
+        let read_pool = match read_only_url {
+            Some(url) => {
+                tracing::info!("Routing read-heavy queries to the read replica");
+                AnyPoolOptions::new()
+                    .max_connections(5)
+                    .connect(url)
+                    .await?
+            }
+            None => pool.clone(),
+        };
+
+        let db = Self {
+            pool,
+            read_pool,
+            user_cache: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CONFIG_CACHE_TTL)
+                .build(),
+            chat_cache: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CONFIG_CACHE_TTL)
+                .build(),
+            rules_cache: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CONFIG_CACHE_TTL)
+                .build(),
+            debug_cache: Cache::builder()
+                .max_capacity(10_000)
+                .time_to_live(CONFIG_CACHE_TTL)
+                .build(),
+            shared: SharedCache::disabled(),
+            #[cfg(test)]
+            test_file: None,
+        };
         db.init().await?;
         Ok(db)
     }
 
+    /// Checks that the primary database answers.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Layers a cross-instance cache behind the in-memory config caches.
+    pub fn with_shared_cache(mut self, shared: SharedCache) -> Self {
+        self.shared = shared;
+        self
+    }
+
     async fn init(&self) -> Result<()> {
         let is_sqlite = self.pool.connect_options().database_url.scheme() == "sqlite";
 
@@ -149,6 +278,19 @@ impl Db {
             )"
         };
         sqlx::query(create_rules).execute(&self.pool).await?;
+        // Drop duplicates left over from before the unique index existed
+        sqlx::query(
+            "DELETE FROM custom_rules WHERE id NOT IN (
+                SELECT MIN(id) FROM custom_rules GROUP BY user_id, pattern
+             )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_custom_rules_user_pattern ON custom_rules (user_id, pattern)",
+        )
+        .execute(&self.pool)
+        .await?;
 
         let create_history = if is_sqlite {
             "CREATE TABLE IF NOT EXISTS cleaned_links (
@@ -189,204 +331,3104 @@ impl Db {
                 .execute(&self.pool)
                 .await?;
         }
-
-        Ok(())
-    }
-
-    pub async fn log_cleaned_link(
-        &self,
-        user_id: i64,
-        original: &str,
-        cleaned: &str,
-        provider: &str,
-    ) -> Result<()> {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs() as i64;
-
+        self.ensure_column(is_sqlite, "cleaned_links", "chat_id", "INTEGER", "BIGINT")
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_cleaned_links_chat ON cleaned_links (chat_id)")
+            .execute(&self.pool)
+            .await?;
+        self.ensure_column(is_sqlite, "cleaned_links", "domain", "TEXT", "TEXT")
+            .await?;
+        self.ensure_column(is_sqlite, "cleaned_links", "deleted_at", "INTEGER", "BIGINT")
+            .await?;
+        self.backfill_domains().await?;
+        self.ensure_column(is_sqlite, "cleaned_links", "bot_id", "INTEGER", "BIGINT")
+            .await?;
+        self.ensure_column(is_sqlite, "cleaned_links", "redirect_chain", "TEXT", "TEXT")
+            .await?;
+        self.ensure_column(
+            is_sqlite,
+            "cleaned_links",
+            "cleaning_source",
+            "TEXT",
+            "TEXT",
+        )
+        .await?;
         sqlx::query(
-            "INSERT INTO cleaned_links (user_id, original_url, cleaned_url, provider_name, timestamp) VALUES (?, ?, ?, ?, ?)"
+            "UPDATE cleaned_links SET cleaning_source = CASE
+                 WHEN provider_name = 'AI (Deep Scan)' THEN 'ai'
+                 WHEN provider_name LIKE 'AI (%' THEN 'rules+ai'
+                 ELSE 'rules' END
+             WHERE cleaning_source IS NULL",
         )
-        .bind(user_id)
-        .bind(original)
-        .bind(cleaned)
-        .bind(provider)
-        .bind(now)
         .execute(&self.pool)
         .await?;
-        Ok(())
-    }
-
-    pub async fn get_history(
-        &self,
-        user_id: i64,
-        limit: i64,
-    ) -> Result<Vec<crate::models::CleanedLink>> {
-        let history = sqlx::query_as::<_, crate::models::CleanedLink>(
-            "SELECT * FROM cleaned_links WHERE user_id = ? ORDER BY timestamp DESC LIMIT ?",
+        self.ensure_column(
+            is_sqlite,
+            "chat_configs",
+            "ai_enabled",
+            "INTEGER NOT NULL DEFAULT 0",
+            "INTEGER NOT NULL DEFAULT 0",
         )
-        .bind(user_id)
-        .bind(limit)
-        .fetch_all(&self.pool)
         .await?;
-        Ok(history)
-    }
-
-    pub async fn get_global_stats(&self) -> Result<(i64, i64)> {
-        let total_cleaned: (Option<i64>,) =
-            sqlx::query_as("SELECT SUM(cleaned_count) FROM user_configs")
-                .fetch_one(&self.pool)
-                .await?;
-        let total_users: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM user_configs")
-            .fetch_one(&self.pool)
+        // Owners recorded from `my_chat_member` (or checked against the chat's
+        // administrators) are verified; older rows guessed from the first
+        // member to post a link are not.
+        self.ensure_column(
+            is_sqlite,
+            "chat_configs",
+            "owner_verified",
+            "INTEGER NOT NULL DEFAULT 0",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "theme",
+            "TEXT NOT NULL DEFAULT 'auto'",
+            "TEXT NOT NULL DEFAULT 'auto'",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "cleaning_level",
+            "TEXT NOT NULL DEFAULT 'standard'",
+            "TEXT NOT NULL DEFAULT 'standard'",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "chat_configs",
+            "cleaning_level",
+            "TEXT NOT NULL DEFAULT 'default'",
+            "TEXT NOT NULL DEFAULT 'default'",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "public_stats",
+            "INTEGER NOT NULL DEFAULT 0",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.ensure_column(is_sqlite, "user_configs", "public_token", "TEXT", "TEXT")
             .await?;
-        Ok((total_cleaned.0.unwrap_or(0), total_users.0))
-    }
-
-    pub async fn get_user_config(&self, user_id: i64) -> Result<UserConfig> {
-        let config =
-            sqlx::query_as::<_, UserConfig>("SELECT * FROM user_configs WHERE user_id = ?")
-                .bind(user_id)
-                .fetch_optional(&self.pool)
-                .await?;
-
-        Ok(config.unwrap_or(UserConfig {
-            user_id,
-            enabled: 1,
-            ai_enabled: 0,
-            mode: "reply".to_string(),
-            ignored_domains: String::new(),
-            cleaned_count: 0,
-            language: "en".to_string(),
-        }))
-    }
-
-    pub async fn save_user_config(&self, config: &UserConfig) -> Result<()> {
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "log_history",
+            "INTEGER NOT NULL DEFAULT 1",
+            "INTEGER NOT NULL DEFAULT 1",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "digest_enabled",
+            "INTEGER NOT NULL DEFAULT 0",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        // Whether the user is already part of the global `users` counter.
+        // NULL until set from the counter below, or for rows created since,
+        // means not counted yet.
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "counted_in_stats",
+            "INTEGER",
+            "INTEGER",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "chat_configs",
+            "onboarded",
+            "INTEGER NOT NULL DEFAULT 0",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "chat_configs",
+            "kind",
+            "TEXT NOT NULL DEFAULT 'group'",
+            "TEXT NOT NULL DEFAULT 'group'",
+        )
+        .await?;
         sqlx::query(
-            "INSERT INTO user_configs (user_id, enabled, ai_enabled, mode, ignored_domains, cleaned_count, language) VALUES (?, ?, ?, ?, ?, ?, ?)
-             ON CONFLICT(user_id) DO UPDATE SET enabled = ?, ai_enabled = ?, mode = ?, ignored_domains = ?, cleaned_count = ?, language = ?"
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_user_configs_public_token ON user_configs (public_token)",
         )
-        .bind(config.user_id)
-        .bind(config.enabled)
-        .bind(config.ai_enabled)
-        .bind(&config.mode)
-        .bind(&config.ignored_domains)
-        .bind(config.cleaned_count)
-        .bind(&config.language)
-        .bind(config.enabled)
-        .bind(config.ai_enabled)
-        .bind(&config.mode)
-        .bind(&config.ignored_domains)
-        .bind(config.cleaned_count)
-        .bind(&config.language)
         .execute(&self.pool)
         .await?;
-        Ok(())
-    }
+        self.ensure_column(
+            is_sqlite,
+            "user_configs",
+            "hidden_widgets",
+            "TEXT NOT NULL DEFAULT ''",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
 
-    pub async fn increment_cleaned_count(&self, user_id: i64, amount: i64) -> Result<()> {
-        sqlx::query("UPDATE user_configs SET cleaned_count = cleaned_count + ? WHERE user_id = ?")
-            .bind(amount)
-            .bind(user_id)
+        let create_audit = if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                actor_id INTEGER NOT NULL,
+                transport TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target_id INTEGER NOT NULL,
+                details TEXT,
+                timestamp INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS audit_log (
+                id BIGSERIAL PRIMARY KEY,
+                actor_id BIGINT NOT NULL,
+                transport TEXT NOT NULL,
+                action TEXT NOT NULL,
+                target_id BIGINT NOT NULL,
+                details TEXT,
+                timestamp BIGINT NOT NULL
+            )"
+        };
+        sqlx::query(create_audit).execute(&self.pool).await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_audit_log_target ON audit_log (target_id)")
             .execute(&self.pool)
             .await?;
-        Ok(())
-    }
 
-    pub async fn get_custom_rules(&self, user_id: i64) -> Result<Vec<crate::models::CustomRule>> {
-        let rules = sqlx::query_as::<_, crate::models::CustomRule>(
-            "SELECT * FROM custom_rules WHERE user_id = ?",
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS usage_stats (
+                name TEXT NOT NULL,
+                day BIGINT NOT NULL,
+                count BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY (name, day)
+            )",
         )
-        .bind(user_id)
-        .fetch_all(&self.pool)
+        .execute(&self.pool)
         .await?;
-        Ok(rules)
-    }
 
-    pub async fn clear_history(&self, user_id: i64) -> Result<()> {
-        sqlx::query("DELETE FROM cleaned_links WHERE user_id = ?")
-            .bind(user_id)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
-    }
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS ai_usage (
+                user_id INTEGER NOT NULL,
+                day INTEGER NOT NULL,
+                requests INTEGER NOT NULL DEFAULT 0,
+                prompt_tokens INTEGER NOT NULL DEFAULT 0,
+                completion_tokens INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (user_id, day)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS ai_usage (
+                user_id BIGINT NOT NULL,
+                day BIGINT NOT NULL,
+                requests BIGINT NOT NULL DEFAULT 0,
+                prompt_tokens BIGINT NOT NULL DEFAULT 0,
+                completion_tokens BIGINT NOT NULL DEFAULT 0,
+                PRIMARY KEY (user_id, day)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
 
-    pub async fn add_custom_rule(&self, user_id: i64, pattern: &str) -> Result<()> {
-        sqlx::query("INSERT INTO custom_rules (user_id, pattern) VALUES (?, ?)")
-            .bind(user_id)
-            .bind(pattern)
-            .execute(&self.pool)
-            .await?;
-        Ok(())
-    }
+        self.ensure_column(
+            is_sqlite,
+            "ai_usage",
+            "successes",
+            "INTEGER NOT NULL DEFAULT 0",
+            "BIGINT NOT NULL DEFAULT 0",
+        )
+        .await?;
+        self.ensure_column(
+            is_sqlite,
+            "ai_usage",
+            "latency_ms",
+            "INTEGER NOT NULL DEFAULT 0",
+            "BIGINT NOT NULL DEFAULT 0",
+        )
+        .await?;
 
-    pub async fn get_stats_by_day(&self, user_id: i64) -> Result<Vec<(String, i64)>> {
-        let is_sqlite = self.pool.connect_options().database_url.scheme() == "sqlite";
-        let query = if is_sqlite {
-            "SELECT date(timestamp, 'unixepoch') as day, COUNT(*) 
-             FROM cleaned_links 
-             WHERE user_id = ? 
-             GROUP BY day ORDER BY day DESC LIMIT 7"
-        } else {
-            "SELECT to_char(to_timestamp(timestamp), 'YYYY-MM-DD') as day, COUNT(*) 
-             FROM cleaned_links 
-             WHERE user_id = ? 
-             GROUP BY day ORDER BY day DESC LIMIT 7"
-        };
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS url_risk (
+                url TEXT PRIMARY KEY,
+                score REAL NOT NULL,
+                reason TEXT NOT NULL,
+                checked_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS url_risk (
+                url TEXT PRIMARY KEY,
+                score DOUBLE PRECISION NOT NULL,
+                reason TEXT NOT NULL,
+                checked_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
 
-        let stats = sqlx::query_as::<_, (String, i64)>(query)
-            .bind(user_id)
-            .fetch_all(&self.pool)
-            .await?;
-        Ok(stats)
-    }
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS debug_targets (
+                target_id INTEGER PRIMARY KEY,
+                created_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS debug_targets (
+                target_id BIGINT PRIMARY KEY,
+                created_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
 
-    pub async fn get_chat_config(&self, chat_id: i64) -> Result<Option<ChatConfig>> {
-        let config =
-            sqlx::query_as::<_, ChatConfig>("SELECT * FROM chat_configs WHERE chat_id = ?")
-                .bind(chat_id)
-                .fetch_optional(&self.pool)
-                .await?;
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS fediverse_accounts (
+                acct TEXT PRIMARY KEY,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                ignored_domains TEXT NOT NULL DEFAULT '',
+                cleaned_count INTEGER NOT NULL DEFAULT 0
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS fediverse_accounts (
+                acct TEXT PRIMARY KEY,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                ignored_domains TEXT NOT NULL DEFAULT '',
+                cleaned_count BIGINT NOT NULL DEFAULT 0
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
 
-        Ok(config)
-    }
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS web_sessions (
+                token_hash TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS web_sessions (
+                token_hash TEXT PRIMARY KEY,
+                user_id BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_web_sessions_user ON web_sessions (user_id)")
+            .execute(&self.pool)
+            .await?;
 
-    pub async fn get_chat_config_or_default(&self, chat_id: i64) -> Result<ChatConfig> {
-        let config = self.get_chat_config(chat_id).await?;
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS admin_confirmations (
+                code_hash TEXT NOT NULL,
+                user_id INTEGER NOT NULL,
+                action TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS admin_confirmations (
+                code_hash TEXT NOT NULL,
+                user_id BIGINT NOT NULL,
+                action TEXT NOT NULL,
+                expires_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+        // The chat id or provider name the code was sent for.
+        self.ensure_column(
+            is_sqlite,
+            "admin_confirmations",
+            "target",
+            "TEXT NOT NULL DEFAULT ''",
+            "TEXT NOT NULL DEFAULT ''",
+        )
+        .await?;
 
-        Ok(config.unwrap_or(ChatConfig {
-            chat_id,
-            title: None,
-            enabled: 1,
-            added_by: 0,
-            mode: "default".to_string(),
-        }))
-    }
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS login_tokens (
+                token_hash TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS login_tokens (
+                token_hash TEXT PRIMARY KEY,
+                user_id BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
 
-    pub async fn save_chat_config(&self, config: &ChatConfig) -> Result<()> {
-        sqlx::query(
-            "INSERT INTO chat_configs (chat_id, title, enabled, added_by, mode) VALUES (?, ?, ?, ?, ?)
-             ON CONFLICT(chat_id) DO UPDATE SET title = ?, enabled = ?, mode = ?"
-        )
-        .bind(config.chat_id)
-        .bind(&config.title)
-        .bind(config.enabled)
-        .bind(config.added_by)
-        .bind(&config.mode)
-        .bind(&config.title)
-        .bind(config.enabled)
-        .bind(&config.mode)
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS chat_digests (
+                chat_id INTEGER PRIMARY KEY,
+                last_sent_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS chat_digests (
+                chat_id BIGINT PRIMARY KEY,
+                last_sent_at BIGINT NOT NULL
+            )"
+        })
         .execute(&self.pool)
         .await?;
-        Ok(())
-    }
 
-    pub async fn get_chats_for_user(&self, user_id: i64) -> Result<Vec<ChatConfig>> {
-        let chats =
-            sqlx::query_as::<_, ChatConfig>("SELECT * FROM chat_configs WHERE added_by = ?")
-                .bind(user_id)
-                .fetch_all(&self.pool)
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS user_digests (
+                user_id INTEGER PRIMARY KEY,
+                last_sent_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS user_digests (
+                user_id BIGINT PRIMARY KEY,
+                last_sent_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS chat_ignored_domains (
+                chat_id INTEGER NOT NULL,
+                domain TEXT NOT NULL,
+                added_by INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, domain)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS chat_ignored_domains (
+                chat_id BIGINT NOT NULL,
+                domain TEXT NOT NULL,
+                added_by BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                PRIMARY KEY (chat_id, domain)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS chat_user_whitelist (
+                chat_id INTEGER NOT NULL,
+                member TEXT NOT NULL,
+                added_by INTEGER NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, member)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS chat_user_whitelist (
+                chat_id BIGINT NOT NULL,
+                member TEXT NOT NULL,
+                added_by BIGINT NOT NULL,
+                created_at BIGINT NOT NULL,
+                PRIMARY KEY (chat_id, member)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS chat_topic_configs (
+                chat_id INTEGER NOT NULL,
+                thread_id INTEGER NOT NULL,
+                enabled INTEGER NOT NULL,
+                updated_by INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (chat_id, thread_id)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS chat_topic_configs (
+                chat_id BIGINT NOT NULL,
+                thread_id BIGINT NOT NULL,
+                enabled INTEGER NOT NULL,
+                updated_by BIGINT NOT NULL,
+                updated_at BIGINT NOT NULL,
+                PRIMARY KEY (chat_id, thread_id)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS translations_overrides (
+                lang TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                updated_at INTEGER NOT NULL,
+                PRIMARY KEY (lang, key)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS translations_overrides (
+                lang TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                updated_at BIGINT NOT NULL,
+                PRIMARY KEY (lang, key)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS account_deletions (
+                user_id INTEGER PRIMARY KEY,
+                requested_at INTEGER NOT NULL,
+                delete_after INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS account_deletions (
+                user_id BIGINT PRIMARY KEY,
+                requested_at BIGINT NOT NULL,
+                delete_after BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS entitlements (
+                user_id INTEGER PRIMARY KEY,
+                plan TEXT NOT NULL,
+                expires_at INTEGER,
+                updated_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS entitlements (
+                user_id BIGINT PRIMARY KEY,
+                plan TEXT NOT NULL,
+                expires_at BIGINT,
+                updated_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS global_providers (
+                name TEXT PRIMARY KEY,
+                definition TEXT NOT NULL,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                updated_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS global_providers (
+                name TEXT PRIMARY KEY,
+                definition TEXT NOT NULL,
+                enabled INTEGER NOT NULL DEFAULT 1,
+                updated_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS profile_imports (
+                user_id INTEGER NOT NULL,
+                source_key TEXT NOT NULL,
+                cleaned_count INTEGER NOT NULL,
+                imported_at INTEGER NOT NULL,
+                PRIMARY KEY (user_id, source_key)
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS profile_imports (
+                user_id BIGINT NOT NULL,
+                source_key TEXT NOT NULL,
+                cleaned_count BIGINT NOT NULL,
+                imported_at BIGINT NOT NULL,
+                PRIMARY KEY (user_id, source_key)
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        // One-off data migrations, each recorded by name once it has run.
+        sqlx::query(if is_sqlite {
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                name TEXT PRIMARY KEY,
+                applied_at INTEGER NOT NULL
+            )"
+        } else {
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                name TEXT PRIMARY KEY,
+                applied_at BIGINT NOT NULL
+            )"
+        })
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS global_stats (
+                name TEXT PRIMARY KEY,
+                value BIGINT NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        // Seeded once from the per-user counters; from then on they are only
+        // ever incremented. SQLite needs the `WHERE` to parse `ON CONFLICT`
+        // after a `SELECT`.
+        sqlx::query(
+            "INSERT INTO global_stats (name, value)
+             SELECT 'cleaned_links', COALESCE(SUM(cleaned_count), 0) FROM user_configs WHERE true
+             ON CONFLICT(name) DO NOTHING",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "INSERT INTO global_stats (name, value)
+             SELECT 'users', COUNT(*) FROM user_configs WHERE cleaned_count > 0
+             ON CONFLICT(name) DO NOTHING",
+        )
+        .execute(&self.pool)
+        .await?;
+        // Rows from before the `counted_in_stats` flag get it from their
+        // counter, like the seed above.
+        sqlx::query(
+            "UPDATE user_configs SET counted_in_stats = CASE WHEN cleaned_count > 0 THEN 1 ELSE 0 END
+             WHERE counted_in_stats IS NULL",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        self.backfill_cleaned_counts().await?;
+
+        Ok(())
+    }
+
+    /// Adds `column` to `table` unless it already exists, using the type
+    /// definition matching the active backend.
+    async fn ensure_column(
+        &self,
+        is_sqlite: bool,
+        table: &str,
+        column: &str,
+        sqlite_def: &str,
+        postgres_def: &str,
+    ) -> Result<()> {
+        if is_sqlite {
+            let table_info: Vec<(i64, String, String, i32, Option<String>, i32)> =
+                sqlx::query_as(&format!("PRAGMA table_info({})", table))
+                    .fetch_all(&self.pool)
+                    .await?;
+            if !table_info.iter().any(|(_, name, _, _, _, _)| name == column) {
+                sqlx::query(&format!(
+                    "ALTER TABLE {} ADD COLUMN {} {}",
+                    table, column, sqlite_def
+                ))
+                .execute(&self.pool)
+                .await?;
+            }
+        } else {
+            sqlx::query(&format!(
+                "ALTER TABLE {} ADD COLUMN IF NOT EXISTS {} {}",
+                table, column, postgres_def
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Fills the `domain` column for rows logged before it existed.
+    async fn backfill_domains(&self) -> Result<()> {
+        let rows: Vec<(i64, String)> =
+            sqlx::query_as("SELECT id, cleaned_url FROM cleaned_links WHERE domain IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+        for (id, cleaned_url) in rows {
+            sqlx::query("UPDATE cleaned_links SET domain = ? WHERE id = ?")
+                .bind(extract_domain(&cleaned_url))
+                .bind(id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Restores counters lost while `increment_cleaned_count` was a bare UPDATE:
+    /// users with history but no config row get one, and counters lagging
+    /// behind their credited history (`dry_run` detections excluded) are
+    /// raised to match it, along with the global stats. Runs once per
+    /// database.
+    async fn backfill_cleaned_counts(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let claimed = sqlx::query(
+            "INSERT INTO schema_migrations (name, applied_at) VALUES ('backfill_cleaned_counts', ?)
+             ON CONFLICT(name) DO NOTHING",
+        )
+        .bind(unix_now()?)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if claimed == 0 {
+            return Ok(());
+        }
+        let lagging: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT l.user_id, l.credited - COALESCE(u.cleaned_count, 0) FROM (
+                 SELECT user_id, COUNT(*) AS credited FROM cleaned_links
+                 WHERE user_id <> 0 AND COALESCE(cleaning_source, '') <> 'dry_run'
+                 GROUP BY user_id
+             ) l LEFT JOIN user_configs u ON u.user_id = l.user_id
+             WHERE l.credited > COALESCE(u.cleaned_count, 0)",
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for (user_id, missing) in lagging {
+            Self::add_cleaned_count(&mut tx, user_id, missing).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn log_cleaned_link(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        bot_id: Option<i64>,
+        original: &str,
+        cleaned: &str,
+        provider: &str,
+        source: &str,
+    ) -> Result<()> {
+        self.log_redirected_link(
+            user_id,
+            chat_id,
+            bot_id,
+            original,
+            cleaned,
+            provider,
+            source,
+            &[],
+        )
+        .await
+    }
+
+    /// Like [`log_cleaned_link`](Self::log_cleaned_link), also storing the
+    /// redirects `original` went through when it was expanded (the chain
+    /// starts with `original`). Chains without a hop are not stored.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn log_redirected_link(
+        &self,
+        user_id: i64,
+        chat_id: Option<i64>,
+        bot_id: Option<i64>,
+        original: &str,
+        cleaned: &str,
+        provider: &str,
+        source: &str,
+        redirect_chain: &[String],
+    ) -> Result<()> {
+        let now = unix_now()?;
+        let redirect_chain = if redirect_chain.len() > 1 {
+            Some(serde_json::to_string(redirect_chain)?)
+        } else {
+            None
+        };
+
+        sqlx::query(
+            "INSERT INTO cleaned_links (user_id, chat_id, bot_id, original_url, cleaned_url, provider_name, domain, timestamp, cleaning_source, redirect_chain) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(user_id)
+        .bind(chat_id)
+        .bind(bot_id)
+        .bind(original)
+        .bind(cleaned)
+        .bind(provider)
+        .bind(extract_domain(cleaned))
+        .bind(now)
+        .bind(source)
+        .bind(redirect_chain)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Adds the cleaned counter of a profile exported by the instance with
+    /// `source_key` to the user's, like
+    /// [`increment_cleaned_count`](Self::increment_cleaned_count). Only the
+    /// part above what earlier bundles from that instance brought is added,
+    /// so importing a bundle twice adds nothing. Returns the amount added.
+    pub async fn import_cleaned_count(
+        &self,
+        user_id: i64,
+        source_key: &str,
+        cleaned_count: i64,
+    ) -> Result<i64> {
+        let mut tx = self.pool.begin().await?;
+        let previous: Option<(i64,)> = sqlx::query_as(
+            "SELECT cleaned_count FROM profile_imports WHERE user_id = ? AND source_key = ?",
+        )
+        .bind(user_id)
+        .bind(source_key)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let previous = previous.map(|(count,)| count);
+        let added = cleaned_count - previous.unwrap_or(0);
+        if added <= 0 {
+            return Ok(0);
+        }
+        // Compare-and-set on the recorded count, so concurrent imports of
+        // the same bundle cannot both add it.
+        let recorded = match previous {
+            None => sqlx::query(
+                "INSERT INTO profile_imports (user_id, source_key, cleaned_count, imported_at) VALUES (?, ?, ?, ?)
+                 ON CONFLICT(user_id, source_key) DO NOTHING",
+            )
+            .bind(user_id)
+            .bind(source_key)
+            .bind(cleaned_count)
+            .bind(unix_now()?),
+            Some(previous) => sqlx::query(
+                "UPDATE profile_imports SET cleaned_count = ?, imported_at = ?
+                 WHERE user_id = ? AND source_key = ? AND cleaned_count = ?",
+            )
+            .bind(cleaned_count)
+            .bind(unix_now()?)
+            .bind(user_id)
+            .bind(source_key)
+            .bind(previous),
+        }
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if recorded == 0 {
+            return Ok(0);
+        }
+        Self::add_cleaned_count(&mut tx, user_id, added).await?;
+        tx.commit().await?;
+        self.user_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("user_config:{}", user_id))
+            .await;
+        Ok(added)
+    }
+
+    /// Adds cleanings exported by another instance to the user's history,
+    /// keeping their timestamps. Links already present (same time and
+    /// original URL) are skipped, so importing twice adds nothing. Returns
+    /// the number of links added.
+    pub async fn import_cleaned_links(
+        &self,
+        user_id: i64,
+        links: &[crate::models::CleanedLink],
+    ) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut added = 0;
+        for link in links.iter().filter(|link| link.deleted_at.is_none()) {
+            added += sqlx::query(
+                "INSERT INTO cleaned_links (user_id, chat_id, original_url, cleaned_url, provider_name, domain, timestamp, cleaning_source, redirect_chain)
+                 SELECT ?, ?, ?, ?, ?, ?, ?, ?, ?
+                 WHERE NOT EXISTS (
+                     SELECT 1 FROM cleaned_links WHERE user_id = ? AND timestamp = ? AND original_url = ?
+                 )",
+            )
+            .bind(user_id)
+            .bind(link.chat_id)
+            .bind(&link.original_url)
+            .bind(&link.cleaned_url)
+            .bind(&link.provider_name)
+            .bind(extract_domain(&link.cleaned_url))
+            .bind(link.timestamp)
+            .bind(&link.cleaning_source)
+            .bind(&link.redirect_chain)
+            .bind(user_id)
+            .bind(link.timestamp)
+            .bind(&link.original_url)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    pub async fn get_history(
+        &self,
+        user_id: i64,
+        limit: i64,
+    ) -> Result<Vec<crate::models::CleanedLink>> {
+        let history = sqlx::query_as::<_, crate::models::CleanedLink>(
+            "SELECT * FROM cleaned_links WHERE user_id = ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' ORDER BY timestamp DESC LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(history)
+    }
+
+    pub async fn get_chat_history(
+        &self,
+        chat_id: i64,
+        limit: i64,
+    ) -> Result<Vec<crate::models::CleanedLink>> {
+        let history = sqlx::query_as::<_, crate::models::CleanedLink>(
+            "SELECT * FROM cleaned_links WHERE chat_id = ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' ORDER BY timestamp DESC LIMIT ?",
+        )
+        .bind(chat_id)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(history)
+    }
+
+    /// Links found in a chat while it was in `dry_run` mode, newest first.
+    /// They are kept apart from the chat's history and counted nowhere.
+    pub async fn get_chat_detections(
+        &self,
+        chat_id: i64,
+        limit: i64,
+    ) -> Result<Vec<crate::models::CleanedLink>> {
+        let detections = sqlx::query_as::<_, crate::models::CleanedLink>(
+            "SELECT * FROM cleaned_links WHERE chat_id = ? AND deleted_at IS NULL AND cleaning_source = 'dry_run' ORDER BY timestamp DESC LIMIT ?",
+        )
+        .bind(chat_id)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(detections)
+    }
+
+    /// Returns `(links cleaned, distinct senders)` for a chat.
+    pub async fn get_chat_stats(&self, chat_id: i64) -> Result<(i64, i64)> {
+        let stats: (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COUNT(DISTINCT user_id) FROM cleaned_links WHERE chat_id = ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'",
+        )
+        .bind(chat_id)
+        .fetch_one(&self.read_pool)
+        .await?;
+        Ok(stats)
+    }
+
+    /// Cleanings per day in the chat between the UTC days `from_day` and
+    /// `to_day` (inclusive, days since the epoch), zero-filled.
+    pub async fn get_chat_stats_by_day(
+        &self,
+        chat_id: i64,
+        from_day: i64,
+        to_day: i64,
+    ) -> Result<Vec<(String, i64)>> {
+        self.stats_by_day("chat_id", chat_id, from_day, to_day)
+            .await
+    }
+
+    /// Counts cleanings per UTC day where `column` equals `id`, with a zero
+    /// entry for every day of the range that had none.
+    async fn stats_by_day(
+        &self,
+        column: &str,
+        id: i64,
+        from_day: i64,
+        to_day: i64,
+    ) -> Result<Vec<(String, i64)>> {
+        let counts: Vec<(i64, i64)> = sqlx::query_as(&format!(
+            "SELECT timestamp / 86400 AS day, COUNT(*) FROM cleaned_links
+             WHERE {} = ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?
+             GROUP BY timestamp / 86400",
+            column
+        ))
+        .bind(id)
+        .bind(from_day * 86400)
+        .bind((to_day + 1) * 86400)
+        .fetch_all(&self.read_pool)
+        .await?;
+        let counts: std::collections::HashMap<i64, i64> = counts.into_iter().collect();
+        Ok((from_day..=to_day)
+            .map(|day| (format_day(day), counts.get(&day).copied().unwrap_or(0)))
+            .collect())
+    }
+
+    /// Cleanings between two days (inclusive) bucketed by UTC weekday
+    /// (Monday first) and hour, for one user or everyone when `user_id` is
+    /// `None`.
+    pub async fn get_activity_heatmap(
+        &self,
+        user_id: Option<i64>,
+        from_day: i64,
+        to_day: i64,
+    ) -> Result<[[i64; 24]; 7]> {
+        // Hours since the epoch modulo a week; 1970-01-01 was a Thursday.
+        let sql = format!(
+            "SELECT (timestamp / 3600) % 168 AS bucket, COUNT(*) FROM cleaned_links
+             WHERE deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?{}
+             GROUP BY (timestamp / 3600) % 168",
+            if user_id.is_some() {
+                " AND user_id = ?"
+            } else {
+                ""
+            }
+        );
+        let mut query = sqlx::query_as::<_, (i64, i64)>(&sql)
+            .bind(from_day * 86400)
+            .bind((to_day + 1) * 86400);
+        if let Some(user_id) = user_id {
+            query = query.bind(user_id);
+        }
+        let mut grid = [[0; 24]; 7];
+        for (bucket, count) in query.fetch_all(&self.read_pool).await? {
+            let weekday = (bucket / 24 + 3) % 7;
+            grid[weekday as usize][(bucket % 24) as usize] += count;
+        }
+        Ok(grid)
+    }
+
+    /// Providers ranked by cleanings across all users between two days
+    /// (inclusive), as `(provider, cleanings, distinct users)`.
+    pub async fn get_provider_leaderboard(
+        &self,
+        from_day: i64,
+        to_day: i64,
+        limit: i64,
+    ) -> Result<Vec<(String, i64, i64)>> {
+        let providers = sqlx::query_as::<_, (String, i64, i64)>(
+            "SELECT provider_name, COUNT(*) AS total, COUNT(DISTINCT user_id) FROM cleaned_links
+             WHERE provider_name IS NOT NULL AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?
+             GROUP BY provider_name ORDER BY total DESC, provider_name LIMIT ?",
+        )
+        .bind(from_day * 86400)
+        .bind((to_day + 1) * 86400)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(providers)
+    }
+
+    /// Global activity per UTC day between two days (inclusive), zero-filled:
+    /// `(day, cleanings, active users, new users, users so far)`. A user is
+    /// new on the day of their first cleaning. Streamed row by row.
+    pub fn stream_global_daily_stats(
+        &self,
+        from_day: i64,
+        to_day: i64,
+    ) -> BoxStream<'static, Result<DailyStats>> {
+        let pool = self.read_pool.clone();
+        Box::pin(async_stream::try_stream! {
+            let (mut users_so_far,): (i64,) = sqlx::query_as(
+                "SELECT COUNT(*) FROM (
+                     SELECT MIN(timestamp) / 86400 AS first_day FROM cleaned_links
+                     WHERE COALESCE(cleaning_source, '') <> 'dry_run' GROUP BY user_id
+                 ) f WHERE first_day < ?",
+            )
+            .bind(from_day)
+            .fetch_one(&pool)
+            .await?;
+            // Activity and first cleanings per day, merged into one ordered
+            // row per day that had either.
+            let mut rows = sqlx::query_as::<_, (i64, i64, i64, i64)>(
+                "SELECT day, CAST(SUM(cleanings) AS BIGINT), CAST(SUM(active) AS BIGINT),
+                        CAST(SUM(new_users) AS BIGINT)
+                 FROM (
+                     SELECT timestamp / 86400 AS day, COUNT(*) AS cleanings,
+                            COUNT(DISTINCT user_id) AS active, 0 AS new_users
+                     FROM cleaned_links
+                     WHERE deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'
+                         AND timestamp >= ? AND timestamp < ?
+                     GROUP BY timestamp / 86400
+                     UNION ALL
+                     SELECT first_day, 0, 0, COUNT(*) FROM (
+                         SELECT MIN(timestamp) / 86400 AS first_day FROM cleaned_links
+                         WHERE COALESCE(cleaning_source, '') <> 'dry_run' GROUP BY user_id
+                     ) f WHERE first_day >= ? AND first_day <= ? GROUP BY first_day
+                 ) d GROUP BY day ORDER BY day",
+            )
+            .bind(from_day * 86400)
+            .bind((to_day + 1) * 86400)
+            .bind(from_day)
+            .bind(to_day)
+            .fetch(&pool);
+            let mut next_day = from_day;
+            while let Some((day, cleanings, active, new)) = rows.try_next().await? {
+                for quiet in next_day..day {
+                    yield (format_day(quiet), 0, 0, 0, users_so_far);
+                }
+                users_so_far += new;
+                yield (format_day(day), cleanings, active, new, users_so_far);
+                next_day = day + 1;
+            }
+            for quiet in next_day..=to_day {
+                yield (format_day(quiet), 0, 0, 0, users_so_far);
+            }
+        })
+    }
+
+    /// Cleanings per UTC day and provider between two days (inclusive),
+    /// streamed row by row as `(day, provider, cleanings)`.
+    pub fn stream_provider_breakdown(
+        &self,
+        from_day: i64,
+        to_day: i64,
+    ) -> BoxStream<'static, Result<(String, String, i64)>> {
+        let pool = self.read_pool.clone();
+        Box::pin(async_stream::try_stream! {
+            let mut rows = sqlx::query_as::<_, (i64, String, i64)>(
+                "SELECT timestamp / 86400 AS day, provider_name, COUNT(*) AS total FROM cleaned_links
+                 WHERE provider_name IS NOT NULL AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?
+                 GROUP BY timestamp / 86400, provider_name ORDER BY day, total DESC, provider_name",
+            )
+            .bind(from_day * 86400)
+            .bind((to_day + 1) * 86400)
+            .fetch(&pool);
+            while let Some((day, provider, total)) = rows.try_next().await? {
+                yield (format_day(day), provider, total);
+            }
+        })
+    }
+
+    /// Cleanings between two UTC days (inclusive), oldest first, for one
+    /// user or everyone. Streamed row by row; cleared history is left out.
+    pub fn stream_cleaned_links(
+        &self,
+        user_id: Option<i64>,
+        from_day: i64,
+        to_day: i64,
+    ) -> BoxStream<'static, Result<crate::models::CleanedLink>> {
+        let pool = self.read_pool.clone();
+        Box::pin(async_stream::try_stream! {
+            let query = match user_id {
+                Some(user_id) => sqlx::query_as::<_, crate::models::CleanedLink>(
+                    "SELECT * FROM cleaned_links
+                     WHERE user_id = ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?
+                     ORDER BY timestamp, id",
+                )
+                .bind(user_id),
+                None => sqlx::query_as::<_, crate::models::CleanedLink>(
+                    "SELECT * FROM cleaned_links
+                     WHERE deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND timestamp >= ? AND timestamp < ?
+                     ORDER BY timestamp, id",
+                ),
+            };
+            let mut rows = query
+                .bind(from_day * 86400)
+                .bind((to_day + 1) * 86400)
+                .fetch(&pool);
+            while let Some(link) = rows.try_next().await? {
+                yield link;
+            }
+        })
+    }
+
+    pub async fn count_distinct_domains(&self, user_id: i64) -> Result<i64> {
+        let count: (i64,) = sqlx::query_as(
+            "SELECT COUNT(DISTINCT domain) FROM cleaned_links WHERE user_id = ? AND domain IS NOT NULL AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'",
+        )
+        .bind(user_id)
+        .fetch_one(&self.read_pool)
+        .await?;
+        Ok(count.0)
+    }
+
+    pub async fn get_top_domains(&self, user_id: i64, limit: i64) -> Result<Vec<(String, i64)>> {
+        let domains = sqlx::query_as::<_, (String, i64)>(
+            "SELECT domain, COUNT(*) as total FROM cleaned_links
+             WHERE user_id = ? AND domain IS NOT NULL AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'
+             GROUP BY domain ORDER BY total DESC LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(domains)
+    }
+
+    /// Switches the user's public stats page on or off and returns its
+    /// token, created the first time the page is switched on.
+    pub async fn set_public_stats(
+        &self,
+        user_id: i64,
+        enabled: bool,
+        actor: Actor,
+    ) -> Result<Option<String>> {
+        let token = enabled.then(crate::links::public_token);
+        sqlx::query(
+            "INSERT INTO user_configs (user_id, public_stats, public_token) VALUES (?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET public_stats = excluded.public_stats,
+                 public_token = COALESCE(user_configs.public_token, excluded.public_token)",
+        )
+        .bind(user_id)
+        .bind(enabled as i32)
+        .bind(&token)
+        .execute(&self.pool)
+        .await?;
+        self.user_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("user_config:{}", user_id))
+            .await;
+        self.record_audit(
+            actor,
+            "user_config.public_stats",
+            user_id,
+            Some(enabled.to_string()),
+        )
+        .await;
+        let token: (Option<String>,) =
+            sqlx::query_as("SELECT public_token FROM user_configs WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(token.0)
+    }
+
+    /// The user behind a public `token` and their cleaned count, or `None`
+    /// unless the user opted in.
+    pub async fn get_public_user(&self, token: &str) -> Result<Option<(i64, i64)>> {
+        let user = sqlx::query_as(
+            "SELECT user_id, cleaned_count FROM user_configs WHERE public_token = ? AND public_stats = 1",
+        )
+        .bind(token)
+        .fetch_optional(&self.read_pool)
+        .await?;
+        Ok(user)
+    }
+
+    /// What the public page behind `token` shows: the user's cleaned count
+    /// and their `limit` most credited providers. `None` unless the user
+    /// opted in.
+    pub async fn get_public_stats(
+        &self,
+        token: &str,
+        limit: i64,
+    ) -> Result<Option<(i64, Vec<(String, i64)>)>> {
+        let Some((user_id, cleaned_count)) = self.get_public_user(token).await? else {
+            return Ok(None);
+        };
+        let providers = sqlx::query_as::<_, (String, i64)>(
+            "SELECT provider_name, COUNT(*) as total FROM cleaned_links
+             WHERE user_id = ? AND provider_name IS NOT NULL AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'
+             GROUP BY provider_name ORDER BY total DESC, provider_name LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(Some((cleaned_count, providers)))
+    }
+
+    /// Links cleaned and users who cleaned at least one, over the lifetime
+    /// of the deployment: erased accounts and purged history still count,
+    /// and an erased user who comes back is counted again as a new user.
+    pub async fn get_global_stats(&self) -> Result<(i64, i64)> {
+        let counters: Vec<(String, i64)> = sqlx::query_as(
+            "SELECT name, value FROM global_stats WHERE name IN ('cleaned_links', 'users')",
+        )
+        .fetch_all(&self.read_pool)
+        .await?;
+        let get = |name: &str| {
+            counters
+                .iter()
+                .find(|(n, _)| n == name)
+                .map_or(0, |(_, value)| *value)
+        };
+        Ok((get("cleaned_links"), get("users")))
+    }
+
+    pub async fn get_user_config(&self, user_id: i64) -> Result<UserConfig> {
+        if let Some(cached) = self.user_cache.get(&user_id).await {
+            return Ok(cached);
+        }
+        let shared_key = format!("user_config:{}", user_id);
+        if let Some(shared) = self.shared.get::<UserConfig>(&shared_key).await {
+            self.user_cache.insert(user_id, shared.clone()).await;
+            return Ok(shared);
+        }
+
+        let config =
+            sqlx::query_as::<_, UserConfig>("SELECT * FROM user_configs WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        let config = config.unwrap_or(UserConfig {
+            user_id,
+            ..Default::default()
+        });
+        self.user_cache.insert(user_id, config.clone()).await;
+        self.shared
+            .set(&shared_key, &config, CONFIG_CACHE_TTL)
+            .await;
+        Ok(config)
+    }
+
+    /// Saves the user's settings. `cleaned_count` is not written: `config`
+    /// is often a cached copy, and the counter belongs to
+    /// [`increment_cleaned_count`](Self::increment_cleaned_count).
+    pub async fn save_user_config(&self, config: &UserConfig, actor: Actor) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO user_configs (user_id, enabled, ai_enabled, mode, ignored_domains, language, theme, hidden_widgets, cleaning_level, log_history, digest_enabled) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET enabled = ?, ai_enabled = ?, mode = ?, ignored_domains = ?, language = ?, theme = ?, hidden_widgets = ?, cleaning_level = ?, log_history = ?, digest_enabled = ?"
+        )
+        .bind(config.user_id)
+        .bind(config.enabled)
+        .bind(config.ai_enabled)
+        .bind(&config.mode)
+        .bind(&config.ignored_domains)
+        .bind(&config.language)
+        .bind(&config.theme)
+        .bind(&config.hidden_widgets)
+        .bind(&config.cleaning_level)
+        .bind(config.log_history)
+        .bind(config.digest_enabled)
+        .bind(config.enabled)
+        .bind(config.ai_enabled)
+        .bind(&config.mode)
+        .bind(&config.ignored_domains)
+        .bind(&config.language)
+        .bind(&config.theme)
+        .bind(&config.hidden_widgets)
+        .bind(&config.cleaning_level)
+        .bind(config.log_history)
+        .bind(config.digest_enabled)
+        .execute(&self.pool)
+        .await?;
+        self.user_cache.invalidate(&config.user_id).await;
+        self.shared
+            .invalidate(&format!("user_config:{}", config.user_id))
+            .await;
+        self.record_audit(
+            actor,
+            "user_config.save",
+            config.user_id,
+            serde_json::to_string(config).ok(),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Creates the settings of a user seen for the first time, in `language`.
+    /// Returns false, changing nothing, when the user already has settings.
+    pub async fn init_user_language(&self, user_id: i64, language: &str) -> Result<bool> {
+        let inserted = sqlx::query(
+            "INSERT INTO user_configs (user_id, language) VALUES (?, ?)
+             ON CONFLICT(user_id) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(language)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if inserted {
+            self.user_cache.invalidate(&user_id).await;
+            self.shared
+                .invalidate(&format!("user_config:{}", user_id))
+                .await;
+        }
+        Ok(inserted)
+    }
+
+    /// Adds `amount` to the user's cleaned counter, creating a default row for
+    /// users that have never saved their settings, and to the global
+    /// counters in the same transaction.
+    pub async fn increment_cleaned_count(&self, user_id: i64, amount: i64) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        Self::add_cleaned_count(&mut tx, user_id, amount).await?;
+        tx.commit().await?;
+        self.user_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("user_config:{}", user_id))
+            .await;
+        Ok(())
+    }
+
+    /// The body of [`increment_cleaned_count`](Self::increment_cleaned_count),
+    /// inside the caller's transaction. A user joins the `users` counter the
+    /// first time a positive amount is added, whatever created their row.
+    async fn add_cleaned_count(conn: &mut AnyConnection, user_id: i64, amount: i64) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO user_configs (user_id, cleaned_count) VALUES (?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET cleaned_count = user_configs.cleaned_count + excluded.cleaned_count",
+        )
+        .bind(user_id)
+        .bind(amount)
+        .execute(&mut *conn)
+        .await?;
+        let first_cleaning = amount > 0
+            && sqlx::query(
+                "UPDATE user_configs SET counted_in_stats = 1
+                 WHERE user_id = ? AND COALESCE(counted_in_stats, 0) = 0",
+            )
+            .bind(user_id)
+            .execute(&mut *conn)
+            .await?
+            .rows_affected()
+                > 0;
+        sqlx::query(
+            "UPDATE global_stats SET value = value + CASE name WHEN 'users' THEN ? ELSE ? END
+             WHERE name IN ('cleaned_links', 'users')",
+        )
+        .bind(i64::from(first_cleaning))
+        .bind(amount)
+        .execute(&mut *conn)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_custom_rules(&self, user_id: i64) -> Result<Vec<CustomRule>> {
+        if let Some(cached) = self.rules_cache.get(&user_id).await {
+            return Ok(cached);
+        }
+        let shared_key = format!("custom_rules:{}", user_id);
+        if let Some(shared) = self.shared.get::<Vec<CustomRule>>(&shared_key).await {
+            self.rules_cache.insert(user_id, shared.clone()).await;
+            return Ok(shared);
+        }
+
+        let rules = sqlx::query_as::<_, CustomRule>("SELECT * FROM custom_rules WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+        self.rules_cache.insert(user_id, rules.clone()).await;
+        self.shared.set(&shared_key, &rules, CONFIG_CACHE_TTL).await;
+        Ok(rules)
+    }
+
+    /// Soft-deletes the user's history. Rows stay restorable through
+    /// `undo_clear_history` for `HISTORY_UNDO_WINDOW_SECS` before the
+    /// retention task purges them.
+    pub async fn clear_history(&self, user_id: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE cleaned_links SET deleted_at = ? WHERE user_id = ? AND deleted_at IS NULL",
+        )
+        .bind(unix_now()?)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Restores history soft-deleted within the undo window.
+    pub async fn undo_clear_history(&self, user_id: i64) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE cleaned_links SET deleted_at = NULL WHERE user_id = ? AND deleted_at >= ?",
+        )
+        .bind(user_id)
+        .bind(unix_now()? - HISTORY_UNDO_WINDOW_SECS)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Permanently removes history rows whose undo window has expired.
+    pub async fn purge_deleted_history(&self) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM cleaned_links WHERE deleted_at < ?")
+            .bind(unix_now()? - HISTORY_UNDO_WINDOW_SECS)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Adds a custom rule, rejecting blank patterns, duplicates and rules beyond
+    /// `max_rules`. Rejections are returned as a [`CustomRuleError`].
+    pub async fn add_custom_rule(
+        &self,
+        user_id: i64,
+        pattern: &str,
+        max_rules: i64,
+        actor: Actor,
+    ) -> Result<()> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(CustomRuleError::EmptyPattern.into());
+        }
+
+        // A duplicate is reported as such even when the user is at the limit,
+        // and the count and insert share a transaction so concurrent adds
+        // cannot both squeeze under it.
+        let mut tx = self.pool.begin().await?;
+        let existing: Option<(i64,)> =
+            sqlx::query_as("SELECT id FROM custom_rules WHERE user_id = ? AND pattern = ?")
+                .bind(user_id)
+                .bind(pattern)
+                .fetch_optional(&mut *tx)
+                .await?;
+        if existing.is_some() {
+            return Err(CustomRuleError::Duplicate.into());
+        }
+        let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM custom_rules WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if count.0 >= max_rules {
+            return Err(CustomRuleError::LimitReached(max_rules).into());
+        }
+
+        let result = sqlx::query(
+            "INSERT INTO custom_rules (user_id, pattern) VALUES (?, ?) ON CONFLICT(user_id, pattern) DO NOTHING",
+        )
+        .bind(user_id)
+        .bind(pattern)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(CustomRuleError::Duplicate.into());
+        }
+        tx.commit().await?;
+        self.rules_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("custom_rules:{}", user_id))
+            .await;
+        self.record_audit(
+            actor,
+            "custom_rule.add",
+            user_id,
+            Some(serde_json::json!({ "pattern": pattern }).to_string()),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Deletes one of the user's custom rules. Returns false when the user
+    /// has no rule with that id.
+    pub async fn delete_custom_rule(
+        &self,
+        user_id: i64,
+        rule_id: i64,
+        actor: Actor,
+    ) -> Result<bool> {
+        let pattern: Option<(String,)> = sqlx::query_as(
+            "DELETE FROM custom_rules WHERE id = ? AND user_id = ? RETURNING pattern",
+        )
+        .bind(rule_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((pattern,)) = pattern else {
+            return Ok(false);
+        };
+        self.rules_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("custom_rules:{}", user_id))
+            .await;
+        self.record_audit(
+            actor,
+            "custom_rule.remove",
+            user_id,
+            Some(serde_json::json!({ "id": rule_id, "pattern": pattern }).to_string()),
+        )
+        .await;
+        Ok(true)
+    }
+
+    /// Cleanings per day for the user between the UTC days `from_day` and
+    /// `to_day` (inclusive, days since the epoch), zero-filled.
+    pub async fn get_stats_by_day(
+        &self,
+        user_id: i64,
+        from_day: i64,
+        to_day: i64,
+    ) -> Result<Vec<(String, i64)>> {
+        self.stats_by_day("user_id", user_id, from_day, to_day)
+            .await
+    }
+
+    pub async fn get_chat_config(&self, chat_id: i64) -> Result<Option<ChatConfig>> {
+        if let Some(cached) = self.chat_cache.get(&chat_id).await {
+            return Ok(cached);
+        }
+        let shared_key = format!("chat_config:{}", chat_id);
+        if let Some(shared) = self.shared.get::<Option<ChatConfig>>(&shared_key).await {
+            self.chat_cache.insert(chat_id, shared.clone()).await;
+            return Ok(shared);
+        }
+
+        let config =
+            sqlx::query_as::<_, ChatConfig>("SELECT * FROM chat_configs WHERE chat_id = ?")
+                .bind(chat_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        self.chat_cache.insert(chat_id, config.clone()).await;
+        self.shared
+            .set(&shared_key, &config, CONFIG_CACHE_TTL)
+            .await;
+        Ok(config)
+    }
+
+    pub async fn get_chat_config_or_default(&self, chat_id: i64) -> Result<ChatConfig> {
+        let config = self.get_chat_config(chat_id).await?;
+
+        Ok(config.unwrap_or(ChatConfig {
+            chat_id,
+            ..ChatConfig::default()
+        }))
+    }
+
+    /// The chat's `added_by` and whether it was verified as a chat
+    /// administrator, or `None` for unknown chats.
+    pub async fn get_chat_owner(&self, chat_id: i64) -> Result<Option<(i64, bool)>> {
+        let owner: Option<(i64, i32)> =
+            sqlx::query_as("SELECT added_by, owner_verified FROM chat_configs WHERE chat_id = ?")
+                .bind(chat_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(owner.map(|(user_id, verified)| (user_id, verified != 0)))
+    }
+
+    /// Makes `user_id`, known to administer the chat, its verified owner,
+    /// recording the chat first if needed.
+    pub async fn set_chat_owner(
+        &self,
+        chat_id: i64,
+        title: Option<&str>,
+        user_id: i64,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chat_configs (chat_id, title, added_by, owner_verified) VALUES (?, ?, ?, 1)
+             ON CONFLICT(chat_id) DO UPDATE SET added_by = excluded.added_by, owner_verified = 1",
+        )
+        .bind(chat_id)
+        .bind(title)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        self.chat_cache.invalidate(&chat_id).await;
+        self.shared
+            .invalidate(&format!("chat_config:{}", chat_id))
+            .await;
+        self.record_audit(
+            actor,
+            "chat_config.owner",
+            chat_id,
+            Some(user_id.to_string()),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Records `user_id` as the unverified owner of a chat nobody owns here,
+    /// recording the chat first if needed, for `/settings` to confirm
+    /// against the chat's administrators. The chat's settings are left
+    /// alone. Returns false when someone owns the chat already.
+    pub async fn claim_chat_unverified(
+        &self,
+        chat_id: i64,
+        title: Option<&str>,
+        kind: &str,
+        user_id: i64,
+        actor: Actor,
+    ) -> Result<bool> {
+        let claimed = sqlx::query(
+            "INSERT INTO chat_configs (chat_id, title, added_by, kind, owner_verified) VALUES (?, ?, ?, ?, 0)
+             ON CONFLICT(chat_id) DO UPDATE SET added_by = excluded.added_by, owner_verified = 0
+             WHERE chat_configs.added_by = 0",
+        )
+        .bind(chat_id)
+        .bind(title)
+        .bind(user_id)
+        .bind(kind)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if claimed {
+            self.chat_cache.invalidate(&chat_id).await;
+            self.shared
+                .invalidate(&format!("chat_config:{}", chat_id))
+                .await;
+            self.record_audit(
+                actor,
+                "chat_config.owner",
+                chat_id,
+                Some(user_id.to_string()),
+            )
+            .await;
+        }
+        Ok(claimed)
+    }
+
+    /// Chats in `digest` mode, with when their last digest went out (0 if
+    /// never).
+    pub async fn get_digest_chats(&self) -> Result<Vec<(ChatConfig, i64)>> {
+        let chats =
+            sqlx::query_as::<_, ChatConfig>("SELECT * FROM chat_configs WHERE mode = 'digest'")
+                .fetch_all(&self.pool)
+                .await?;
+        let sent: Vec<(i64, i64)> = sqlx::query_as(
+            "SELECT d.chat_id, d.last_sent_at FROM chat_digests d
+             JOIN chat_configs c ON c.chat_id = d.chat_id WHERE c.mode = 'digest'",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let sent: std::collections::HashMap<i64, i64> = sent.into_iter().collect();
+        Ok(chats
+            .into_iter()
+            .map(|chat| {
+                let last_sent_at = sent.get(&chat.chat_id).copied().unwrap_or(0);
+                (chat, last_sent_at)
+            })
+            .collect())
+    }
+
+    /// Records that the digest covering up to `now` is being sent, unless
+    /// another replica already did so after `previous`. Returns whether this
+    /// caller should send it.
+    pub async fn claim_chat_digest(&self, chat_id: i64, previous: i64, now: i64) -> Result<bool> {
+        let claimed = sqlx::query(
+            "INSERT INTO chat_digests (chat_id, last_sent_at) VALUES (?, ?)
+             ON CONFLICT(chat_id) DO UPDATE SET last_sent_at = excluded.last_sent_at
+             WHERE chat_digests.last_sent_at = ?",
+        )
+        .bind(chat_id)
+        .bind(now)
+        .bind(previous)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(claimed == 1)
+    }
+
+    /// Cleanings in a chat since `since`: the total, the most frequent
+    /// provider with its count, and the bot that cleaned most recently.
+    pub async fn get_chat_digest(
+        &self,
+        chat_id: i64,
+        since: i64,
+    ) -> Result<(i64, Option<(String, i64)>, Option<i64>)> {
+        let (total, bot_id): (i64, Option<i64>) = sqlx::query_as(
+            "SELECT COUNT(*),
+                    (SELECT bot_id FROM cleaned_links WHERE chat_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'
+                     ORDER BY timestamp DESC, id DESC LIMIT 1)
+             FROM cleaned_links WHERE chat_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'",
+        )
+        .bind(chat_id)
+        .bind(since)
+        .bind(chat_id)
+        .bind(since)
+        .fetch_one(&self.read_pool)
+        .await?;
+        let top: Option<(String, i64)> = sqlx::query_as(
+            "SELECT provider_name, COUNT(*) AS total FROM cleaned_links
+             WHERE chat_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND provider_name IS NOT NULL
+             GROUP BY provider_name ORDER BY total DESC, provider_name LIMIT 1",
+        )
+        .bind(chat_id)
+        .bind(since)
+        .fetch_optional(&self.read_pool)
+        .await?;
+        Ok((total, top, bot_id))
+    }
+
+    /// Users who asked for the weekly summary, with their language and when
+    /// their last one was sent (0 if never).
+    pub async fn get_weekly_digest_users(&self) -> Result<Vec<(i64, String, i64)>> {
+        let users = sqlx::query_as(
+            "SELECT u.user_id, u.language, COALESCE(d.last_sent_at, 0) FROM user_configs u
+             LEFT JOIN user_digests d ON d.user_id = u.user_id WHERE u.digest_enabled = 1",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(users)
+    }
+
+    /// Marks the chat's setup message as posted. Returns false when it
+    /// already was, here or on another replica.
+    pub async fn claim_chat_onboarding(&self, chat_id: i64) -> Result<bool> {
+        let claimed = sqlx::query(
+            "UPDATE chat_configs SET onboarded = 1 WHERE chat_id = ? AND onboarded = 0",
+        )
+        .bind(chat_id)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(claimed == 1)
+    }
+
+    /// Like [`claim_chat_digest`](Self::claim_chat_digest), for a user's
+    /// weekly summary.
+    pub async fn claim_user_digest(&self, user_id: i64, previous: i64, now: i64) -> Result<bool> {
+        let claimed = sqlx::query(
+            "INSERT INTO user_digests (user_id, last_sent_at) VALUES (?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET last_sent_at = excluded.last_sent_at
+             WHERE user_digests.last_sent_at = ?",
+        )
+        .bind(user_id)
+        .bind(now)
+        .bind(previous)
+        .execute(&self.pool)
+        .await?
+        .rows_affected();
+        Ok(claimed == 1)
+    }
+
+    /// A user's cleanings since `since`, with their `limit` most frequent
+    /// providers and domains.
+    pub async fn get_weekly_stats(
+        &self,
+        user_id: i64,
+        since: i64,
+        limit: i64,
+    ) -> Result<WeeklyStats> {
+        let (cleaned, bot_id): (i64, Option<i64>) = sqlx::query_as(
+            "SELECT COUNT(*),
+                    (SELECT bot_id FROM cleaned_links WHERE user_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'
+                     ORDER BY timestamp DESC, id DESC LIMIT 1)
+             FROM cleaned_links WHERE user_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run'",
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(user_id)
+        .bind(since)
+        .fetch_one(&self.read_pool)
+        .await?;
+        let top_providers = sqlx::query_as(
+            "SELECT provider_name, COUNT(*) AS total FROM cleaned_links
+             WHERE user_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND provider_name IS NOT NULL
+             GROUP BY provider_name ORDER BY total DESC, provider_name LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        let top_domains = sqlx::query_as(
+            "SELECT domain, COUNT(*) AS total FROM cleaned_links
+             WHERE user_id = ? AND timestamp >= ? AND deleted_at IS NULL AND COALESCE(cleaning_source, '') <> 'dry_run' AND domain IS NOT NULL
+             GROUP BY domain ORDER BY total DESC, domain LIMIT ?",
+        )
+        .bind(user_id)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(WeeklyStats {
+            cleaned,
+            top_providers,
+            top_domains,
+            bot_id,
+        })
+    }
+
+    /// Domains whose links are left alone in a chat, alphabetically.
+    pub async fn get_chat_ignored_domains(&self, chat_id: i64) -> Result<Vec<String>> {
+        let domains: Vec<(String,)> = sqlx::query_as(
+            "SELECT domain FROM chat_ignored_domains WHERE chat_id = ? ORDER BY domain",
+        )
+        .bind(chat_id)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(domains.into_iter().map(|(domain,)| domain).collect())
+    }
+
+    /// Adds `domain` to the chat's ignore list. Returns false when it was
+    /// already there.
+    pub async fn add_chat_ignored_domain(
+        &self,
+        chat_id: i64,
+        domain: &str,
+        actor: Actor,
+    ) -> Result<bool> {
+        let added = sqlx::query(
+            "INSERT INTO chat_ignored_domains (chat_id, domain, added_by, created_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(chat_id, domain) DO NOTHING",
+        )
+        .bind(chat_id)
+        .bind(domain)
+        .bind(actor.user_id)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if added {
+            self.record_audit(
+                actor,
+                "chat_ignored_domain.add",
+                chat_id,
+                Some(domain.to_string()),
+            )
+            .await;
+        }
+        Ok(added)
+    }
+
+    /// Removes `domain` from the chat's ignore list. Returns false when it
+    /// was not there.
+    pub async fn remove_chat_ignored_domain(
+        &self,
+        chat_id: i64,
+        domain: &str,
+        actor: Actor,
+    ) -> Result<bool> {
+        let removed =
+            sqlx::query("DELETE FROM chat_ignored_domains WHERE chat_id = ? AND domain = ?")
+                .bind(chat_id)
+                .bind(domain)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0;
+        if removed {
+            self.record_audit(
+                actor,
+                "chat_ignored_domain.remove",
+                chat_id,
+                Some(domain.to_string()),
+            )
+            .await;
+        }
+        Ok(removed)
+    }
+
+    /// Members whose messages are left alone in a chat: user ids, and
+    /// lowercase usernames for members named as `@user`.
+    pub async fn get_chat_whitelist(&self, chat_id: i64) -> Result<Vec<String>> {
+        let members: Vec<(String,)> = sqlx::query_as(
+            "SELECT member FROM chat_user_whitelist WHERE chat_id = ? ORDER BY member",
+        )
+        .bind(chat_id)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(members.into_iter().map(|(member,)| member).collect())
+    }
+
+    /// Whether the sender `user_id`, known as `username`, is on the chat's
+    /// whitelist under either.
+    pub async fn is_chat_whitelisted(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        username: Option<&str>,
+    ) -> Result<bool> {
+        let found: Option<(String,)> = sqlx::query_as(
+            "SELECT member FROM chat_user_whitelist WHERE chat_id = ? AND (member = ? OR member = ?)",
+        )
+        .bind(chat_id)
+        .bind(user_id.to_string())
+        .bind(username.map(str::to_lowercase).unwrap_or_default())
+        .fetch_optional(&self.read_pool)
+        .await?;
+        Ok(found.is_some())
+    }
+
+    /// Adds `member` to the chat's whitelist. Returns false when it was
+    /// already there.
+    pub async fn add_chat_whitelisted_member(
+        &self,
+        chat_id: i64,
+        member: &str,
+        actor: Actor,
+    ) -> Result<bool> {
+        let added = sqlx::query(
+            "INSERT INTO chat_user_whitelist (chat_id, member, added_by, created_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(chat_id, member) DO NOTHING",
+        )
+        .bind(chat_id)
+        .bind(member)
+        .bind(actor.user_id)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?
+        .rows_affected()
+            > 0;
+        if added {
+            self.record_audit(
+                actor,
+                "chat_whitelist.add",
+                chat_id,
+                Some(member.to_string()),
+            )
+            .await;
+        }
+        Ok(added)
+    }
+
+    /// Removes `member` from the chat's whitelist. Returns false when it was
+    /// not there.
+    pub async fn remove_chat_whitelisted_member(
+        &self,
+        chat_id: i64,
+        member: &str,
+        actor: Actor,
+    ) -> Result<bool> {
+        let removed =
+            sqlx::query("DELETE FROM chat_user_whitelist WHERE chat_id = ? AND member = ?")
+                .bind(chat_id)
+                .bind(member)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0;
+        if removed {
+            self.record_audit(
+                actor,
+                "chat_whitelist.remove",
+                chat_id,
+                Some(member.to_string()),
+            )
+            .await;
+        }
+        Ok(removed)
+    }
+
+    /// Whether links are cleaned in a forum topic (0 for General): as set
+    /// with `/topic`, or else unless some other topic was turned on, since
+    /// then only the topics turned on are cleaned.
+    pub async fn is_topic_enabled(&self, chat_id: i64, thread_id: i64) -> Result<bool> {
+        let topics: Vec<(i64, i32)> = sqlx::query_as(
+            "SELECT thread_id, enabled FROM chat_topic_configs
+             WHERE chat_id = ? AND (thread_id = ? OR enabled = 1)",
+        )
+        .bind(chat_id)
+        .bind(thread_id)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(match topics.iter().find(|(id, _)| *id == thread_id) {
+            Some((_, enabled)) => *enabled != 0,
+            None => topics.is_empty(),
+        })
+    }
+
+    /// Turns cleaning on or off in one forum topic.
+    pub async fn set_topic_enabled(
+        &self,
+        chat_id: i64,
+        thread_id: i64,
+        enabled: bool,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chat_topic_configs (chat_id, thread_id, enabled, updated_by, updated_at) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(chat_id, thread_id) DO UPDATE SET enabled = excluded.enabled, updated_by = excluded.updated_by, updated_at = excluded.updated_at",
+        )
+        .bind(chat_id)
+        .bind(thread_id)
+        .bind(enabled as i32)
+        .bind(actor.user_id)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?;
+        self.record_audit(
+            actor,
+            "chat_topic.enabled",
+            chat_id,
+            Some(format!("{}={}", thread_id, enabled)),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Leaves the chat without an owner, for when the owner is gone.
+    pub async fn clear_chat_owner(&self, chat_id: i64, actor: Actor) -> Result<()> {
+        sqlx::query("UPDATE chat_configs SET added_by = 0, owner_verified = 0 WHERE chat_id = ?")
+            .bind(chat_id)
+            .execute(&self.pool)
+            .await?;
+        self.chat_cache.invalidate(&chat_id).await;
+        self.shared
+            .invalidate(&format!("chat_config:{}", chat_id))
+            .await;
+        self.record_audit(actor, "chat_config.owner", chat_id, Some("0".to_string()))
+            .await;
+        Ok(())
+    }
+
+    pub async fn save_chat_config(&self, config: &ChatConfig, actor: Actor) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chat_configs (chat_id, title, enabled, added_by, mode, ai_enabled, cleaning_level, kind) VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(chat_id) DO UPDATE SET title = ?, enabled = ?, mode = ?, ai_enabled = ?, cleaning_level = ?, kind = ?"
+        )
+        .bind(config.chat_id)
+        .bind(&config.title)
+        .bind(config.enabled)
+        .bind(config.added_by)
+        .bind(&config.mode)
+        .bind(config.ai_enabled)
+        .bind(&config.cleaning_level)
+        .bind(&config.kind)
+        .bind(&config.title)
+        .bind(config.enabled)
+        .bind(&config.mode)
+        .bind(config.ai_enabled)
+        .bind(&config.cleaning_level)
+        .bind(&config.kind)
+        .execute(&self.pool)
+        .await?;
+        self.chat_cache.invalidate(&config.chat_id).await;
+        self.shared
+            .invalidate(&format!("chat_config:{}", config.chat_id))
+            .await;
+        self.record_audit(
+            actor,
+            "chat_config.save",
+            config.chat_id,
+            serde_json::to_string(config).ok(),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Emits and persists an audit event for a configuration change. The
+    /// change itself is already committed, so a failed insert is only logged.
+    async fn record_audit(
+        &self,
+        actor: Actor,
+        action: &str,
+        target_id: i64,
+        details: Option<String>,
+    ) {
+        tracing::info!(
+            target: "audit",
+            actor_id = actor.user_id,
+            transport = actor.transport.as_str(),
+            action,
+            target_id,
+            "Configuration changed"
+        );
+        let timestamp = match unix_now() {
+            Ok(ts) => ts,
+            Err(e) => {
+                tracing::warn!(error = %e, action, "Failed to persist audit event");
+                return;
+            }
+        };
+        if let Err(e) = sqlx::query(
+            "INSERT INTO audit_log (actor_id, transport, action, target_id, details, timestamp) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(actor.user_id)
+        .bind(actor.transport.as_str())
+        .bind(action)
+        .bind(target_id)
+        .bind(details)
+        .bind(timestamp)
+        .execute(&self.pool)
+        .await
+        {
+            tracing::warn!(error = %e, action, "Failed to persist audit event");
+        }
+    }
+
+    /// Bumps today's counter for a command (`/start`) or feature (`mode:reply`).
+    pub async fn record_usage(&self, name: &str) -> Result<()> {
+        let day = unix_now()? / 86400;
+        sqlx::query(
+            "INSERT INTO usage_stats (name, day, count) VALUES (?, ?, 1)
+             ON CONFLICT(name, day) DO UPDATE SET count = usage_stats.count + 1",
+        )
+        .bind(name)
+        .bind(day)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Usage totals over the last `days` days, most used first.
+    pub async fn get_usage_stats(&self, days: i64) -> Result<Vec<(String, i64)>> {
+        let since = unix_now()? / 86400 - days + 1;
+        let totals = sqlx::query_as::<_, (String, i64)>(
+            "SELECT name, CAST(SUM(count) AS BIGINT) AS total FROM usage_stats
+             WHERE day >= ? GROUP BY name ORDER BY total DESC, name",
+        )
+        .bind(since)
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(totals)
+    }
+
+    /// Adds one AI request, its token usage and latency to the user's daily total.
+    pub async fn record_ai_usage(
+        &self,
+        user_id: i64,
+        prompt_tokens: i64,
+        completion_tokens: i64,
+        latency_ms: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO ai_usage (user_id, day, requests, prompt_tokens, completion_tokens, latency_ms) VALUES (?, ?, 1, ?, ?, ?)
+             ON CONFLICT(user_id, day) DO UPDATE SET requests = ai_usage.requests + 1,
+                 prompt_tokens = ai_usage.prompt_tokens + excluded.prompt_tokens,
+                 completion_tokens = ai_usage.completion_tokens + excluded.completion_tokens,
+                 latency_ms = ai_usage.latency_ms + excluded.latency_ms",
+        )
+        .bind(user_id)
+        .bind(unix_now()? / 86400)
+        .bind(prompt_tokens)
+        .bind(completion_tokens)
+        .bind(latency_ms)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Counts an AI request that actually changed the URL (the rest are no-ops).
+    pub async fn record_ai_success(&self, user_id: i64) -> Result<()> {
+        sqlx::query("UPDATE ai_usage SET successes = successes + 1 WHERE user_id = ? AND day = ?")
+            .bind(user_id)
+            .bind(unix_now()? / 86400)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns (AI-assisted cleanings, AI requests, successful requests,
+    /// average latency in ms) over a user's whole history.
+    pub async fn get_ai_stats(&self, user_id: i64) -> Result<(i64, i64, i64, i64)> {
+        let assisted: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM cleaned_links
+             WHERE user_id = ? AND deleted_at IS NULL AND cleaning_source IN ('ai', 'rules+ai')",
+        )
+        .bind(user_id)
+        .fetch_one(&self.read_pool)
+        .await?;
+        let (requests, successes, latency_ms): (i64, i64, i64) = sqlx::query_as(
+            "SELECT CAST(COALESCE(SUM(requests), 0) AS BIGINT),
+                    CAST(COALESCE(SUM(successes), 0) AS BIGINT),
+                    CAST(COALESCE(SUM(latency_ms), 0) AS BIGINT)
+             FROM ai_usage WHERE user_id = ?",
+        )
+        .bind(user_id)
+        .fetch_one(&self.read_pool)
+        .await?;
+        let avg_latency = if requests > 0 {
+            latency_ms / requests
+        } else {
+            0
+        };
+        Ok((assisted.0, requests, successes, avg_latency))
+    }
+
+    /// Returns today's (requests, total tokens) for a user.
+    pub async fn get_ai_usage_today(&self, user_id: i64) -> Result<(i64, i64)> {
+        let usage: Option<(i64, i64)> = sqlx::query_as(
+            "SELECT requests, prompt_tokens + completion_tokens FROM ai_usage WHERE user_id = ? AND day = ?",
+        )
+        .bind(user_id)
+        .bind(unix_now()? / 86400)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(usage.unwrap_or((0, 0)))
+    }
+
+    /// Returns (requests, total tokens) across all users over the last `days` days.
+    pub async fn get_ai_usage_totals(&self, days: i64) -> Result<(i64, i64)> {
+        let totals: (i64, i64) = sqlx::query_as(
+            "SELECT CAST(COALESCE(SUM(requests), 0) AS BIGINT),
+                    CAST(COALESCE(SUM(prompt_tokens + completion_tokens), 0) AS BIGINT)
+             FROM ai_usage WHERE day >= ?",
+        )
+        .bind(unix_now()? / 86400 - days + 1)
+        .fetch_one(&self.read_pool)
+        .await?;
+        Ok(totals)
+    }
+
+    /// Returns a cached (score, reason) phishing assessment younger than a week.
+    pub async fn get_url_risk(&self, url: &str) -> Result<Option<(f64, String)>> {
+        let risk = sqlx::query_as::<_, (f64, String)>(
+            "SELECT score, reason FROM url_risk WHERE url = ? AND checked_at >= ?",
+        )
+        .bind(url)
+        .bind(unix_now()? - URL_RISK_TTL_SECS)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(risk)
+    }
+
+    pub async fn save_url_risk(&self, url: &str, score: f64, reason: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO url_risk (url, score, reason, checked_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(url) DO UPDATE SET score = excluded.score, reason = excluded.reason, checked_at = excluded.checked_at",
+        )
+        .bind(url)
+        .bind(score)
+        .bind(reason)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Turns verbose per-message tracing on or off for a user or chat id.
+    pub async fn set_debug_target(
+        &self,
+        target_id: i64,
+        enabled: bool,
+        actor: Actor,
+    ) -> Result<()> {
+        if enabled {
+            sqlx::query(
+                "INSERT INTO debug_targets (target_id, created_at) VALUES (?, ?) ON CONFLICT(target_id) DO NOTHING",
+            )
+            .bind(target_id)
+            .bind(unix_now()?)
+            .execute(&self.pool)
+            .await?;
+        } else {
+            sqlx::query("DELETE FROM debug_targets WHERE target_id = ?")
+                .bind(target_id)
+                .execute(&self.pool)
+                .await?;
+        }
+        self.debug_cache.invalidate(&target_id).await;
+        let action = if enabled {
+            "debug.enable"
+        } else {
+            "debug.disable"
+        };
+        self.record_audit(actor, action, target_id, None).await;
+        Ok(())
+    }
+
+    /// Whether verbose tracing is enabled for `target_id`. Lookup failures
+    /// count as disabled.
+    pub async fn is_debug_target(&self, target_id: i64) -> bool {
+        if let Some(cached) = self.debug_cache.get(&target_id).await {
+            return cached;
+        }
+        let enabled = match sqlx::query_as::<_, (i64,)>(
+            "SELECT target_id FROM debug_targets WHERE target_id = ?",
+        )
+        .bind(target_id)
+        .fetch_optional(&self.pool)
+        .await
+        {
+            Ok(row) => row.is_some(),
+            Err(e) => {
+                tracing::warn!(error = %e, target_id, "Failed to look up debug target");
+                return false;
+            }
+        };
+        self.debug_cache.insert(target_id, enabled).await;
+        enabled
+    }
+
+    pub async fn get_debug_targets(&self) -> Result<Vec<i64>> {
+        let rows =
+            sqlx::query_as::<_, (i64,)>("SELECT target_id FROM debug_targets ORDER BY created_at")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Settings of a Fediverse account, or the defaults if it never
+    /// interacted with the Mastodon bot.
+    pub async fn get_fediverse_account(&self, acct: &str) -> Result<FediverseAccount> {
+        let account = sqlx::query_as::<_, FediverseAccount>(
+            "SELECT * FROM fediverse_accounts WHERE acct = ?",
+        )
+        .bind(acct)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(account.unwrap_or_else(|| FediverseAccount::new(acct)))
+    }
+
+    pub async fn save_fediverse_account(&self, account: &FediverseAccount) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO fediverse_accounts (acct, enabled, ignored_domains, cleaned_count) VALUES (?, ?, ?, ?)
+             ON CONFLICT(acct) DO UPDATE SET enabled = excluded.enabled, ignored_domains = excluded.ignored_domains, cleaned_count = excluded.cleaned_count",
+        )
+        .bind(&account.acct)
+        .bind(account.enabled)
+        .bind(&account.ignored_domains)
+        .bind(account.cleaned_count)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_translation_overrides(&self) -> Result<Vec<TranslationOverride>> {
+        let overrides = sqlx::query_as::<_, TranslationOverride>(
+            "SELECT lang, key, value FROM translations_overrides ORDER BY lang, key",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(overrides)
+    }
+
+    pub async fn set_translation_override(
+        &self,
+        entry: &TranslationOverride,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO translations_overrides (lang, key, value, updated_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(lang, key) DO UPDATE SET value = excluded.value, updated_at = excluded.updated_at",
+        )
+        .bind(&entry.lang)
+        .bind(&entry.key)
+        .bind(&entry.value)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?;
+        self.record_audit(
+            actor,
+            "translation.set",
+            0,
+            Some(format!("{}:{}", entry.lang, entry.key)),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Restores the built-in string. Returns false if it was not overridden.
+    pub async fn delete_translation_override(
+        &self,
+        lang: &str,
+        key: &str,
+        actor: Actor,
+    ) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM translations_overrides WHERE lang = ? AND key = ?")
+            .bind(lang)
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+        self.record_audit(
+            actor,
+            "translation.delete",
+            0,
+            Some(format!("{}:{}", lang, key)),
+        )
+        .await;
+        Ok(true)
+    }
+
+    /// Stores a dashboard session for `user_id`. Only a hash of `token` is
+    /// kept, so a leaked database does not leak live sessions.
+    pub async fn create_web_session(&self, token: &str, user_id: i64, ttl: Duration) -> Result<()> {
+        let now = unix_now()?;
+        sqlx::query(
+            "INSERT INTO web_sessions (token_hash, user_id, created_at, expires_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(hash_token(token))
+        .bind(user_id)
+        .bind(now)
+        .bind(now + ttl.as_secs() as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// The user owning an unexpired dashboard session.
+    pub async fn get_web_session_user(&self, token: &str) -> Result<Option<i64>> {
+        let user: Option<(i64,)> = sqlx::query_as(
+            "SELECT user_id FROM web_sessions WHERE token_hash = ? AND expires_at > ?",
+        )
+        .bind(hash_token(token))
+        .bind(unix_now()?)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user.map(|(user_id,)| user_id))
+    }
+
+    pub async fn delete_web_session(&self, token: &str) -> Result<()> {
+        sqlx::query("DELETE FROM web_sessions WHERE token_hash = ?")
+            .bind(hash_token(token))
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Drops expired dashboard sessions and unused login links.
+    pub async fn purge_expired_web_sessions(&self) -> Result<u64> {
+        let now = unix_now()?;
+        let mut purged = 0;
+        for sql in [
+            "DELETE FROM web_sessions WHERE expires_at <= ?",
+            "DELETE FROM login_tokens WHERE expires_at <= ?",
+            "DELETE FROM admin_confirmations WHERE expires_at <= ?",
+        ] {
+            purged += sqlx::query(sql)
+                .bind(now)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        }
+        Ok(purged)
+    }
+
+    /// Stores a one-time code confirming `action` on `target` for
+    /// `user_id`, replacing any earlier code for the same action.
+    pub async fn create_admin_confirmation(
+        &self,
+        code: &str,
+        user_id: i64,
+        action: &str,
+        target: &str,
+        ttl: Duration,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM admin_confirmations WHERE user_id = ? AND action = ?")
+            .bind(user_id)
+            .bind(action)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO admin_confirmations (code_hash, user_id, action, target, expires_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(hash_token(code))
+        .bind(user_id)
+        .bind(action)
+        .bind(target)
+        .bind(unix_now()? + ttl.as_secs() as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Redeems the code confirming `action` on `target`. Whatever the
+    /// outcome, the pending code is spent, so each code allows a single
+    /// guess.
+    pub async fn consume_admin_confirmation(
+        &self,
+        code: &str,
+        user_id: i64,
+        action: &str,
+        target: &str,
+    ) -> Result<bool> {
+        let matched: Option<(i64,)> = sqlx::query_as(
+            "SELECT user_id FROM admin_confirmations WHERE code_hash = ? AND user_id = ? AND action = ? AND target = ? AND expires_at > ?",
+        )
+        .bind(hash_token(code))
+        .bind(user_id)
+        .bind(action)
+        .bind(target)
+        .bind(unix_now()?)
+        .fetch_optional(&self.pool)
+        .await?;
+        let deleted =
+            sqlx::query("DELETE FROM admin_confirmations WHERE user_id = ? AND action = ?")
+                .bind(user_id)
+                .bind(action)
+                .execute(&self.pool)
+                .await?
+                .rows_affected();
+        Ok(matched.is_some() && deleted > 0)
+    }
+
+    /// Stores a one-time login link token for `user_id`; only its hash is kept.
+    pub async fn create_login_token(&self, token: &str, user_id: i64, ttl: Duration) -> Result<()> {
+        sqlx::query("INSERT INTO login_tokens (token_hash, user_id, expires_at) VALUES (?, ?, ?)")
+            .bind(hash_token(token))
+            .bind(user_id)
+            .bind(unix_now()? + ttl.as_secs() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Redeems a login link token: returns its user the first time it is
+    /// used before expiring, `None` afterwards.
+    pub async fn consume_login_token(&self, token: &str) -> Result<Option<i64>> {
+        let token_hash = hash_token(token);
+        let user: Option<(i64,)> = sqlx::query_as(
+            "SELECT user_id FROM login_tokens WHERE token_hash = ? AND expires_at > ?",
+        )
+        .bind(&token_hash)
+        .bind(unix_now()?)
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some((user_id,)) = user else {
+            return Ok(None);
+        };
+        // Only the request that actually deletes the row wins a race.
+        let deleted = sqlx::query("DELETE FROM login_tokens WHERE token_hash = ?")
+            .bind(&token_hash)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok((deleted == 1).then_some(user_id))
+    }
+
+    /// The user's entitlement, if one was ever granted (expired or not).
+    pub async fn get_entitlement(&self, user_id: i64) -> Result<Option<Entitlement>> {
+        let entitlement =
+            sqlx::query_as::<_, Entitlement>("SELECT * FROM entitlements WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(entitlement)
+    }
+
+    /// The plan granted to the user, unless it has expired.
+    pub async fn get_active_plan(&self, user_id: i64) -> Result<Option<Plan>> {
+        let entitlement = self.get_entitlement(user_id).await?;
+        let now = unix_now()?;
+        Ok(entitlement
+            .filter(|e| e.expires_at.is_none_or(|expires_at| expires_at > now))
+            .and_then(|e| Plan::parse(&e.plan)))
+    }
+
+    /// Grants `plan` to the user until `expires_at` (forever when `None`).
+    pub async fn set_entitlement(
+        &self,
+        user_id: i64,
+        plan: Plan,
+        expires_at: Option<i64>,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO entitlements (user_id, plan, expires_at, updated_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET plan = excluded.plan, expires_at = excluded.expires_at, updated_at = excluded.updated_at",
+        )
+        .bind(user_id)
+        .bind(plan.as_str())
+        .bind(expires_at)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?;
+        self.record_audit(
+            actor,
+            "entitlement.set",
+            user_id,
+            Some(serde_json::json!({ "plan": plan, "expires_at": expires_at }).to_string()),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Deletes history older than `cutoff` of users currently on `plan`:
+    /// those with an active entitlement for it, plus everyone without one
+    /// when it is the default plan.
+    pub async fn purge_history_for_plan(
+        &self,
+        plan: Plan,
+        default_plan: Plan,
+        cutoff: i64,
+    ) -> Result<u64> {
+        let active =
+            "SELECT user_id FROM entitlements WHERE (expires_at IS NULL OR expires_at > ?)";
+        let sql = if plan == default_plan {
+            format!(
+                "DELETE FROM cleaned_links WHERE timestamp < ? AND user_id NOT IN ({} AND plan <> ?)",
+                active
+            )
+        } else {
+            format!(
+                "DELETE FROM cleaned_links WHERE timestamp < ? AND user_id IN ({} AND plan = ?)",
+                active
+            )
+        };
+        let result = sqlx::query(&sql)
+            .bind(cutoff)
+            .bind(unix_now()?)
+            .bind(plan.as_str())
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Every admin-defined provider, enabled or not, by name.
+    pub async fn get_global_providers(&self) -> Result<Vec<GlobalProvider>> {
+        let providers =
+            sqlx::query_as::<_, GlobalProvider>("SELECT * FROM global_providers ORDER BY name")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(providers)
+    }
+
+    /// Creates or replaces the provider named `provider.name`. The
+    /// definition is expected to be validated by the caller.
+    pub async fn save_global_provider(
+        &self,
+        provider: &GlobalProvider,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO global_providers (name, definition, enabled, updated_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(name) DO UPDATE SET definition = excluded.definition, enabled = excluded.enabled, updated_at = excluded.updated_at",
+        )
+        .bind(&provider.name)
+        .bind(&provider.definition)
+        .bind(provider.enabled)
+        .bind(unix_now()?)
+        .execute(&self.pool)
+        .await?;
+        self.record_audit(
+            actor,
+            "global_provider.save",
+            0,
+            Some(
+                serde_json::json!({ "name": provider.name, "enabled": provider.enabled != 0 })
+                    .to_string(),
+            ),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Returns `false` when no provider had that name.
+    pub async fn delete_global_provider(&self, name: &str, actor: Actor) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM global_providers WHERE name = ?")
+            .bind(name)
+            .execute(&self.pool)
+            .await?;
+        let deleted = result.rows_affected() > 0;
+        if deleted {
+            self.record_audit(
+                actor,
+                "global_provider.delete",
+                0,
+                Some(serde_json::json!({ "name": name }).to_string()),
+            )
+            .await;
+        }
+        Ok(deleted)
+    }
+
+    /// Signs the user out of every dashboard session.
+    pub async fn delete_web_sessions_for_user(&self, user_id: i64) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM web_sessions WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Schedules `delete_all_user_data` for `delete_after` (Unix seconds),
+    /// replacing any earlier request.
+    pub async fn schedule_account_deletion(
+        &self,
+        user_id: i64,
+        delete_after: i64,
+        actor: Actor,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO account_deletions (user_id, requested_at, delete_after) VALUES (?, ?, ?)
+             ON CONFLICT(user_id) DO UPDATE SET requested_at = excluded.requested_at, delete_after = excluded.delete_after",
+        )
+        .bind(user_id)
+        .bind(unix_now()?)
+        .bind(delete_after)
+        .execute(&self.pool)
+        .await?;
+        self.record_audit(
+            actor,
+            "account.delete_scheduled",
+            user_id,
+            Some(delete_after.to_string()),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Withdraws a pending deletion; returns whether there was one.
+    pub async fn cancel_account_deletion(&self, user_id: i64, actor: Actor) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM account_deletions WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+        let cancelled = result.rows_affected() > 0;
+        if cancelled {
+            self.record_audit(actor, "account.delete_cancelled", user_id, None)
+                .await;
+        }
+        Ok(cancelled)
+    }
+
+    /// When the user's pending deletion takes effect, if any.
+    pub async fn get_account_deletion(&self, user_id: i64) -> Result<Option<i64>> {
+        let row: Option<(i64,)> =
+            sqlx::query_as("SELECT delete_after FROM account_deletions WHERE user_id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(row.map(|(delete_after,)| delete_after))
+    }
+
+    /// Carries out every scheduled deletion whose grace period is over.
+    pub async fn run_due_account_deletions(&self) -> Result<u64> {
+        let due: Vec<(i64,)> =
+            sqlx::query_as("SELECT user_id FROM account_deletions WHERE delete_after <= ?")
+                .bind(unix_now()?)
+                .fetch_all(&self.pool)
+                .await?;
+        for (user_id,) in &due {
+            self.delete_all_user_data(*user_id, Actor::system()).await?;
+        }
+        Ok(due.len() as u64)
+    }
+
+    /// Erases everything stored about a user in one transaction: settings,
+    /// custom rules, history, AI usage, dashboard sessions and audit entries
+    /// about them. Groups they added stay configured but lose the link to
+    /// them, and no chat whitelists them any longer. Only the `account.delete`
+    /// audit event is kept, and the global counters, being lifetime totals,
+    /// keep the user's cleanings.
+    pub async fn delete_all_user_data(&self, user_id: i64, actor: Actor) -> Result<()> {
+        let chats = self.get_chats_for_user(user_id).await?;
+        let mut tx = self.pool.begin().await?;
+        for sql in [
+            "DELETE FROM cleaned_links WHERE user_id = ?",
+            "DELETE FROM custom_rules WHERE user_id = ?",
+            "DELETE FROM ai_usage WHERE user_id = ?",
+            "DELETE FROM web_sessions WHERE user_id = ?",
+            "DELETE FROM login_tokens WHERE user_id = ?",
+            "DELETE FROM debug_targets WHERE target_id = ?",
+            "DELETE FROM audit_log WHERE target_id = ?",
+            "DELETE FROM account_deletions WHERE user_id = ?",
+            "DELETE FROM entitlements WHERE user_id = ?",
+            "DELETE FROM user_digests WHERE user_id = ?",
+            "DELETE FROM profile_imports WHERE user_id = ?",
+            "DELETE FROM user_configs WHERE user_id = ?",
+            "UPDATE chat_configs SET added_by = 0, owner_verified = 0 WHERE added_by = ?",
+            "UPDATE chat_ignored_domains SET added_by = 0 WHERE added_by = ?",
+            "UPDATE chat_user_whitelist SET added_by = 0 WHERE added_by = ?",
+            "UPDATE chat_topic_configs SET updated_by = 0 WHERE updated_by = ?",
+        ] {
+            sqlx::query(sql).bind(user_id).execute(&mut *tx).await?;
+        }
+        sqlx::query("DELETE FROM chat_user_whitelist WHERE member = ?")
+            .bind(user_id.to_string())
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        self.user_cache.invalidate(&user_id).await;
+        self.rules_cache.invalidate(&user_id).await;
+        self.debug_cache.invalidate(&user_id).await;
+        self.shared
+            .invalidate(&format!("user_config:{}", user_id))
+            .await;
+        self.shared
+            .invalidate(&format!("custom_rules:{}", user_id))
+            .await;
+        for chat in chats {
+            self.chat_cache.invalidate(&chat.chat_id).await;
+            self.shared
+                .invalidate(&format!("chat_config:{}", chat.chat_id))
+                .await;
+        }
+        self.record_audit(actor, "account.delete", user_id, None)
+            .await;
+        Ok(())
+    }
+
+    /// Records that the admin opened `user_id`'s data in the admin panel.
+    pub async fn record_user_view(&self, actor: Actor, user_id: i64) {
+        self.record_audit(actor, "admin.view_user", user_id, None)
+            .await;
+    }
+
+    /// Records that the admin exported cleaning events, of one user or of
+    /// everyone (`user_id` 0).
+    pub async fn record_events_export(&self, actor: Actor, user_id: i64, details: String) {
+        self.record_audit(actor, "admin.export_events", user_id, Some(details))
+            .await;
+    }
+
+    /// Most recent audit events, optionally limited to one user or chat.
+    pub async fn get_audit_log(
+        &self,
+        target_id: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<AuditEvent>> {
+        let events = match target_id {
+            Some(target_id) => {
+                sqlx::query_as::<_, AuditEvent>(
+                    "SELECT * FROM audit_log WHERE target_id = ? ORDER BY timestamp DESC, id DESC LIMIT ?",
+                )
+                .bind(target_id)
+                .bind(limit)
+                .fetch_all(&self.read_pool)
+                .await?
+            }
+            None => {
+                sqlx::query_as::<_, AuditEvent>(
+                    "SELECT * FROM audit_log ORDER BY timestamp DESC, id DESC LIMIT ?",
+                )
+                .bind(limit)
+                .fetch_all(&self.read_pool)
+                .await?
+            }
+        };
+        Ok(events)
+    }
+
+    /// Every known chat with its cleaning count and latest cleaning, most
+    /// recently active first.
+    pub async fn get_chat_summaries(&self) -> Result<Vec<ChatSummary>> {
+        let chats = sqlx::query_as::<_, ChatSummary>(
+            "SELECT c.chat_id, c.title, c.enabled, c.added_by, c.mode, c.kind,
+                    COALESCE(l.cleanings, 0) AS cleanings, l.last_activity
+             FROM chat_configs c
+             LEFT JOIN (
+                 SELECT chat_id, COUNT(*) AS cleanings, MAX(timestamp) AS last_activity
+                 FROM cleaned_links WHERE chat_id IS NOT NULL AND COALESCE(cleaning_source, '') <> 'dry_run' GROUP BY chat_id
+             ) l ON l.chat_id = c.chat_id
+             ORDER BY l.last_activity IS NULL, l.last_activity DESC, c.chat_id",
+        )
+        .fetch_all(&self.read_pool)
+        .await?;
+        Ok(chats)
+    }
+
+    pub async fn get_chats_for_user(&self, user_id: i64) -> Result<Vec<ChatConfig>> {
+        let chats =
+            sqlx::query_as::<_, ChatConfig>("SELECT * FROM chat_configs WHERE added_by = ?")
+                .bind(user_id)
+                .fetch_all(&self.pool)
                 .await?;
         Ok(chats)
     }
 }
+
+/// Extracts the lowercase host of a URL, without a leading `www.`.
+fn extract_domain(url: &str) -> Option<String> {
+    let parsed = if url.contains("://") {
+        url::Url::parse(url)
+    } else {
+        url::Url::parse(&format!("http://{}", url))
+    };
+    let host = parsed.ok()?.host_str()?.to_lowercase();
+    Some(host.strip_prefix("www.").map(str::to_string).unwrap_or(host))
+}
+
+pub fn unix_now() -> Result<i64> {
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64)
+}
+
+/// `YYYY-MM-DD` for a count of days since the Unix epoch.
+pub fn format_day(day: i64) -> String {
+    time::OffsetDateTime::from_unix_timestamp(day * 86400)
+        .map(|t| t.date().to_string())
+        .unwrap_or_default()
+}
+
+/// One day of [`Db::stream_global_daily_stats`]: `(day, cleanings, active
+/// users, new users, users so far)`.
+pub type DailyStats = (String, i64, i64, i64, i64);
+
+fn hash_token(token: &str) -> String {
+    use sha2::{Digest, Sha256};
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn cleaned_counts_survive_a_missing_config_row() {
+        let db = Db::test("db-cleaned-count").await;
+        db.increment_cleaned_count(7, 3).await.unwrap();
+        db.increment_cleaned_count(7, 2).await.unwrap();
+        assert_eq!(db.get_user_config(7).await.unwrap().cleaned_count, 5);
+
+        for (url, source) in [
+            ("https://a.com/?utm_source=x", "rules"),
+            ("https://b.com/?fbclid=y", "rules"),
+            ("https://c.com/?fbclid=y", "dry_run"),
+        ] {
+            db.log_cleaned_link(8, None, None, url, url, "test", source)
+                .await
+                .unwrap();
+        }
+        // Already applied by `init`, so nothing changes until the marker
+        // is gone; then it runs once.
+        db.backfill_cleaned_counts().await.unwrap();
+        assert_eq!(db.get_global_stats().await.unwrap(), (5, 1));
+        sqlx::query("DELETE FROM schema_migrations")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        db.backfill_cleaned_counts().await.unwrap();
+        db.backfill_cleaned_counts().await.unwrap();
+        assert_eq!(db.get_user_config(8).await.unwrap().cleaned_count, 2);
+        assert_eq!(db.get_global_stats().await.unwrap(), (7, 2));
+    }
+
+    #[tokio::test]
+    async fn users_are_counted_on_their_first_cleaning() {
+        let db = Db::test("db-global-users").await;
+        db.init_user_language(7, "it").await.unwrap();
+        db.increment_cleaned_count(8, 0).await.unwrap();
+        assert_eq!(db.get_global_stats().await.unwrap(), (0, 0));
+
+        db.increment_cleaned_count(7, 2).await.unwrap();
+        db.increment_cleaned_count(8, 1).await.unwrap();
+        db.increment_cleaned_count(8, 1).await.unwrap();
+        assert_eq!(db.get_global_stats().await.unwrap(), (4, 2));
+
+        // Lifetime totals: erasing an account keeps its cleanings, and the
+        // user counts again if they come back.
+        db.delete_all_user_data(7, Actor::system()).await.unwrap();
+        assert_eq!(db.get_global_stats().await.unwrap(), (4, 2));
+        db.increment_cleaned_count(7, 1).await.unwrap();
+        assert_eq!(db.get_global_stats().await.unwrap(), (5, 3));
+    }
+
+    #[tokio::test]
+    async fn saving_a_stale_config_keeps_the_cleaned_count() {
+        let db = Db::test("db-stale-count").await;
+        let mut config = db.get_user_config(7).await.unwrap();
+        db.increment_cleaned_count(7, 3).await.unwrap();
+        config.mode = "delete".into();
+        db.save_user_config(&config, Actor::web(7)).await.unwrap();
+        let saved = db.get_user_config(7).await.unwrap();
+        assert_eq!((saved.mode.as_str(), saved.cleaned_count), ("delete", 3));
+    }
+
+    #[tokio::test]
+    async fn chat_stats_count_links_and_members() {
+        let db = Db::test("db-chat-stats").await;
+        let url = "https://example.com/";
+        for (user_id, chat_id) in [(7, Some(-5)), (8, Some(-5)), (7, Some(-5)), (7, None)] {
+            db.log_cleaned_link(user_id, chat_id, None, url, url, "test", "rules")
+                .await
+                .unwrap();
+        }
+        assert_eq!(db.get_chat_stats(-5).await.unwrap(), (3, 2));
+        assert_eq!(db.get_chat_stats(-6).await.unwrap(), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn domains_are_stored_without_www() {
+        let db = Db::test("db-top-domains").await;
+        for url in [
+            "https://www.Example.com/a",
+            "https://example.com/b",
+            "https://news.site/c",
+        ] {
+            db.log_cleaned_link(7, None, None, url, url, "test", "rules")
+                .await
+                .unwrap();
+        }
+        assert_eq!(db.count_distinct_domains(7).await.unwrap(), 2);
+        assert_eq!(
+            db.get_top_domains(7, 10).await.unwrap(),
+            [("example.com".to_string(), 2), ("news.site".to_string(), 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn cleared_history_can_be_restored_until_purged() {
+        let db = Db::test("db-clear-history").await;
+        let url = "https://example.com/";
+        db.log_cleaned_link(7, None, None, url, url, "test", "rules")
+            .await
+            .unwrap();
+
+        assert_eq!(db.clear_history(7).await.unwrap(), 1);
+        assert!(db.get_history(7, 10).await.unwrap().is_empty());
+        assert_eq!(db.purge_deleted_history().await.unwrap(), 0);
+        assert_eq!(db.undo_clear_history(7).await.unwrap(), 1);
+        assert_eq!(db.get_history(7, 10).await.unwrap().len(), 1);
+
+        db.clear_history(7).await.unwrap();
+        sqlx::query("UPDATE cleaned_links SET deleted_at = deleted_at - ?")
+            .bind(HISTORY_UNDO_WINDOW_SECS + 1)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(db.undo_clear_history(7).await.unwrap(), 0);
+        assert_eq!(db.purge_deleted_history().await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn history_is_read_from_the_replica() {
+        let url = |name| format!("sqlite:{}", Db::test_path(name).display());
+        let primary = Db::test("db-primary").await;
+        let replica = Db::test("db-replica").await;
+        let db = Db::with_replica(&url("db-primary"), Some(&url("db-replica")))
+            .await
+            .unwrap();
+
+        let link = "https://example.com/";
+        db.log_cleaned_link(7, None, None, link, link, "test", "rules")
+            .await
+            .unwrap();
+        assert!(db.get_history(7, 10).await.unwrap().is_empty());
+        assert_eq!(primary.get_history(7, 10).await.unwrap().len(), 1);
+
+        replica
+            .log_cleaned_link(7, None, None, link, link, "test", "rules")
+            .await
+            .unwrap();
+        assert_eq!(db.get_history(7, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn configs_are_cached_until_saved() {
+        let db = Db::test("db-config-cache").await;
+        let mut user = db.get_user_config(7).await.unwrap();
+        db.save_user_config(&user, Actor::system()).await.unwrap();
+        db.get_user_config(7).await.unwrap();
+        assert!(db.get_chat_config(-5).await.unwrap().is_none());
+
+        // Writes behind the Db's back stay invisible until the next save.
+        sqlx::query("UPDATE user_configs SET mode = 'delete' WHERE user_id = 7")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO chat_configs (chat_id, added_by) VALUES (-5, 7)")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(db.get_user_config(7).await.unwrap().mode, "reply");
+        assert!(db.get_chat_config(-5).await.unwrap().is_none());
+
+        user.ai_enabled = 1;
+        db.save_user_config(&user, Actor::system()).await.unwrap();
+        assert_eq!(db.get_user_config(7).await.unwrap().ai_enabled, 1);
+        let chat = ChatConfig {
+            chat_id: -5,
+            ..Default::default()
+        };
+        db.save_chat_config(&chat, Actor::system()).await.unwrap();
+        assert!(db.get_chat_config(-5).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn history_is_tagged_with_the_bot() {
+        let db = Db::test("db-bot-id").await;
+        let url = "https://example.com/";
+        for bot_id in [Some(2), Some(1)] {
+            db.log_cleaned_link(7, Some(-5), bot_id, url, url, "test", "rules")
+                .await
+                .unwrap();
+        }
+        let mut bots: Vec<_> = db
+            .get_history(7, 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|link| link.bot_id)
+            .collect();
+        bots.sort();
+        assert_eq!(bots, [Some(1), Some(2)]);
+    }
+
+    #[tokio::test]
+    async fn changes_are_audited_per_target() {
+        let db = Db::test("db-audit").await;
+        let user = UserConfig {
+            user_id: 7,
+            mode: "delete".into(),
+            ..Default::default()
+        };
+        db.save_user_config(&user, Actor::bot(7)).await.unwrap();
+        db.add_custom_rule(7, "ref", 10, Actor::web(7))
+            .await
+            .unwrap();
+        let chat = ChatConfig {
+            chat_id: -5,
+            added_by: 7,
+            ..Default::default()
+        };
+        db.save_chat_config(&chat, Actor::bot(7)).await.unwrap();
+
+        let events = db.get_audit_log(Some(7), 10).await.unwrap();
+        let actions: Vec<_> = events
+            .iter()
+            .map(|e| (e.action.as_str(), e.transport.as_str(), e.actor_id))
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                ("custom_rule.add", "web", 7),
+                ("user_config.save", "bot", 7)
+            ]
+        );
+        assert!(events[1].details.as_deref().unwrap().contains("\"delete\""));
+        assert_eq!(db.get_audit_log(None, 10).await.unwrap().len(), 3);
+    }
+}
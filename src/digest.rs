@@ -0,0 +1,218 @@
+//! Daily summaries for chats in `digest` mode. The bot stays silent there
+//! while cleaning; once a day, at `DIGEST_HOUR` (UTC) unless
+//! `SCHEDULE_DIGEST` says otherwise, each such chat gets one message with
+//! the day's count and most frequent tracker.
+//!
+//! Users who opt in from /settings also get a weekly summary of their own
+//! cleanings in private, on Mondays unless `SCHEDULE_WEEKLY_DIGEST` says
+//! otherwise.
+
+use crate::db::{unix_now, Db};
+use crate::i18n;
+use crate::messenger::{Messenger, SendOptions};
+use anyhow::Result;
+use teloxide::types::ChatId;
+use teloxide::utils::html;
+
+/// A digest never covers more than the last day, even after downtime.
+const MAX_DIGEST_SECS: i64 = 86400;
+/// Likewise for the weekly summaries.
+const MAX_WEEKLY_DIGEST_SECS: i64 = 7 * 86400;
+/// Providers and domains listed in a weekly summary.
+const WEEKLY_TOP: i64 = 3;
+
+/// The `digest` job: posts the digests that are due through whichever of
+/// `bots` cleaned most recently in each chat (the first one otherwise).
+pub async fn run_digests<M: Messenger>(bots: &[(i64, M)], db: &Db) -> Result<()> {
+    let sent = send_digests(bots, db, unix_now()?).await?;
+    if sent > 0 {
+        tracing::info!(sent, "Sent chat digests");
+    }
+    Ok(())
+}
+
+/// Sends the digest of every enabled `digest` chat with cleanings since its
+/// last one. Returns how many went out.
+pub async fn send_digests<M: Messenger>(bots: &[(i64, M)], db: &Db, now: i64) -> Result<usize> {
+    let mut sent = 0;
+    for (chat, last_sent_at) in db.get_digest_chats().await? {
+        if !chat.is_enabled() {
+            continue;
+        }
+        let since = last_sent_at.max(now - MAX_DIGEST_SECS);
+        let (total, top, bot_id) = db.get_chat_digest(chat.chat_id, since).await?;
+        if total == 0
+            || !db
+                .claim_chat_digest(chat.chat_id, last_sent_at, now)
+                .await?
+        {
+            continue;
+        }
+        let Some((_, bot)) = bots
+            .iter()
+            .find(|(id, _)| Some(*id) == bot_id)
+            .or(bots.first())
+        else {
+            break;
+        };
+
+        let language = db
+            .get_user_config(chat.added_by)
+            .await
+            .map(|c| c.language)
+            .unwrap_or_default();
+        let tr = i18n::get_translations(&language);
+        let mut text = tr.digest_summary.replace("{}", &total.to_string());
+        if let Some((provider, count)) = top {
+            text.push_str(
+                &tr.digest_top_provider
+                    .replacen("{}", &html::escape(&provider), 1)
+                    .replacen("{}", &count.to_string(), 1),
+            );
+        }
+        match bot
+            .send_message(ChatId(chat.chat_id), text, SendOptions::default())
+            .await
+        {
+            Ok(()) => sent += 1,
+            Err(e) => tracing::warn!(error = %e, chat_id = chat.chat_id, "Failed to send digest"),
+        }
+    }
+    Ok(sent)
+}
+
+/// The `weekly-digest` job: sends the weekly summaries that are due.
+pub async fn run_weekly_digests<M: Messenger>(bots: &[(i64, M)], db: &Db) -> Result<()> {
+    let sent = send_weekly_digests(bots, db, unix_now()?).await?;
+    if sent > 0 {
+        tracing::info!(sent, "Sent weekly digests");
+    }
+    Ok(())
+}
+
+/// Sends every opted-in user with cleanings since their last summary their
+/// count, top trackers and top domains, through the bot that last cleaned
+/// for them. Returns how many went out.
+pub async fn send_weekly_digests<M: Messenger>(
+    bots: &[(i64, M)],
+    db: &Db,
+    now: i64,
+) -> Result<usize> {
+    let mut sent = 0;
+    for (user_id, language, last_sent_at) in db.get_weekly_digest_users().await? {
+        let since = last_sent_at.max(now - MAX_WEEKLY_DIGEST_SECS);
+        let stats = db.get_weekly_stats(user_id, since, WEEKLY_TOP).await?;
+        if stats.cleaned == 0 || !db.claim_user_digest(user_id, last_sent_at, now).await? {
+            continue;
+        }
+        let Some((_, bot)) = bots
+            .iter()
+            .find(|(id, _)| Some(*id) == stats.bot_id)
+            .or(bots.first())
+        else {
+            break;
+        };
+
+        let tr = i18n::get_translations(&language);
+        let mut text = tr.weekly_digest.replace("{}", &stats.cleaned.to_string());
+        for (title, top) in [
+            (tr.weekly_top_providers, &stats.top_providers),
+            (tr.weekly_top_domains, &stats.top_domains),
+        ] {
+            if top.is_empty() {
+                continue;
+            }
+            text.push_str(title);
+            for (name, count) in top {
+                text.push_str(&format!("\n• {} ({})", html::escape(name), count));
+            }
+        }
+        text.push_str(tr.weekly_digest_footer);
+        match bot
+            .send_message(ChatId(user_id), text, SendOptions::default())
+            .await
+        {
+            Ok(()) => sent += 1,
+            Err(e) => tracing::warn!(error = %e, user_id, "Failed to send weekly digest"),
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messenger::MockMessenger;
+    use crate::models::{Actor, ChatConfig, UserConfig};
+
+    #[tokio::test]
+    async fn digest_chats_get_one_summary() {
+        let db = Db::test("digest-daily").await;
+        for (chat_id, mode) in [(-1, "digest"), (-2, "reply"), (-3, "digest")] {
+            let chat = ChatConfig {
+                chat_id,
+                mode: mode.into(),
+                ..Default::default()
+            };
+            db.save_chat_config(&chat, Actor::system()).await.unwrap();
+        }
+        for (chat_id, provider) in [(-1, "amazon"), (-1, "amazon"), (-1, "google"), (-2, "x")] {
+            db.log_cleaned_link(
+                7,
+                Some(chat_id),
+                Some(2),
+                "a",
+                "https://a.com/",
+                provider,
+                "rules",
+            )
+            .await
+            .unwrap();
+        }
+        let bots = [(1, MockMessenger::default()), (2, MockMessenger::default())];
+        let now = unix_now().unwrap() + 1;
+
+        assert_eq!(send_digests(&bots, &db, now).await.unwrap(), 1);
+        let sent = bots[1].1.sent.lock().unwrap().clone();
+        assert!(bots[0].1.sent.lock().unwrap().is_empty());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(-1));
+        assert!(sent[0].1.contains('3'));
+        assert!(sent[0].1.contains("<b>amazon</b> (2)"));
+        // Nothing new since the last digest.
+        assert_eq!(send_digests(&bots, &db, now + 10).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn opted_in_users_get_a_weekly_summary() {
+        let db = Db::test("digest-weekly").await;
+        for (user_id, digest_enabled) in [(7, 1), (8, 0)] {
+            let config = UserConfig {
+                user_id,
+                digest_enabled,
+                ..Default::default()
+            };
+            db.save_user_config(&config, Actor::system()).await.unwrap();
+            for (provider, url) in [
+                ("amazon", "https://amazon.com/"),
+                ("amazon", "https://amazon.com/"),
+                ("google", "https://google.com/"),
+            ] {
+                db.log_cleaned_link(user_id, None, Some(2), "a", url, provider, "rules")
+                    .await
+                    .unwrap();
+            }
+        }
+        let bots = [(1, MockMessenger::default()), (2, MockMessenger::default())];
+        let now = unix_now().unwrap() + 1;
+
+        assert_eq!(send_weekly_digests(&bots, &db, now).await.unwrap(), 1);
+        let sent = bots[1].1.sent.lock().unwrap().clone();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, ChatId(7));
+        assert!(sent[0].1.contains("I cleaned 3 link(s)"));
+        assert!(sent[0].1.contains("• amazon (2)\n• google (1)"));
+        assert!(sent[0].1.contains("• amazon.com (2)\n• google.com (1)"));
+        assert_eq!(send_weekly_digests(&bots, &db, now + 10).await.unwrap(), 0);
+    }
+}
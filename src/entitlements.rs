@@ -0,0 +1,111 @@
+//! Per-plan limits. The AI quota, custom rule limit and history retention
+//! are all looked up here from the user's plan instead of being read from
+//! the configuration at each call site.
+
+use crate::config::Config;
+use crate::db::Db;
+use crate::models::Plan;
+use anyhow::Result;
+
+/// What a plan allows. Zero quotas and retention mean unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub ai_daily_quota: i64,
+    pub max_custom_rules: i64,
+    pub history_retention_days: i64,
+}
+
+pub fn limits(plan: Plan, config: &Config) -> Limits {
+    match plan {
+        Plan::Free => Limits {
+            ai_daily_quota: config.ai_daily_quota,
+            max_custom_rules: config.max_custom_rules,
+            history_retention_days: config.history_retention_days,
+        },
+        Plan::Premium => Limits {
+            ai_daily_quota: config.premium_ai_daily_quota,
+            max_custom_rules: config.premium_max_custom_rules,
+            history_retention_days: 0,
+        },
+        Plan::Unlimited => Limits {
+            ai_daily_quota: 0,
+            max_custom_rules: i64::MAX,
+            history_retention_days: 0,
+        },
+    }
+}
+
+/// The user's active plan, or `DEFAULT_PLAN`. Lookup failures fall back to
+/// the default plan rather than blocking the user.
+pub async fn user_plan(db: &Db, config: &Config, user_id: i64) -> Plan {
+    match db.get_active_plan(user_id).await {
+        Ok(plan) => plan.unwrap_or(config.default_plan),
+        Err(e) => {
+            tracing::warn!(error = %e, user_id, "Failed to read entitlement");
+            config.default_plan
+        }
+    }
+}
+
+pub async fn user_limits(db: &Db, config: &Config, user_id: i64) -> Limits {
+    limits(user_plan(db, config, user_id).await, config)
+}
+
+/// Deletes history past each plan's retention period.
+pub async fn purge_expired_history(db: &Db, config: &Config) -> Result<u64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let mut purged = 0;
+    for plan in Plan::ALL {
+        let days = limits(plan, config).history_retention_days;
+        if days > 0 {
+            purged += db
+                .purge_history_for_plan(plan, config.default_plan, now - days * 86400)
+                .await?;
+        }
+    }
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Actor;
+
+    #[tokio::test]
+    async fn plans_decide_limits_and_retention() {
+        let db = Db::test("entitlements").await;
+        let mut config = Config::load(None);
+        config.default_plan = Plan::Free;
+        config.max_custom_rules = 5;
+        config.history_retention_days = 30;
+
+        db.set_entitlement(2, Plan::Premium, None, Actor::system())
+            .await
+            .unwrap();
+        db.set_entitlement(3, Plan::Unlimited, Some(1), Actor::system())
+            .await
+            .unwrap();
+        assert_eq!(user_plan(&db, &config, 1).await, Plan::Free);
+        assert_eq!(user_plan(&db, &config, 2).await, Plan::Premium);
+        // Expired entitlements fall back to the default plan.
+        assert_eq!(user_plan(&db, &config, 3).await, Plan::Free);
+        assert_eq!(user_limits(&db, &config, 1).await.max_custom_rules, 5);
+
+        for user_id in [1, 2] {
+            db.log_cleaned_link(user_id, None, None, "a", "https://a.com/", "p", "rules")
+                .await
+                .unwrap();
+        }
+        // Nothing is old enough yet; then pretend everything is.
+        assert_eq!(purge_expired_history(&db, &config).await.unwrap(), 0);
+        let purged = db
+            .purge_history_for_plan(Plan::Free, Plan::Free, i64::MAX)
+            .await
+            .unwrap();
+        assert_eq!(purged, 1);
+        assert!(db.get_history(1, 10).await.unwrap().is_empty());
+        assert_eq!(db.get_history(2, 10).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,177 @@
+//! Personal data export: a zip with everything stored about one user, served
+//! by the dashboard's `/dashboard/download-my-data` and the bot's `/export`.
+
+use crate::db::Db;
+use crate::models::CleanedLink;
+use anyhow::{Context, Result};
+use futures::stream::{BoxStream, StreamExt};
+use serde::Serialize;
+use std::io::{Cursor, Write};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Builds the archive for `user_id`: configuration, chats, custom rules and
+/// the full cleaning history as both JSON and CSV.
+pub async fn export_user_data(db: &Db, user_id: i64) -> Result<Vec<u8>> {
+    let config = db.get_user_config(user_id).await?;
+    let chats = db.get_chats_for_user(user_id).await?;
+    let rules = db.get_custom_rules(user_id).await?;
+    let history = db.get_history(user_id, i64::MAX).await?;
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let files = [
+        ("config.json", serde_json::to_vec_pretty(&config)?),
+        ("chats.json", serde_json::to_vec_pretty(&chats)?),
+        ("custom_rules.json", serde_json::to_vec_pretty(&rules)?),
+        ("history.json", serde_json::to_vec_pretty(&history)?),
+        ("history.csv", history_csv(&history).into_bytes()),
+    ];
+    for (name, contents) in files {
+        zip.start_file(name, options)
+            .with_context(|| format!("Failed to add {} to export", name))?;
+        zip.write_all(&contents)?;
+    }
+    Ok(zip
+        .finish()
+        .context("Failed to finish export archive")?
+        .into_inner())
+}
+
+/// File name offered to the user, e.g. `clear_urls_42.zip`.
+pub fn archive_name(user_id: i64) -> String {
+    format!("clear_urls_{}.zip", user_id)
+}
+
+fn history_csv(history: &[CleanedLink]) -> String {
+    let mut csv = String::from(
+        "id,timestamp,chat_id,original_url,cleaned_url,provider,domain,cleaning_source\r\n",
+    );
+    for link in history {
+        let fields = [
+            link.id.to_string(),
+            link.timestamp.to_string(),
+            link.chat_id.map(|id| id.to_string()).unwrap_or_default(),
+            link.original_url.clone(),
+            link.cleaned_url.clone(),
+            link.provider_name.clone().unwrap_or_default(),
+            link.domain.clone().unwrap_or_default(),
+            link.cleaning_source.clone().unwrap_or_default(),
+        ];
+        csv.push_str(&csv_line(&fields));
+    }
+    csv
+}
+
+/// One cleaning as exported for analytics pipelines, with the fields of
+/// the live cleaning events.
+#[derive(Debug, Serialize)]
+pub struct CleaningEvent {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: Option<i64>,
+    pub bot_id: Option<i64>,
+    pub timestamp: i64,
+    pub original_url: String,
+    pub cleaned_url: String,
+    pub provider_name: Option<String>,
+    pub domain: Option<String>,
+    pub cleaning_source: Option<String>,
+    pub redirect_chain: Option<Vec<String>>,
+}
+
+impl From<CleanedLink> for CleaningEvent {
+    fn from(link: CleanedLink) -> Self {
+        Self {
+            id: link.id,
+            user_id: link.user_id,
+            chat_id: link.chat_id,
+            bot_id: link.bot_id,
+            timestamp: link.timestamp,
+            original_url: link.original_url,
+            cleaned_url: link.cleaned_url,
+            provider_name: link.provider_name,
+            domain: link.domain,
+            cleaning_source: link.cleaning_source,
+            redirect_chain: link
+                .redirect_chain
+                .and_then(|chain| serde_json::from_str(&chain).ok()),
+        }
+    }
+}
+
+/// Encodes cleanings as JSON Lines, one [`CleaningEvent`] per line, as
+/// they are read.
+pub fn json_lines(
+    links: BoxStream<'static, Result<CleanedLink>>,
+) -> BoxStream<'static, Result<String>> {
+    links
+        .map(|link| {
+            let line = serde_json::to_string(&CleaningEvent::from(link?))?;
+            Ok(format!("{}\n", line))
+        })
+        .boxed()
+}
+
+/// Joins fields into one CSV line, including the trailing line break.
+pub fn csv_line<S: AsRef<str>>(fields: &[S]) -> String {
+    let row: Vec<String> = fields.iter().map(|f| csv_field(f.as_ref())).collect();
+    format!("{}\r\n", row.join(","))
+}
+
+/// Quotes a field when it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields_only_when_needed() {
+        assert_eq!(csv_field("https://a.com/?x=1"), "https://a.com/?x=1");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[tokio::test]
+    async fn archive_contains_every_file() {
+        let db = Db::test("exporter-archive").await;
+        db.log_cleaned_link(
+            7,
+            None,
+            None,
+            "https://a.com/?utm=1",
+            "https://a.com/",
+            "p",
+            "rules",
+        )
+        .await
+        .unwrap();
+
+        let bytes = export_user_data(&db, 7).await.unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort_unstable();
+        assert_eq!(
+            names,
+            [
+                "chats.json",
+                "config.json",
+                "custom_rules.json",
+                "history.csv",
+                "history.json"
+            ]
+        );
+        let mut csv = String::new();
+        std::io::Read::read_to_string(&mut archive.by_name("history.csv").unwrap(), &mut csv)
+            .unwrap();
+        assert_eq!(csv.lines().count(), 2);
+        assert!(csv.contains("https://a.com/?utm=1"));
+    }
+}
@@ -0,0 +1,47 @@
+//! Dead-man monitoring: pings `HEARTBEAT_URL` on a schedule, but only while
+//! every supervised task (bot dispatchers, web server, ...) is running and
+//! the database answers. The monitor alerts when the pings stop.
+
+use crate::{config::SharedConfig, db::Db, supervisor::Supervisor};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// Runs until the process exits. Returns immediately when `HEARTBEAT_URL` is
+/// not configured.
+pub async fn run_heartbeat(supervisor: Supervisor, db: Db, config: SharedConfig) -> Result<()> {
+    let snapshot = config.load_full();
+    if snapshot.heartbeat_url.is_none() {
+        return Ok(());
+    }
+    let client = crate::http::client_builder(snapshot.proxy_url.as_deref())?
+        .timeout(Duration::from_secs(10))
+        .build()
+        .context("Failed to build heartbeat client")?;
+
+    loop {
+        // Give freshly (re)started tasks a full interval before reporting.
+        let secs = config.load().heartbeat_interval_secs.max(10);
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+        let Some(url) = config.load().heartbeat_url.clone() else {
+            continue;
+        };
+
+        if !supervisor.healthy() {
+            tracing::warn!("Skipping heartbeat: a background task is down");
+            continue;
+        }
+        if let Err(e) = db.ping().await {
+            tracing::warn!(error = %e, "Skipping heartbeat: database unavailable");
+            continue;
+        }
+        match client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+        {
+            Ok(_) => tracing::debug!("Heartbeat sent"),
+            Err(e) => tracing::warn!(error = %e, "Failed to send heartbeat"),
+        }
+    }
+}
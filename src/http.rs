@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+
+/// Returns a `reqwest` client builder routed through `proxy_url` when set.
+///
+/// Accepts `http://`, `https://`, `socks5://` and `socks5h://` proxies, with
+/// optional `user:password@` credentials.
+pub fn client_builder(proxy_url: Option<&str>) -> Result<reqwest::ClientBuilder> {
+    let builder = reqwest::Client::builder();
+    match proxy_url {
+        Some(url) => {
+            let proxy = reqwest::Proxy::all(url).context("Invalid PROXY_URL")?;
+            Ok(builder.proxy(proxy))
+        }
+        None => Ok(builder),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn requests_go_through_the_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let len = socket.read(&mut request).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+
+        let client = client_builder(Some(&proxy)).unwrap().build().unwrap();
+        let response = client
+            .get("http://example.invalid/page")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+        assert!(server
+            .await
+            .unwrap()
+            .starts_with("GET http://example.invalid/page HTTP/1.1"));
+
+        assert!(client_builder(Some("not a proxy")).is_err());
+    }
+}
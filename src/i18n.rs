@@ -1,3 +1,27 @@
+//! Built-in locales, plus admin overrides stored in `translations_overrides`
+//! and layered over them at lookup time.
+
+use crate::models::TranslationOverride;
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
+
+/// Overrides keyed by `(lang, key)`, where `lang` may be `*`.
+static OVERRIDES: LazyLock<RwLock<HashMap<(String, String), &'static str>>> =
+    LazyLock::new(|| RwLock::new(HashMap::new()));
+
+/// Locale codes with built-in strings; `*` targets all of them.
+pub const LANGUAGES: &[&str] = &["en", "it"];
+
+/// The locale for a Telegram `language_code` such as `it` or `en-GB`, if the
+/// bot speaks it.
+pub fn language_for_code(code: &str) -> Option<&'static str> {
+    let primary = code.split(['-', '_']).next().unwrap_or(code);
+    LANGUAGES
+        .iter()
+        .find(|l| l.eq_ignore_ascii_case(primary))
+        .copied()
+}
+
 pub struct Translations {
     pub welcome: &'static str,
     pub help_text: &'static str,
@@ -25,13 +49,471 @@ pub struct Translations {
     pub group_activated: &'static str,
     pub unknown: &'static str,
     pub open_web_app: &'static str,
+    pub group_stats_text: &'static str,
+    pub group_only: &'static str,
+    pub top_domains_text: &'static str,
+    pub no_history: &'static str,
+    pub history_cleared: &'static str,
+    pub history_restored: &'static str,
+    pub nothing_to_undo: &'static str,
+    pub generic_error: &'static str,
+    pub rules_title: &'static str,
+    pub rule_added: &'static str,
+    pub rule_usage: &'static str,
+    pub rule_duplicate: &'static str,
+    pub rule_limit: &'static str,
+    pub config_reloaded: &'static str,
+    pub debug_enabled: &'static str,
+    pub debug_disabled: &'static str,
+    pub debug_usage: &'static str,
+    pub usage_title: &'static str,
+    pub ai_usage_text: &'static str,
+    pub ai_stats_text: &'static str,
+    pub owner_only: &'static str,
+    pub risk_warning: &'static str,
+    pub group_ai_enabled: &'static str,
+    pub group_ai_disabled: &'static str,
+    pub group_ai_usage: &'static str,
+    pub translation_usage: &'static str,
+    pub translation_saved: &'static str,
+    pub translation_removed: &'static str,
+    pub translation_invalid: &'static str,
+    pub export_caption: &'static str,
+    pub private_only: &'static str,
+    pub forgetme_confirm: &'static str,
+    pub forgetme_done: &'static str,
+    pub plan_usage: &'static str,
+    pub plan_status: &'static str,
+    pub plan_until: &'static str,
+    pub settings_link: &'static str,
+    pub settings_link_sent: &'static str,
+    pub settings_dm_failed: &'static str,
+    pub dashboard_unavailable: &'static str,
+    pub login_link: &'static str,
+    pub admin_confirmation_code: &'static str,
+    pub ownership_offer: &'static str,
+    pub ownership_accept: &'static str,
+    pub ownership_taken: &'static str,
+    pub ownership_unavailable: &'static str,
+    pub cleaned_from: &'static str,
+    pub dm_start_bot: &'static str,
+    pub digest_summary: &'static str,
+    pub digest_top_provider: &'static str,
+    pub redirect_chain: &'static str,
+    pub level_usage: &'static str,
+    pub level_set: &'static str,
+    pub instant_view: &'static str,
+    pub share: &'static str,
+    pub inline_cleaned: &'static str,
+    pub inline_already_clean: &'static str,
+    pub clean_usage: &'static str,
+    pub clean_result: &'static str,
+    pub clean_removed: &'static str,
+    pub clean_nothing: &'static str,
+    pub settings_menu: &'static str,
+    pub setting_enabled: &'static str,
+    pub setting_ai: &'static str,
+    pub setting_mode: &'static str,
+    pub setting_language: &'static str,
+    pub setting_on: &'static str,
+    pub setting_off: &'static str,
+    pub settings_saved: &'static str,
+    pub ignore_usage: &'static str,
+    pub ignore_added: &'static str,
+    pub ignore_limit: &'static str,
+    pub unignore_removed: &'static str,
+    pub unignore_missing: &'static str,
+    pub chat_admins_only: &'static str,
+    pub reposted_for: &'static str,
+    pub reaction_hint: &'static str,
+    pub show_cleaned: &'static str,
+    pub links_expired: &'static str,
+    pub mode_usage: &'static str,
+    pub mode_set: &'static str,
+    pub held_links: &'static str,
+    pub held_links_more: &'static str,
+    pub language_usage: &'static str,
+    pub language_set: &'static str,
+    pub privacy_on: &'static str,
+    pub privacy_off: &'static str,
+    pub global_stats_text: &'static str,
+    pub rules_embedded: &'static str,
+    pub rules_age: &'static str,
+    pub weekly_digest: &'static str,
+    pub weekly_top_providers: &'static str,
+    pub weekly_top_domains: &'static str,
+    pub weekly_digest_footer: &'static str,
+    pub setting_digest: &'static str,
+    pub group_setup: &'static str,
+    pub group_setup_prompt: &'static str,
+    pub group_setup_open: &'static str,
+    pub setting_level: &'static str,
+    pub onboarding: &'static str,
+    pub whitelist_usage: &'static str,
+    pub whitelist_added: &'static str,
+    pub whitelist_limit: &'static str,
+    pub unwhitelist_removed: &'static str,
+    pub unwhitelist_missing: &'static str,
+    pub topic_usage: &'static str,
+    pub topic_on: &'static str,
+    pub topic_off: &'static str,
+    pub forum_only: &'static str,
+    pub config_reload_failed: &'static str,
+    pub rule_removed: &'static str,
+    pub rule_not_found: &'static str,
+    pub rule_remove_usage: &'static str,
+    pub mastodon_paused: &'static str,
+    pub mastodon_resumed: &'static str,
+    pub mastodon_cleaned: &'static str,
+    pub usage_ai: &'static str,
+}
+
+/// Every string a translation override can replace.
+pub const KEYS: &[&str] = &[
+    "welcome",
+    "help_text",
+    "stats_text",
+    "cleaned_links",
+    "open_dashboard",
+    "cleaned_for",
+    "d_title",
+    "d_global_settings",
+    "d_enable_bot",
+    "d_enable_ai",
+    "d_action_mode",
+    "d_mode_reply",
+    "d_mode_delete",
+    "d_ignored_domains",
+    "d_save",
+    "d_history",
+    "d_managed_chats",
+    "d_no_chats",
+    "d_links_cleaned",
+    "d_active_chats",
+    "group_activated",
+    "unknown",
+    "open_web_app",
+    "group_stats_text",
+    "group_only",
+    "top_domains_text",
+    "no_history",
+    "history_cleared",
+    "history_restored",
+    "nothing_to_undo",
+    "generic_error",
+    "rules_title",
+    "rule_added",
+    "rule_usage",
+    "rule_duplicate",
+    "rule_limit",
+    "config_reloaded",
+    "debug_enabled",
+    "debug_disabled",
+    "debug_usage",
+    "usage_title",
+    "ai_usage_text",
+    "ai_stats_text",
+    "owner_only",
+    "risk_warning",
+    "group_ai_enabled",
+    "group_ai_disabled",
+    "group_ai_usage",
+    "translation_usage",
+    "translation_saved",
+    "translation_removed",
+    "translation_invalid",
+    "export_caption",
+    "private_only",
+    "forgetme_confirm",
+    "forgetme_done",
+    "plan_usage",
+    "plan_status",
+    "plan_until",
+    "settings_link",
+    "settings_link_sent",
+    "settings_dm_failed",
+    "dashboard_unavailable",
+    "login_link",
+    "admin_confirmation_code",
+    "ownership_offer",
+    "ownership_accept",
+    "ownership_taken",
+    "ownership_unavailable",
+    "cleaned_from",
+    "dm_start_bot",
+    "digest_summary",
+    "digest_top_provider",
+    "redirect_chain",
+    "level_usage",
+    "level_set",
+    "instant_view",
+    "share",
+    "inline_cleaned",
+    "inline_already_clean",
+    "clean_usage",
+    "clean_result",
+    "clean_removed",
+    "clean_nothing",
+    "settings_menu",
+    "setting_enabled",
+    "setting_ai",
+    "setting_mode",
+    "setting_language",
+    "setting_on",
+    "setting_off",
+    "settings_saved",
+    "ignore_usage",
+    "ignore_added",
+    "ignore_limit",
+    "unignore_removed",
+    "unignore_missing",
+    "chat_admins_only",
+    "reposted_for",
+    "reaction_hint",
+    "show_cleaned",
+    "links_expired",
+    "mode_usage",
+    "mode_set",
+    "held_links",
+    "held_links_more",
+    "language_usage",
+    "language_set",
+    "privacy_on",
+    "privacy_off",
+    "global_stats_text",
+    "rules_embedded",
+    "rules_age",
+    "weekly_digest",
+    "weekly_top_providers",
+    "weekly_top_domains",
+    "weekly_digest_footer",
+    "setting_digest",
+    "group_setup",
+    "group_setup_prompt",
+    "group_setup_open",
+    "setting_level",
+    "onboarding",
+    "whitelist_usage",
+    "whitelist_added",
+    "whitelist_limit",
+    "unwhitelist_removed",
+    "unwhitelist_missing",
+    "topic_usage",
+    "topic_on",
+    "topic_off",
+    "forum_only",
+    "config_reload_failed",
+    "rule_removed",
+    "rule_not_found",
+    "rule_remove_usage",
+    "mastodon_paused",
+    "mastodon_resumed",
+    "mastodon_cleaned",
+    "usage_ai",
+];
+
+impl Translations {
+    fn field_mut(&mut self, key: &str) -> Option<&mut &'static str> {
+        Some(match key {
+            "welcome" => &mut self.welcome,
+            "help_text" => &mut self.help_text,
+            "stats_text" => &mut self.stats_text,
+            "cleaned_links" => &mut self.cleaned_links,
+            "open_dashboard" => &mut self.open_dashboard,
+            "cleaned_for" => &mut self.cleaned_for,
+            "d_title" => &mut self.d_title,
+            "d_global_settings" => &mut self.d_global_settings,
+            "d_enable_bot" => &mut self.d_enable_bot,
+            "d_enable_ai" => &mut self.d_enable_ai,
+            "d_action_mode" => &mut self.d_action_mode,
+            "d_mode_reply" => &mut self.d_mode_reply,
+            "d_mode_delete" => &mut self.d_mode_delete,
+            "d_ignored_domains" => &mut self.d_ignored_domains,
+            "d_save" => &mut self.d_save,
+            "d_history" => &mut self.d_history,
+            "d_managed_chats" => &mut self.d_managed_chats,
+            "d_no_chats" => &mut self.d_no_chats,
+            "d_links_cleaned" => &mut self.d_links_cleaned,
+            "d_active_chats" => &mut self.d_active_chats,
+            "group_activated" => &mut self.group_activated,
+            "unknown" => &mut self.unknown,
+            "open_web_app" => &mut self.open_web_app,
+            "group_stats_text" => &mut self.group_stats_text,
+            "group_only" => &mut self.group_only,
+            "top_domains_text" => &mut self.top_domains_text,
+            "no_history" => &mut self.no_history,
+            "history_cleared" => &mut self.history_cleared,
+            "history_restored" => &mut self.history_restored,
+            "nothing_to_undo" => &mut self.nothing_to_undo,
+            "generic_error" => &mut self.generic_error,
+            "rules_title" => &mut self.rules_title,
+            "rule_added" => &mut self.rule_added,
+            "rule_usage" => &mut self.rule_usage,
+            "rule_duplicate" => &mut self.rule_duplicate,
+            "rule_limit" => &mut self.rule_limit,
+            "config_reloaded" => &mut self.config_reloaded,
+            "debug_enabled" => &mut self.debug_enabled,
+            "debug_disabled" => &mut self.debug_disabled,
+            "debug_usage" => &mut self.debug_usage,
+            "usage_title" => &mut self.usage_title,
+            "ai_usage_text" => &mut self.ai_usage_text,
+            "ai_stats_text" => &mut self.ai_stats_text,
+            "owner_only" => &mut self.owner_only,
+            "risk_warning" => &mut self.risk_warning,
+            "group_ai_enabled" => &mut self.group_ai_enabled,
+            "group_ai_disabled" => &mut self.group_ai_disabled,
+            "group_ai_usage" => &mut self.group_ai_usage,
+            "translation_usage" => &mut self.translation_usage,
+            "translation_saved" => &mut self.translation_saved,
+            "translation_removed" => &mut self.translation_removed,
+            "translation_invalid" => &mut self.translation_invalid,
+            "export_caption" => &mut self.export_caption,
+            "private_only" => &mut self.private_only,
+            "forgetme_confirm" => &mut self.forgetme_confirm,
+            "forgetme_done" => &mut self.forgetme_done,
+            "plan_usage" => &mut self.plan_usage,
+            "plan_status" => &mut self.plan_status,
+            "plan_until" => &mut self.plan_until,
+            "settings_link" => &mut self.settings_link,
+            "settings_link_sent" => &mut self.settings_link_sent,
+            "settings_dm_failed" => &mut self.settings_dm_failed,
+            "dashboard_unavailable" => &mut self.dashboard_unavailable,
+            "login_link" => &mut self.login_link,
+            "admin_confirmation_code" => &mut self.admin_confirmation_code,
+            "ownership_offer" => &mut self.ownership_offer,
+            "ownership_accept" => &mut self.ownership_accept,
+            "ownership_taken" => &mut self.ownership_taken,
+            "ownership_unavailable" => &mut self.ownership_unavailable,
+            "cleaned_from" => &mut self.cleaned_from,
+            "dm_start_bot" => &mut self.dm_start_bot,
+            "digest_summary" => &mut self.digest_summary,
+            "digest_top_provider" => &mut self.digest_top_provider,
+            "redirect_chain" => &mut self.redirect_chain,
+            "level_usage" => &mut self.level_usage,
+            "level_set" => &mut self.level_set,
+            "instant_view" => &mut self.instant_view,
+            "share" => &mut self.share,
+            "inline_cleaned" => &mut self.inline_cleaned,
+            "inline_already_clean" => &mut self.inline_already_clean,
+            "clean_usage" => &mut self.clean_usage,
+            "clean_result" => &mut self.clean_result,
+            "clean_removed" => &mut self.clean_removed,
+            "clean_nothing" => &mut self.clean_nothing,
+            "settings_menu" => &mut self.settings_menu,
+            "setting_enabled" => &mut self.setting_enabled,
+            "setting_ai" => &mut self.setting_ai,
+            "setting_mode" => &mut self.setting_mode,
+            "setting_language" => &mut self.setting_language,
+            "setting_on" => &mut self.setting_on,
+            "setting_off" => &mut self.setting_off,
+            "settings_saved" => &mut self.settings_saved,
+            "ignore_usage" => &mut self.ignore_usage,
+            "ignore_added" => &mut self.ignore_added,
+            "ignore_limit" => &mut self.ignore_limit,
+            "unignore_removed" => &mut self.unignore_removed,
+            "unignore_missing" => &mut self.unignore_missing,
+            "chat_admins_only" => &mut self.chat_admins_only,
+            "reposted_for" => &mut self.reposted_for,
+            "reaction_hint" => &mut self.reaction_hint,
+            "show_cleaned" => &mut self.show_cleaned,
+            "links_expired" => &mut self.links_expired,
+            "mode_usage" => &mut self.mode_usage,
+            "mode_set" => &mut self.mode_set,
+            "held_links" => &mut self.held_links,
+            "held_links_more" => &mut self.held_links_more,
+            "language_usage" => &mut self.language_usage,
+            "language_set" => &mut self.language_set,
+            "privacy_on" => &mut self.privacy_on,
+            "privacy_off" => &mut self.privacy_off,
+            "global_stats_text" => &mut self.global_stats_text,
+            "rules_embedded" => &mut self.rules_embedded,
+            "rules_age" => &mut self.rules_age,
+            "weekly_digest" => &mut self.weekly_digest,
+            "weekly_top_providers" => &mut self.weekly_top_providers,
+            "weekly_top_domains" => &mut self.weekly_top_domains,
+            "weekly_digest_footer" => &mut self.weekly_digest_footer,
+            "setting_digest" => &mut self.setting_digest,
+            "group_setup" => &mut self.group_setup,
+            "group_setup_prompt" => &mut self.group_setup_prompt,
+            "group_setup_open" => &mut self.group_setup_open,
+            "setting_level" => &mut self.setting_level,
+            "onboarding" => &mut self.onboarding,
+            "whitelist_usage" => &mut self.whitelist_usage,
+            "whitelist_added" => &mut self.whitelist_added,
+            "whitelist_limit" => &mut self.whitelist_limit,
+            "unwhitelist_removed" => &mut self.unwhitelist_removed,
+            "unwhitelist_missing" => &mut self.unwhitelist_missing,
+            "topic_usage" => &mut self.topic_usage,
+            "topic_on" => &mut self.topic_on,
+            "topic_off" => &mut self.topic_off,
+            "forum_only" => &mut self.forum_only,
+            "config_reload_failed" => &mut self.config_reload_failed,
+            "rule_removed" => &mut self.rule_removed,
+            "rule_not_found" => &mut self.rule_not_found,
+            "rule_remove_usage" => &mut self.rule_remove_usage,
+            "mastodon_paused" => &mut self.mastodon_paused,
+            "mastodon_resumed" => &mut self.mastodon_resumed,
+            "mastodon_cleaned" => &mut self.mastodon_cleaned,
+            "usage_ai" => &mut self.usage_ai,
+            _ => return None,
+        })
+    }
+}
+
+/// Replaces the active overrides, typically with the rows of the
+/// `translations_overrides` table. Translations hand out `&'static str`, so
+/// new values are leaked; unchanged ones are reused, which keeps the leak
+/// bounded by the number of edits.
+pub fn set_overrides(overrides: &[TranslationOverride]) {
+    let Ok(mut active) = OVERRIDES.write() else {
+        return;
+    };
+    let mut next = HashMap::new();
+    for o in overrides {
+        if !KEYS.contains(&o.key.as_str()) {
+            tracing::warn!(key = %o.key, "Ignoring override of unknown translation key");
+            continue;
+        }
+        let id = (o.lang.clone(), o.key.clone());
+        let value = match active.get(&id) {
+            Some(current) if *current == o.value => *current,
+            _ => &*Box::leak(o.value.clone().into_boxed_str()),
+        };
+        next.insert(id, value);
+    }
+    *active = next;
+}
+
+/// Loads the overrides from the database and makes them active.
+pub async fn load_overrides(db: &crate::db::Db) -> anyhow::Result<usize> {
+    let overrides = db.get_translation_overrides().await?;
+    set_overrides(&overrides);
+    Ok(overrides.len())
 }
 
+/// Strings for `lang`, with the `*` overrides and then the ones for `lang`
+/// applied on top of the built-in locale.
 pub fn get_translations(lang: &str) -> Translations {
+    // Unknown languages fall back to English, and so do their overrides.
+    let locale = LANGUAGES.iter().find(|l| **l == lang).unwrap_or(&"en");
+    let mut tr = builtin_translations(locale);
+    if let Ok(overrides) = OVERRIDES.read() {
+        for scope in ["*", locale] {
+            for ((_, key), value) in overrides.iter().filter(|((l, _), _)| l == scope) {
+                if let Some(field) = tr.field_mut(key) {
+                    *field = value;
+                }
+            }
+        }
+    }
+    tr
+}
+
+fn builtin_translations(lang: &str) -> Translations {
     match lang {
         "it" => Translations {
             welcome: "<b>Benvenuto nel gestore ClearURLs!</b>\n\nIl tuo ID utente è: <code>{}</code>\n\nPuoi configurare il bot e gestire i tuoi link puliti direttamente dal dashboard web protetto.",
-            help_text: "<b>Guida ClearURLs Bot</b> 🛡️\n\nQuesto bot rimuove automaticamente i parametri di tracciamento dai link che invii.\n\n<b>Comandi:</b>\n/start - Inizia e ricevi il link al dashboard\n/help - Mostra questo messaggio\n/stats - Visualizza le tue statistiche di pulizia\n\nPuoi usarmi in chat privata o aggiungermi ai gruppi!",
+            help_text: "<b>Guida ClearURLs Bot</b> 🛡️\n\nQuesto bot rimuove automaticamente i parametri di tracciamento dai link che invii.\n\n<b>Comandi:</b>\n/start - Inizia e ricevi il link al dashboard\n/login - Ricevi un link per accedere al dashboard\n/help - Mostra questo messaggio\n/clean - Pulisci un link su richiesta\n/stats - Visualizza le tue statistiche di pulizia\n/topdomains - I domini che pulisci più spesso\n/groupstats - Statistiche del gruppo corrente\n/groupai - Attiva o disattiva l'AI per il gruppo\n/level - Scegli quanto a fondo pulire i link\n/mode - Scegli come il bot risponde nel gruppo\n/language - Scegli la lingua del bot\n/privacy - Pulisci i link senza salvarli nella cronologia\n/ignore - Non pulire un dominio nel gruppo (/unignore per riprendere)\n/whitelist - Non pulire i link di un membro del gruppo (/unwhitelist per riprendere)\n/topic - Attiva o disattiva la pulizia in un argomento del forum\n/settings - Le tue impostazioni, o quelle del gruppo nel dashboard\n/clearhistory - Cancella la tua cronologia (/undo per annullare)\n/addrule - Aggiungi un parametro da rimuovere sempre\n/rules - Elenca le tue regole personalizzate\n/export - Scarica tutti i tuoi dati\n/forgetme - Cancella tutti i tuoi dati\n\nPuoi usarmi in chat privata o aggiungermi ai gruppi!",
             stats_text: "<b>Le tue statistiche</b> 📊\n\nLink puliti finora: <b>{}</b>\n\nGrazie per proteggere la tua privacy!",
             cleaned_links: "<b>Link Puliti:</b>\n",
             open_dashboard: "🚀 Apri Dashboard",
@@ -53,10 +535,127 @@ pub fn get_translations(lang: &str) -> Translations {
             group_activated: "🛡️ <b>ClearURLs attivato!</b>\n\nHo iniziato a proteggere il gruppo: <b>{}</b>\n\nPuoi disattivarlo o cambiare modalità dal tuo dashboard.",
             unknown: "Sconosciuto",
             open_web_app: "📱 Apri Web App",
+            group_stats_text: "<b>Statistiche del gruppo</b> 📊\n\nLink puliti in questa chat: <b>{}</b>\nMembri protetti: <b>{}</b>",
+            group_only: "Questo comando funziona solo nei gruppi.",
+            top_domains_text: "<b>I tuoi domini principali</b> 🌐\n\nDomini distinti puliti: <b>{}</b>\n\n",
+            no_history: "Nessun link pulito finora.",
+            history_cleared: "🗑️ Cronologia cancellata. Invia /undo entro {} minuti per ripristinarla.",
+            history_restored: "♻️ Cronologia ripristinata.",
+            nothing_to_undo: "Niente da ripristinare: la finestra per annullare è scaduta.",
+            generic_error: "⚠️ Qualcosa è andato storto, riprova più tardi.",
+            rules_title: "<b>Le tue regole personalizzate</b> 🧩\n\n",
+            rule_added: "✅ Regola aggiunta: i parametri che contengono <code>{}</code> verranno rimossi.",
+            rule_usage: "Uso: <code>/addrule nome_parametro</code>",
+            rule_duplicate: "Questa regola esiste già.",
+            rule_limit: "Hai raggiunto il limite di {} regole personalizzate.",
+            config_reloaded: "🔄 Configurazione ricaricata.",
+            debug_enabled: "🔍 Tracciamento dettagliato attivo per <code>{}</code>.",
+            debug_disabled: "Tracciamento dettagliato disattivato per <code>{}</code>.",
+            debug_usage: "Uso: <code>/debug &lt;id&gt; [off]</code>\nAttivi: {}",
+            usage_title: "<b>Utilizzo negli ultimi 30 giorni</b> 📈\n\n",
+            ai_usage_text: "\n\n🤖 Richieste AI oggi: <b>{}</b> / {} ({} token)",
+            ai_stats_text: "\nLink puliti con l'AI: <b>{}</b> · successo {}% · latenza media {} ms",
+            owner_only: "Solo chi ha aggiunto il bot può cambiare questa impostazione.",
+            risk_warning: "⚠️ <b>Possibile truffa</b> ({}): ",
+            group_ai_enabled: "🤖 AI Deep Scan attivato per questo gruppo.",
+            group_ai_disabled: "AI Deep Scan disattivato per questo gruppo.",
+            group_ai_usage: "Uso: <code>/groupai on</code> oppure <code>/groupai off</code>",
+            translation_usage: "Uso: <code>/translation &lt;lingua|*&gt; &lt;chiave&gt; [testo]</code>\nSenza testo ripristina il messaggio predefinito.\n\n<b>Personalizzazioni attive</b>\n",
+            translation_saved: "✅ Testo <code>{}</code> aggiornato.",
+            translation_removed: "♻️ Testo <code>{}</code> ripristinato.",
+            translation_invalid: "Lingua o chiave sconosciuta. Lingue: {}",
+            export_caption: "📦 Ecco tutti i dati che conservo su di te: configurazione, chat, regole e cronologia.",
+            private_only: "Questo comando funziona solo in chat privata.",
+            forgetme_confirm: "⚠️ Verranno cancellati definitivamente impostazioni, regole personalizzate, cronologia e sessioni del dashboard. I gruppi che hai aggiunto restano configurati.\n\nInvia <code>/forgetme confirm</code> per procedere.",
+            forgetme_done: "🗑️ Tutti i tuoi dati sono stati cancellati.",
+            plan_usage: "Uso: <code>/plan &lt;user_id&gt; [free|premium|unlimited] [giorni]</code>",
+            plan_status: "Utente <code>{}</code>: piano <b>{}</b>",
+            plan_until: " fino al {}",
+            settings_link: "⚙️ Impostazioni di <b>{}</b>: <a href=\"{}\">apri nel dashboard</a>. Il link scade tra 10 minuti.",
+            settings_link_sent: "📬 Ti ho inviato il link alle impostazioni in privato.",
+            settings_dm_failed: "Non riesco a scriverti in privato: avviami in chat privata e riprova.",
+            dashboard_unavailable: "Il dashboard non è configurato su questa istanza.",
+            login_link: "🔑 <a href=\"{}\">Accedi al dashboard</a>. Il link funziona una sola volta e scade tra 5 minuti: non condividerlo.",
+            admin_confirmation_code: "🔐 Codice di conferma per <code>{}</code> su <code>{}</code>: <code>{}</code>\nScade tra 5 minuti. Se non l'hai richiesto tu, qualcuno ha accesso al tuo dashboard.",
+            ownership_offer: "👑 Chi gestiva ClearURLs in <b>{}</b> non è più amministratore del gruppo. Vuoi occupartene tu?",
+            ownership_accept: "Gestisci il gruppo",
+            ownership_taken: "Ora gestisci ClearURLs in questo gruppo.",
+            ownership_unavailable: "Il gruppo ha già un nuovo responsabile, oppure non sei più amministratore.",
+            cleaned_from: "<b>Link puliti da {}:</b>\n",
+            dm_start_bot: "🔒 {}, in questo gruppo invio i link puliti in privato: <a href=\"{}\">avviami</a> per riceverli.",
+            digest_summary: "📊 <b>Riepilogo di oggi</b>: ho pulito {} link in questo gruppo.",
+            digest_top_provider: "\nTracker più frequente: <b>{}</b> ({})",
+            redirect_chain: "↪️ Reindirizzamenti:",
+            level_usage: "Livello di pulizia attuale: <b>{}</b>\n\nUso: <code>/level conservative</code> (solo regole), <code>/level standard</code> oppure <code>/level strict</code> (anche i parametri che sembrano traccianti). Nei gruppi, <code>/level default</code> usa il livello di chi invia il link.",
+            level_set: "✅ Livello di pulizia impostato su <b>{}</b>.",
+            instant_view: "📖 Leggi in Instant View",
+            share: "↗️ Condividi",
+            inline_cleaned: "🧹 Tracciamento rimosso ({})",
+            inline_already_clean: "✅ Link già pulito",
+            clean_usage: "Uso: <code>/clean &lt;link&gt;</code>\nPulisce un link su richiesta, anche dove il bot è disattivato o il dominio è ignorato.",
+            clean_result: "🧹 Pulito con le regole <b>{}</b>:\n<code>{}</code>",
+            clean_removed: "\nParametri rimossi: {}",
+            clean_nothing: "✅ Niente da rimuovere: il link è già pulito.",
+            settings_menu: "⚙️ <b>Le tue impostazioni</b>\nTocca un pulsante per cambiarla.",
+            setting_enabled: "🧹 Pulizia: {}",
+            setting_ai: "🤖 AI Deep Scan: {}",
+            setting_mode: "💬 Modalità: {}",
+            setting_language: "🌐 Lingua: {}",
+            setting_on: "attiva",
+            setting_off: "disattiva",
+            settings_saved: "✅ Salvato",
+            ignore_usage: "Uso: <code>/ignore example.com</code> o <code>/unignore example.com</code> (amministratori della chat).\nIgnorati qui: {}",
+            ignore_added: "🙈 I link a <b>{}</b> non verranno più puliti in questa chat.",
+            ignore_limit: "Questa chat ignora già {} domini, il massimo.",
+            unignore_removed: "🧹 I link a <b>{}</b> vengono di nuovo puliti in questa chat.",
+            unignore_missing: "<b>{}</b> non era ignorato in questa chat.",
+            chat_admins_only: "Solo gli amministratori della chat possono farlo.",
+            reposted_for: "<b>{}</b> ha scritto:\n",
+            reaction_hint: "🧹 Tracciamento rimosso da {} link.",
+            show_cleaned: "Mostra i link puliti",
+            links_expired: "Questi link non sono più disponibili.",
+            mode_usage: "Modalità attuale del gruppo: <b>{}</b>\n\nUso: <code>/mode reply</code> (risponde), <code>delete</code> (cancella e invia i link), <code>repost</code> (cancella e ripubblica il testo), <code>reaction</code> (solo una reazione), <code>dm</code>, <code>digest</code>, <code>dry_run</code> (registra i link nel dashboard senza scrivere nel gruppo) oppure <code>default</code> (la modalità di chi invia il link).",
+            mode_set: "✅ Modalità del gruppo impostata su <b>{}</b>.",
+            held_links: "<b>Link puliti nell'ultimo minuto:</b>\n",
+            held_links_more: "…e altri {}\n",
+            language_usage: "Lingua attuale: <b>{}</b>\n\nUso: <code>/language {}</code>",
+            language_set: "✅ Lingua impostata su <b>{}</b>.",
+            privacy_on: "🔒 Modalità privacy <b>attiva</b>: i link vengono puliti ma non salvati nella cronologia. Il contatore continua a crescere.\n\n/clearhistory cancella quelli già salvati, <code>/privacy off</code> torna a salvarli.",
+            privacy_off: "Modalità privacy <b>disattivata</b>: i link puliti vengono salvati nella cronologia (dashboard, /topdomains, /undo).\n\n<code>/privacy on</code> smette di salvarli.",
+            global_stats_text: "<b>Statistiche globali</b> 🌍\n\nLink puliti: <b>{}</b>\nUtenti: <b>{}</b>\nProvider caricati: <b>{}</b>\nRegole aggiornate: <b>{}</b>\n\n<b>Provider principali (30 giorni)</b>\n",
+            rules_embedded: "mai (regole incorporate)",
+            rules_age: "{} fa",
+            weekly_digest: "📬 <b>La tua settimana</b>: ho pulito {} link per te.",
+            weekly_top_providers: "\n\n<b>Tracker più frequenti</b>",
+            weekly_top_domains: "\n\n<b>Domini più puliti</b>",
+            weekly_digest_footer: "\n\n<i>Puoi disattivare questo riepilogo da /settings.</i>",
+            setting_digest: "📬 Riepilogo settimanale: {}",
+            group_setup: "⚙️ <b>{}</b>\n\nScegli come rispondo nel gruppo, quanto a fondo pulisco i link e se uso l'AI. Ogni pulsante passa al valore successivo.",
+            group_setup_prompt: "Configura il gruppo in chat privata con me:",
+            group_setup_open: "⚙️ Configura",
+            setting_level: "🧽 Livello: {}",
+            onboarding: "👋 Grazie per avermi aggiunto a <b>{}</b>! Rimuovo i parametri di tracciamento dai link inviati qui.\n\nScegli come rispondo (solo per gli amministratori del gruppo); <code>/mode</code> la cambia in seguito.",
+            whitelist_usage: "Uso: rispondi al messaggio di un membro con <code>/whitelist</code>, oppure <code>/whitelist @utente</code>; <code>/unwhitelist @utente</code> per toglierlo (amministratori della chat).\nEsclusi qui: {}",
+            whitelist_added: "🙈 I link di {} non verranno più puliti in questa chat.",
+            whitelist_limit: "Questa chat esclude già {} membri, il massimo.",
+            unwhitelist_removed: "🧹 I link di {} vengono di nuovo puliti in questa chat.",
+            unwhitelist_missing: "{} non era escluso in questa chat.",
+            topic_usage: "Uso: <code>/topic on</code> o <code>/topic off</code> in un argomento (amministratori della chat). Se un argomento è attivo, vengono puliti solo gli argomenti attivi.\nPulizia in questo argomento: {}",
+            topic_on: "🧹 I link in questo argomento vengono puliti.",
+            topic_off: "🙈 I link in questo argomento non verranno più puliti.",
+            forum_only: "Questo comando funziona solo nei gruppi con argomenti.",
+            config_reload_failed: "⚠️ Configurazione non ricaricata, resta quella attuale:\n<code>{}</code>",
+            rule_removed: "🗑️ Regola rimossa: <code>{}</code>.",
+            rule_not_found: "Non hai nessuna regola <code>{}</code>. Usa /rules per vederle.",
+            rule_remove_usage: "Per rimuovere una regola: <code>/delrule nome_parametro</code>",
+            mastodon_paused: "In pausa: non pulirò i link nelle tue menzioni finché non mi scrivi \"start\".",
+            mastodon_resumed: "Ripreso: menzionami con un link e ti risponderò con una copia pulita.",
+            mastodon_cleaned: "Link puliti:",
+            usage_ai: "\nIA: {} richieste, {} token",
         },
         _ => Translations {
             welcome: "<b>Welcome to ClearURLs Manager!</b>\n\nYour User ID is: <code>{}</code>\n\nYou can configure the bot and manage your cleaned links directly from the secure web dashboard.",
-            help_text: "<b>ClearURLs Bot Guide</b> 🛡️\n\nThis bot automatically removes tracking parameters from links you send.\n\n<b>Commands:</b>\n/start - Start and get the dashboard link\n/help - Show this message\n/stats - View your cleaning statistics\n\nYou can use me in private chats or add me to groups!",
+            help_text: "<b>ClearURLs Bot Guide</b> 🛡️\n\nThis bot automatically removes tracking parameters from links you send.\n\n<b>Commands:</b>\n/start - Start and get the dashboard link\n/login - Get a link to sign in to the dashboard\n/help - Show this message\n/clean - Clean one link on request\n/stats - View your cleaning statistics\n/topdomains - The domains you clean most often\n/groupstats - Statistics for the current group\n/groupai - Turn AI on or off for the group\n/level - Choose how thoroughly links are cleaned\n/mode - Choose how the bot answers in the group\n/language - Choose the bot's language\n/privacy - Clean links without keeping them in your history\n/ignore - Stop cleaning a domain in the group (/unignore to resume)\n/whitelist - Stop cleaning a member's links in the group (/unwhitelist to resume)\n/topic - Turn cleaning on or off in a forum topic\n/settings - Your settings, or the group's in the dashboard\n/clearhistory - Clear your history (/undo to restore it)\n/addrule - Add a parameter to always strip\n/rules - List your custom rules\n/export - Download all your data\n/forgetme - Delete all your data\n\nYou can use me in private chats or add me to groups!",
             stats_text: "<b>Your Statistics</b> 📊\n\nLinks cleaned so far: <b>{}</b>\n\nThank you for protecting your privacy!",
             cleaned_links: "<b>Cleaned Link(s):</b>\n",
             open_dashboard: "🚀 Open Dashboard",
@@ -78,6 +677,171 @@ pub fn get_translations(lang: &str) -> Translations {
             group_activated: "🛡️ <b>ClearURLs activated!</b>\n\nI have started protecting the group: <b>{}</b>\n\nYou can disable it or change settings from your dashboard.",
             unknown: "Unknown",
             open_web_app: "📱 Open Web App",
+            group_stats_text: "<b>Group Statistics</b> 📊\n\nLinks cleaned in this chat: <b>{}</b>\nMembers protected: <b>{}</b>",
+            group_only: "This command only works in groups.",
+            top_domains_text: "<b>Your Top Domains</b> 🌐\n\nDistinct domains cleaned: <b>{}</b>\n\n",
+            no_history: "No links cleaned yet.",
+            history_cleared: "🗑️ History cleared. Send /undo within {} minutes to restore it.",
+            history_restored: "♻️ History restored.",
+            nothing_to_undo: "Nothing to restore: the undo window has expired.",
+            generic_error: "⚠️ Something went wrong, please try again later.",
+            rules_title: "<b>Your Custom Rules</b> 🧩\n\n",
+            rule_added: "✅ Rule added: parameters containing <code>{}</code> will be removed.",
+            rule_usage: "Usage: <code>/addrule parameter_name</code>",
+            rule_duplicate: "This rule already exists.",
+            rule_limit: "You have reached the limit of {} custom rules.",
+            config_reloaded: "🔄 Configuration reloaded.",
+            debug_enabled: "🔍 Verbose tracing enabled for <code>{}</code>.",
+            debug_disabled: "Verbose tracing disabled for <code>{}</code>.",
+            debug_usage: "Usage: <code>/debug &lt;id&gt; [off]</code>\nActive: {}",
+            usage_title: "<b>Usage in the last 30 days</b> 📈\n\n",
+            ai_usage_text: "\n\n🤖 AI requests today: <b>{}</b> / {} ({} tokens)",
+            ai_stats_text: "\nAI-assisted cleanings: <b>{}</b> · {}% success · {} ms average latency",
+            owner_only: "Only the member who added the bot can change this setting.",
+            risk_warning: "⚠️ <b>Possible scam</b> ({}): ",
+            group_ai_enabled: "🤖 AI Deep Scan enabled for this group.",
+            group_ai_disabled: "AI Deep Scan disabled for this group.",
+            group_ai_usage: "Usage: <code>/groupai on</code> or <code>/groupai off</code>",
+            translation_usage: "Usage: <code>/translation &lt;lang|*&gt; &lt;key&gt; [text]</code>\nWithout text the built-in message is restored.\n\n<b>Active overrides</b>\n",
+            translation_saved: "✅ Text <code>{}</code> updated.",
+            translation_removed: "♻️ Text <code>{}</code> restored.",
+            translation_invalid: "Unknown language or key. Languages: {}",
+            export_caption: "📦 Here is everything I store about you: settings, chats, rules and history.",
+            private_only: "This command only works in private chats.",
+            forgetme_confirm: "⚠️ This permanently deletes your settings, custom rules, history and dashboard sessions. Groups you added stay configured.\n\nSend <code>/forgetme confirm</code> to proceed.",
+            forgetme_done: "🗑️ All your data has been deleted.",
+            plan_usage: "Usage: <code>/plan &lt;user_id&gt; [free|premium|unlimited] [days]</code>",
+            plan_status: "User <code>{}</code>: <b>{}</b> plan",
+            plan_until: " until {}",
+            settings_link: "⚙️ Settings for <b>{}</b>: <a href=\"{}\">open in the dashboard</a>. The link expires in 10 minutes.",
+            settings_link_sent: "📬 I sent you the settings link in private.",
+            settings_dm_failed: "I can't message you privately: start me in a private chat and try again.",
+            dashboard_unavailable: "The dashboard isn't configured on this instance.",
+            login_link: "🔑 <a href=\"{}\">Sign in to the dashboard</a>. The link works once and expires in 5 minutes: don't share it.",
+            admin_confirmation_code: "🔐 Confirmation code for <code>{}</code> on <code>{}</code>: <code>{}</code>\nIt expires in 5 minutes. If you didn't request it, someone has access to your dashboard.",
+            ownership_offer: "👑 The member who managed ClearURLs in <b>{}</b> is no longer an administrator there. Do you want to take over?",
+            ownership_accept: "Take over",
+            ownership_taken: "You now manage ClearURLs in this group.",
+            ownership_unavailable: "The group already has a new manager, or you are no longer an administrator there.",
+            cleaned_from: "<b>Link(s) cleaned from {}:</b>\n",
+            dm_start_bot: "🔒 {}, in this group I send cleaned links privately: <a href=\"{}\">start me</a> to receive them.",
+            digest_summary: "📊 <b>Today's summary</b>: I cleaned {} link(s) in this group.",
+            digest_top_provider: "\nTop tracker: <b>{}</b> ({})",
+            redirect_chain: "↪️ Redirects:",
+            level_usage: "Current cleaning level: <b>{}</b>\n\nUsage: <code>/level conservative</code> (rules only), <code>/level standard</code> or <code>/level strict</code> (also parameters that look like trackers). In groups, <code>/level default</code> uses each sender's own level.",
+            level_set: "✅ Cleaning level set to <b>{}</b>.",
+            instant_view: "📖 Instant View",
+            share: "↗️ Share",
+            inline_cleaned: "🧹 Tracking removed ({})",
+            inline_already_clean: "✅ Link already clean",
+            clean_usage: "Usage: <code>/clean &lt;link&gt;</code>\nCleans one link on request, even where the bot is disabled or the domain is ignored.",
+            clean_result: "🧹 Cleaned with the <b>{}</b> rules:\n<code>{}</code>",
+            clean_removed: "\nRemoved parameters: {}",
+            clean_nothing: "✅ Nothing to remove: this link is already clean.",
+            settings_menu: "⚙️ <b>Your settings</b>\nTap a button to change it.",
+            setting_enabled: "🧹 Cleaning: {}",
+            setting_ai: "🤖 AI Deep Scan: {}",
+            setting_mode: "💬 Mode: {}",
+            setting_language: "🌐 Language: {}",
+            setting_on: "on",
+            setting_off: "off",
+            settings_saved: "✅ Saved",
+            ignore_usage: "Usage: <code>/ignore example.com</code> or <code>/unignore example.com</code> (chat administrators).\nIgnored here: {}",
+            ignore_added: "🙈 Links to <b>{}</b> are no longer cleaned in this chat.",
+            ignore_limit: "This chat already ignores {} domains, the maximum.",
+            unignore_removed: "🧹 Links to <b>{}</b> are cleaned again in this chat.",
+            unignore_missing: "<b>{}</b> was not ignored in this chat.",
+            chat_admins_only: "Only the chat's administrators can do this.",
+            reposted_for: "<b>{}</b> wrote:\n",
+            reaction_hint: "🧹 Tracking removed from {} link(s).",
+            show_cleaned: "Show cleaned links",
+            links_expired: "These links are no longer available.",
+            mode_usage: "Current group mode: <b>{}</b>\n\nUsage: <code>/mode reply</code> (answer), <code>delete</code> (delete and post the links), <code>repost</code> (delete and repost the text), <code>reaction</code> (just a reaction), <code>dm</code>, <code>digest</code>, <code>dry_run</code> (record the links in the dashboard without posting in the group) or <code>default</code> (each sender's own mode).",
+            mode_set: "✅ Group mode set to <b>{}</b>.",
+            held_links: "<b>Links cleaned in the last minute:</b>\n",
+            held_links_more: "…and {} more\n",
+            language_usage: "Current language: <b>{}</b>\n\nUsage: <code>/language {}</code>",
+            language_set: "✅ Language set to <b>{}</b>.",
+            privacy_on: "🔒 Privacy mode is <b>on</b>: links are cleaned but not kept in your history. Your counter still grows.\n\n/clearhistory deletes the ones already kept, <code>/privacy off</code> keeps them again.",
+            privacy_off: "Privacy mode is <b>off</b>: cleaned links are kept in your history (dashboard, /topdomains, /undo).\n\n<code>/privacy on</code> stops keeping them.",
+            global_stats_text: "<b>Global statistics</b> 🌍\n\nLinks cleaned: <b>{}</b>\nUsers: <b>{}</b>\nProviders loaded: <b>{}</b>\nRules updated: <b>{}</b>\n\n<b>Top providers (30 days)</b>\n",
+            rules_embedded: "never (built-in rules)",
+            rules_age: "{} ago",
+            weekly_digest: "📬 <b>Your week</b>: I cleaned {} link(s) for you.",
+            weekly_top_providers: "\n\n<b>Top trackers</b>",
+            weekly_top_domains: "\n\n<b>Most cleaned domains</b>",
+            weekly_digest_footer: "\n\n<i>You can turn this summary off in /settings.</i>",
+            setting_digest: "📬 Weekly summary: {}",
+            group_setup: "⚙️ <b>{}</b>\n\nChoose how I answer in the group, how thoroughly I clean links and whether AI helps. Each button moves to the next value.",
+            group_setup_prompt: "Set up the group in a private chat with me:",
+            group_setup_open: "⚙️ Set up",
+            setting_level: "🧽 Level: {}",
+            onboarding: "👋 Thanks for adding me to <b>{}</b>! I remove tracking parameters from the links posted here.\n\nChoose how I answer (group administrators only); <code>/mode</code> changes it later.",
+            whitelist_usage: "Usage: reply to a member's message with <code>/whitelist</code>, or <code>/whitelist @user</code>; <code>/unwhitelist @user</code> to remove them (chat administrators).\nExempt here: {}",
+            whitelist_added: "🙈 Links from {} are no longer cleaned in this chat.",
+            whitelist_limit: "This chat already exempts {} members, the maximum.",
+            unwhitelist_removed: "🧹 Links from {} are cleaned again in this chat.",
+            unwhitelist_missing: "{} was not exempt in this chat.",
+            topic_usage: "Usage: <code>/topic on</code> or <code>/topic off</code> in a topic (chat administrators). Once a topic is turned on, only the topics turned on are cleaned.\nCleaning in this topic: {}",
+            topic_on: "🧹 Links in this topic are cleaned.",
+            topic_off: "🙈 Links in this topic are no longer cleaned.",
+            forum_only: "This command only works in groups with topics.",
+            config_reload_failed: "⚠️ Configuration not reloaded, the current one stays:\n<code>{}</code>",
+            rule_removed: "🗑️ Rule removed: <code>{}</code>.",
+            rule_not_found: "You have no rule <code>{}</code>. Use /rules to list them.",
+            rule_remove_usage: "To remove a rule: <code>/delrule parameter_name</code>",
+            mastodon_paused: "Paused: I won't clean links in your mentions until you send \"start\".",
+            mastodon_resumed: "Resumed: mention me with a link and I'll reply with a clean copy.",
+            mastodon_cleaned: "Cleaned link(s):",
+            usage_ai: "\nAI: {} requests, {} tokens",
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(lang: &str, key: &str, value: &str) -> TranslationOverride {
+        TranslationOverride {
+            lang: lang.into(),
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn overrides_layer_over_builtin_strings() {
+        assert!(KEYS
+            .iter()
+            .all(|key| builtin_translations("en").field_mut(key).is_some()));
+
+        set_overrides(&[
+            entry("*", "welcome", "Welcome to Acme"),
+            entry("it", "welcome", "Benvenuto in Acme"),
+            entry("en", "no_such_key", "ignored"),
+        ]);
+        assert_eq!(get_translations("en").welcome, "Welcome to Acme");
+        assert_eq!(get_translations("fr").welcome, "Welcome to Acme");
+        assert_eq!(get_translations("it").welcome, "Benvenuto in Acme");
+        assert_eq!(
+            get_translations("it").help_text,
+            builtin_translations("it").help_text
+        );
+
+        set_overrides(&[]);
+        assert_eq!(
+            get_translations("en").welcome,
+            builtin_translations("en").welcome
+        );
+    }
+
+    #[test]
+    fn telegram_language_codes_map_to_locales() {
+        assert_eq!(language_for_code("it"), Some("it"));
+        assert_eq!(language_for_code("en-GB"), Some("en"));
+        assert_eq!(language_for_code("IT_ch"), Some("it"));
+        assert_eq!(language_for_code("de"), None);
+        assert_eq!(language_for_code(""), None);
+    }
+}
@@ -0,0 +1,37 @@
+//! Telegram Instant View links. `t.me/iv?url=...&rhash=...` opens an
+//! article through the Instant View template identified by `rhash`, so it
+//! reads without the site's scripts, trackers and clutter.
+
+use url::Url;
+
+const INSTANT_VIEW_BASE: &str = "https://t.me/iv";
+
+/// The Instant View link for `article_url`, or `None` for anything but an
+/// http(s) URL, which Telegram cannot render.
+pub fn link(article_url: &str, rhash: &str) -> Option<Url> {
+    let article = Url::parse(article_url.trim()).ok()?;
+    if !matches!(article.scheme(), "http" | "https") {
+        return None;
+    }
+    Url::parse_with_params(
+        INSTANT_VIEW_BASE,
+        [("url", article.as_str()), ("rhash", rhash)],
+    )
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_web_urls_only() {
+        let link = link("https://example.com/post?id=1&x=2", "abc123").unwrap();
+        assert_eq!(
+            link.as_str(),
+            "https://t.me/iv?url=https%3A%2F%2Fexample.com%2Fpost%3Fid%3D1%26x%3D2&rhash=abc123"
+        );
+        assert!(super::link("tg://resolve?domain=x", "abc123").is_none());
+        assert!(super::link("not a url", "abc123").is_none());
+    }
+}
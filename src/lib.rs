@@ -1,9 +1,34 @@
+pub use clear_urls_core;
+
 pub mod ai_sanitizer;
+#[cfg(feature = "bot")]
 pub mod bot;
+pub mod chat_admins;
 pub mod config;
 pub mod db;
+#[cfg(feature = "bot")]
+pub mod digest;
+pub mod entitlements;
+pub mod exporter;
+pub mod heartbeat;
+pub mod http;
 pub mod i18n;
+pub mod instant_view;
+pub mod links;
 pub mod logging;
+#[cfg(feature = "mastodon")]
+pub mod mastodon;
+#[cfg(feature = "bot")]
+pub mod messenger;
+pub mod metrics;
 pub mod models;
+pub mod profile;
+pub mod rate_limit;
+pub mod reload;
 pub mod sanitizer;
-
+pub mod scheduler;
+pub mod shared_cache;
+pub mod supervisor;
+pub mod telegram;
+#[cfg(feature = "web")]
+pub mod web;
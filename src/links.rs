@@ -0,0 +1,85 @@
+//! Links from the bot into the dashboard. `/settings` links are HMAC-signed
+//! with a key derived from the bot token and carry their own expiry, so the
+//! web server verifies them without shared state. `/login` links must work
+//! only once, so their random tokens are stored (hashed) in `login_tokens`.
+
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+/// How long a `/settings` link stays valid.
+pub const CHAT_LINK_TTL_SECS: i64 = 600;
+/// How long a `/login` link stays valid; it also stops working once used.
+pub const LOGIN_LINK_TTL: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// A random 256-bit token, hex encoded. Used for sessions and login links,
+/// which are stored hashed.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// A random 128-bit token, hex encoded, addressing a user's public stats
+/// page. Shorter than [`random_token`] since it only ever reveals counts.
+pub fn public_token() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Separate from the Login Widget key (`SHA256(bot_token)`) so a token of one
+/// kind can never pass as the other. Zero-padded to the SHA-256 block size,
+/// exactly as HMAC pads shorter keys.
+fn signature(bot_token: &str, payload: &str) -> Hmac<Sha256> {
+    let mut key = [0u8; 64];
+    key[..32].copy_from_slice(&Sha256::digest(format!("dashboard-link:{}", bot_token)));
+    let mut mac = Hmac::<Sha256>::new(&key.into());
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Token letting `user_id` open `chat_id`'s dashboard page until
+/// `expires_at` (Unix seconds).
+pub fn chat_link_token(bot_token: &str, chat_id: i64, user_id: i64, expires_at: i64) -> String {
+    let payload = format!("{}.{}.{}", chat_id, user_id, expires_at);
+    let sig = hex::encode(signature(bot_token, &payload).finalize().into_bytes());
+    format!("{}.{}", payload, sig)
+}
+
+/// Checks a [`chat_link_token`] and returns its `(chat_id, user_id)`.
+pub fn verify_chat_link(bot_token: &str, token: &str, now: i64) -> Option<(i64, i64)> {
+    let (payload, sig) = token.rsplit_once('.')?;
+    signature(bot_token, payload)
+        .verify_slice(&hex::decode(sig).ok()?)
+        .ok()?;
+    let mut fields = payload.splitn(3, '.').map(|f| f.parse::<i64>().ok());
+    let (chat_id, user_id, expires_at) = (fields.next()??, fields.next()??, fields.next()??);
+    (now < expires_at).then_some((chat_id, user_id))
+}
+
+/// The dashboard page for `chat_id`, signed in through `token`.
+pub fn chat_settings_url(dashboard_url: &str, chat_id: i64, token: &str) -> String {
+    format!("{}/chats/{}?token={}", dashboard_url, chat_id, token)
+}
+
+/// The dashboard page redeeming a `/login` token.
+pub fn login_url(dashboard_url: &str, token: &str) -> String {
+    format!("{}/login?token={}", dashboard_url, token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_links_are_signed_and_expire() {
+        let token = chat_link_token("123:abc", -100, 42, 1_000);
+        assert_eq!(verify_chat_link("123:abc", &token, 999), Some((-100, 42)));
+        assert_eq!(verify_chat_link("123:abc", &token, 1_000), None);
+        assert_eq!(verify_chat_link("123:xyz", &token, 999), None);
+        let forged = token.replacen("-100.42", "-100.43", 1);
+        assert_eq!(verify_chat_link("123:abc", &forged, 999), None);
+        assert_eq!(verify_chat_link("123:abc", "garbage", 999), None);
+    }
+}
@@ -1,29 +1,135 @@
-use std::env;
+use crate::config::Config;
 use tracing_error::ErrorLayer;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
 
+/// Keeps the OpenTelemetry providers alive and flushes pending spans and
+/// metrics when dropped at shutdown.
+#[derive(Default)]
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    providers: Option<(
+        opentelemetry_sdk::trace::SdkTracerProvider,
+        opentelemetry_sdk::metrics::SdkMeterProvider,
+    )>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some((tracer_provider, meter_provider)) = self.providers.take() {
+            if let Err(e) = tracer_provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry traces: {}", e);
+            }
+            if let Err(e) = meter_provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry metrics: {}", e);
+            }
+        }
+    }
+}
+
+/// Builds the OTLP tracing layer and installs the global meter provider when
+/// an endpoint is configured.
+#[cfg(feature = "otel")]
+fn otel_layer<S>(config: &Config) -> (Option<impl tracing_subscriber::Layer<S>>, TelemetryGuard)
+where
+    S: tracing::Subscriber + for<'span> tracing_subscriber::registry::LookupSpan<'span>,
+{
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+    use opentelemetry_sdk::{metrics::SdkMeterProvider, trace::SdkTracerProvider, Resource};
+
+    let Some(endpoint) = config.otlp_endpoint.as_deref() else {
+        return (None, TelemetryGuard::default());
+    };
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder()
+        .with_service_name(env!("CARGO_PKG_NAME"))
+        .with_attribute(opentelemetry::KeyValue::new(
+            "deployment.environment",
+            config.profile.as_str(),
+        ))
+        .build();
+
+    let span_exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint))
+        .with_headers(config.otlp_headers.clone())
+        .build();
+    let metric_exporter = opentelemetry_otlp::MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/metrics", endpoint))
+        .with_headers(config.otlp_headers.clone())
+        .build();
+    let (span_exporter, metric_exporter) = match (span_exporter, metric_exporter) {
+        (Ok(spans), Ok(metrics)) => (spans, metrics),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to build OTLP exporter, telemetry disabled: {}", e);
+            return (None, TelemetryGuard::default());
+        }
+    };
+
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_resource(resource.clone())
+        .build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_periodic_exporter(metric_exporter)
+        .with_resource(resource)
+        .build();
+    opentelemetry::global::set_tracer_provider(tracer_provider.clone());
+    opentelemetry::global::set_meter_provider(meter_provider.clone());
+
+    let layer =
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("clear_urls_bot"));
+    let guard = TelemetryGuard {
+        providers: Some((tracer_provider, meter_provider)),
+    };
+    (Some(layer), guard)
+}
+
+#[cfg(not(feature = "otel"))]
+fn otel_layer(config: &Config) -> (Option<tracing_subscriber::layer::Identity>, TelemetryGuard) {
+    if config.otlp_endpoint.is_some() {
+        eprintln!(
+            "OTEL_EXPORTER_OTLP_ENDPOINT is set but the binary was built without the `otel` feature"
+        );
+    }
+    (None, TelemetryGuard::default())
+}
+
 /// Initializes the logging and tracing system.
 ///
-/// Supports two modes based on the `APP_ENV` environment variable:
+/// The output format follows the active profile unless `LOG_FORMAT` overrides it:
 /// - `development` (default): Pretty-printed, colored logs for console.
-/// - `production`: JSON-formatted logs for aggregation (Datadog, ELK, etc.).
-pub fn init_logging() {
+/// - `staging` / `production`: JSON-formatted logs for aggregation (Datadog, ELK, etc.).
+///
+/// With the `otel` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are also
+/// exported over OTLP/HTTP. Logs go to stderr so command output on stdout stays
+/// pipeable. Keep the returned guard alive until shutdown.
+pub fn init_logging(config: &Config) -> TelemetryGuard {
     let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("clear_urls_bot=info,teloxide=info,axum=info"));
+        .unwrap_or_else(|_| EnvFilter::new(config.profile.default_log_filter()));
 
-    let env = env::var("APP_ENV").unwrap_or_else(|_| "development".to_string());
+    let env = config.profile.as_str();
 
     let registry = Registry::default()
         .with(env_filter)
         .with(ErrorLayer::default());
+    let (otel, guard) = otel_layer(config);
+    let registry = registry.with(otel);
 
-    if env == "production" {
-        let json_layer = fmt::layer().json().with_thread_ids(true).with_target(true);
+    if config.json_logs {
+        let json_layer = fmt::layer()
+            .json()
+            .with_writer(std::io::stderr)
+            .with_thread_ids(true)
+            .with_target(true);
 
         registry.with(json_layer).init();
     } else {
         let fmt_layer = fmt::layer()
             .pretty()
+            .with_writer(std::io::stderr)
             .with_thread_ids(true)
             .with_target(true);
 
@@ -31,6 +137,7 @@ pub fn init_logging() {
     }
 
     tracing::info!(env = %env, "Logging system initialized");
+    guard
 }
 
 /// Debugging utility for tracking execution time of a block/future.
@@ -58,3 +165,26 @@ impl Drop for Timer {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn telemetry_is_exported_only_with_an_endpoint() {
+        let mut config = Config::load(None);
+        config.otlp_endpoint = None;
+        let (layer, _guard) = otel_layer(&config);
+        assert!(layer.is_none());
+        let _ = Registry::default().with(layer);
+
+        #[cfg(feature = "otel")]
+        {
+            config.otlp_endpoint = Some("http://127.0.0.1:4318/".into());
+            let (layer, guard) = otel_layer(&config);
+            assert!(layer.is_some());
+            assert!(guard.providers.is_some());
+            let _ = Registry::default().with(layer);
+        }
+    }
+}
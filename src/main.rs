@@ -1,66 +1,456 @@
+use anyhow::Context;
+use arc_swap::ArcSwap;
+use clap::{Parser, Subcommand};
+#[cfg(feature = "bot")]
+use clear_urls_bot::{ai_sanitizer::AiEngine, bot};
 use clear_urls_bot::{
-    ai_sanitizer::AiEngine,
-    bot,
-    config::Config,
+    config::{Config, SharedConfig},
     db::Db,
-    logging,
+    logging, reload,
     sanitizer::RuleEngine,
+    scheduler::Scheduler,
+    shared_cache::SharedCache,
+    supervisor::Supervisor,
 };
+use clear_urls_core::CleaningLevel;
+use std::path::PathBuf;
+use std::sync::Arc;
+#[cfg(any(feature = "bot", feature = "web"))]
 use std::time::Duration;
-use teloxide::Bot;
-use tokio::time::interval;
+#[cfg(feature = "bot")]
+use teloxide::{types::ChatId, Bot};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Telegram bot that strips tracking parameters from URLs"
+)]
+struct Cli {
+    /// Path to a TOML/YAML config file (overrides CONFIG_PATH)
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the bot and its background tasks (default)
+    Serve,
+    /// Clean URLs from arguments, files or stdin and print them one per line
+    Clean {
+        /// URLs to clean; stdin is scanned when neither URLs nor files are given
+        urls: Vec<String>,
+        /// Scan a file for URLs ("-" for stdin); repeatable
+        #[arg(long, short, value_name = "PATH")]
+        file: Vec<PathBuf>,
+        /// Do not follow shortener redirects
+        #[arg(long)]
+        no_expand: bool,
+        /// Use the rules embedded in the binary; implies --no-expand
+        #[arg(long)]
+        offline: bool,
+        /// How far to go: conservative, standard or strict
+        #[arg(long, default_value = "standard", value_parser = parse_level)]
+        level: CleaningLevel,
+        /// Print one JSON object per URL instead of plain text
+        #[arg(long)]
+        json: bool,
+        /// Exit with status 1 if any URL had something to clean
+        #[arg(long)]
+        check: bool,
+    },
+    /// Download and compile the rules to check the configured source
+    RefreshRules,
+    /// Apply database migrations and exit
+    Migrate,
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    logging::init_logging();
+    let cli = Cli::parse();
+    let config = Config::load(cli.config);
+    let _telemetry = logging::init_logging(&config);
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        Command::Clean {
+            urls,
+            file,
+            no_expand,
+            offline,
+            level,
+            json,
+            check,
+        } => {
+            clean(
+                &config,
+                urls,
+                file,
+                no_expand || offline,
+                offline,
+                level,
+                json,
+                check,
+            )
+            .await
+        }
+        Command::RefreshRules => {
+            let rules = RuleEngine::new_lazy(&config.clearurls_source)
+                .with_proxy(config.proxy_url.as_deref());
+            rules.refresh().await?;
+            println!(
+                "Loaded {} providers from {}",
+                rules.provider_count(),
+                config.clearurls_source
+            );
+            Ok(())
+        }
+        Command::Migrate => {
+            Db::new(&config.database_url).await?;
+            println!("Database is up to date");
+            Ok(())
+        }
+    }
+}
+
+fn parse_level(value: &str) -> Result<CleaningLevel, String> {
+    CleaningLevel::parse(value).ok_or_else(|| format!("unknown cleaning level {:?}", value))
+}
 
+/// Bulk cleaning for scripts and CI link checks.
+#[allow(clippy::too_many_arguments)]
+async fn clean(
+    config: &Config,
+    mut urls: Vec<String>,
+    mut files: Vec<PathBuf>,
+    no_expand: bool,
+    offline: bool,
+    level: CleaningLevel,
+    json: bool,
+    check: bool,
+) -> anyhow::Result<()> {
+    if urls.is_empty() && files.is_empty() {
+        files.push(PathBuf::from("-"));
+    }
+    for path in &files {
+        let text = if path.as_os_str() == "-" {
+            std::io::read_to_string(std::io::stdin())?
+        } else {
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+        };
+        urls.extend(clear_urls_core::find_urls(&text).map(str::to_string));
+    }
+
+    let rules =
+        RuleEngine::new_lazy(&config.clearurls_source).with_proxy(config.proxy_url.as_deref());
+    let rules = if offline {
+        rules.with_embedded_rules()
+    } else {
+        rules.refresh().await?;
+        rules
+    };
+
+    let mut any_changed = false;
+    for original in urls {
+        let url = if no_expand {
+            original.clone()
+        } else {
+            rules.expand_url(&original).await
+        };
+        let result = rules.sanitize(&url, &[], &[], level);
+        let changed = result.is_some() || url != original;
+        any_changed |= changed;
+        let (cleaned, provider) = match result {
+            Some((cleaned, provider)) => (cleaned, Some(provider)),
+            None => (url, None),
+        };
+        if json {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "original": original,
+                    "cleaned": cleaned,
+                    "changed": changed,
+                    "provider": provider,
+                })
+            );
+        } else {
+            println!("{}", cleaned);
+        }
+    }
+
+    if check && any_changed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+async fn serve(config: Config) -> anyhow::Result<()> {
     let pid = std::process::id();
     tracing::info!(pid = %pid, "ClearURLs Bot starting up");
 
-    let config = Config::from_env();
     config.validate();
 
-    let db = Db::new(&config.database_url).await?;
-    let rules = RuleEngine::new_lazy(&config.clearurls_source);
-    let ai = AiEngine::new(&config);
+    let shared = SharedCache::connect(config.redis_url.as_deref()).await?;
+    let db = Db::with_replica(&config.database_url, config.database_url_ro.as_deref())
+        .await?
+        .with_shared_cache(shared.clone());
+    let rules = RuleEngine::new_lazy(&config.clearurls_source)
+        .with_shared_cache(shared.clone())
+        .with_proxy(config.proxy_url.as_deref());
+    rules.reconfigure(&config);
+    match rules.load_global_providers(&db).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!(count, "Loaded global providers"),
+        Err(e) => tracing::error!(error = %e, "Failed to load global providers"),
+    }
+    match clear_urls_bot::i18n::load_overrides(&db).await {
+        Ok(0) => {}
+        Ok(count) => tracing::info!(count, "Loaded translation overrides"),
+        Err(e) => tracing::error!(error = %e, "Failed to load translation overrides"),
+    }
 
     // Create a custom reqwest client with a longer timeout for Telegram polling
-    let client = reqwest::Client::builder()
+    #[cfg(feature = "bot")]
+    let client = clear_urls_bot::http::client_builder(config.proxy_url.as_deref())?
         .timeout(Duration::from_secs(60))
         .build()?;
-    let bot = Bot::with_client(&config.bot_token, client);
+    #[cfg(feature = "bot")]
+    let api_url =
+        reqwest::Url::parse(&config.telegram_api_url).context("Invalid TELEGRAM_API_URL")?;
+    #[cfg(feature = "bot")]
+    let bots: Vec<Bot> = std::iter::once(&config.bot_token)
+        .chain(&config.extra_bot_tokens)
+        .map(|token| Bot::with_client(token, client.clone()).set_api_url(api_url.clone()))
+        .collect();
+
+    let config: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+    reload::spawn_sighup_listener(config.clone(), rules.clone());
 
     // Canale per eventi real-time (SSE) - kept for bot logic, though not used in GraphQL yet
     let (event_tx, _) = tokio::sync::broadcast::channel::<serde_json::Value>(100);
+    let instance_id = format!(
+        "{}:{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "local".to_string()),
+        pid
+    );
+    shared.spawn_event_bridge(instance_id, event_tx.clone());
 
-    let bot_task = tokio::spawn(bot::run_bot(
-        bot,
-        db.clone(),
-        rules.clone(),
-        ai,
-        config.clone(),
-        event_tx.clone(),
-    ));
-
-    let rules_refresh = rules.clone();
-    let refresh_task = tokio::spawn(async move {
-        if let Err(e) = rules_refresh.refresh().await {
-            tracing::error!("Failed initial rules fetch: {}", e);
+    let supervisor = Supervisor::new();
+    #[cfg(feature = "bot")]
+    let supervisor = {
+        // Failures are reported to the admin through the first bot.
+        let admin_id = config.load().admin_id;
+        match bots.first().cloned() {
+            Some(alert_bot) if admin_id != 0 => supervisor.with_alert(move |message| {
+                let alert_bot = alert_bot.clone();
+                async move {
+                    use teloxide::prelude::Requester;
+                    let text = format!("⚠️ {}", message);
+                    if let Err(e) = alert_bot.send_message(ChatId(admin_id), text).await {
+                        tracing::warn!(error = %e, "Failed to send task alert");
+                    }
+                }
+            }),
+            _ => supervisor,
         }
-        let mut interval = interval(Duration::from_secs(86400));
-        interval.tick().await;
-        loop {
-            interval.tick().await;
-            if let Err(e) = rules_refresh.refresh().await {
-                tracing::error!("Failed to refresh rules: {}", e);
-            }
+    };
+    let scheduler = Scheduler::new(config.clone());
+
+    #[cfg(feature = "bot")]
+    {
+        // Digests go out through the bot that cleaned in the chat; a bot's id
+        // is the part of its token before the colon.
+        let digest_bots: Vec<(i64, Bot)> = bots
+            .iter()
+            .filter_map(|bot| {
+                let id = bot.token().split(':').next()?.parse().ok()?;
+                Some((id, bot.clone()))
+            })
+            .collect();
+        let (digest_bots, digest_db) = (Arc::new(digest_bots), db.clone());
+        let (weekly_bots, weekly_db) = (digest_bots.clone(), db.clone());
+        scheduler.register(&supervisor, "digest", false, move || {
+            let (digest_bots, digest_db) = (digest_bots.clone(), digest_db.clone());
+            async move { clear_urls_bot::digest::run_digests(&digest_bots, &digest_db).await }
+        });
+        scheduler.register(&supervisor, "weekly-digest", false, move || {
+            let (weekly_bots, weekly_db) = (weekly_bots.clone(), weekly_db.clone());
+            async move { clear_urls_bot::digest::run_weekly_digests(&weekly_bots, &weekly_db).await }
+        });
+
+        let ai = AiEngine::new(config.clone()).with_usage_tracking(db.clone());
+        for (i, bot) in bots.into_iter().enumerate() {
+            let (db, rules, ai, config, event_tx) = (
+                db.clone(),
+                rules.clone(),
+                ai.clone(),
+                config.clone(),
+                event_tx.clone(),
+            );
+            supervisor.spawn(format!("bot-{}", i), move || {
+                bot::run_bot(
+                    bot.clone(),
+                    db.clone(),
+                    rules.clone(),
+                    ai.clone(),
+                    config.clone(),
+                    event_tx.clone(),
+                )
+            });
+        }
+    }
+
+    let (rules_refresh, refresh_db) = (rules.clone(), db.clone());
+    scheduler.register(&supervisor, "rules-refresh", true, move || {
+        let (rules_refresh, refresh_db) = (rules_refresh.clone(), refresh_db.clone());
+        async move {
+            rules_refresh
+                .refresh()
+                .await
+                .context("Failed to refresh rules")?;
+            // Picks up providers edited on another replica.
+            rules_refresh
+                .load_global_providers(&refresh_db)
+                .await
+                .context("Failed to reload global providers")?;
+            Ok(())
         }
     });
 
-    tokio::select! {
-        res = bot_task => tracing::error!("Bot task finished: {:?}", res),
-        res = refresh_task => tracing::error!("Refresh task finished: {:?}", res),
+    #[cfg(feature = "web")]
+    {
+        let web_state = clear_urls_bot::web::AppState {
+            db: db.clone(),
+            config: config.clone(),
+            rules: rules.clone(),
+            clean_limiter: clear_urls_bot::rate_limit::RateLimiter::new(
+                "clean",
+                Duration::from_secs(60),
+            )
+            .with_shared_cache(shared.clone()),
+            scheduler: scheduler.clone(),
+            chat_admins: clear_urls_bot::chat_admins::AdminCache::new(),
+        };
+        let server_addr = config.load().server_addr.clone();
+        supervisor.spawn("web", move || {
+            let (server_addr, web_state) = (server_addr.clone(), web_state.clone());
+            async move { clear_urls_bot::web::serve(&server_addr, web_state).await }
+        });
+    }
+
+    #[cfg(feature = "mastodon")]
+    {
+        let (db, rules, config) = (db.clone(), rules.clone(), config.clone());
+        supervisor.spawn("mastodon", move || {
+            clear_urls_bot::mastodon::run_mastodon_bot(db.clone(), rules.clone(), config.clone())
+        });
     }
 
+    let (retention_db, retention_config) = (db.clone(), config.clone());
+    scheduler.register(&supervisor, "retention", true, move || {
+        let (retention_db, retention_config) = (retention_db.clone(), retention_config.clone());
+        async move { run_retention(&retention_db, &retention_config.load_full()).await }
+    });
+
+    let (heartbeat_supervisor, heartbeat_db, heartbeat_config) =
+        (supervisor.clone(), db.clone(), config.clone());
+    supervisor.spawn("heartbeat", move || {
+        clear_urls_bot::heartbeat::run_heartbeat(
+            heartbeat_supervisor.clone(),
+            heartbeat_db.clone(),
+            heartbeat_config.clone(),
+        )
+    });
+
+    tokio::signal::ctrl_c().await?;
+    tracing::info!("Shutting down");
+
+    Ok(())
+}
+
+/// The `retention` job. Every step runs even when an earlier one fails; the
+/// job fails if any did.
+async fn run_retention(db: &Db, config: &Config) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    match db.purge_deleted_history().await {
+        Ok(0) => {}
+        Ok(purged) => tracing::info!(purged, "Purged soft-deleted history"),
+        Err(e) => {
+            tracing::error!("Failed to purge deleted history: {}", e);
+            failed.push("deleted history");
+        }
+    }
+    if let Err(e) = db.purge_expired_web_sessions().await {
+        tracing::error!("Failed to purge expired dashboard sessions: {}", e);
+        failed.push("dashboard sessions");
+    }
+    match clear_urls_bot::entitlements::purge_expired_history(db, config).await {
+        Ok(0) => {}
+        Ok(purged) => tracing::info!(purged, "Purged history past plan retention"),
+        Err(e) => {
+            tracing::error!("Failed to purge history past retention: {}", e);
+            failed.push("plan retention");
+        }
+    }
+    match db.run_due_account_deletions().await {
+        Ok(0) => {}
+        Ok(deleted) => tracing::info!(deleted, "Deleted accounts after grace period"),
+        Err(e) => {
+            tracing::error!("Failed to delete scheduled accounts: {}", e);
+            failed.push("account deletions");
+        }
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Failed steps: {}", failed.join(", "));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_parses_subcommands() {
+        Cli::command().debug_assert();
+
+        let cli = Cli::try_parse_from(["clear_urls_bot", "--config", "bot.toml"]).unwrap();
+        assert_eq!(cli.config, Some(PathBuf::from("bot.toml")));
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from([
+            "clear_urls_bot",
+            "clean",
+            "--offline",
+            "--level",
+            "strict",
+            "-f",
+            "links.txt",
+            "https://example.com/?utm_source=x",
+        ])
+        .unwrap();
+        match cli.command {
+            Some(Command::Clean {
+                urls,
+                file,
+                offline,
+                no_expand,
+                level,
+                ..
+            }) => {
+                assert_eq!(urls, ["https://example.com/?utm_source=x"]);
+                assert_eq!(file, [PathBuf::from("links.txt")]);
+                assert!(offline && !no_expand);
+                assert_eq!(level, CleaningLevel::Strict);
+            }
+            _ => panic!("expected the clean subcommand"),
+        }
+
+        assert!(Cli::try_parse_from(["clear_urls_bot", "clean", "--level", "max"]).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+//! Mastodon mention bot: polls the account's mention notifications, replies
+//! with cleaned versions of the links in them and dismisses each handled
+//! notification, so nothing is answered twice across restarts. A mention
+//! that keeps failing is dismissed after `MAX_MENTION_ATTEMPTS` polls.
+//!
+//! Mentions without links act as commands: `stop` pauses cleaning for the
+//! sender, `start` resumes it.
+
+use crate::{config::SharedConfig, db::Db, i18n, sanitizer::RuleEngine};
+use anyhow::{Context, Result};
+use clear_urls_core::CleaningLevel;
+use regex::Regex;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+static HTML_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+
+/// Polls a mention is retried before its notification is dismissed
+/// unanswered.
+const MAX_MENTION_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Deserialize)]
+struct Notification {
+    id: String,
+    account: Account,
+    status: Option<Status>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Account {
+    acct: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    id: String,
+    content: String,
+    visibility: String,
+}
+
+/// Plain text of a status' HTML content. Mastodon shortens long links by
+/// hiding parts of them in `invisible` spans, which stripping the tags keeps.
+/// `&amp;` is decoded last, so escaped entities such as `&amp;lt;` stay
+/// literal.
+fn status_text(content: &str) -> String {
+    let text = content.replace("<br>", "\n").replace("</p>", "\n");
+    HTML_TAG
+        .replace_all(&text, "")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MentionCommand {
+    Stop,
+    Start,
+}
+
+/// The command of a mention without links: its first word that is not
+/// itself a mention.
+fn mention_command(text: &str) -> Option<MentionCommand> {
+    let word = text.split_whitespace().find(|w| !w.starts_with('@'))?;
+    match word.to_lowercase().as_str() {
+        "stop" => Some(MentionCommand::Stop),
+        "start" => Some(MentionCommand::Start),
+        _ => None,
+    }
+}
+
+struct MastodonClient {
+    client: Client,
+    instance: String,
+    token: String,
+}
+
+impl MastodonClient {
+    async fn mentions(&self) -> Result<Vec<Notification>> {
+        Ok(self
+            .client
+            .get(format!("{}/api/v1/notifications", self.instance))
+            .query(&[("types[]", "mention"), ("limit", "30")])
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    async fn reply(&self, to: &Status, text: &str) -> Result<()> {
+        // Never broadcast replies on the public timelines.
+        let visibility = if to.visibility == "public" {
+            "unlisted"
+        } else {
+            to.visibility.as_str()
+        };
+        self.client
+            .post(format!("{}/api/v1/statuses", self.instance))
+            .bearer_auth(&self.token)
+            .json(&json!({
+                "status": text,
+                "in_reply_to_id": to.id,
+                "visibility": visibility,
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn dismiss(&self, notification_id: &str) -> Result<()> {
+        self.client
+            .post(format!(
+                "{}/api/v1/notifications/{}/dismiss",
+                self.instance, notification_id
+            ))
+            .bearer_auth(&self.token)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Runs the mention bot until the process exits. Returns immediately when
+/// `MASTODON_INSTANCE` or `MASTODON_TOKEN` is not configured.
+pub async fn run_mastodon_bot(db: Db, rules: RuleEngine, config: SharedConfig) -> Result<()> {
+    let snapshot = config.load_full();
+    let (Some(instance), Some(token)) = (
+        snapshot.mastodon_instance.clone(),
+        snapshot.mastodon_token.clone(),
+    ) else {
+        return Ok(());
+    };
+    let client = crate::http::client_builder(snapshot.proxy_url.as_deref())?
+        .timeout(Duration::from_secs(30))
+        .build()
+        .context("Failed to build Mastodon client")?;
+    let mastodon = MastodonClient {
+        client,
+        instance,
+        token,
+    };
+    tracing::info!(instance = %mastodon.instance, "Mastodon mention bot started");
+
+    // Failed attempts per notification id, cleared once it is dismissed.
+    let mut failures: HashMap<String, u32> = HashMap::new();
+    loop {
+        let tr = i18n::get_translations(&config.load().mastodon_language);
+        match mastodon.mentions().await {
+            Ok(notifications) => {
+                // The API returns the newest first; answer in posting order.
+                for notification in notifications.into_iter().rev() {
+                    if let Err(e) = handle_mention(&mastodon, &db, &rules, &tr, &notification).await
+                    {
+                        let attempts = failures.entry(notification.id.clone()).or_default();
+                        *attempts += 1;
+                        if *attempts < MAX_MENTION_ATTEMPTS {
+                            tracing::warn!(error = %e, acct = %notification.account.acct, attempts = *attempts, "Failed to handle Mastodon mention");
+                            continue;
+                        }
+                        tracing::warn!(error = %e, acct = %notification.account.acct, "Giving up on Mastodon mention");
+                    }
+                    failures.remove(&notification.id);
+                    if let Err(e) = mastodon.dismiss(&notification.id).await {
+                        tracing::warn!(error = %e, "Failed to dismiss Mastodon notification");
+                    }
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Failed to fetch Mastodon mentions"),
+        }
+        let secs = config.load().mastodon_poll_interval_secs.max(5);
+        tokio::time::sleep(Duration::from_secs(secs)).await;
+    }
+}
+
+async fn handle_mention(
+    mastodon: &MastodonClient,
+    db: &Db,
+    rules: &RuleEngine,
+    tr: &i18n::Translations,
+    notification: &Notification,
+) -> Result<()> {
+    let Some(status) = &notification.status else {
+        return Ok(());
+    };
+    let acct = &notification.account.acct;
+    let text = status_text(&status.content);
+    let urls: Vec<String> = clear_urls_core::find_urls(&text)
+        .map(str::to_string)
+        .collect();
+    let mut account = db.get_fediverse_account(acct).await?;
+
+    if urls.is_empty() {
+        let reply = match mention_command(&text) {
+            Some(MentionCommand::Stop) => {
+                account.enabled = 0;
+                db.save_fediverse_account(&account).await?;
+                tr.mastodon_paused
+            }
+            Some(MentionCommand::Start) => {
+                account.enabled = 1;
+                db.save_fediverse_account(&account).await?;
+                tr.mastodon_resumed
+            }
+            None => return Ok(()),
+        };
+        return mastodon
+            .reply(status, &format!("@{} {}", acct, reply))
+            .await;
+    }
+
+    if !account.is_enabled() {
+        return Ok(());
+    }
+    let ignored_domains: Vec<String> = account
+        .ignored_domains
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let mut cleaned = Vec::new();
+    for url in urls {
+        let expanded = rules.expand_url(&url).await;
+        if let Some((clean, _)) =
+            rules.sanitize(&expanded, &[], &ignored_domains, CleaningLevel::Standard)
+        {
+            if !cleaned.contains(&clean) {
+                cleaned.push(clean);
+            }
+        }
+    }
+    if cleaned.is_empty() {
+        return Ok(());
+    }
+
+    let reply = format!("@{} {}\n{}", acct, tr.mastodon_cleaned, cleaned.join("\n"));
+    mastodon.reply(status, &reply).await?;
+    tracing::info!(acct = %acct, count = cleaned.len(), "Replied to Mastodon mention");
+    // The reply is out: a failed counter update must not get the mention
+    // answered again.
+    account.cleaned_count += cleaned.len() as i64;
+    if let Err(e) = db.save_fediverse_account(&account).await {
+        tracing::warn!(error = %e, acct = %acct, "Failed to update Fediverse account");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_text_strips_markup() {
+        let content = concat!(
+            r#"<p><span class="h-card"><a href="https://m.example/@bot" class="u-url mention">@<span>bot</span></a></span> look</p>"#,
+            r#"<p><a href="https://shop.example/item?id=1&amp;utm_source=x" rel="nofollow">"#,
+            r#"<span class="invisible">https://</span><span class="ellipsis">shop.example/item?id=1&amp;</span>"#,
+            r#"<span class="invisible">utm_source=x</span></a><br>&quot;a&quot; &lt;b&gt; it&#39;s</p>"#,
+        );
+        assert_eq!(
+            status_text(content),
+            "@bot look\nhttps://shop.example/item?id=1&utm_source=x\n\"a\" <b> it's\n"
+        );
+    }
+
+    #[test]
+    fn escaped_entities_are_decoded_once() {
+        assert_eq!(status_text("<p>&amp;lt;b&amp;gt;</p>"), "&lt;b&gt;\n");
+        assert_eq!(
+            status_text("https://a.example/?q=&amp;quot;x&amp;amp;y"),
+            "https://a.example/?q=&quot;x&amp;y"
+        );
+    }
+
+    #[test]
+    fn commands_are_the_first_word_after_the_mentions() {
+        assert_eq!(mention_command("@bot STOP"), Some(MentionCommand::Stop));
+        assert_eq!(
+            mention_command("@bot @friend start please"),
+            Some(MentionCommand::Start)
+        );
+        assert_eq!(mention_command("@bot please stop"), None);
+        assert_eq!(mention_command("@bot"), None);
+        assert_eq!(mention_command(""), None);
+    }
+}
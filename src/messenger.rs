@@ -0,0 +1,415 @@
+//! The Telegram calls made by the message handler, behind a trait so the
+//! cleaning and reply logic can run against an in-memory mock in tests.
+
+use async_trait::async_trait;
+use teloxide::prelude::*;
+use teloxide::types::{
+    CallbackQueryId, InlineKeyboardMarkup, InlineQueryId, InlineQueryResult, InputFile,
+    LinkPreviewOptions, MessageEntity, MessageId, ParseMode, ReactionType, ReplyParameters,
+    ThreadId,
+};
+use teloxide::RequestError;
+
+/// How an HTML message is delivered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SendOptions {
+    /// Reply to this message in the same chat.
+    pub reply_to: Option<MessageId>,
+    /// Forum topic the message belongs to.
+    pub thread_id: Option<ThreadId>,
+    pub disable_link_preview: bool,
+    /// Inline buttons under the message.
+    pub keyboard: Option<InlineKeyboardMarkup>,
+}
+
+#[async_trait]
+pub trait Messenger: Send + Sync {
+    /// Sends an HTML-formatted message.
+    async fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        options: SendOptions,
+    ) -> Result<(), RequestError>;
+
+    async fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError>;
+
+    /// Replaces the text of a message, or its caption when `caption` is set,
+    /// with `text` formatted by `entities`.
+    async fn edit_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        entities: Vec<MessageEntity>,
+        caption: bool,
+    ) -> Result<(), RequestError>;
+
+    /// Replaces the text and inline buttons of an HTML message the bot sent.
+    async fn edit_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError>;
+
+    /// Copies a message into another chat without the "forwarded" header.
+    async fn copy_message(
+        &self,
+        to: ChatId,
+        from: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError>;
+
+    /// Sets the bot's reaction to a message to `emoji`, which must be one
+    /// of the emoji Telegram allows as reactions.
+    async fn set_reaction(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        emoji: String,
+    ) -> Result<(), RequestError>;
+
+    /// Uploads an in-memory file with an HTML caption.
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: String,
+        contents: Vec<u8>,
+        caption: String,
+    ) -> Result<(), RequestError>;
+
+    /// Whether the user is the creator or an administrator of the chat.
+    async fn is_chat_admin(&self, chat_id: ChatId, user_id: i64) -> Result<bool, RequestError>;
+
+    /// The chat's human administrators, creator first.
+    async fn chat_administrators(&self, chat_id: ChatId) -> Result<Vec<i64>, RequestError>;
+
+    /// Acknowledges an inline button press with a short notification.
+    async fn answer_callback(
+        &self,
+        query_id: CallbackQueryId,
+        text: String,
+    ) -> Result<(), RequestError>;
+
+    /// Answers an inline query. Results are personal, as they depend on the
+    /// user's custom rules.
+    async fn answer_inline(
+        &self,
+        query_id: InlineQueryId,
+        results: Vec<InlineQueryResult>,
+    ) -> Result<(), RequestError>;
+}
+
+#[async_trait]
+impl Messenger for Bot {
+    async fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        options: SendOptions,
+    ) -> Result<(), RequestError> {
+        let mut request = Requester::send_message(self, chat_id, text).parse_mode(ParseMode::Html);
+        if let Some(reply_to) = options.reply_to {
+            request = request.reply_parameters(ReplyParameters::new(reply_to));
+        }
+        if let Some(thread_id) = options.thread_id {
+            request = request.message_thread_id(thread_id);
+        }
+        if options.disable_link_preview {
+            request = request.link_preview_options(LinkPreviewOptions {
+                is_disabled: true,
+                url: None,
+                prefer_small_media: false,
+                prefer_large_media: false,
+                show_above_text: false,
+            });
+        }
+        if let Some(keyboard) = options.keyboard {
+            request = request.reply_markup(keyboard);
+        }
+        request.await?;
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError> {
+        Requester::delete_message(self, chat_id, message_id).await?;
+        Ok(())
+    }
+
+    async fn edit_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        entities: Vec<MessageEntity>,
+        caption: bool,
+    ) -> Result<(), RequestError> {
+        if caption {
+            self.edit_message_caption(chat_id, message_id)
+                .caption(text)
+                .caption_entities(entities)
+                .await?;
+        } else {
+            self.edit_message_text(chat_id, message_id, text)
+                .entities(entities)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn edit_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError> {
+        self.edit_message_text(chat_id, message_id, text)
+            .parse_mode(ParseMode::Html)
+            .reply_markup(keyboard)
+            .await?;
+        Ok(())
+    }
+
+    async fn copy_message(
+        &self,
+        to: ChatId,
+        from: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError> {
+        Requester::copy_message(self, to, from, message_id).await?;
+        Ok(())
+    }
+
+    async fn set_reaction(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        emoji: String,
+    ) -> Result<(), RequestError> {
+        self.set_message_reaction(chat_id, message_id)
+            .reaction(vec![ReactionType::Emoji { emoji }])
+            .await?;
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: String,
+        contents: Vec<u8>,
+        caption: String,
+    ) -> Result<(), RequestError> {
+        Requester::send_document(
+            self,
+            chat_id,
+            InputFile::memory(contents).file_name(file_name),
+        )
+        .caption(caption)
+        .parse_mode(ParseMode::Html)
+        .await?;
+        Ok(())
+    }
+
+    async fn is_chat_admin(&self, chat_id: ChatId, user_id: i64) -> Result<bool, RequestError> {
+        let member = self
+            .get_chat_member(chat_id, UserId(user_id as u64))
+            .await?;
+        Ok(member.is_privileged())
+    }
+
+    async fn chat_administrators(&self, chat_id: ChatId) -> Result<Vec<i64>, RequestError> {
+        let mut admins = self.get_chat_administrators(chat_id).await?;
+        admins.retain(|member| !member.user.is_bot);
+        admins.sort_by_key(|member| !member.is_owner());
+        Ok(admins
+            .into_iter()
+            .map(|member| member.user.id.0 as i64)
+            .collect())
+    }
+
+    async fn answer_callback(
+        &self,
+        query_id: CallbackQueryId,
+        text: String,
+    ) -> Result<(), RequestError> {
+        self.answer_callback_query(query_id).text(text).await?;
+        Ok(())
+    }
+
+    async fn answer_inline(
+        &self,
+        query_id: InlineQueryId,
+        results: Vec<InlineQueryResult>,
+    ) -> Result<(), RequestError> {
+        self.answer_inline_query(query_id, results)
+            .is_personal(true)
+            .cache_time(10)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+type Calls<T> = std::sync::Arc<std::sync::Mutex<Vec<T>>>;
+
+/// Records every call instead of talking to Telegram.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct MockMessenger {
+    pub sent: Calls<(ChatId, String, SendOptions)>,
+    pub deleted: Calls<(ChatId, MessageId)>,
+    pub edited: Calls<(ChatId, MessageId, String, Vec<MessageEntity>)>,
+    pub edited_texts: Calls<(ChatId, MessageId, String, InlineKeyboardMarkup)>,
+    pub copied: Calls<(ChatId, ChatId, MessageId)>,
+    pub reactions: Calls<(ChatId, MessageId, String)>,
+    pub documents: Calls<(ChatId, String, Vec<u8>)>,
+    /// Users `is_chat_admin` reports as administrators of every chat.
+    pub chat_admins: Calls<i64>,
+    pub answered: Calls<(CallbackQueryId, String)>,
+    pub inline_answers: Calls<(InlineQueryId, Vec<InlineQueryResult>)>,
+    /// Make `delete_message` and `edit_message` fail, as when the bot lacks
+    /// admin rights.
+    pub fail_delete: bool,
+    /// Chats `send_message` fails for, as for users who never started the bot.
+    pub unreachable: Calls<ChatId>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl Messenger for MockMessenger {
+    async fn send_message(
+        &self,
+        chat_id: ChatId,
+        text: String,
+        options: SendOptions,
+    ) -> Result<(), RequestError> {
+        if self.unreachable.lock().unwrap().contains(&chat_id) {
+            return Err(RequestError::Api(
+                teloxide::ApiError::CantInitiateConversation,
+            ));
+        }
+        self.sent.lock().unwrap().push((chat_id, text, options));
+        Ok(())
+    }
+
+    async fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError> {
+        if self.fail_delete {
+            return Err(RequestError::Api(teloxide::ApiError::MessageCantBeDeleted));
+        }
+        self.deleted.lock().unwrap().push((chat_id, message_id));
+        Ok(())
+    }
+
+    async fn edit_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        entities: Vec<MessageEntity>,
+        _caption: bool,
+    ) -> Result<(), RequestError> {
+        if self.fail_delete {
+            return Err(RequestError::Api(teloxide::ApiError::MessageCantBeEdited));
+        }
+        self.edited
+            .lock()
+            .unwrap()
+            .push((chat_id, message_id, text, entities));
+        Ok(())
+    }
+
+    async fn edit_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+        keyboard: InlineKeyboardMarkup,
+    ) -> Result<(), RequestError> {
+        self.edited_texts
+            .lock()
+            .unwrap()
+            .push((chat_id, message_id, text, keyboard));
+        Ok(())
+    }
+
+    async fn copy_message(
+        &self,
+        to: ChatId,
+        from: ChatId,
+        message_id: MessageId,
+    ) -> Result<(), RequestError> {
+        self.copied.lock().unwrap().push((to, from, message_id));
+        Ok(())
+    }
+
+    async fn set_reaction(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        emoji: String,
+    ) -> Result<(), RequestError> {
+        self.reactions
+            .lock()
+            .unwrap()
+            .push((chat_id, message_id, emoji));
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat_id: ChatId,
+        file_name: String,
+        contents: Vec<u8>,
+        _caption: String,
+    ) -> Result<(), RequestError> {
+        self.documents
+            .lock()
+            .unwrap()
+            .push((chat_id, file_name, contents));
+        Ok(())
+    }
+
+    async fn is_chat_admin(&self, _chat_id: ChatId, user_id: i64) -> Result<bool, RequestError> {
+        Ok(self.chat_admins.lock().unwrap().contains(&user_id))
+    }
+
+    async fn chat_administrators(&self, _chat_id: ChatId) -> Result<Vec<i64>, RequestError> {
+        Ok(self.chat_admins.lock().unwrap().clone())
+    }
+
+    async fn answer_callback(
+        &self,
+        query_id: CallbackQueryId,
+        text: String,
+    ) -> Result<(), RequestError> {
+        self.answered.lock().unwrap().push((query_id, text));
+        Ok(())
+    }
+
+    async fn answer_inline(
+        &self,
+        query_id: InlineQueryId,
+        results: Vec<InlineQueryResult>,
+    ) -> Result<(), RequestError> {
+        self.inline_answers
+            .lock()
+            .unwrap()
+            .push((query_id, results));
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! In-process metrics rendered in the Prometheus text format by `/metrics`.
+//!
+//! With the `otel` feature the same measurements are also recorded on the
+//! global OpenTelemetry meter, so they reach the OTLP collector as well.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+const LATENCY_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+const ITERATION_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 5.0];
+
+static SANITIZE_DURATION: LazyLock<Histogram> = LazyLock::new(|| Histogram::new(LATENCY_BUCKETS));
+static SANITIZE_ITERATIONS: LazyLock<Histogram> =
+    LazyLock::new(|| Histogram::new(ITERATION_BUCKETS));
+static PROVIDER_MATCHES: LazyLock<Mutex<BTreeMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+static TASK_RESTARTS: LazyLock<Mutex<BTreeMap<String, u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// Cumulative counts per bound, plus the `+Inf` bucket.
+    buckets: Vec<AtomicU64>,
+    /// Sum of observations in millionths, to stay lock-free.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            if value <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.buckets[self.bounds.len()].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((value * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.buckets[self.bounds.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(
+            out,
+            "{}_sum {}",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+#[cfg(feature = "otel")]
+struct OtelInstruments {
+    duration: opentelemetry::metrics::Histogram<f64>,
+    iterations: opentelemetry::metrics::Histogram<u64>,
+    provider_matches: opentelemetry::metrics::Counter<u64>,
+}
+
+#[cfg(feature = "otel")]
+static OTEL: LazyLock<OtelInstruments> = LazyLock::new(|| {
+    let meter = opentelemetry::global::meter("clear_urls_bot");
+    OtelInstruments {
+        duration: meter
+            .f64_histogram("clear_urls.sanitize.duration")
+            .with_unit("s")
+            .build(),
+        iterations: meter
+            .u64_histogram("clear_urls.sanitize.iterations")
+            .build(),
+        provider_matches: meter.u64_counter("clear_urls.provider.matches").build(),
+    }
+});
+
+/// Records one top-level `clean_url_in_place` run.
+pub fn record_sanitization(elapsed: Duration, iterations: usize, providers: &[String]) {
+    SANITIZE_DURATION.observe(elapsed.as_secs_f64());
+    SANITIZE_ITERATIONS.observe(iterations as f64);
+    if let Ok(mut matches) = PROVIDER_MATCHES.lock() {
+        for provider in providers {
+            *matches.entry(provider.clone()).or_default() += 1;
+        }
+    }
+
+    #[cfg(feature = "otel")]
+    {
+        OTEL.duration.record(elapsed.as_secs_f64(), &[]);
+        OTEL.iterations.record(iterations as u64, &[]);
+        for provider in providers {
+            OTEL.provider_matches.add(
+                1,
+                &[opentelemetry::KeyValue::new("provider", provider.clone())],
+            );
+        }
+    }
+}
+
+/// Counts a background task going down and being scheduled for a restart.
+pub fn record_task_restart(task: &str) {
+    if let Ok(mut restarts) = TASK_RESTARTS.lock() {
+        *restarts.entry(task.to_string()).or_default() += 1;
+    }
+}
+
+fn render_labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &Mutex<BTreeMap<String, u64>>,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    if let Ok(values) = values.lock() {
+        for (value, count) in values.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                value.replace('\\', "\\\\").replace('"', "\\\""),
+                count
+            );
+        }
+    }
+}
+
+/// Renders every metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    SANITIZE_DURATION.render(
+        &mut out,
+        "clear_urls_sanitize_duration_seconds",
+        "Time spent cleaning a single URL against the rule set.",
+    );
+    SANITIZE_ITERATIONS.render(
+        &mut out,
+        "clear_urls_sanitize_iterations",
+        "Rule passes needed before a URL stopped changing.",
+    );
+    render_labeled_counter(
+        &mut out,
+        "clear_urls_provider_matches_total",
+        "URLs matched by each ClearURLs provider.",
+        "provider",
+        &PROVIDER_MATCHES,
+    );
+    render_labeled_counter(
+        &mut out,
+        "clear_urls_task_restarts_total",
+        "Background tasks that failed and were restarted.",
+        "task",
+        &TASK_RESTARTS,
+    );
+    out
+}
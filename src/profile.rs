@@ -0,0 +1,591 @@
+//! Profile bundles for moving between instances, e.g. from the hosted bot
+//! to a self-hosted one: a user's settings, ignored domains, custom rules,
+//! owned chats and optionally their history, as JSON signed with an
+//! Ed25519 key derived from the bot token.
+//!
+//! Any intact bundle carries settings and rules over, which the user could
+//! set by hand anyway. The cleaned counter, history and chat ownership are
+//! only taken from this instance's own bundles or those signed by a key in
+//! `TRUSTED_PROFILE_KEYS`.
+
+use crate::db::{unix_now, CustomRuleError, Db};
+use crate::i18n;
+use crate::models::{
+    Actor, ChatConfig, CleanedLink, CHAT_MODES, DASHBOARD_WIDGETS, THEMES, USER_MODES,
+};
+use anyhow::{Context, Result};
+use clear_urls_core::CleaningLevel;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Identifies a bundle among other JSON files.
+pub const FORMAT: &str = "clear_urls_profile";
+pub const VERSION: u32 = 1;
+
+/// What a user downloads from one instance and uploads to another.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileBundle {
+    pub format: String,
+    pub version: u32,
+    /// The [`Profile`] as JSON, signed as is so re-encoding cannot break
+    /// the signature.
+    pub payload: String,
+    /// Hex-encoded public key of the exporting instance.
+    pub public_key: String,
+    /// Hex-encoded Ed25519 signature of `payload`.
+    pub signature: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub user_id: i64,
+    pub exported_at: i64,
+    pub settings: ProfileSettings,
+    pub cleaned_count: i64,
+    pub ignored_domains: Vec<String>,
+    pub custom_rules: Vec<String>,
+    /// Chats the user added the bot to.
+    pub chats: Vec<ChatConfig>,
+    /// Only present when the user asked for it.
+    #[serde(default)]
+    pub history: Option<Vec<CleanedLink>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileSettings {
+    pub enabled: i32,
+    pub ai_enabled: i32,
+    pub mode: String,
+    pub language: String,
+    pub theme: String,
+    pub hidden_widgets: String,
+    pub cleaning_level: String,
+    /// Missing from bundles made before history could be turned off.
+    #[serde(default = "keep_history")]
+    pub log_history: i32,
+    #[serde(default)]
+    pub digest_enabled: i32,
+}
+
+fn keep_history() -> i32 {
+    1
+}
+
+/// Reasons a bundle is refused, meant to be shown to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileError {
+    Malformed,
+    UnsupportedVersion(u32),
+    BadSignature,
+    /// The bundle belongs to another Telegram account.
+    OtherUser,
+}
+
+impl std::fmt::Display for ProfileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "This is not a profile bundle"),
+            Self::UnsupportedVersion(v) => {
+                write!(f, "Profile bundle version {} is not supported", v)
+            }
+            Self::BadSignature => write!(f, "The profile bundle was modified after export"),
+            Self::OtherUser => write!(f, "This profile belongs to another account"),
+        }
+    }
+}
+
+impl std::error::Error for ProfileError {}
+
+/// Signs this instance's bundles.
+pub struct ProfileSigner {
+    key_pair: Ed25519KeyPair,
+}
+
+impl ProfileSigner {
+    /// Derived from the bot token like the dashboard link keys, so the key
+    /// survives restarts and replicas agree on it without configuration.
+    pub fn from_bot_token(bot_token: &str) -> Self {
+        let seed = Sha256::digest(format!("profile-signing:{}", bot_token));
+        let key_pair =
+            Ed25519KeyPair::from_seed_unchecked(&seed).expect("a SHA-256 digest is a valid seed");
+        Self { key_pair }
+    }
+
+    /// The hex key other instances list in `TRUSTED_PROFILE_KEYS`.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key_pair.public_key().as_ref())
+    }
+
+    pub fn sign(&self, profile: &Profile) -> Result<ProfileBundle> {
+        let payload = serde_json::to_string(profile)?;
+        let signature = self.key_pair.sign(payload.as_bytes());
+        Ok(ProfileBundle {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            payload,
+            public_key: self.public_key(),
+            signature: hex::encode(signature.as_ref()),
+        })
+    }
+}
+
+/// A bundle whose signature checked out.
+#[derive(Debug, Clone)]
+pub struct VerifiedProfile {
+    pub profile: Profile,
+    /// Signed by a key in the trusted list.
+    pub trusted: bool,
+    /// Hex-encoded public key of the exporting instance.
+    pub source_key: String,
+}
+
+impl ProfileBundle {
+    /// Checks the signature against the bundle's own key and whether that
+    /// key is one of `trusted_keys` (hex, case-insensitive).
+    pub fn verify(&self, trusted_keys: &[String]) -> Result<VerifiedProfile, ProfileError> {
+        if self.format != FORMAT {
+            return Err(ProfileError::Malformed);
+        }
+        if self.version != VERSION {
+            return Err(ProfileError::UnsupportedVersion(self.version));
+        }
+        let public_key = hex::decode(&self.public_key).map_err(|_| ProfileError::Malformed)?;
+        let signature = hex::decode(&self.signature).map_err(|_| ProfileError::Malformed)?;
+        UnparsedPublicKey::new(&ED25519, &public_key)
+            .verify(self.payload.as_bytes(), &signature)
+            .map_err(|_| ProfileError::BadSignature)?;
+        let profile = serde_json::from_str(&self.payload).map_err(|_| ProfileError::Malformed)?;
+        let trusted = trusted_keys
+            .iter()
+            .any(|key| key.trim().eq_ignore_ascii_case(&self.public_key));
+        Ok(VerifiedProfile {
+            profile,
+            trusted,
+            source_key: self.public_key.to_lowercase(),
+        })
+    }
+}
+
+/// Collects everything a bundle carries for `user_id`.
+pub async fn export_profile(db: &Db, user_id: i64, include_history: bool) -> Result<Profile> {
+    let config = db.get_user_config(user_id).await?;
+    let custom_rules = db
+        .get_custom_rules(user_id)
+        .await?
+        .into_iter()
+        .map(|rule| rule.pattern)
+        .collect();
+    let chats = db.get_chats_for_user(user_id).await?;
+    let history = if include_history {
+        Some(db.get_history(user_id, i64::MAX).await?)
+    } else {
+        None
+    };
+    Ok(Profile {
+        user_id,
+        exported_at: unix_now()?,
+        settings: ProfileSettings {
+            enabled: config.enabled,
+            ai_enabled: config.ai_enabled,
+            mode: config.mode,
+            language: config.language,
+            theme: config.theme,
+            hidden_widgets: config.hidden_widgets,
+            cleaning_level: config.cleaning_level,
+            log_history: config.log_history,
+            digest_enabled: config.digest_enabled,
+        },
+        cleaned_count: config.cleaned_count,
+        ignored_domains: split_list(&config.ignored_domains),
+        custom_rules,
+        chats,
+        history,
+    })
+}
+
+/// What an import changed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ImportSummary {
+    pub trusted: bool,
+    pub ignored_domains: usize,
+    pub custom_rules: usize,
+    pub chats: usize,
+    pub history: u64,
+    pub cleaned_count: i64,
+}
+
+/// Applies a verified bundle to `user_id`, merging with what the user
+/// already has here: settings are replaced, domains and rules added (up to
+/// `max_custom_rules`), and from trusted bundles the counter is added to
+/// (once per bundle) and history appended. Chats the user owns here take
+/// the exported settings; others nobody owns here are claimed, unverified
+/// until `/settings` checks the user administers them.
+pub async fn import_profile(
+    db: &Db,
+    user_id: i64,
+    verified: VerifiedProfile,
+    max_custom_rules: i64,
+    actor: Actor,
+) -> Result<ImportSummary> {
+    let VerifiedProfile {
+        profile,
+        trusted,
+        source_key,
+    } = verified;
+    if profile.user_id != user_id {
+        return Err(ProfileError::OtherUser.into());
+    }
+    let mut summary = ImportSummary {
+        trusted,
+        ..ImportSummary::default()
+    };
+
+    let mut config = db.get_user_config(user_id).await?;
+    let settings = profile.settings;
+    config.enabled = i32::from(settings.enabled != 0);
+    config.ai_enabled = i32::from(settings.ai_enabled != 0);
+    if USER_MODES.contains(&settings.mode.as_str()) {
+        config.mode = settings.mode;
+    }
+    if i18n::LANGUAGES.contains(&settings.language.as_str()) {
+        config.language = settings.language;
+    }
+    if THEMES.contains(&settings.theme.as_str()) {
+        config.theme = settings.theme;
+    }
+    config.hidden_widgets = split_list(&settings.hidden_widgets)
+        .into_iter()
+        .filter(|w| DASHBOARD_WIDGETS.contains(&w.as_str()))
+        .collect::<Vec<_>>()
+        .join(",");
+    if let Some(level) = CleaningLevel::parse(&settings.cleaning_level) {
+        config.cleaning_level = level.as_str().to_string();
+    }
+    config.log_history = i32::from(settings.log_history != 0);
+    config.digest_enabled = i32::from(settings.digest_enabled != 0);
+    let mut domains = split_list(&config.ignored_domains);
+    for domain in profile.ignored_domains {
+        let domain = domain.trim().to_lowercase();
+        if !domain.is_empty() && !domains.contains(&domain) {
+            domains.push(domain);
+            summary.ignored_domains += 1;
+        }
+    }
+    config.ignored_domains = domains.join(",");
+    db.save_user_config(&config, actor).await?;
+    if trusted {
+        summary.cleaned_count = db
+            .import_cleaned_count(user_id, &source_key, profile.cleaned_count)
+            .await?;
+    }
+
+    for pattern in profile.custom_rules {
+        match db
+            .add_custom_rule(user_id, &pattern, max_custom_rules, actor)
+            .await
+        {
+            Ok(()) => summary.custom_rules += 1,
+            Err(e) => match e.downcast_ref::<CustomRuleError>() {
+                Some(CustomRuleError::LimitReached(_)) => break,
+                Some(_) => continue,
+                None => return Err(e),
+            },
+        }
+    }
+
+    if !trusted {
+        return Ok(summary);
+    }
+    for chat in profile.chats {
+        match db.get_chat_owner(chat.chat_id).await? {
+            // Already the user's here: the exported settings replace these.
+            Some((owner_id, true)) if owner_id == user_id => {
+                let Some(mut existing) = db.get_chat_config(chat.chat_id).await? else {
+                    continue;
+                };
+                existing.enabled = chat.enabled;
+                existing.ai_enabled = chat.ai_enabled;
+                // Like the user settings, values this instance does not know
+                // keep what is here.
+                if CHAT_MODES.contains(&chat.mode.as_str()) {
+                    existing.mode = chat.mode;
+                }
+                if chat.cleaning_level == "default" {
+                    existing.cleaning_level = chat.cleaning_level;
+                } else if let Some(level) = CleaningLevel::parse(&chat.cleaning_level) {
+                    existing.cleaning_level = level.as_str().to_string();
+                }
+                db.save_chat_config(&existing, actor).await?;
+            }
+            // Anything else is at most claimed, pending the same check
+            // against the chat's administrators as any other claim.
+            _ => {
+                if !db
+                    .claim_chat_unverified(
+                        chat.chat_id,
+                        chat.title.as_deref(),
+                        &chat.kind,
+                        user_id,
+                        actor,
+                    )
+                    .await?
+                {
+                    continue;
+                }
+            }
+        }
+        summary.chats += 1;
+    }
+    if let Some(history) = profile.history {
+        summary.history = db
+            .import_cleaned_links(user_id, &history)
+            .await
+            .context("Failed to import history")?;
+    }
+    Ok(summary)
+}
+
+/// File name offered to the user, e.g. `clear_urls_profile_42.json`.
+pub fn bundle_name(user_id: i64) -> String {
+    format!("clear_urls_profile_{}.json", user_id)
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UserConfig;
+
+    #[tokio::test]
+    async fn moves_a_profile_between_instances() {
+        let hosted = Db::test("profile-hosted").await;
+        let own = Db::test("profile-own").await;
+        let hosted_key = ProfileSigner::from_bot_token("1:hosted");
+        let own_key = ProfileSigner::from_bot_token("2:own").public_key();
+
+        let config = UserConfig {
+            user_id: 7,
+            mode: "delete".into(),
+            ignored_domains: "a.com,b.com".into(),
+            language: "it".into(),
+            cleaning_level: "strict".into(),
+            log_history: 0,
+            ..UserConfig::default()
+        };
+        hosted
+            .save_user_config(&config, Actor::system())
+            .await
+            .unwrap();
+        hosted
+            .add_custom_rule(7, "ref_*", 10, Actor::system())
+            .await
+            .unwrap();
+        hosted
+            .set_chat_owner(-100, Some("Links"), 7, Actor::system())
+            .await
+            .unwrap();
+        hosted
+            .log_cleaned_link(7, Some(-100), None, "a", "https://a.com/", "p", "rules")
+            .await
+            .unwrap();
+        hosted.increment_cleaned_count(7, 1).await.unwrap();
+        let profile = export_profile(&hosted, 7, true).await.unwrap();
+        let bundle = hosted_key.sign(&profile).unwrap();
+
+        // Someone else's chat is never taken over.
+        own.set_chat_owner(-200, None, 8, Actor::system())
+            .await
+            .unwrap();
+        let mut with_foreign_chat = profile.clone();
+        with_foreign_chat.chats[0].chat_id = -200;
+        let foreign = hosted_key.sign(&with_foreign_chat).unwrap();
+
+        // Untrusted: settings and rules only.
+        let verified = bundle.verify(std::slice::from_ref(&own_key)).unwrap();
+        assert!(!verified.trusted);
+        let summary = import_profile(&own, 7, verified, 10, Actor::web(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ImportSummary {
+                ignored_domains: 2,
+                custom_rules: 1,
+                ..ImportSummary::default()
+            }
+        );
+        let imported = own.get_user_config(7).await.unwrap();
+        assert_eq!(imported.mode, "delete");
+        assert_eq!(imported.cleaning_level, "strict");
+        assert!(!imported.keeps_history());
+        assert_eq!(imported.cleaned_count, 0);
+        assert!(own.get_history(7, 10).await.unwrap().is_empty());
+
+        // Trusted: counter, history and chats too, and only once.
+        let trusted = [own_key, hosted_key.public_key()];
+        let summary = import_profile(&own, 7, bundle.verify(&trusted).unwrap(), 10, Actor::web(7))
+            .await
+            .unwrap();
+        assert_eq!(
+            (summary.chats, summary.history, summary.cleaned_count),
+            (1, 1, 1)
+        );
+        assert_eq!(own.get_chat_owner(-100).await.unwrap(), Some((7, false)));
+        let again = import_profile(&own, 7, bundle.verify(&trusted).unwrap(), 10, Actor::web(7))
+            .await
+            .unwrap();
+        assert_eq!((again.custom_rules, again.history), (0, 0));
+        let summary = import_profile(
+            &own,
+            7,
+            foreign.verify(&trusted).unwrap(),
+            10,
+            Actor::web(7),
+        )
+        .await
+        .unwrap();
+        assert_eq!(summary.chats, 0);
+        assert_eq!(own.get_chat_owner(-200).await.unwrap(), Some((8, true)));
+
+        // Tampered or foreign bundles are refused.
+        let mut tampered = bundle.clone();
+        tampered.payload = tampered.payload.replace("\"delete\"", "\"reply\"");
+        assert_eq!(
+            tampered.verify(&trusted).unwrap_err(),
+            ProfileError::BadSignature
+        );
+        let err = import_profile(&own, 8, bundle.verify(&trusted).unwrap(), 10, Actor::web(8))
+            .await
+            .unwrap_err();
+        assert_eq!(err.downcast_ref(), Some(&ProfileError::OtherUser));
+    }
+
+    #[tokio::test]
+    async fn imports_the_cleaned_counter_once() {
+        let hosted = Db::test("profile-count-hosted").await;
+        let own = Db::test("profile-count-own").await;
+        let hosted_key = ProfileSigner::from_bot_token("1:hosted");
+        let trusted = [hosted_key.public_key()];
+        hosted.increment_cleaned_count(7, 3).await.unwrap();
+        own.increment_cleaned_count(7, 2).await.unwrap();
+
+        let bundle = hosted_key
+            .sign(&export_profile(&hosted, 7, false).await.unwrap())
+            .unwrap();
+        for expected in [3, 0] {
+            let summary =
+                import_profile(&own, 7, bundle.verify(&trusted).unwrap(), 10, Actor::web(7))
+                    .await
+                    .unwrap();
+            assert_eq!(summary.cleaned_count, expected);
+        }
+        assert_eq!(own.get_user_config(7).await.unwrap().cleaned_count, 5);
+        assert_eq!(own.get_global_stats().await.unwrap(), (5, 1));
+
+        // A later export only brings what was cleaned since.
+        hosted.increment_cleaned_count(7, 1).await.unwrap();
+        let later = hosted_key
+            .sign(&export_profile(&hosted, 7, false).await.unwrap())
+            .unwrap();
+        let summary = import_profile(&own, 7, later.verify(&trusted).unwrap(), 10, Actor::web(7))
+            .await
+            .unwrap();
+        assert_eq!(summary.cleaned_count, 1);
+        assert_eq!(own.get_user_config(7).await.unwrap().cleaned_count, 6);
+        assert_eq!(own.get_global_stats().await.unwrap(), (6, 1));
+    }
+
+    #[tokio::test]
+    async fn imported_chats_are_only_merged_into_owned_ones() {
+        let own = Db::test("profile-chats-own").await;
+        let hosted_key = ProfileSigner::from_bot_token("1:hosted");
+        let trusted = [hosted_key.public_key()];
+        // -100 is the user's here, -200 someone else's, -300 nobody's and
+        // -400 unknown.
+        own.set_chat_owner(-100, Some("Mine"), 7, Actor::system())
+            .await
+            .unwrap();
+        own.set_chat_owner(-200, Some("Theirs"), 8, Actor::system())
+            .await
+            .unwrap();
+        own.set_chat_owner(-300, Some("Orphan"), 9, Actor::system())
+            .await
+            .unwrap();
+        own.clear_chat_owner(-300, Actor::system()).await.unwrap();
+
+        let mut profile = export_profile(&own, 7, false).await.unwrap();
+        profile.chats = [-100, -200, -300, -400]
+            .into_iter()
+            .map(|chat_id| ChatConfig {
+                chat_id,
+                title: Some("Exported".into()),
+                enabled: 0,
+                added_by: 7,
+                mode: "delete".into(),
+                ..ChatConfig::default()
+            })
+            .collect();
+        let bundle = hosted_key.sign(&profile).unwrap();
+        let summary = import_profile(&own, 7, bundle.verify(&trusted).unwrap(), 10, Actor::web(7))
+            .await
+            .unwrap();
+        assert_eq!(summary.chats, 3);
+
+        let mine = own.get_chat_config(-100).await.unwrap().unwrap();
+        assert_eq!((mine.enabled, mine.mode.as_str()), (0, "delete"));
+        assert_eq!(own.get_chat_owner(-100).await.unwrap(), Some((7, true)));
+        let theirs = own.get_chat_config(-200).await.unwrap().unwrap();
+        assert_eq!((theirs.enabled, theirs.added_by), (1, 8));
+        for chat_id in [-300, -400] {
+            let chat = own.get_chat_config(chat_id).await.unwrap().unwrap();
+            assert_eq!((chat.enabled, chat.mode.as_str()), (1, "default"));
+            assert_eq!(own.get_chat_owner(chat_id).await.unwrap(), Some((7, false)));
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_chat_settings_keep_the_existing_ones() {
+        let own = Db::test("profile-chat-settings").await;
+        let hosted_key = ProfileSigner::from_bot_token("1:hosted");
+        let trusted = [hosted_key.public_key()];
+        for chat_id in [-100, -200] {
+            own.set_chat_owner(chat_id, None, 7, Actor::system())
+                .await
+                .unwrap();
+        }
+
+        let mut profile = export_profile(&own, 7, false).await.unwrap();
+        profile.chats = [(-100, "shout", "nuclear"), (-200, "dry_run", "Strict")]
+            .into_iter()
+            .map(|(chat_id, mode, level)| ChatConfig {
+                chat_id,
+                mode: mode.into(),
+                cleaning_level: level.into(),
+                ..ChatConfig::default()
+            })
+            .collect();
+        let bundle = hosted_key.sign(&profile).unwrap();
+        import_profile(&own, 7, bundle.verify(&trusted).unwrap(), 10, Actor::web(7))
+            .await
+            .unwrap();
+
+        let kept = own.get_chat_config(-100).await.unwrap().unwrap();
+        assert_eq!(
+            (kept.mode.as_str(), kept.cleaning_level.as_str()),
+            ("default", "default")
+        );
+        let imported = own.get_chat_config(-200).await.unwrap().unwrap();
+        assert_eq!(
+            (imported.mode.as_str(), imported.cleaning_level.as_str()),
+            ("dry_run", "strict")
+        );
+    }
+}
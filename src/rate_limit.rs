@@ -0,0 +1,78 @@
+//! Fixed-window hit counters. With Redis the windows are shared by every
+//! replica through [`SharedCache::incr_window`]; otherwise each process
+//! counts on its own.
+
+use crate::shared_cache::SharedCache;
+use moka::future::Cache;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    /// Namespaces the Redis keys of this limiter.
+    prefix: &'static str,
+    window: Duration,
+    shared: SharedCache,
+    local: Cache<(String, u64), Arc<AtomicI64>>,
+}
+
+impl RateLimiter {
+    pub fn new(prefix: &'static str, window: Duration) -> Self {
+        Self {
+            prefix,
+            window,
+            shared: SharedCache::disabled(),
+            // Two windows so the current one never expires early.
+            local: Cache::builder()
+                .max_capacity(100_000)
+                .time_to_live(window * 2)
+                .build(),
+        }
+    }
+
+    pub fn with_shared_cache(mut self, shared: SharedCache) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Counts a hit for `key` at `now` (Unix seconds) and returns the hits
+    /// in the current window, this one included.
+    pub async fn hit(&self, key: &str, now: i64) -> i64 {
+        let window = self.window.as_secs().max(1);
+        let index = now.max(0) as u64 / window;
+        let shared_key = format!("rate:{}:{}:{}", self.prefix, key, index);
+        if let Some(count) = self.shared.incr_window(&shared_key, self.window).await {
+            return count;
+        }
+        self.local
+            .get_with((key.to_string(), index), async {
+                Arc::new(AtomicI64::new(0))
+            })
+            .await
+            .fetch_add(1, Ordering::Relaxed)
+            + 1
+    }
+
+    /// Seconds until the window containing `now` ends.
+    pub fn retry_after(&self, now: i64) -> u64 {
+        let window = self.window.as_secs().max(1);
+        window - now.max(0) as u64 % window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_per_key_and_window() {
+        let limiter = RateLimiter::new("test", Duration::from_secs(60));
+        assert_eq!(limiter.hit("a", 120).await, 1);
+        assert_eq!(limiter.hit("a", 179).await, 2);
+        assert_eq!(limiter.hit("b", 130).await, 1);
+        // A new window starts over.
+        assert_eq!(limiter.hit("a", 180).await, 1);
+        assert_eq!(limiter.retry_after(170), 10);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::config::{Config, SharedConfig};
+use crate::sanitizer::RuleEngine;
+use anyhow::Result;
+use std::sync::Arc;
+
+/// Re-reads the configuration and swaps in the settings that can change at
+/// runtime: rules source, AI endpoint/model/key/limits, refresh interval, job
+/// schedules, shortener list, redirect display and Instant View template,
+/// fragment exception list, path rule toggles, custom rule limit, plan
+/// limits, the admin IP allowlist, trusted profile keys, the `/clean` rate
+/// limit, the repeat window and the digest hour.
+/// Tokens, database and listen addresses keep their startup values and still
+/// require a restart. When the file cannot be read the running configuration
+/// stays as it is and the error is returned.
+pub async fn reload_config(config: &SharedConfig, rules: &RuleEngine) -> Result<()> {
+    let current = config.load_full();
+    let fresh = Config::try_load(current.config_path.clone())?;
+
+    let mut updated = (*current).clone();
+    updated.clearurls_source = fresh.clearurls_source;
+    updated.ai_api_key = fresh.ai_api_key;
+    updated.ai_api_base = fresh.ai_api_base;
+    updated.ai_model = fresh.ai_model;
+    updated.ai_timeout_secs = fresh.ai_timeout_secs;
+    updated.ai_max_retries = fresh.ai_max_retries;
+    updated.ai_daily_quota = fresh.ai_daily_quota;
+    updated.ai_summaries = fresh.ai_summaries;
+    updated.ai_phishing_check = fresh.ai_phishing_check;
+    updated.ai_phishing_threshold = fresh.ai_phishing_threshold;
+    updated.rules_refresh_interval_secs = fresh.rules_refresh_interval_secs;
+    updated.schedules = fresh.schedules;
+    updated.shorteners = fresh.shorteners;
+    updated.show_redirects = fresh.show_redirects;
+    updated.instant_view_rhash = fresh.instant_view_rhash;
+    updated.fragment_exceptions = fresh.fragment_exceptions;
+    updated.disabled_path_rules = fresh.disabled_path_rules;
+    updated.max_custom_rules = fresh.max_custom_rules;
+    updated.default_plan = fresh.default_plan;
+    updated.premium_ai_daily_quota = fresh.premium_ai_daily_quota;
+    updated.premium_max_custom_rules = fresh.premium_max_custom_rules;
+    updated.history_retention_days = fresh.history_retention_days;
+    updated.admin_ip_allowlist = fresh.admin_ip_allowlist;
+    updated.trusted_profile_keys = fresh.trusted_profile_keys;
+    updated.trust_proxy_headers = fresh.trust_proxy_headers;
+    updated.public_clean_rate_limit = fresh.public_clean_rate_limit;
+    updated.repeat_window_secs = fresh.repeat_window_secs;
+    updated.chat_reply_limit = fresh.chat_reply_limit;
+    updated.digest_hour = fresh.digest_hour;
+
+    let source_changed = rules.reconfigure(&updated);
+    config.store(Arc::new(updated));
+    tracing::info!(source_changed, "Configuration reloaded");
+
+    if source_changed {
+        rules.refresh().await?;
+    }
+    Ok(())
+}
+
+/// Reloads the configuration whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub fn spawn_sighup_listener(config: SharedConfig, rules: RuleEngine) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to install SIGHUP handler");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            tracing::info!("SIGHUP received, reloading configuration");
+            if let Err(e) = reload_config(&config, &rules).await {
+                tracing::error!(error = %e, "Failed to reload configuration");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_listener(_config: SharedConfig, _rules: RuleEngine) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn broken_files_keep_the_running_configuration() {
+        let path = std::env::temp_dir().join(format!("reload-{}.toml", std::process::id()));
+        let write = |body: &str| std::fs::write(&path, body).unwrap();
+        write("clearurls_source = \"\"\nrepeat_window = 30\n");
+        let config: SharedConfig = Arc::new(arc_swap::ArcSwap::from_pointee(
+            Config::try_load(Some(path.clone())).unwrap(),
+        ));
+        let rules = RuleEngine::new_lazy("").with_embedded_rules();
+        assert_eq!(config.load().repeat_window_secs, 30);
+
+        write("clearurls_source = \"\"\nrepeat_window = [\n");
+        assert!(reload_config(&config, &rules).await.is_err());
+        assert_eq!(config.load().repeat_window_secs, 30);
+        std::fs::remove_file(&path).unwrap();
+        let missing = reload_config(&config, &rules).await.unwrap_err();
+        assert!(format!("{:#}", missing).contains("cannot read config file"));
+        assert_eq!(config.load().repeat_window_secs, 30);
+
+        write("clearurls_source = \"\"\nrepeat_window = 5\n");
+        reload_config(&config, &rules).await.unwrap();
+        assert_eq!(config.load().repeat_window_secs, 5);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,90 +1,188 @@
+use crate::config::Config;
+use crate::db::Db;
+use crate::shared_cache::SharedCache;
 use anyhow::{Context, Result};
+use clear_urls_core::{CleaningLevel, PassStats, Rules};
 use moka::future::Cache;
-use regex::Regex;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::sync::{Arc, LazyLock, RwLock};
+use std::sync::{Arc, RwLock};
 use tracing::info;
 use url::Url;
 
-static SENSITIVE_PATTERNS: LazyLock<HashMap<&'static str, Regex>> = LazyLock::new(|| {
-    let mut m = HashMap::new();
-    // Use \b (word boundary) instead of look-arounds
-    m.insert(
-        "aws_access_key",
-        Regex::new(r"(?i)\b[A-Z0-9]{20}\b").unwrap(),
-    );
-    m.insert(
-        "aws_secret_key",
-        Regex::new(r"(?i)\b[A-Za-z0-9/+=]{40}\b").unwrap(),
-    );
-    m.insert(
-        "password",
-        Regex::new(r"(?i)password\s*[:=]\s*[^\s]+").unwrap(),
-    );
-    m.insert("ipv4", Regex::new(r"(?:\d{1,3}\.){3}\d{1,3}").unwrap());
-    m.insert(
-        "email",
-        Regex::new(r"[a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+\.[a-zA-Z]{2,}").unwrap(),
-    );
-    m
-});
-
-#[derive(Debug, Deserialize)]
-#[allow(non_snake_case)]
-struct RawProvider {
-    #[serde(default)]
-    urlPattern: String,
-    #[serde(default)]
-    rules: Vec<String>,
-    #[serde(default)]
-    exceptions: Vec<String>,
-    #[serde(default)]
-    rawRules: Vec<String>,
-    #[serde(default)]
-    redirections: Vec<String>,
-    #[serde(default)]
-    referralMarketing: Vec<String>,
-    #[serde(default)]
-    forceRedirection: bool,
-}
-
-#[derive(Debug, Deserialize)]
-struct ClearUrlsData {
-    providers: HashMap<String, RawProvider>,
-}
-
-#[derive(Clone)]
-struct CompiledProvider {
-    name: String,
-    url_pattern: Regex,
-    rules: Vec<Regex>,
-    exceptions: Vec<Regex>,
-    raw_rules: Vec<Regex>,
-    redirections: Vec<Regex>,
-    referral_marketing: Vec<Regex>,
-    _force_redirection: bool,
-}
+/// Redirects followed when expanding a shortened URL.
+const MAX_REDIRECTS: usize = 5;
+
+/// Shortener hosts expanded before cleaning unless overridden by `SHORTENERS`.
+pub const DEFAULT_SHORTENERS: &[&str] = &[
+    "bit.ly",
+    "tinyurl.com",
+    "t.co",
+    "goo.gl",
+    "rebrand.ly",
+    "buff.ly",
+    "is.gd",
+    "ow.ly",
+    "t.me",
+    "shorturl.at",
+];
+
+/// Hosts whose fragments hold app state rather than trackers, left alone
+/// unless overridden by `FRAGMENT_EXCEPTIONS`.
+pub const DEFAULT_FRAGMENT_EXCEPTIONS: &[&str] = &[
+    "mega.nz",
+    "outlook.live.com",
+    "outlook.office.com",
+    "teams.microsoft.com",
+];
 
 #[derive(Clone)]
 pub struct RuleEngine {
-    providers: Arc<RwLock<Vec<CompiledProvider>>>,
-    source_url: String,
-    cache: Cache<String, String>,
+    rules: Arc<RwLock<Rules>>,
+    /// The upstream (or embedded) rule set, before `supplemental` is merged.
+    base: Arc<RwLock<Rules>>,
+    /// Admin-defined providers from `global_providers`.
+    supplemental: Arc<RwLock<Rules>>,
+    source_url: Arc<RwLock<String>>,
+    /// When the last `refresh` succeeded (unix seconds); `None` while the
+    /// embedded rules are in use.
+    refreshed_at: Arc<RwLock<Option<i64>>>,
+    shorteners: Arc<RwLock<Vec<String>>>,
+    fragment_exceptions: Arc<RwLock<Vec<String>>>,
+    disabled_path_rules: Arc<RwLock<Vec<String>>>,
+    /// Shortlink expansions as the full redirect chain.
+    cache: Cache<String, Vec<String>>,
+    shared: SharedCache,
+    proxy_url: Option<String>,
 }
 
 impl RuleEngine {
     pub fn new_lazy(source_url: &str) -> Self {
         Self {
-            providers: Arc::new(RwLock::new(Vec::new())),
-            source_url: source_url.to_string(),
+            rules: Arc::new(RwLock::new(Rules::default())),
+            base: Arc::new(RwLock::new(Rules::default())),
+            supplemental: Arc::new(RwLock::new(Rules::default())),
+            source_url: Arc::new(RwLock::new(source_url.to_string())),
+            refreshed_at: Arc::new(RwLock::new(None)),
+            shorteners: Arc::new(RwLock::new(
+                DEFAULT_SHORTENERS.iter().map(|s| s.to_string()).collect(),
+            )),
+            fragment_exceptions: Arc::new(RwLock::new(
+                DEFAULT_FRAGMENT_EXCEPTIONS
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect(),
+            )),
+            disabled_path_rules: Arc::new(RwLock::new(Vec::new())),
             cache: Cache::builder()
                 .max_capacity(10_000)
                 .time_to_live(std::time::Duration::from_secs(3600)) // 1 hour TTL
                 .build(),
+            shared: SharedCache::disabled(),
+            proxy_url: None,
         }
     }
 
+    /// Routes rules downloads and URL expansion through a proxy.
+    pub fn with_proxy(mut self, proxy_url: Option<&str>) -> Self {
+        self.proxy_url = proxy_url.map(str::to_string);
+        self
+    }
+
+    /// Shares URL expansion results with other replicas.
+    pub fn with_shared_cache(mut self, shared: SharedCache) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Starts from the rule set compiled into the binary, for use without
+    /// network access or until the first `refresh` succeeds.
+    pub fn with_embedded_rules(self) -> Self {
+        if let Err(e) = self.install(Rules::embedded()) {
+            tracing::error!(error = %e, "Failed to install embedded rules");
+        }
+        self
+    }
+
+    /// Makes `rules` the base rule set and rebuilds the active one.
+    fn install(&self, rules: Rules) -> Result<()> {
+        *self
+            .base
+            .write()
+            .map_err(|_| anyhow::anyhow!("Lock error"))? = rules;
+        self.rebuild()
+    }
+
+    /// Recompiles the active rule set from the base rules plus the
+    /// supplemental providers, with the configured fragment exceptions and
+    /// path rule toggles applied.
+    fn rebuild(&self) -> Result<()> {
+        let mut rules = self
+            .base
+            .read()
+            .map(|r| r.clone())
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        let supplemental = self
+            .supplemental
+            .read()
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        rules.extend(&supplemental);
+        drop(supplemental);
+        let exceptions = self
+            .fragment_exceptions
+            .read()
+            .map(|e| e.clone())
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        let disabled = self
+            .disabled_path_rules
+            .read()
+            .map(|d| d.clone())
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        rules.set_fragment_exceptions(exceptions);
+        rules.set_disabled_path_rules(disabled);
+        let mut w = self
+            .rules
+            .write()
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        *w = rules;
+        Ok(())
+    }
+
+    /// Swaps the hot-reloadable settings. Returns `true` when the rules
+    /// source changed and the caller should `refresh`.
+    pub fn reconfigure(&self, config: &Config) -> bool {
+        let mut source_changed = false;
+        if let Ok(mut current) = self.source_url.write() {
+            source_changed = *current != config.clearurls_source;
+            current.clone_from(&config.clearurls_source);
+        }
+        if let Ok(mut current) = self.shorteners.write() {
+            current.clone_from(&config.shorteners);
+        }
+        if let Ok(mut current) = self.fragment_exceptions.write() {
+            current.clone_from(&config.fragment_exceptions);
+        }
+        if let Ok(mut current) = self.disabled_path_rules.write() {
+            current.clone_from(&config.disabled_path_rules);
+        }
+        if let Ok(mut rules) = self.rules.write() {
+            rules.set_fragment_exceptions(config.fragment_exceptions.clone());
+            rules.set_disabled_path_rules(config.disabled_path_rules.clone());
+        }
+        source_changed
+    }
+
+    async fn remember_expansion(&self, input_url: &str, chain: &[String]) {
+        self.cache
+            .insert(input_url.to_string(), chain.to_vec())
+            .await;
+        self.shared
+            .set(
+                &format!("expand_chain:{}", input_url),
+                &chain,
+                std::time::Duration::from_secs(3600),
+            )
+            .await;
+    }
+
     pub async fn new(source_url: &str) -> Result<Self> {
         let engine = Self::new_lazy(source_url);
         engine.refresh().await?;
@@ -92,142 +190,170 @@ impl RuleEngine {
     }
 
     pub async fn refresh(&self) -> Result<()> {
-        info!("Fetching rules from {}", self.source_url);
-        let client = reqwest::Client::builder()
+        let source_url = self
+            .source_url
+            .read()
+            .map(|s| s.clone())
+            .map_err(|_| anyhow::anyhow!("Lock error"))?;
+        info!("Fetching rules from {}", source_url);
+        let client = crate::http::client_builder(self.proxy_url.as_deref())?
             .timeout(std::time::Duration::from_secs(30))
             .build()?;
-        let resp = client.get(&self.source_url).send().await?.text().await?;
+        let resp = client.get(&source_url).send().await?.text().await?;
+
+        let compiled = Rules::from_json(&resp).context("Failed to parse ClearURLs JSON")?;
 
-        let data: ClearUrlsData =
-            serde_json::from_str(&resp).context("Failed to parse ClearURLs JSON")?;
+        let count = compiled.len();
+        self.install(compiled)?;
+        if let Ok(mut refreshed_at) = self.refreshed_at.write() {
+            *refreshed_at = crate::db::unix_now().ok();
+        }
 
-        let mut compiled_providers = Vec::new();
+        info!("Loaded {} providers", count);
+        Ok(())
+    }
 
-        for (name, provider) in data.providers {
-            if provider.urlPattern.is_empty() {
+    /// Replaces the supplemental providers with the enabled rows of
+    /// `global_providers` and returns how many were compiled. Providers that
+    /// no longer compile are skipped rather than failing the whole set.
+    pub async fn load_global_providers(&self, db: &Db) -> Result<usize> {
+        let mut providers = serde_json::Map::new();
+        for provider in db.get_global_providers().await? {
+            if provider.enabled == 0 {
                 continue;
             }
-
-            let url_pattern = match Regex::new(&provider.urlPattern) {
-                Ok(r) => r,
-                Err(_) => continue,
-            };
-
-            let compile_list = |list: &[String]| -> Vec<Regex> {
-                list.iter().filter_map(|s| Regex::new(s).ok()).collect()
-            };
-
-            compiled_providers.push(CompiledProvider {
-                name,
-                url_pattern,
-                rules: compile_list(&provider.rules),
-                exceptions: compile_list(&provider.exceptions),
-                raw_rules: compile_list(&provider.rawRules),
-                redirections: compile_list(&provider.redirections),
-                referral_marketing: compile_list(&provider.referralMarketing),
-                _force_redirection: provider.forceRedirection,
-            });
-        }
-
-        let count = compiled_providers.len();
-        {
-            if let Ok(mut w) = self.providers.write() {
-                *w = compiled_providers;
-            } else {
-                tracing::error!("Failed to acquire write lock for providers");
-                return Err(anyhow::anyhow!("Lock error"));
+            match serde_json::from_str(&provider.definition) {
+                Ok(definition) => {
+                    providers.insert(provider.name, definition);
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, name = %provider.name, "Skipping invalid global provider")
+                }
             }
         }
+        let supplemental =
+            Rules::from_json(&serde_json::json!({ "providers": providers }).to_string())
+                .context("Failed to compile global providers")?;
+        let count = supplemental.len();
+        *self
+            .supplemental
+            .write()
+            .map_err(|_| anyhow::anyhow!("Lock error"))? = supplemental;
+        self.rebuild()?;
+        Ok(count)
+    }
 
-        info!("Loaded {} providers", count);
-        Ok(())
+    /// Number of providers compiled from the last successful refresh.
+    pub fn provider_count(&self) -> usize {
+        self.rules.read().map(|r| r.len()).unwrap_or(0)
     }
 
-    #[tracing::instrument(skip(self))]
+    /// When the rules were last downloaded, or `None` if they never were.
+    pub fn refreshed_at(&self) -> Option<i64> {
+        self.refreshed_at.read().map(|r| *r).unwrap_or(None)
+    }
+
+    /// Expands a shortened URL, returning it unchanged when it is not on a
+    /// known shortener or cannot be reached.
     pub async fn expand_url(&self, input_url: &str) -> String {
+        self.expand_url_chain(input_url)
+            .await
+            .pop()
+            .unwrap_or_else(|| input_url.to_string())
+    }
+
+    /// Like [`expand_url`](Self::expand_url), but returns every URL visited:
+    /// the input, each redirect hop, and the destination last.
+    #[tracing::instrument(skip(self))]
+    pub async fn expand_url_chain(&self, input_url: &str) -> Vec<String> {
         if let Some(cached) = self.cache.get(input_url).await {
             tracing::debug!(url = %input_url, "Cache hit for URL expansion");
             return cached;
         }
-
-        let client = match reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(10))
-            .redirect(reqwest::redirect::Policy::limited(5))
-            .build()
+        if let Some(shared) = self
+            .shared
+            .get::<Vec<String>>(&format!("expand_chain:{}", input_url))
+            .await
         {
-            Ok(c) => c,
-            Err(_) => return input_url.to_string(),
+            tracing::debug!(url = %input_url, "Shared cache hit for URL expansion");
+            self.cache
+                .insert(input_url.to_string(), shared.clone())
+                .await;
+            return shared;
+        }
+        let unchanged = vec![input_url.to_string()];
+
+        // We only want to expand links on a known shortener's host
+        let Ok(mut current) = Url::parse(input_url) else {
+            return unchanged;
         };
+        let is_shortener = self
+            .shorteners
+            .read()
+            .map(|list| is_shortener_host(&current, &list))
+            .unwrap_or(false);
+        if !is_shortener {
+            return unchanged;
+        }
 
-        // We only want to expand common shorteners or if it looks like a redirector
-        let url_lower = input_url.to_lowercase();
-        let shorteners = [
-            "bit.ly",
-            "tinyurl.com",
-            "t.co",
-            "goo.gl",
-            "rebrand.ly",
-            "buff.ly",
-            "is.gd",
-            "ow.ly",
-            "t.me",
-            "shorturl.at",
-        ];
-
-        let is_shortener = shorteners.iter().any(|s| url_lower.contains(s));
-
-        if is_shortener {
-            tracing::debug!(url = %input_url, "Attempting to expand shortened URL");
-            if let Ok(resp) = client.head(input_url).send().await {
-                let final_url = resp.url().to_string();
-                if final_url != input_url {
-                    tracing::info!(original = %input_url, expanded = %final_url, "URL expanded successfully");
-                    self.cache.insert(input_url.to_string(), final_url.clone()).await;
-                    return final_url;
-                }
+        // Redirects are followed by hand so every hop can be recorded, and
+        // checked like page fetches: a hop to a local or private address is
+        // neither requested nor recorded.
+        tracing::debug!(url = %input_url, "Attempting to expand shortened URL");
+        let mut addr = crate::ai_sanitizer::resolve_public(&current).await;
+        let mut chain = unchanged;
+        while chain.len() <= MAX_REDIRECTS {
+            let Some(resolved) = addr else {
+                break;
+            };
+            let client = crate::http::client_builder(self.proxy_url.as_deref()).and_then(|b| {
+                let b = b
+                    .timeout(std::time::Duration::from_secs(10))
+                    .redirect(reqwest::redirect::Policy::none());
+                let b = match current.host() {
+                    Some(url::Host::Domain(domain)) => b.resolve(domain, resolved),
+                    _ => b,
+                };
+                Ok(b.build()?)
+            });
+            let Ok(client) = client else {
+                break;
+            };
+            let Ok(resp) = client.head(current.clone()).send().await else {
+                break;
+            };
+            let next = resp
+                .status()
+                .is_redirection()
+                .then(|| resp.headers().get(reqwest::header::LOCATION))
+                .flatten()
+                .and_then(|location| location.to_str().ok())
+                .and_then(|location| current.join(location).ok());
+            let Some(next) = next.filter(|next| !chain.contains(&next.to_string())) else {
+                break;
+            };
+            addr = crate::ai_sanitizer::resolve_public(&next).await;
+            if addr.is_none() {
+                tracing::debug!(url = %next, "Not following a redirect to a non-public address");
+                break;
             }
+            chain.push(next.to_string());
+            current = next;
         }
-
-        if is_shortener {
-             self.cache.insert(input_url.to_string(), input_url.to_string()).await;
+        if chain.len() > 1 {
+            tracing::info!(
+                original = %input_url,
+                expanded = %chain[chain.len() - 1],
+                hops = chain.len() - 1,
+                "URL expanded successfully"
+            );
         }
-
-        input_url.to_string()
+        self.remember_expansion(input_url, &chain).await;
+        chain
     }
 
     pub fn redact_sensitive(&self, text: &str) -> String {
-        let mut redacted = text.to_string();
-        for (name, re) in SENSITIVE_PATTERNS.iter() {
-            redacted = re
-                .replace_all(&redacted, format!("[REDACTED {}]", name.to_uppercase()))
-                .to_string();
-        }
-        redacted
-    }
-
-    fn clean_github_url(&self, url: &mut Url) -> bool {
-        if let Some(host) = url.host_str() {
-            if host == "github.com" {
-                let path_segments: Vec<String> = url
-                    .path_segments()
-                    .map(|s| s.map(String::from).collect())
-                    .unwrap_or_default();
-
-                // If it's a deep link (e.g. /owner/repo/blob/main/file.ext), truncate to /owner/repo
-                if path_segments.len() > 2 {
-                    let owner = &path_segments[0];
-                    let repo = &path_segments[1];
-                    let new_path = format!("/{}/{}", owner, repo);
-                    if url.path() != new_path {
-                        url.set_path(&new_path);
-                        url.set_query(None);
-                        url.set_fragment(None);
-                        return true;
-                    }
-                }
-            }
-        }
-        false
+        clear_urls_core::redact_sensitive(text)
     }
 
     #[tracing::instrument(skip(self, custom_rules, ignored_domains))]
@@ -236,287 +362,61 @@ impl RuleEngine {
         text: &str,
         custom_rules: &[crate::models::CustomRule],
         ignored_domains: &[String],
+        level: CleaningLevel,
     ) -> Option<(String, String)> {
         tracing::debug!(url = %self.redact_sensitive(text), "Starting sanitization");
 
-        let mut url_to_parse = text.to_string();
-        if !url_to_parse.contains("://") && !url_to_parse.starts_with("mailto:") {
-            url_to_parse = format!("http://{}", url_to_parse);
+        let custom_params: Vec<String> = custom_rules.iter().map(|r| r.pattern.clone()).collect();
+        let started = std::time::Instant::now();
+        let mut stats = PassStats::default();
+        let result = self.rules.read().ok().and_then(|rules| {
+            rules.sanitize_at_level(text, &custom_params, ignored_domains, level, &mut stats)
+        });
+        if stats.passes > 0 {
+            crate::metrics::record_sanitization(started.elapsed(), stats.passes, &stats.matched);
         }
 
-        if let Ok(mut url) = Url::parse(&url_to_parse) {
-            if let Some(host) = url.host_str() {
-                if ignored_domains.iter().any(|d| host.contains(d)) {
-                    tracing::debug!(host = %host, "URL host is in ignored domains");
-                    return None;
-                }
-            }
-
-            let mut provider_name = String::from("Custom/Other");
-            let github_changed = self.clean_github_url(&mut url);
-            if github_changed {
-                provider_name = "GitHub (Repo Root)".to_string();
-            }
-
-            // 1. Apply Custom User Rules FIRST
-            let mut custom_changed = false;
-            if let Some(_query) = url.query() {
-                let query_pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
-                let mut new_query = url::form_urlencoded::Serializer::new(String::new());
-                let mut any_kept = false;
-
-                for (key, value) in query_pairs {
-                    let mut keep = true;
-                    for crule in custom_rules {
-                        if key.contains(&crule.pattern) {
-                            keep = false;
-                            custom_changed = true;
-                            tracing::debug!(param = %key, rule = %crule.pattern, "Custom rule matched");
-                            break;
-                        }
-                    }
-                    if keep {
-                        new_query.append_pair(&key, &value);
-                        any_kept = true;
-                    }
-                }
-
-                if custom_changed {
-                    if any_kept {
-                        url.set_query(Some(&new_query.finish()));
-                    } else {
-                        url.set_query(None);
-                    }
-                }
-            }
-
-            // 2. Identify Provider
-            {
-                if let Ok(providers) = self.providers.read() {
-                    for p in providers.iter() {
-                        if p.url_pattern.is_match(text) {
-                            provider_name = p.name.clone();
-                            tracing::debug!(provider = %provider_name, "Provider identified");
-                            break;
-                        }
-                    }
-                }
-            }
-
-            // 3. Apply Extended Algorithm
-            let mut changed = self.clean_url_in_place(&mut url);
-
-            // 4. Aggressive Fallback for common trackers not in the ruleset
-            // (e.g. Google Search gs_lcrp, oq, client, etc.)
-            if let Some(_query) = url.query() {
-                let query_pairs: Vec<(String, String)> = url.query_pairs().into_owned().collect();
-                let mut new_query = url::form_urlencoded::Serializer::new(String::new());
-                let mut aggressive_changed = false;
-                let mut any_kept = false;
-
-                let aggressive_trackers = [
-                    "gs_lcrp",
-                    "oq",
-                    "sourceid",
-                    "client",
-                    "bih",
-                    "biw",
-                    "ved",
-                    "ei",
-                    "iflsig",
-                    "adgrpid",
-                    "nw",
-                    "matchtype",
-                ];
-
-                for (key, value) in query_pairs {
-                    if aggressive_trackers.contains(&key.as_str()) {
-                        aggressive_changed = true;
-                        tracing::debug!(param = %key, "Aggressive tracker stripped");
-                        continue;
-                    }
-                    new_query.append_pair(&key, &value);
-                    any_kept = true;
-                }
-
-                if aggressive_changed {
-                    changed = true;
-                    if any_kept {
-                        url.set_query(Some(&new_query.finish()));
-                    } else {
-                        url.set_query(None);
-                    }
-                }
-            }
-
-            if changed || custom_changed || github_changed {
-                let cleaned = url.to_string();
-                tracing::info!(
-                    original = %self.redact_sensitive(text),
-                    cleaned = %cleaned,
-                    provider = %provider_name,
-                    "URL successfully cleaned"
-                );
-                return Some((cleaned, provider_name));
-            }
+        if let Some((cleaned, provider_name)) = &result {
+            tracing::info!(
+                original = %self.redact_sensitive(text),
+                cleaned = %cleaned,
+                provider = %provider_name,
+                "URL successfully cleaned"
+            );
         }
-        None
+        result
     }
 
+    /// Applies the provider rules to `url` until it stops changing, recording
+    /// latency, pass count and matched providers in [`crate::metrics`].
     #[tracing::instrument(skip(self, url))]
     pub fn clean_url_in_place(&self, url: &mut Url) -> bool {
-        let mut changed = false;
-        let mut iterations = 0;
-        const MAX_ITERATIONS: usize = 5;
-
-        while iterations < MAX_ITERATIONS {
-            let url_str = url.to_string();
-            let mut current_iteration_changed = false;
-
-            if let Ok(providers) = self.providers.read() {
-                // 1. Match specific providers AND the global/generic one if it exists
-                for provider in providers.iter() {
-                    // "generic" provider usually matches everything or has a catch-all pattern
-                    if provider.url_pattern.is_match(&url_str) || provider.name == "generic" {
-                        let mut provider_changed = false;
-
-                        // Exceptions check
-                        let mut is_exception = false;
-                        for exception in &provider.exceptions {
-                            if exception.is_match(&url_str) {
-                                is_exception = true;
-                                break;
-                            }
-                        }
-                        if is_exception {
-                            continue;
-                        }
-
-                        // Handle redirections
-                        for redirection_regex in &provider.redirections {
-                            if let Some(caps) = redirection_regex.captures(&url_str) {
-                                if let Some(m) = caps.get(1) {
-                                    if let Ok(new_url) = Url::parse(m.as_str()) {
-                                        *url = new_url;
-                                        current_iteration_changed = true;
-                                        provider_changed = true;
-                                        changed = true;
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-
-                        if provider_changed {
-                            continue;
-                        }
-
-                        // Handle Query Parameters
-                        if let Some(_query) = url.query() {
-                            let query_pairs: Vec<(String, String)> =
-                                url.query_pairs().into_owned().collect();
-                            let mut new_query =
-                                url::form_urlencoded::Serializer::new(String::new());
-                            let mut params_removed = false;
-                            let mut any_kept = false;
-
-                            for (key, mut value) in query_pairs {
-                                let mut keep = true;
-
-                                // Apply rules
-                                for rule in &provider.rules {
-                                    if rule.is_match(&key) {
-                                        keep = false;
-                                        break;
-                                    }
-                                }
-                                if keep {
-                                    for rule in &provider.referral_marketing {
-                                        if rule.is_match(&key) {
-                                            keep = false;
-                                            break;
-                                        }
-                                    }
-                                }
-
-                                if keep {
-                                    // Recursive cleaning: check if value is a URL
-                                    if value.starts_with("http") {
-                                        if let Ok(mut inner_url) = Url::parse(&value) {
-                                            if self.clean_url_in_place(&mut inner_url) {
-                                                value = inner_url.to_string();
-                                                changed = true;
-                                            }
-                                        }
-                                    }
-                                    new_query.append_pair(&key, &value);
-                                    any_kept = true;
-                                } else {
-                                    params_removed = true;
-                                }
-                            }
-
-                            if params_removed {
-                                changed = true;
-                                current_iteration_changed = true;
-                                if any_kept {
-                                    url.set_query(Some(&new_query.finish()));
-                                } else {
-                                    url.set_query(None);
-                                }
-                            }
-                        }
-
-                        // Handle Fragment (hash) - some tracking is after #
-                        if let Some(fragment) = url.fragment() {
-                            if fragment.contains('=') {
-                                // Try to parse fragment as query string
-                                let frag_url_str = format!("http://localhost?{}", fragment);
-                                if let Ok(mut frag_url) = Url::parse(&frag_url_str) {
-                                    if self.clean_url_in_place(&mut frag_url) {
-                                        if let Some(new_frag) = frag_url.query() {
-                                            url.set_fragment(Some(new_frag));
-                                        } else {
-                                            url.set_fragment(None);
-                                        }
-                                        changed = true;
-                                        current_iteration_changed = true;
-                                    }
-                                }
-                            }
-                        }
-
-                        // Raw rules
-                        let mut intermediate_url_str = url.to_string();
-                        let mut raw_changed = false;
-                        for raw in &provider.raw_rules {
-                            let new_str = raw.replace_all(&intermediate_url_str, "");
-                            if new_str != intermediate_url_str {
-                                intermediate_url_str = new_str.to_string();
-                                raw_changed = true;
-                            }
-                        }
-
-                        if raw_changed {
-                            if let Ok(new_url) = Url::parse(&intermediate_url_str) {
-                                *url = new_url;
-                                changed = true;
-                                current_iteration_changed = true;
-                            }
-                        }
-                    }
-                }
-            }
-
-            if !current_iteration_changed {
-                break;
-            }
-            iterations += 1;
-        }
+        let started = std::time::Instant::now();
+        let mut stats = PassStats::default();
+        let (changed, passes) = match self.rules.read() {
+            Ok(rules) => rules.clean_url(url, &mut stats),
+            Err(_) => return false,
+        };
+        crate::metrics::record_sanitization(started.elapsed(), passes, &stats.matched);
         changed
     }
 }
 
+/// Whether `url`'s host is one of `shorteners` or a subdomain of one.
+fn is_shortener_host(url: &Url, shorteners: &[String]) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_lowercase();
+    shorteners.iter().any(|shortener| {
+        let shortener = shortener.trim().to_lowercase();
+        !shortener.is_empty()
+            && host
+                .strip_suffix(shortener.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -526,22 +426,14 @@ mod tests {
         let engine = RuleEngine::new_lazy("");
 
         // Mock a generic provider
-        {
-            let mut w = engine.providers.write().unwrap();
-            w.push(CompiledProvider {
-                name: "generic".to_string(),
-                url_pattern: Regex::new(".*").unwrap(),
-                rules: vec![Regex::new("utm_.*").unwrap()],
-                exceptions: vec![],
-                raw_rules: vec![],
-                redirections: vec![],
-                referral_marketing: vec![],
-                _force_redirection: false,
-            });
-        }
+        *engine.rules.write().unwrap() =
+            Rules::from_json(r#"{"providers":{"generic":{"urlPattern":".*","rules":["utm_.*"]}}}"#)
+                .unwrap();
 
         let input = "https://example.com/?utm_source=test&foo=bar";
-        let (cleaned, _) = engine.sanitize(input, &[], &[]).unwrap();
+        let (cleaned, _) = engine
+            .sanitize(input, &[], &[], CleaningLevel::Standard)
+            .unwrap();
         assert_eq!(cleaned, "https://example.com/?foo=bar");
     }
 
@@ -559,8 +451,56 @@ mod tests {
     async fn test_github_cleaning() {
         let engine = RuleEngine::new_lazy("");
         let input = "https://github.com/owner/repo/blob/main/README.md?foo=bar#L10";
-        let (cleaned, provider) = engine.sanitize(input, &[], &[]).unwrap();
+        let (cleaned, provider) = engine
+            .sanitize(input, &[], &[], CleaningLevel::Standard)
+            .unwrap();
         assert_eq!(cleaned, "https://github.com/owner/repo");
         assert_eq!(provider, "GitHub (Repo Root)");
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn redirects_to_private_addresses_are_not_followed() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap());
+        let engine = RuleEngine::new_lazy("").with_proxy(Some(&proxy));
+        *engine.shorteners.write().unwrap() = vec!["93.184.216.34".into(), "127.0.0.1".into()];
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            while let Ok(Ok((mut socket, _))) =
+                tokio::time::timeout(std::time::Duration::from_millis(500), listener.accept()).await
+            {
+                let mut request = vec![0; 1024];
+                let len = socket.read(&mut request).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..len]).into_owned());
+                socket
+                    .write_all(b"HTTP/1.1 301 Moved Permanently\r\nLocation: http://169.254.169.254/latest/meta-data\r\nContent-Length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+            requests
+        });
+
+        let short = "http://93.184.216.34/abc";
+        assert_eq!(engine.expand_url_chain(short).await, [short]);
+        let local = "http://127.0.0.1/x";
+        assert_eq!(engine.expand_url_chain(local).await, [local]);
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with("HEAD http://93.184.216.34/abc HTTP/1.1"));
+    }
+
+    #[test]
+    fn shorteners_are_matched_on_the_host() {
+        let list = vec!["bit.ly".to_string(), "t.co".to_string()];
+        let check = |url: &str| is_shortener_host(&Url::parse(url).unwrap(), &list);
+        assert!(check("https://bit.ly/abc"));
+        assert!(check("https://BIT.LY/abc"));
+        assert!(check("https://www.bit.ly/abc"));
+        assert!(!check("https://evil.example/?x=bit.ly"));
+        assert!(!check("https://notbit.ly/abc"));
+        assert!(!check("https://t.com/abc"));
+        assert!(!check("https://bit.ly.evil.example/abc"));
+    }
+}
@@ -0,0 +1,435 @@
+//! Periodic background jobs (rules refresh, retention, digests, ...) on
+//! cron schedules. Each job runs in a supervised loop that re-reads its
+//! schedule from the configuration before every wait, so a reload applies
+//! to the next run. The outcome of each run is kept for the admin's
+//! `/admin/jobs`.
+//!
+//! Schedules are standard five-field cron expressions (`minute hour
+//! day-of-month month day-of-week`) in UTC, set per job with
+//! `SCHEDULE_<JOB>` (e.g. `SCHEDULE_RETENTION="*/30 * * * *"`). Fields take
+//! `*`, numbers, ranges, lists and `/` steps; `@hourly`, `@daily`,
+//! `@weekly` and `@monthly` are accepted too.
+
+use crate::config::{Config, SharedConfig};
+use crate::db::unix_now;
+use crate::supervisor::Supervisor;
+use anyhow::Result;
+use futures::future::BoxFuture;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+/// Jobs whose schedule can be configured, as named in `SCHEDULE_<JOB>`
+/// (upper case, `-` as `_`) and on `/admin/jobs`.
+pub const JOBS: &[&str] = &["rules-refresh", "retention", "digest", "weekly-digest"];
+
+/// Longest a job loop sleeps before looking at its schedule again.
+const RECHECK_SECS: i64 = 3600;
+
+/// A parsed cron expression. Each field is a bit set of allowed values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronSchedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day-of-month and day-of-week restrict the day together only when
+    /// one of them is `*`; otherwise either may match, as in cron.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronSchedule {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let expanded = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!(
+                "expected 5 fields in \"{}\", found {}",
+                expression,
+                fields.len()
+            ));
+        };
+        // 7 is Sunday as well as 0.
+        let weekdays = parse_field(weekday, 0, 7)?;
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// The first matching minute strictly after `now` (Unix seconds), or
+    /// `None` when nothing matches within five years (e.g. February 30).
+    pub fn next_after(&self, now: i64) -> Option<i64> {
+        let mut t = now.div_euclid(60) * 60 + 60;
+        let limit = t + 5 * 366 * 86400;
+        while t < limit {
+            let at = OffsetDateTime::from_unix_timestamp(t).ok()?;
+            if !has(self.months, u8::from(at.month()).into()) || !self.day_matches(&at) {
+                t = (t.div_euclid(86400) + 1) * 86400;
+            } else if !has(self.hours, at.hour().into()) {
+                t = (t.div_euclid(3600) + 1) * 3600;
+            } else if !has(self.minutes, at.minute().into()) {
+                t += 60;
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, at: &OffsetDateTime) -> bool {
+        let day = has(self.days, at.day().into());
+        let weekday = has(self.weekdays, at.weekday().number_days_from_sunday().into());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn has(set: u64, value: u64) -> bool {
+    set & (1 << value) != 0
+}
+
+/// Parses one field (`*`, `5`, `1-5`, `*/15`, `0-30/10`, `1,15`) into a
+/// bit set of the values between `min` and `max`.
+fn parse_field(field: &str, min: u64, max: u64) -> Result<u64, String> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u64 = step
+                    .parse()
+                    .ok()
+                    .filter(|s| *s > 0)
+                    .ok_or_else(|| format!("invalid step in \"{}\"", part))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let value = |v: &str| -> Result<u64, String> {
+            v.parse()
+                .ok()
+                .filter(|v| (min..=max).contains(v))
+                .ok_or_else(|| format!("\"{}\" is not between {} and {}", v, min, max))
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/10` means from 5 to the end, every 10.
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return Err(format!("empty range \"{}\"", range));
+        }
+        for v in (start..=end).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Cron(CronSchedule),
+    /// A fixed interval from the previous run, for settings given in
+    /// seconds such as `RULES_REFRESH_INTERVAL`.
+    Every(Duration),
+}
+
+impl Schedule {
+    /// The next run after `now` for a job last started at `last_run`.
+    pub fn next_after(&self, now: i64, last_run: i64) -> Option<i64> {
+        match self {
+            Self::Cron(cron) => cron.next_after(now),
+            Self::Every(interval) => Some(last_run + interval.as_secs().max(1) as i64),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Self::Cron(cron) => cron.expression.clone(),
+            Self::Every(interval) => format!("every {}s", interval.as_secs()),
+        }
+    }
+}
+
+/// The schedule of `job`: `SCHEDULE_<JOB>` when set, otherwise what the
+/// older dedicated settings imply.
+pub fn schedule_for(config: &Config, job: &str) -> Result<Schedule, String> {
+    if let Some(expression) = config.schedules.get(job) {
+        return CronSchedule::parse(expression).map(Schedule::Cron);
+    }
+    let expression = match job {
+        "rules-refresh" => {
+            let secs = config.rules_refresh_interval_secs.max(60);
+            return Ok(Schedule::Every(Duration::from_secs(secs)));
+        }
+        "digest" => format!("0 {} * * *", config.digest_hour.min(23)),
+        "weekly-digest" => format!("0 {} * * 1", config.digest_hour.min(23)),
+        _ => "@hourly".to_string(),
+    };
+    CronSchedule::parse(&expression).map(Schedule::Cron)
+}
+
+/// The environment variable holding the schedule of `job`.
+pub fn schedule_key(job: &str) -> String {
+    format!("SCHEDULE_{}", job.to_uppercase().replace('-', "_"))
+}
+
+/// Health of one job, as shown on `/admin/jobs`. Times are Unix seconds.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub next_run_at: Option<i64>,
+    pub last_started_at: Option<i64>,
+    pub last_duration_ms: Option<u64>,
+    /// Whether the last run succeeded; `None` before the first one.
+    pub last_ok: Option<bool>,
+    pub last_error: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+}
+
+type JobFn = Arc<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Scheduler {
+    config: SharedConfig,
+    jobs: Arc<Mutex<BTreeMap<String, JobStatus>>>,
+}
+
+impl Scheduler {
+    pub fn new(config: SharedConfig) -> Self {
+        Self {
+            config,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Runs `job` on the schedule of `name` under `supervisor`, first right
+    /// away when `run_at_start`. A failing run is recorded and the job runs
+    /// again at its next scheduled time.
+    pub fn register<F, Fut>(
+        &self,
+        supervisor: &Supervisor,
+        name: &'static str,
+        run_at_start: bool,
+        job: F,
+    ) where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let job: JobFn = Arc::new(move || Box::pin(job()));
+        self.update(name, |_| {});
+        let scheduler = self.clone();
+        supervisor.spawn(format!("job-{}", name), move || {
+            let (scheduler, job) = (scheduler.clone(), job.clone());
+            async move { scheduler.run_loop(name, run_at_start, job).await }
+        });
+    }
+
+    /// Every registered job, by name.
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.jobs
+            .lock()
+            .map(|jobs| jobs.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn update(&self, name: &str, change: impl FnOnce(&mut JobStatus)) {
+        if let Ok(mut jobs) = self.jobs.lock() {
+            let status = jobs.entry(name.to_string()).or_insert_with(|| JobStatus {
+                name: name.to_string(),
+                ..JobStatus::default()
+            });
+            change(status);
+        }
+    }
+
+    async fn run_loop(&self, name: &'static str, run_at_start: bool, job: JobFn) -> Result<()> {
+        let mut last_run = unix_now()?;
+        if run_at_start {
+            self.run_once(name, &job).await;
+        }
+        loop {
+            let now = unix_now()?;
+            let next = match schedule_for(&self.config.load(), name) {
+                Ok(schedule) => {
+                    let next = schedule.next_after(now, last_run);
+                    self.update(name, |s| {
+                        s.schedule = schedule.describe();
+                        s.next_run_at = next;
+                    });
+                    next
+                }
+                Err(e) => {
+                    tracing::error!(job = name, error = %e, "Invalid job schedule");
+                    self.update(name, |s| {
+                        s.schedule = format!("invalid: {}", e);
+                        s.next_run_at = None;
+                    });
+                    None
+                }
+            };
+            // Far-off runs are re-planned later so schedule edits apply.
+            match next {
+                Some(next) if next - now <= RECHECK_SECS => {
+                    tokio::time::sleep(Duration::from_secs((next - now).max(0) as u64)).await;
+                    last_run = unix_now()?;
+                    self.run_once(name, &job).await;
+                }
+                _ => tokio::time::sleep(Duration::from_secs(RECHECK_SECS as u64)).await,
+            }
+        }
+    }
+
+    async fn run_once(&self, name: &str, job: &JobFn) {
+        let started = Instant::now();
+        let started_at = unix_now().unwrap_or(0);
+        self.update(name, |s| {
+            s.running = true;
+            s.last_started_at = Some(started_at);
+        });
+        // Spawned so a panicking run is recorded like a failed one.
+        let error = match tokio::spawn(job()).await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("{:#}", e)),
+            Err(e) => Some(format!("panicked: {}", e)),
+        };
+        if let Some(error) = &error {
+            tracing::error!(job = name, error = %error, "Scheduled job failed");
+        }
+        self.update(name, |s| {
+            s.running = false;
+            s.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+            s.last_ok = Some(error.is_none());
+            s.runs += 1;
+            s.failures += u64::from(error.is_some());
+            s.last_error = error;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const MONDAY: i64 = 1_704_067_200;
+
+    fn next(expression: &str, now: i64) -> i64 {
+        CronSchedule::parse(expression)
+            .unwrap()
+            .next_after(now)
+            .unwrap()
+    }
+
+    #[test]
+    fn finds_the_next_matching_minute() {
+        assert_eq!(next("* * * * *", MONDAY), MONDAY + 60);
+        assert_eq!(next("*/15 * * * *", MONDAY + 60), MONDAY + 15 * 60);
+        assert_eq!(next("@hourly", MONDAY + 1), MONDAY + 3600);
+        assert_eq!(next("30 2 * * *", MONDAY), MONDAY + 2 * 3600 + 30 * 60);
+        // Sunday is both 0 and 7.
+        assert_eq!(next("0 0 * * 0", MONDAY), MONDAY + 6 * 86400);
+        assert_eq!(next("0 0 * * 7", MONDAY), MONDAY + 6 * 86400);
+        assert_eq!(
+            next("0 9 * * 1-5", MONDAY + 10 * 3600),
+            MONDAY + 86400 + 9 * 3600
+        );
+        // Day of month or day of week when both are given.
+        assert_eq!(next("0 0 15 * 3", MONDAY), MONDAY + 2 * 86400);
+        assert_eq!(next("0 0 1 2 *", MONDAY), MONDAY + 31 * 86400);
+        assert!(CronSchedule::parse("0 0 30 2 *")
+            .unwrap()
+            .next_after(MONDAY)
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "60 * * * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "a * * * *",
+        ] {
+            assert!(CronSchedule::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn older_settings_give_the_default_schedules() {
+        let mut config = Config::load(None);
+        config.digest_hour = 20;
+        config.rules_refresh_interval_secs = 3600;
+        config.schedules.clear();
+        let digest = schedule_for(&config, "digest").unwrap();
+        assert_eq!(digest.next_after(MONDAY, 0), Some(MONDAY + 20 * 3600));
+        assert_eq!(
+            digest.next_after(MONDAY + 20 * 3600, 0),
+            Some(MONDAY + 86400 + 20 * 3600)
+        );
+        let refresh = schedule_for(&config, "rules-refresh").unwrap();
+        assert_eq!(refresh.next_after(MONDAY + 10, MONDAY), Some(MONDAY + 3600));
+        let weekly = schedule_for(&config, "weekly-digest").unwrap();
+        assert_eq!(
+            weekly.next_after(MONDAY + 20 * 3600, 0),
+            Some(MONDAY + 7 * 86400 + 20 * 3600)
+        );
+
+        config.schedules.insert("digest".into(), "0 8 * * 1".into());
+        let digest = schedule_for(&config, "digest").unwrap();
+        assert_eq!(digest.describe(), "0 8 * * 1");
+        assert_eq!(schedule_key("rules-refresh"), "SCHEDULE_RULES_REFRESH");
+    }
+
+    #[tokio::test]
+    async fn records_the_outcome_of_each_run() {
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(Config::load(None)));
+        let scheduler = Scheduler::new(config);
+        scheduler.register(&Supervisor::new(), "retention", true, || async {
+            anyhow::bail!("database is locked")
+        });
+        for _ in 0..50 {
+            if scheduler.statuses()[0].runs > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let status = &scheduler.statuses()[0];
+        assert_eq!(status.name, "retention");
+        assert_eq!((status.runs, status.failures), (1, 1));
+        assert_eq!(status.last_ok, Some(false));
+        assert_eq!(status.last_error.as_deref(), Some("database is locked"));
+    }
+}
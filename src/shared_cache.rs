@@ -0,0 +1,263 @@
+//! Optional Redis layer shared by every replica of the bot.
+//!
+//! Without the `redis` cargo feature (or without `REDIS_URL`) every method is a
+//! no-op, so callers can use it unconditionally and fall back to their local
+//! in-memory caches.
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+
+/// Pub/sub channel used to mirror real-time cleaning events across replicas.
+pub const EVENTS_CHANNEL: &str = "clear_urls:events";
+
+#[derive(Clone, Default)]
+pub struct SharedCache {
+    #[cfg(feature = "redis")]
+    inner: Option<RedisInner>,
+}
+
+#[cfg(feature = "redis")]
+#[derive(Clone)]
+struct RedisInner {
+    client: redis::Client,
+    conn: redis::aio::ConnectionManager,
+}
+
+impl SharedCache {
+    /// A cache that never stores anything.
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    #[cfg(feature = "redis")]
+    pub async fn connect(redis_url: Option<&str>) -> Result<Self> {
+        let Some(url) = redis_url else {
+            return Ok(Self::disabled());
+        };
+        let client = redis::Client::open(url)?;
+        let conn = client.get_connection_manager().await?;
+        tracing::info!("Connected to Redis shared cache");
+        Ok(Self {
+            inner: Some(RedisInner { client, conn }),
+        })
+    }
+
+    #[cfg(not(feature = "redis"))]
+    pub async fn connect(redis_url: Option<&str>) -> Result<Self> {
+        if redis_url.is_some() {
+            tracing::warn!("REDIS_URL is set but the binary was built without the `redis` feature");
+        }
+        Ok(Self::disabled())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        #[cfg(feature = "redis")]
+        {
+            self.inner.is_some()
+        }
+        #[cfg(not(feature = "redis"))]
+        {
+            false
+        }
+    }
+
+    /// Reads a JSON value. Connection and decoding errors are logged and
+    /// treated as a miss.
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        #[cfg(feature = "redis")]
+        if let Some(inner) = &self.inner {
+            use redis::AsyncCommands;
+            let mut conn = inner.conn.clone();
+            match conn.get::<_, Option<String>>(key).await {
+                Ok(Some(raw)) => return serde_json::from_str(&raw).ok(),
+                Ok(None) => return None,
+                Err(e) => tracing::warn!(error = %e, key, "Redis GET failed"),
+            }
+        }
+        let _ = key;
+        None
+    }
+
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T, ttl: Duration) {
+        #[cfg(feature = "redis")]
+        if let Some(inner) = &self.inner {
+            use redis::AsyncCommands;
+            let Ok(raw) = serde_json::to_string(value) else {
+                return;
+            };
+            let mut conn = inner.conn.clone();
+            if let Err(e) = conn
+                .set_ex::<_, _, ()>(key, raw, ttl.as_secs().max(1))
+                .await
+            {
+                tracing::warn!(error = %e, key, "Redis SET failed");
+            }
+        }
+        let _ = (key, value, ttl);
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        #[cfg(feature = "redis")]
+        if let Some(inner) = &self.inner {
+            use redis::AsyncCommands;
+            let mut conn = inner.conn.clone();
+            if let Err(e) = conn.del::<_, ()>(key).await {
+                tracing::warn!(error = %e, key, "Redis DEL failed");
+            }
+        }
+        let _ = key;
+    }
+
+    /// Increments a fixed-window counter shared by all replicas and returns
+    /// the new count, or `None` when Redis is unavailable.
+    pub async fn incr_window(&self, key: &str, window: Duration) -> Option<i64> {
+        #[cfg(feature = "redis")]
+        if let Some(inner) = &self.inner {
+            use redis::AsyncCommands;
+            let mut conn = inner.conn.clone();
+            match conn.incr::<_, _, i64>(key, 1).await {
+                Ok(count) => {
+                    if count == 1 {
+                        let _ = conn
+                            .expire::<_, ()>(key, window.as_secs().max(1) as i64)
+                            .await;
+                    }
+                    return Some(count);
+                }
+                Err(e) => tracing::warn!(error = %e, key, "Redis INCR failed"),
+            }
+        }
+        let _ = (key, window);
+        None
+    }
+
+    /// Mirrors local events to other replicas and remote events into `tx`.
+    /// Events are tagged with `instance_id` so they are never echoed back.
+    pub fn spawn_event_bridge(
+        &self,
+        instance_id: String,
+        tx: tokio::sync::broadcast::Sender<serde_json::Value>,
+    ) {
+        #[cfg(feature = "redis")]
+        if let Some(inner) = self.inner.clone() {
+            let local_rx = tx.subscribe();
+            let conn = inner.conn.clone();
+            let publisher_id = instance_id.clone();
+            tokio::spawn(forward_local_events(local_rx, publisher_id, move |event| {
+                let mut conn = conn.clone();
+                async move {
+                    use redis::AsyncCommands;
+                    if let Err(e) = conn
+                        .publish::<_, _, ()>(EVENTS_CHANNEL, event.to_string())
+                        .await
+                    {
+                        tracing::warn!(error = %e, "Failed to publish event to Redis");
+                    }
+                }
+            }));
+
+            tokio::spawn(async move {
+                use futures::StreamExt;
+                let mut pubsub = match inner.client.get_async_pubsub().await {
+                    Ok(p) => p,
+                    Err(e) => {
+                        tracing::error!(error = %e, "Failed to open Redis pub/sub connection");
+                        return;
+                    }
+                };
+                if let Err(e) = pubsub.subscribe(EVENTS_CHANNEL).await {
+                    tracing::error!(error = %e, "Failed to subscribe to Redis events");
+                    return;
+                }
+                let mut messages = pubsub.on_message();
+                while let Some(msg) = messages.next().await {
+                    let Ok(payload) = msg.get_payload::<String>() else {
+                        continue;
+                    };
+                    let Ok(event) = serde_json::from_str::<serde_json::Value>(&payload) else {
+                        continue;
+                    };
+                    if event.get("instance").and_then(|v| v.as_str()) != Some(&instance_id) {
+                        let _ = tx.send(event);
+                    }
+                }
+            });
+        }
+        #[cfg(not(feature = "redis"))]
+        let _ = (instance_id, tx);
+    }
+}
+
+/// Tags every event produced by this replica with `instance_id` and hands it
+/// to `publish`. Events that already carry an instance came from another
+/// replica and are skipped. A lagging receiver only loses the dropped events;
+/// the loop ends once the sender is gone.
+#[cfg(any(feature = "redis", test))]
+async fn forward_local_events<F, Fut>(
+    mut rx: tokio::sync::broadcast::Receiver<serde_json::Value>,
+    instance_id: String,
+    mut publish: F,
+) where
+    F: FnMut(serde_json::Value) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    use tokio::sync::broadcast::error::RecvError;
+    loop {
+        let mut event = match rx.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    skipped,
+                    "Event bridge lagged behind, some events were not mirrored"
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        if event.get("instance").is_some() {
+            continue;
+        }
+        event["instance"] = serde_json::Value::String(instance_id.clone());
+        publish(event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn bridge_keeps_forwarding_after_lagging() {
+        let (tx, rx) = tokio::sync::broadcast::channel(2);
+        for n in 0..5 {
+            tx.send(json!({ "n": n })).unwrap();
+        }
+        tx.send(json!({ "n": 5, "instance": "other" })).unwrap();
+        tx.send(json!({ "n": 6 })).unwrap();
+        drop(tx);
+
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let sink = published.clone();
+        forward_local_events(rx, "me".to_string(), move |event| {
+            sink.lock().unwrap().push(event);
+            async {}
+        })
+        .await;
+
+        assert_eq!(
+            *published.lock().unwrap(),
+            vec![json!({ "n": 6, "instance": "me" })]
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_cache_leaves_counting_to_the_caller() {
+        let cache = SharedCache::disabled();
+        assert!(!cache.is_enabled());
+        assert_eq!(cache.incr_window("k", Duration::from_secs(60)).await, None);
+        assert_eq!(cache.get::<String>("k").await, None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Keeps long-running background tasks alive: a task that fails or panics is
+//! restarted after an exponential backoff and reported through an optional
+//! alert hook. A task that returns `Ok(())` is considered done.
+
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+type AlertFn = Arc<dyn Fn(String) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// A run at least this long resets the backoff to its minimum.
+const HEALTHY_RUN: Duration = Duration::from_secs(300);
+
+#[derive(Clone)]
+pub struct Supervisor {
+    min_backoff: Duration,
+    max_backoff: Duration,
+    alert: Option<AlertFn>,
+    /// Whether each supervised task is currently running (not waiting out a
+    /// backoff). Tasks that finished cleanly are removed.
+    running: Arc<Mutex<HashMap<String, bool>>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+            alert: None,
+            running: Arc::default(),
+        }
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+
+    /// Called with a human-readable message every time a task goes down.
+    pub fn with_alert<F, Fut>(mut self, alert: F) -> Self
+    where
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.alert = Some(Arc::new(move |message| Box::pin(alert(message))));
+        self
+    }
+
+    /// True when no supervised task is down waiting to be restarted.
+    pub fn healthy(&self) -> bool {
+        self.running
+            .lock()
+            .map(|running| running.values().all(|up| *up))
+            .unwrap_or(false)
+    }
+
+    fn set_running(&self, name: &str, up: Option<bool>) {
+        if let Ok(mut running) = self.running.lock() {
+            match up {
+                Some(up) => running.insert(name.to_string(), up),
+                None => running.remove(name),
+            };
+        }
+    }
+
+    /// Runs `task()` in the background, calling it again whenever the
+    /// future it returned fails or panics.
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, mut task: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = supervisor.min_backoff;
+            loop {
+                let started = Instant::now();
+                supervisor.set_running(&name, Some(true));
+                // A nested spawn turns a panic into a JoinError instead of
+                // taking the supervisor down with it.
+                let outcome = match tokio::spawn(task()).await {
+                    Ok(Ok(())) => {
+                        tracing::info!(task = %name, "Background task finished");
+                        supervisor.set_running(&name, None);
+                        return;
+                    }
+                    Ok(Err(e)) => format!("failed: {:#}", e),
+                    Err(e) if e.is_panic() => "panicked".to_string(),
+                    Err(_) => "was cancelled".to_string(),
+                };
+                supervisor.set_running(&name, Some(false));
+                if started.elapsed() >= HEALTHY_RUN {
+                    backoff = supervisor.min_backoff;
+                }
+                crate::metrics::record_task_restart(&name);
+                tracing::error!(task = %name, retry_in = ?backoff, "Background task {}", outcome);
+                if let Some(alert) = &supervisor.alert {
+                    alert(format!(
+                        "Task {} {}; restarting in {}s",
+                        name,
+                        outcome,
+                        backoff.as_secs_f64()
+                    ))
+                    .await;
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(supervisor.max_backoff);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[tokio::test]
+    async fn restarts_failed_tasks_until_they_succeed() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+
+        let recorded = alerts.clone();
+        let supervisor = Supervisor::new()
+            .with_backoff(Duration::from_millis(1), Duration::from_millis(5))
+            .with_alert(move |message| {
+                recorded.lock().unwrap().push(message);
+                async {}
+            });
+        let counter = runs.clone();
+        supervisor.spawn("flaky", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            let done_tx = done_tx.clone();
+            async move {
+                match run {
+                    0 => anyhow::bail!("boom"),
+                    1 => panic!("kaboom"),
+                    _ => {
+                        if let Some(tx) = done_tx.lock().unwrap().take() {
+                            let _ = tx.send(());
+                        }
+                        Ok(())
+                    }
+                }
+            }
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), done_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        let alerts = alerts.lock().unwrap();
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts[0].starts_with("Task flaky failed: boom"));
+        assert!(alerts[1].starts_with("Task flaky panicked"));
+    }
+}
@@ -0,0 +1,82 @@
+//! Direct Bot API calls for code paths without a teloxide `Bot`, such as the
+//! web server. Only the main bot (`TELOXIDE_TOKEN`) is used.
+
+use crate::config::Config;
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+/// Sends an HTML message from the main bot to `chat_id`.
+pub async fn send_message(config: &Config, chat_id: i64, text: &str) -> Result<()> {
+    call(
+        config,
+        "sendMessage",
+        serde_json::json!({
+            "chat_id": chat_id,
+            "text": text,
+            "parse_mode": "HTML",
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Makes the main bot leave `chat_id`.
+pub async fn leave_chat(config: &Config, chat_id: i64) -> Result<()> {
+    call(
+        config,
+        "leaveChat",
+        serde_json::json!({ "chat_id": chat_id }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// The human administrators of `chat_id`, creator first.
+pub async fn chat_administrators(config: &Config, chat_id: i64) -> Result<Vec<i64>> {
+    let members = call(
+        config,
+        "getChatAdministrators",
+        serde_json::json!({ "chat_id": chat_id }),
+    )
+    .await?;
+    let mut admins: Vec<(bool, i64)> = members
+        .as_array()
+        .context("getChatAdministrators returned no list")?
+        .iter()
+        .filter(|member| member["user"]["is_bot"] != true)
+        .filter_map(|member| {
+            let is_creator = member["status"] == "creator";
+            Some((!is_creator, member["user"]["id"].as_i64()?))
+        })
+        .collect();
+    admins.sort_by_key(|(not_creator, _)| *not_creator);
+    Ok(admins.into_iter().map(|(_, id)| id).collect())
+}
+
+/// Calls `method` and returns its `result`.
+async fn call(
+    config: &Config,
+    method: &str,
+    params: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let client = crate::http::client_builder(config.proxy_url.as_deref())?
+        .timeout(Duration::from_secs(10))
+        .build()
+        .context("Failed to build Telegram client")?;
+    let response = client
+        .post(format!(
+            "{}/bot{}/{}",
+            config.telegram_api_url, config.bot_token, method
+        ))
+        .json(&params)
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        // Errors would otherwise include the URL, and with it the bot token.
+        .map_err(|e| anyhow::anyhow!("{} failed: {}", method, e.without_url()))?;
+    let mut body: serde_json::Value = response
+        .json()
+        .await
+        .map_err(|e| anyhow::anyhow!("{} failed: {}", method, e.without_url()))?;
+    Ok(body["result"].take())
+}
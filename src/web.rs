@@ -0,0 +1,158 @@
+//! HTTP endpoints served alongside the bot on `SERVER_ADDR`: health and
+//! metrics, plus the dashboard API behind a Telegram login session, the
+//! admin API restricted to `ADMIN_ID`, the public pages users opt into and
+//! the anonymous `/clean` endpoint.
+
+mod admin;
+mod auth;
+mod clean;
+mod dashboard;
+mod public;
+
+use crate::chat_admins::AdminCache;
+use crate::rate_limit::RateLimiter;
+use crate::scheduler::Scheduler;
+use crate::{config::SharedConfig, db::Db, sanitizer::RuleEngine};
+use axum::extract::DefaultBodyLimit;
+use axum::routing::{delete, post, put};
+use axum::{extract::State, http::header, http::StatusCode, response::IntoResponse, routing::get};
+use axum::{middleware, Router};
+use tower_http::trace::TraceLayer;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub db: Db,
+    pub config: SharedConfig,
+    pub rules: RuleEngine,
+    /// Per-client budget of the anonymous `/clean` endpoint.
+    pub clean_limiter: RateLimiter,
+    /// Background jobs, for their health on `/admin/jobs`.
+    pub scheduler: Scheduler,
+    /// Administrators of the chats owners manage from the dashboard.
+    pub chat_admins: AdminCache,
+}
+
+#[cfg(test)]
+impl AppState {
+    /// State for handler tests, with no rules loaded.
+    pub(crate) fn test(db: Db, config: SharedConfig) -> Self {
+        Self {
+            db,
+            config: config.clone(),
+            rules: RuleEngine::new_lazy(""),
+            clean_limiter: RateLimiter::new("clean", std::time::Duration::from_secs(60)),
+            scheduler: Scheduler::new(config),
+            chat_admins: AdminCache::new(),
+        }
+    }
+}
+
+/// Profile bundles with history outgrow axum's 2 MB default.
+const MAX_PROFILE_BUNDLE_BYTES: usize = 64 * 1024 * 1024;
+
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/metrics", get(metrics))
+        .route("/auth/telegram", post(auth::telegram_login))
+        .route("/auth/logout", post(auth::logout))
+        .route("/auth/chat-link", post(auth::chat_link_login))
+        .route("/auth/login-link", post(auth::login_link))
+        .route(
+            "/dashboard/preferences",
+            get(dashboard::get_preferences).put(dashboard::put_preferences),
+        )
+        .route("/dashboard/stats", get(dashboard::get_stats))
+        .route("/dashboard/heatmap", get(dashboard::get_heatmap))
+        .route("/dashboard/ai-stats", get(dashboard::get_ai_stats))
+        .route(
+            "/dashboard/download-my-data",
+            get(dashboard::download_my_data),
+        )
+        .route("/dashboard/account", delete(dashboard::delete_account))
+        .route("/dashboard/rules", get(dashboard::get_rules))
+        .route("/dashboard/rules/{id}", delete(dashboard::delete_rule))
+        .route(
+            "/dashboard/chats/{chat_id}",
+            get(dashboard::get_chat).put(dashboard::put_chat),
+        )
+        .route(
+            "/dashboard/chats/{chat_id}/history",
+            get(dashboard::get_chat_history),
+        )
+        .route(
+            "/dashboard/chats/{chat_id}/detections",
+            get(dashboard::get_chat_detections),
+        )
+        .route("/dashboard/export/events", get(dashboard::export_events))
+        .route(
+            "/dashboard/profile",
+            get(dashboard::export_profile)
+                .post(dashboard::import_profile)
+                .layer(DefaultBodyLimit::max(MAX_PROFILE_BUNDLE_BYTES)),
+        )
+        .route(
+            "/dashboard/public-page",
+            get(dashboard::get_public_page).put(dashboard::put_public_page),
+        )
+        .route(
+            "/dashboard/privacy",
+            get(dashboard::get_privacy).put(dashboard::put_privacy),
+        )
+        .route("/u/{token}", get(public::public_stats))
+        // `{file}` is `{public_token}.svg`; the router has no suffix matching.
+        .route("/badge/{file}", get(public::badge))
+        .route("/clean", get(clean::clean))
+        .merge(admin_router(state.clone()))
+        .layer(TraceLayer::new_for_http())
+        .with_state(state)
+}
+
+/// Admin API routes, all behind the IP allowlist.
+fn admin_router(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/admin/providers", get(admin::list_providers))
+        .route("/admin/confirmations", post(admin::request_confirmation))
+        .route("/admin/stats/providers", get(admin::provider_leaderboard))
+        .route("/admin/export/stats", get(admin::export_stats))
+        .route("/admin/export/events", get(admin::export_events))
+        .route("/admin/users/{user_id}", get(admin::view_user))
+        .route("/admin/jobs", get(admin::list_jobs))
+        .route("/admin/chats", get(admin::list_chats))
+        .route("/admin/chats/{chat_id}", put(admin::put_chat))
+        .route("/admin/chats/{chat_id}/leave", post(admin::leave_chat))
+        .route(
+            "/admin/providers/{name}",
+            put(admin::put_provider).delete(admin::delete_provider),
+        )
+        .route_layer(middleware::from_fn_with_state(state, admin::ip_allowlist))
+}
+
+pub async fn serve(addr: &str, state: AppState) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(addr = %addr, "HTTP server listening");
+    // Peer addresses feed the admin IP allowlist.
+    axum::serve(
+        listener,
+        router(state).into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn health(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.ping().await {
+        Ok(_) => (StatusCode::OK, "ok"),
+        Err(e) => {
+            tracing::error!(error = %e, "Health check failed");
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        }
+    }
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        crate::metrics::render(),
+    )
+}
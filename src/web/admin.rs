@@ -0,0 +1,1038 @@
+//! Admin-only JSON endpoints.
+//!
+//! Destructive actions need a second factor: the dashboard first calls
+//! `POST /admin/confirmations` with the action name, the bot DMs the admin a
+//! one-time code, and the action request carries it in `X-Confirmation-Code`.
+//!
+//! Every route here sits behind [`ip_allowlist`] as well.
+
+use super::auth::AdminUser;
+use super::dashboard::{events_response, today, RangeQuery};
+use super::AppState;
+use crate::db::format_day;
+use crate::exporter;
+use crate::models::{
+    Actor, ChatConfig, ChatSummary, CleanedLink, CustomRule, GlobalProvider, UserConfig,
+};
+use crate::scheduler::JobStatus;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, Path, Query, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use clear_urls_core::Rules;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use ipnet::IpNet;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Admin actions that only run with a confirmation code.
+pub const CONFIRMED_ACTIONS: &[&str] = &["provider.delete", "chat.leave"];
+const CONFIRMATION_HEADER: &str = "x-confirmation-code";
+const CONFIRMATION_TTL: Duration = Duration::from_secs(300);
+
+fn internal_error(e: anyhow::Error) -> StatusCode {
+    tracing::error!(error = %e, "Admin request failed");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// Middleware answering 403 to clients outside `ADMIN_IP_ALLOWLIST`, when
+/// one is configured. Requests whose address is unknown are rejected too.
+pub async fn ip_allowlist(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let config = state.config.load_full();
+    if config.admin_ip_allowlist.is_empty() {
+        return next.run(request).await;
+    }
+    let client = client_ip(&request, config.trust_proxy_headers);
+    if client.is_some_and(|ip| is_allowed(&config.admin_ip_allowlist, ip)) {
+        return next.run(request).await;
+    }
+    tracing::warn!(client = ?client, path = %request.uri().path(), "Admin request from outside the allowlist");
+    StatusCode::FORBIDDEN.into_response()
+}
+
+/// The peer address, or the one the nearest reverse proxy appended to
+/// `X-Forwarded-For` when proxy headers are trusted.
+pub(super) fn client_ip(request: &Request, trust_proxy_headers: bool) -> Option<IpAddr> {
+    let forwarded = trust_proxy_headers
+        .then(|| {
+            request
+                .headers()
+                .get_all("x-forwarded-for")
+                .iter()
+                .next_back()
+        })
+        .flatten()
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .and_then(|v| v.trim().parse().ok());
+    forwarded
+        .or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|info| info.0.ip())
+        })
+        .map(|ip| ip.to_canonical())
+}
+
+/// Whether `ip` falls in one of the CIDR blocks or equals one of the
+/// addresses in `allowlist`. Unparsable entries match nothing.
+fn is_allowed(allowlist: &[String], ip: IpAddr) -> bool {
+    allowlist.iter().any(|entry| match entry.parse::<IpNet>() {
+        Ok(net) => net.contains(&ip),
+        Err(_) => entry.parse::<IpAddr>().is_ok_and(|addr| addr == ip),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeaderboardQuery {
+    /// How many providers to return; defaults to 20, at most 100.
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct ProviderRank {
+    pub provider: String,
+    pub count: i64,
+    /// Distinct users whose links the provider cleaned.
+    pub users: i64,
+}
+
+#[derive(Serialize)]
+pub struct LeaderboardResponse {
+    pub from: String,
+    pub to: String,
+    pub providers: Vec<ProviderRank>,
+}
+
+/// `GET /admin/stats/providers`: the providers behind the most cleanings
+/// across all users, over the same ranges as `/dashboard/stats`.
+pub async fn provider_leaderboard(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+    Query(range): Query<RangeQuery>,
+    Query(query): Query<LeaderboardQuery>,
+) -> Result<Json<LeaderboardResponse>, StatusCode> {
+    let (from, to) = range.resolve(today())?;
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    let providers = state
+        .db
+        .get_provider_leaderboard(from, to, limit)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|(provider, count, users)| ProviderRank {
+            provider,
+            count,
+            users,
+        })
+        .collect();
+    Ok(Json(LeaderboardResponse {
+        from: format_day(from),
+        to: format_day(to),
+        providers,
+    }))
+}
+
+/// Longest range `/admin/export/stats` serves in one request.
+const MAX_EXPORT_DAYS: i64 = 10 * 366;
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportDataset {
+    /// One row per day: cleanings, active users, new users, users so far.
+    Daily,
+    /// One row per day and provider.
+    Providers,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    pub dataset: ExportDataset,
+    #[serde(default)]
+    pub format: ExportFormat,
+}
+
+#[derive(Serialize)]
+pub struct DailyRow {
+    pub day: String,
+    pub cleanings: i64,
+    pub active_users: i64,
+    /// Users whose first cleaning happened that day.
+    pub new_users: i64,
+    /// Users with at least one cleaning up to and including that day.
+    pub total_users: i64,
+}
+
+#[derive(Serialize)]
+pub struct ProviderDayRow {
+    pub day: String,
+    pub provider: String,
+    pub count: i64,
+}
+
+/// A row of an export, written as CSV or as one element of a JSON array.
+trait ExportRow: Serialize {
+    const HEADER: &'static [&'static str];
+    fn fields(&self) -> Vec<String>;
+}
+
+impl ExportRow for DailyRow {
+    const HEADER: &'static [&'static str] = &[
+        "day",
+        "cleanings",
+        "active_users",
+        "new_users",
+        "total_users",
+    ];
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.day.clone(),
+            self.cleanings.to_string(),
+            self.active_users.to_string(),
+            self.new_users.to_string(),
+            self.total_users.to_string(),
+        ]
+    }
+}
+
+impl ExportRow for ProviderDayRow {
+    const HEADER: &'static [&'static str] = &["day", "provider", "count"];
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.day.clone(),
+            self.provider.clone(),
+            self.count.to_string(),
+        ]
+    }
+}
+
+/// Encodes rows as they arrive so the export is never held in memory. A
+/// failure halfway through aborts the response.
+fn export_body<T: ExportRow + Send + 'static>(
+    format: ExportFormat,
+    mut rows: BoxStream<'static, anyhow::Result<T>>,
+) -> Body {
+    let encoded: BoxStream<'static, anyhow::Result<String>> = Box::pin(async_stream::try_stream! {
+        match format {
+            ExportFormat::Csv => yield exporter::csv_line(T::HEADER),
+            ExportFormat::Json => yield "[".to_string(),
+        }
+        let mut first = true;
+        while let Some(row) = rows.next().await {
+            let row = row.inspect_err(|e| tracing::error!(error = %e, "Stats export failed"))?;
+            match format {
+                ExportFormat::Csv => yield exporter::csv_line(&row.fields()),
+                ExportFormat::Json => {
+                    let separator = if first { "" } else { "," };
+                    let json = serde_json::to_string(&row)?;
+                    yield format!("{}{}", separator, json);
+                }
+            }
+            first = false;
+        }
+        if let ExportFormat::Json = format {
+            yield "]".to_string();
+        }
+    });
+    Body::from_stream(encoded)
+}
+
+/// `GET /admin/export/stats?dataset=daily|providers&format=csv|json`, over
+/// the same ranges as `/dashboard/stats` but up to ten years long. Served as
+/// a download and streamed row by row.
+pub async fn export_stats(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+    Query(range): Query<RangeQuery>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, StatusCode> {
+    let (from, to) = range.resolve_within(today(), MAX_EXPORT_DAYS)?;
+    let (name, body) = match query.dataset {
+        ExportDataset::Daily => {
+            let rows = state
+                .db
+                .stream_global_daily_stats(from, to)
+                .map_ok(
+                    |(day, cleanings, active_users, new_users, total_users)| DailyRow {
+                        day,
+                        cleanings,
+                        active_users,
+                        new_users,
+                        total_users,
+                    },
+                )
+                .boxed();
+            ("daily", export_body(query.format, rows))
+        }
+        ExportDataset::Providers => {
+            let rows = state
+                .db
+                .stream_provider_breakdown(from, to)
+                .map_ok(|(day, provider, count)| ProviderDayRow {
+                    day,
+                    provider,
+                    count,
+                })
+                .boxed();
+            ("providers", export_body(query.format, rows))
+        }
+    };
+    let (content_type, extension) = match query.format {
+        ExportFormat::Csv => ("text/csv; charset=utf-8", "csv"),
+        ExportFormat::Json => ("application/json", "json"),
+    };
+    let disposition = format!(
+        "attachment; filename=\"clear_urls_{}_{}_{}.{}\"",
+        name,
+        format_day(from),
+        format_day(to),
+        extension
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// One user's cleanings instead of everyone's.
+    pub user_id: Option<i64>,
+}
+
+/// `GET /admin/export/events?user_id=...`: cleanings as JSON Lines over the
+/// `/admin/export/stats` ranges, for analytics pipelines. Each export is
+/// recorded in the audit log as `admin.export_events`.
+pub async fn export_events(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Query(range): Query<RangeQuery>,
+    Query(query): Query<EventsQuery>,
+) -> Result<Response, StatusCode> {
+    let (from, to) = range.resolve_within(today(), MAX_EXPORT_DAYS)?;
+    state
+        .db
+        .record_events_export(
+            Actor::web(admin.user_id),
+            query.user_id.unwrap_or(0),
+            format!("{}..{}", format_day(from), format_day(to)),
+        )
+        .await;
+    let links = state.db.stream_cleaned_links(query.user_id, from, to);
+    Ok(events_response(from, to, links))
+}
+
+/// How many recent cleanings `/admin/users/{user_id}` includes.
+const VIEWED_HISTORY: i64 = 50;
+
+/// What the user's own dashboard would show them.
+#[derive(Serialize)]
+pub struct UserView {
+    pub user_id: i64,
+    pub config: UserConfig,
+    pub custom_rules: Vec<CustomRule>,
+    pub chats: Vec<ChatConfig>,
+    pub history: Vec<CleanedLink>,
+}
+
+/// `GET /admin/users/{user_id}`: a read-only copy of a user's configuration,
+/// rules, chats and recent history for support requests. Every call is
+/// recorded in the audit log.
+pub async fn view_user(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(user_id): Path<i64>,
+) -> Result<Json<UserView>, StatusCode> {
+    let db = &state.db;
+    let view = UserView {
+        user_id,
+        config: db.get_user_config(user_id).await.map_err(internal_error)?,
+        custom_rules: db.get_custom_rules(user_id).await.map_err(internal_error)?,
+        chats: db
+            .get_chats_for_user(user_id)
+            .await
+            .map_err(internal_error)?,
+        history: db
+            .get_history(user_id, VIEWED_HISTORY)
+            .await
+            .map_err(internal_error)?,
+    };
+    db.record_user_view(Actor::web(admin.user_id), user_id)
+        .await;
+    Ok(Json(view))
+}
+
+/// `GET /admin/jobs`: schedule and last outcome of every background job on
+/// this replica.
+pub async fn list_jobs(State(state): State<AppState>, _admin: AdminUser) -> Json<Vec<JobStatus>> {
+    Json(state.scheduler.statuses())
+}
+
+/// `GET /admin/chats`: every chat the bot knows, most recently active first.
+pub async fn list_chats(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<ChatSummary>>, StatusCode> {
+    let chats = state
+        .db
+        .get_chat_summaries()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(chats))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatUpdate {
+    pub enabled: bool,
+}
+
+/// Enables or disables the bot in a chat; a disabled chat's links are
+/// ignored. Returns `false` for unknown chats.
+async fn set_chat_enabled(
+    state: &AppState,
+    admin: &AdminUser,
+    chat_id: i64,
+    enabled: bool,
+) -> Result<bool, StatusCode> {
+    let Some(mut chat) = state
+        .db
+        .get_chat_config(chat_id)
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok(false);
+    };
+    chat.enabled = enabled as i32;
+    state
+        .db
+        .save_chat_config(&chat, Actor::web(admin.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(true)
+}
+
+/// `PUT /admin/chats/{chat_id}` with `{"enabled": false}` to silence the
+/// bot in a chat (or `true` to turn it back on).
+pub async fn put_chat(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(chat_id): Path<i64>,
+    Json(update): Json<ChatUpdate>,
+) -> Result<StatusCode, StatusCode> {
+    if !set_chat_enabled(&state, &admin, chat_id, update.enabled).await? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// `POST /admin/chats/{chat_id}/leave`, confirmed with a code: the bot
+/// leaves the chat, which is also disabled so re-adding the bot does not
+/// bring it back until the admin re-enables it.
+pub async fn leave_chat(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(chat_id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    require_confirmation(&state, &admin, "chat.leave", &chat_id.to_string(), &headers).await?;
+    if !set_chat_enabled(&state, &admin, chat_id, false).await? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let config = state.config.load_full();
+    if let Err(e) = crate::telegram::leave_chat(&config, chat_id).await {
+        tracing::error!(error = %e, chat_id, "Failed to leave chat");
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+pub struct ProviderEntry {
+    pub name: String,
+    /// The ClearURLs provider object.
+    pub definition: Value,
+    pub enabled: bool,
+    pub updated_at: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProviderRequest {
+    pub definition: Value,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConfirmationRequest {
+    pub action: String,
+    /// What the action applies to: the chat id for `chat.leave`, the
+    /// provider name for `provider.delete`.
+    pub target: String,
+}
+
+/// `POST /admin/confirmations`: DMs the admin a 6-digit code for `action`
+/// on `target`, valid for five minutes and a single attempt.
+pub async fn request_confirmation(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Json(request): Json<ConfirmationRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let target = request.target.trim();
+    if !CONFIRMED_ACTIONS.contains(&request.action.as_str()) || target.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+    state
+        .db
+        .create_admin_confirmation(
+            &code,
+            admin.user_id,
+            &request.action,
+            target,
+            CONFIRMATION_TTL,
+        )
+        .await
+        .map_err(internal_error)?;
+    let language = state
+        .db
+        .get_user_config(admin.user_id)
+        .await
+        .map(|c| c.language)
+        .unwrap_or_default();
+    let text = crate::i18n::get_translations(&language)
+        .admin_confirmation_code
+        .replacen("{}", &request.action, 1)
+        .replacen("{}", &super::public::escape_html(target), 1)
+        .replacen("{}", &code, 1);
+    let config = state.config.load_full();
+    if let Err(e) = crate::telegram::send_message(&config, admin.user_id, &text).await {
+        tracing::error!(error = %e, "Failed to send confirmation code");
+        return Err(StatusCode::BAD_GATEWAY);
+    }
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Spends the code sent for `action` on `target`: 428 when the request has
+/// none, 403 when it is wrong, expired or was sent for another target.
+async fn require_confirmation(
+    state: &AppState,
+    admin: &AdminUser,
+    action: &str,
+    target: &str,
+    headers: &HeaderMap,
+) -> Result<(), StatusCode> {
+    let code = headers
+        .get(CONFIRMATION_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(StatusCode::PRECONDITION_REQUIRED)?;
+    let confirmed = state
+        .db
+        .consume_admin_confirmation(code.trim(), admin.user_id, action, target)
+        .await
+        .map_err(internal_error)?;
+    if !confirmed {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(())
+}
+
+/// Recompiles the engine after a change. The database is already updated,
+/// so a failure here is only logged; the next rules refresh retries.
+async fn reload_providers(state: &AppState) {
+    if let Err(e) = state.rules.load_global_providers(&state.db).await {
+        tracing::error!(error = %e, "Failed to reload global providers");
+    }
+}
+
+/// `GET /admin/providers`: the supplemental providers, enabled or not.
+pub async fn list_providers(
+    State(state): State<AppState>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<ProviderEntry>>, StatusCode> {
+    let providers = state
+        .db
+        .get_global_providers()
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(
+        providers
+            .into_iter()
+            .map(|p| ProviderEntry {
+                definition: serde_json::from_str(&p.definition).unwrap_or(Value::Null),
+                name: p.name,
+                enabled: p.enabled != 0,
+                updated_at: p.updated_at,
+            })
+            .collect(),
+    ))
+}
+
+/// `PUT /admin/providers/{name}`: creates or replaces a provider. Invalid
+/// definitions are rejected with 422 and the reason.
+pub async fn put_provider(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(name): Path<String>,
+    Json(request): Json<ProviderRequest>,
+) -> Result<Response, StatusCode> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    if let Err(e) = Rules::validate_provider(&request.definition) {
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(serde_json::json!({ "error": e.to_string() })),
+        )
+            .into_response());
+    }
+    let provider = GlobalProvider {
+        name: name.to_string(),
+        definition: request.definition.to_string(),
+        enabled: request.enabled as i32,
+        updated_at: 0,
+    };
+    state
+        .db
+        .save_global_provider(&provider, Actor::web(admin.user_id))
+        .await
+        .map_err(internal_error)?;
+    reload_providers(&state).await;
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+/// `DELETE /admin/providers/{name}`, confirmed with a code.
+pub async fn delete_provider(
+    State(state): State<AppState>,
+    admin: AdminUser,
+    Path(name): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    require_confirmation(&state, &admin, "provider.delete", &name, &headers).await?;
+    let deleted = state
+        .db
+        .delete_global_provider(&name, Actor::web(admin.user_id))
+        .await
+        .map_err(internal_error)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    reload_providers(&state).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Db;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn matches_allowlisted_networks() {
+        let allowlist =
+            ["10.0.0.0/8", "2001:db8::/32", "203.0.113.7", "nonsense"].map(String::from);
+        for allowed in ["10.1.2.3", "2001:db8::1", "203.0.113.7"] {
+            assert!(
+                is_allowed(&allowlist, allowed.parse().unwrap()),
+                "{}",
+                allowed
+            );
+        }
+        for denied in ["11.0.0.1", "203.0.113.8", "2001:db9::1"] {
+            assert!(
+                !is_allowed(&allowlist, denied.parse().unwrap()),
+                "{}",
+                denied
+            );
+        }
+
+        let request = |forwarded: Option<&str>| {
+            let mut request = Request::new(axum::body::Body::empty());
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))));
+            if let Some(forwarded) = forwarded {
+                request
+                    .headers_mut()
+                    .insert("x-forwarded-for", forwarded.parse().unwrap());
+            }
+            request
+        };
+        let spoofed = request(Some("10.0.0.1, 198.51.100.4"));
+        assert_eq!(client_ip(&spoofed, false), Some([127, 0, 0, 1].into()));
+        assert_eq!(client_ip(&spoofed, true), Some([198, 51, 100, 4].into()));
+        assert_eq!(client_ip(&request(None), true), Some([127, 0, 0, 1].into()));
+        let mapped = "::ffff:10.0.0.1".parse::<IpAddr>().unwrap().to_canonical();
+        assert!(is_allowed(&allowlist, mapped));
+    }
+
+    /// Bot API calls made during a test, as `(method path, parameters)`.
+    type Sent = Arc<Mutex<Vec<(String, Value)>>>;
+
+    /// State for admin `1`, with Bot API calls going to a local stand-in.
+    async fn harness(name: &str) -> (AppState, Sent) {
+        let sent = Sent::default();
+        let sink = sent.clone();
+        let api = axum::Router::new().route(
+            "/{*method}",
+            axum::routing::post(move |Path(method): Path<String>, Json(body): Json<Value>| {
+                let sink = sink.clone();
+                async move {
+                    sink.lock().unwrap().push((method, body));
+                    Json(serde_json::json!({ "ok": true }))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, api).await });
+
+        let db = Db::test(&format!("admin-{}", name)).await;
+        let mut config = Config::load(None);
+        config.admin_id = 1;
+        config.proxy_url = None;
+        config.telegram_api_url = format!("http://{}", addr);
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        let state = AppState::test(db, config);
+        (state, sent)
+    }
+
+    /// The code in the latest DM to the admin.
+    fn last_code(sent: &Sent) -> String {
+        let (method, message) = sent.lock().unwrap().last().unwrap().clone();
+        assert!(method.ends_with("/sendMessage"));
+        assert_eq!(message["chat_id"], 1);
+        let text = message["text"].as_str().unwrap();
+        text.split("<code>")
+            .nth(3)
+            .unwrap()
+            .chars()
+            .take(6)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn provider_deletion_needs_the_dmed_code() {
+        let (state, sent) = harness("providers").await;
+        let db = state.db.clone();
+        let provider = GlobalProvider {
+            name: "shop".into(),
+            definition: r#"{"urlPattern":"shop"}"#.into(),
+            enabled: 1,
+            updated_at: 0,
+        };
+        db.save_global_provider(&provider, Actor::system())
+            .await
+            .unwrap();
+        let admin = || AdminUser { user_id: 1 };
+        let delete = |code: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(code) = code {
+                headers.insert(CONFIRMATION_HEADER, code.parse().unwrap());
+            }
+            delete_provider(State(state.clone()), admin(), Path("shop".into()), headers)
+        };
+        let request = |target: &str| {
+            request_confirmation(
+                State(state.clone()),
+                admin(),
+                Json(ConfirmationRequest {
+                    action: "provider.delete".into(),
+                    target: target.into(),
+                }),
+            )
+        };
+
+        assert_eq!(delete(None).await, Err(StatusCode::PRECONDITION_REQUIRED));
+        assert_eq!(request("shop").await, Ok(StatusCode::ACCEPTED));
+        // A wrong guess spends the code.
+        assert_eq!(delete(Some("abcdef")).await, Err(StatusCode::FORBIDDEN));
+        // So does a code sent for another provider.
+        assert_eq!(request("other").await, Ok(StatusCode::ACCEPTED));
+        let code = last_code(&sent);
+        assert_eq!(delete(Some(&code)).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(request("shop").await, Ok(StatusCode::ACCEPTED));
+        let dm = sent.lock().unwrap().last().unwrap().1["text"].clone();
+        assert!(dm.as_str().unwrap().contains("<code>shop</code>"));
+        let code = last_code(&sent);
+        assert_eq!(delete(Some(&code)).await, Ok(StatusCode::NO_CONTENT));
+        assert!(db.get_global_providers().await.unwrap().is_empty());
+        assert_eq!(delete(Some(&code)).await, Err(StatusCode::FORBIDDEN));
+    }
+
+    #[tokio::test]
+    async fn events_export_as_json_lines() {
+        let (state, _) = harness("events").await;
+        let db = state.db.clone();
+        for user_id in [7, 8, 7] {
+            db.log_cleaned_link(user_id, None, None, "a", "https://a.com/", "p", "rules")
+                .await
+                .unwrap();
+        }
+        let chain = ["https://s.io/x".to_string(), "https://a.com/".to_string()];
+        db.log_redirected_link(8, None, None, "b", "https://a.com/", "p", "rules", &chain)
+            .await
+            .unwrap();
+        let export = |user_id: Option<i64>| {
+            let state = state.clone();
+            async move {
+                let range = RangeQuery {
+                    days: Some(1),
+                    from: None,
+                    to: None,
+                };
+                let response = export_events(
+                    State(state),
+                    AdminUser { user_id: 1 },
+                    Query(range),
+                    Query(EventsQuery { user_id }),
+                )
+                .await
+                .unwrap();
+                assert_eq!(
+                    response.headers()[header::CONTENT_TYPE],
+                    "application/x-ndjson"
+                );
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec())
+                    .unwrap()
+                    .lines()
+                    .map(|line| serde_json::from_str::<Value>(line).unwrap())
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let everyone = export(None).await;
+        assert_eq!(everyone.len(), 4);
+        assert_eq!(everyone[3]["redirect_chain"][0], "https://s.io/x");
+        assert!(everyone[0]["redirect_chain"].is_null());
+        let one = export(Some(7)).await;
+        assert_eq!(one.len(), 2);
+        assert!(one.iter().all(|event| event["user_id"] == 7));
+        let events = db.get_audit_log(Some(7), 1).await.unwrap();
+        assert_eq!(events[0].action, "admin.export_events");
+    }
+
+    #[tokio::test]
+    async fn exports_stream_csv_and_json() {
+        let (state, _) = harness("export").await;
+        for (user_id, provider) in [(7, "amazon"), (7, "google"), (8, "amazon")] {
+            state
+                .db
+                .log_cleaned_link(
+                    user_id,
+                    None,
+                    None,
+                    "a",
+                    "https://a.com/",
+                    provider,
+                    "rules",
+                )
+                .await
+                .unwrap();
+        }
+        let export = |dataset: ExportDataset, format: ExportFormat| {
+            let state = state.clone();
+            async move {
+                let range = RangeQuery {
+                    days: Some(2),
+                    from: None,
+                    to: None,
+                };
+                let query = ExportQuery { dataset, format };
+                let response = export_stats(
+                    State(state),
+                    AdminUser { user_id: 1 },
+                    Query(range),
+                    Query(query),
+                )
+                .await
+                .unwrap();
+                let disposition = response.headers()[header::CONTENT_DISPOSITION].clone();
+                assert!(disposition.to_str().unwrap().starts_with("attachment"));
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+        };
+
+        let csv = export(ExportDataset::Daily, ExportFormat::Csv).await;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "day,cleanings,active_users,new_users,total_users");
+        assert!(lines[1].ends_with(",0,0,0,0"));
+        assert_eq!(lines[2], format!("{},3,2,2,2", format_day(today())));
+
+        let json = export(ExportDataset::Providers, ExportFormat::Json).await;
+        let rows: Vec<Value> = serde_json::from_str(&json).unwrap();
+        let counts: Vec<(&str, i64)> = rows
+            .iter()
+            .map(|r| {
+                (
+                    r["provider"].as_str().unwrap(),
+                    r["count"].as_i64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(counts, [("amazon", 2), ("google", 1)]);
+    }
+
+    #[tokio::test]
+    async fn provider_leaderboard_ranks_everyones_cleanings() {
+        let (state, _) = harness("leaderboard").await;
+        for (user_id, provider) in [(7, "google"), (7, "amazon"), (8, "amazon"), (8, "amazon")] {
+            state
+                .db
+                .log_cleaned_link(
+                    user_id,
+                    None,
+                    None,
+                    "a",
+                    "https://a.com/",
+                    provider,
+                    "rules",
+                )
+                .await
+                .unwrap();
+        }
+        let leaderboard = |limit: Option<i64>| {
+            let range = RangeQuery {
+                days: Some(7),
+                from: None,
+                to: None,
+            };
+            provider_leaderboard(
+                State(state.clone()),
+                AdminUser { user_id: 1 },
+                Query(range),
+                Query(LeaderboardQuery { limit }),
+            )
+        };
+
+        let Json(all) = leaderboard(None).await.unwrap();
+        assert_eq!(all.to, format_day(today()));
+        let ranks: Vec<(&str, i64, i64)> = all
+            .providers
+            .iter()
+            .map(|r| (r.provider.as_str(), r.count, r.users))
+            .collect();
+        assert_eq!(ranks, [("amazon", 3, 2), ("google", 1, 1)]);
+        let Json(top) = leaderboard(Some(0)).await.unwrap();
+        assert_eq!(top.providers.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn viewing_a_user_is_audited() {
+        let (state, _) = harness("view").await;
+        let db = state.db.clone();
+        db.add_custom_rule(7, "ref", 10, Actor::bot(7))
+            .await
+            .unwrap();
+        db.log_cleaned_link(7, None, None, "a", "https://a.com/", "p", "rules")
+            .await
+            .unwrap();
+
+        let Json(view) = view_user(State(state.clone()), AdminUser { user_id: 1 }, Path(7))
+            .await
+            .unwrap();
+        assert_eq!(view.custom_rules[0].pattern, "ref");
+        assert_eq!(view.history.len(), 1);
+        let events = db.get_audit_log(Some(7), 1).await.unwrap();
+        assert_eq!(events[0].action, "admin.view_user");
+        assert_eq!(events[0].actor_id, 1);
+    }
+
+    #[tokio::test]
+    async fn chats_can_be_listed_disabled_and_left() {
+        let (state, sent) = harness("chats").await;
+        let db = state.db.clone();
+        for chat_id in [-1, -2] {
+            let chat = crate::models::ChatConfig {
+                chat_id,
+                title: Some(format!("Chat {}", chat_id)),
+                enabled: 1,
+                added_by: 7,
+                ..Default::default()
+            };
+            db.save_chat_config(&chat, Actor::system()).await.unwrap();
+        }
+        db.log_cleaned_link(7, Some(-2), None, "a", "https://a.com/", "p", "rules")
+            .await
+            .unwrap();
+        let admin = || AdminUser { user_id: 1 };
+
+        let Json(chats) = list_chats(State(state.clone()), admin()).await.unwrap();
+        let listed: Vec<(i64, i64)> = chats.iter().map(|c| (c.chat_id, c.cleanings)).collect();
+        assert_eq!(listed, [(-2, 1), (-1, 0)]);
+        assert!(chats[0].last_activity.is_some() && chats[1].last_activity.is_none());
+
+        let update = |chat_id: i64| {
+            put_chat(
+                State(state.clone()),
+                admin(),
+                Path(chat_id),
+                Json(ChatUpdate { enabled: false }),
+            )
+        };
+        assert_eq!(update(-1).await, Ok(StatusCode::NO_CONTENT));
+        assert_eq!(update(-3).await, Err(StatusCode::NOT_FOUND));
+        assert!(!db.get_chat_config(-1).await.unwrap().unwrap().is_enabled());
+
+        let leave =
+            |headers: HeaderMap| leave_chat(State(state.clone()), admin(), Path(-2), headers);
+        assert_eq!(
+            leave(HeaderMap::new()).await,
+            Err(StatusCode::PRECONDITION_REQUIRED)
+        );
+        let confirm = |target: &str| {
+            let request = ConfirmationRequest {
+                action: "chat.leave".into(),
+                target: target.into(),
+            };
+            request_confirmation(State(state.clone()), admin(), Json(request))
+        };
+        let with_code = |code: String| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONFIRMATION_HEADER, code.parse().unwrap());
+            headers
+        };
+        // A code for another chat does not leave this one.
+        confirm("-1").await.unwrap();
+        let code = last_code(&sent);
+        assert_eq!(leave(with_code(code)).await, Err(StatusCode::FORBIDDEN));
+        confirm("-2").await.unwrap();
+        let code = last_code(&sent);
+        assert_eq!(leave(with_code(code)).await, Ok(StatusCode::NO_CONTENT));
+        let (method, params) = sent.lock().unwrap().last().unwrap().clone();
+        assert!(method.ends_with("/leaveChat"));
+        assert_eq!(params["chat_id"], -2);
+        assert!(!db.get_chat_config(-2).await.unwrap().unwrap().is_enabled());
+    }
+}
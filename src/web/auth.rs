@@ -0,0 +1,292 @@
+//! Dashboard sign-in with the Telegram Login Widget and cookie sessions.
+//!
+//! The widget's payload is signed with the bot token; once verified, a
+//! random session token is set as an `HttpOnly` cookie and only its hash is
+//! stored in `web_sessions`.
+
+use super::AppState;
+use crate::links;
+use crate::models::Actor;
+use axum::extract::{FromRequestParts, State};
+use axum::http::{header, request::Parts, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+pub const SESSION_COOKIE: &str = "session";
+pub const SESSION_TTL: Duration = Duration::from_secs(30 * 24 * 3600);
+/// Login widget payloads older than this are rejected as replays.
+const MAX_AUTH_AGE_SECS: i64 = 24 * 3600;
+
+/// The signed-in dashboard user. Rejects the request with 401 otherwise.
+pub struct DashboardUser {
+    pub user_id: i64,
+}
+
+impl FromRequestParts<AppState> for DashboardUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, StatusCode> {
+        let token = session_token(&parts.headers).ok_or(StatusCode::UNAUTHORIZED)?;
+        match state.db.get_web_session_user(&token).await {
+            Ok(Some(user_id)) => Ok(Self { user_id }),
+            Ok(None) => Err(StatusCode::UNAUTHORIZED),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to look up dashboard session");
+                Err(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        }
+    }
+}
+
+/// A signed-in dashboard user who is also `ADMIN_ID`. Rejects other users
+/// with 403.
+pub struct AdminUser {
+    pub user_id: i64,
+}
+
+impl FromRequestParts<AppState> for AdminUser {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, StatusCode> {
+        let user = DashboardUser::from_request_parts(parts, state).await?;
+        let admin_id = state.config.load().admin_id;
+        if admin_id == 0 || user.user_id != admin_id {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(Self {
+            user_id: user.user_id,
+        })
+    }
+}
+
+/// Reads the session token from the `Cookie` header.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token.to_string())
+}
+
+/// Checks a Telegram Login Widget payload against the bot token and returns
+/// the user id it vouches for.
+pub fn verify_telegram_login(
+    payload: &BTreeMap<String, Value>,
+    bot_token: &str,
+    now: i64,
+) -> Option<i64> {
+    let hash = payload.get("hash")?.as_str()?;
+    let data_check_string = payload
+        .iter()
+        .filter(|(key, _)| key.as_str() != "hash")
+        .map(|(key, value)| match value {
+            Value::String(s) => format!("{}={}", key, s),
+            other => format!("{}={}", key, other),
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let secret = Sha256::digest(bot_token.as_bytes());
+    let mut mac = Hmac::<Sha256>::new_from_slice(&secret).ok()?;
+    mac.update(data_check_string.as_bytes());
+    mac.verify_slice(&hex::decode(hash).ok()?).ok()?;
+
+    let field = |name: &str| -> Option<i64> {
+        match payload.get(name)? {
+            Value::Number(n) => n.as_i64(),
+            Value::String(s) => s.parse().ok(),
+            _ => None,
+        }
+    };
+    let auth_date = field("auth_date")?;
+    if now - auth_date > MAX_AUTH_AGE_SECS {
+        return None;
+    }
+    field("id")
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Starts a session for `user_id` and returns the `Set-Cookie` value.
+pub async fn start_session(state: &AppState, user_id: i64) -> anyhow::Result<String> {
+    let token = links::random_token();
+    state
+        .db
+        .create_web_session(&token, user_id, SESSION_TTL)
+        .await?;
+    Ok(format!(
+        "{}={}; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE,
+        token,
+        SESSION_TTL.as_secs()
+    ))
+}
+
+pub(super) fn clear_cookie() -> String {
+    format!(
+        "{}=; Path=/; HttpOnly; Secure; SameSite=Lax; Max-Age=0",
+        SESSION_COOKIE
+    )
+}
+
+/// `POST /auth/telegram` with the Login Widget's user object.
+pub async fn telegram_login(
+    State(state): State<AppState>,
+    Json(payload): Json<BTreeMap<String, Value>>,
+) -> Response {
+    let bot_token = state.config.load().bot_token.clone();
+    let now = unix_now();
+    let Some(user_id) = verify_telegram_login(&payload, &bot_token, now) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    sign_in(&state, user_id, json!({})).await
+}
+
+/// Starts a session for a verified `user_id` and answers with the cookie and
+/// `body` plus `user_id` and `deletion_cancelled`.
+async fn sign_in(state: &AppState, user_id: i64, mut body: Value) -> Response {
+    // Signing in during the grace period keeps the account.
+    let deletion_cancelled = match state
+        .db
+        .cancel_account_deletion(user_id, Actor::web(user_id))
+        .await
+    {
+        Ok(cancelled) => cancelled,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to cancel account deletion");
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+    body["user_id"] = json!(user_id);
+    body["deletion_cancelled"] = json!(deletion_cancelled);
+    match start_session(state, user_id).await {
+        Ok(cookie) => ([(header::SET_COOKIE, cookie)], Json(body)).into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create dashboard session");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+/// `POST /auth/login-link` with the token of a `/login` link. Each link
+/// signs in once; replays get 401.
+pub async fn login_link(State(state): State<AppState>, Json(link): Json<LinkToken>) -> Response {
+    match state.db.consume_login_token(&link.token).await {
+        Ok(Some(user_id)) => sign_in(&state, user_id, json!({})).await,
+        Ok(None) => StatusCode::UNAUTHORIZED.into_response(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to redeem login link");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct LinkToken {
+    pub token: String,
+}
+
+/// `POST /auth/chat-link` with the token of a `/settings` link: signs the
+/// user in and returns the `chat_id` the dashboard should open. Rejected
+/// once the user no longer owns the chat.
+pub async fn chat_link_login(
+    State(state): State<AppState>,
+    Json(link): Json<LinkToken>,
+) -> Response {
+    let config = state.config.load_full();
+    let Some((chat_id, user_id)) =
+        links::verify_chat_link(&config.bot_token, &link.token, unix_now())
+    else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match super::dashboard::can_manage_chat(&state, user_id, chat_id).await {
+        Ok(true) => sign_in(&state, user_id, json!({ "chat_id": chat_id })).await,
+        Ok(false) => StatusCode::FORBIDDEN.into_response(),
+        Err(status) => status.into_response(),
+    }
+}
+
+/// `POST /auth/logout`: ends the current session.
+pub async fn logout(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(token) = session_token(&headers) {
+        if let Err(e) = state.db.delete_web_session(&token).await {
+            tracing::warn!(error = %e, "Failed to delete dashboard session");
+        }
+    }
+    (
+        [(header::SET_COOKIE, clear_cookie())],
+        StatusCode::NO_CONTENT,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(fields: &[(&str, Value)], bot_token: &str) -> BTreeMap<String, Value> {
+        let mut payload: BTreeMap<String, Value> = fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        let check = payload
+            .iter()
+            .map(|(k, v)| match v {
+                Value::String(s) => format!("{}={}", k, s),
+                other => format!("{}={}", k, other),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(bot_token)).unwrap();
+        mac.update(check.as_bytes());
+        let hash = hex::encode(mac.finalize().into_bytes());
+        payload.insert("hash".into(), Value::String(hash));
+        payload
+    }
+
+    #[test]
+    fn verifies_login_widget_payloads() {
+        let payload = signed(
+            &[
+                ("id", json!(42)),
+                ("first_name", json!("Ada")),
+                ("auth_date", json!(1_000)),
+            ],
+            "123:abc",
+        );
+        assert_eq!(verify_telegram_login(&payload, "123:abc", 1_100), Some(42));
+        // Wrong bot, tampered field, stale payload.
+        assert_eq!(verify_telegram_login(&payload, "123:xyz", 1_100), None);
+        let mut tampered = payload.clone();
+        tampered.insert("id".into(), json!(43));
+        assert_eq!(verify_telegram_login(&tampered, "123:abc", 1_100), None);
+        assert_eq!(
+            verify_telegram_login(&payload, "123:abc", 1_000 + MAX_AUTH_AGE_SECS + 1),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            "theme=dark; session=abc123".parse().unwrap(),
+        );
+        assert_eq!(session_token(&headers).as_deref(), Some("abc123"));
+    }
+}
@@ -0,0 +1,178 @@
+//! Anonymous `GET /clean`, so a deployment doubles as a small public
+//! ClearURLs service. Requests are rate limited per client address and
+//! nothing is stored.
+
+use super::admin::client_ip;
+use super::AppState;
+use axum::extract::{Query, Request, State};
+use axum::http::{header, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use clear_urls_core::CleaningLevel;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use url::Url;
+
+/// Longer inputs are rejected before any rule runs.
+const MAX_URL_LENGTH: usize = 4096;
+
+const RATE_LIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+const RATE_LIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+
+#[derive(Debug, Deserialize)]
+pub struct CleanQuery {
+    pub url: String,
+    /// `conservative`, `standard` (default) or `strict`.
+    pub level: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CleanResponse {
+    pub url: String,
+    pub cleaned_url: String,
+    pub changed: bool,
+    pub provider: Option<String>,
+}
+
+/// `GET /clean?url=...[&level=strict]`: the cleaned URL as JSON. Shortened
+/// links are not expanded, so anonymous callers cannot make the server
+/// fetch addresses of their choosing. 404 while `PUBLIC_CLEAN_RATE_LIMIT`
+/// is 0, 429 with `Retry-After` once a client is over it.
+pub async fn clean(State(state): State<AppState>, request: Request) -> Response {
+    let config = state.config.load_full();
+    let limit = config.public_clean_rate_limit;
+    if limit <= 0 {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let client = client_ip(&request, config.trust_proxy_headers);
+    let now = crate::db::unix_now().unwrap_or(0);
+    let hits = state.clean_limiter.hit(&rate_key(client), now).await;
+    let limits = [
+        (RATE_LIMIT_LIMIT, limit.to_string()),
+        (RATE_LIMIT_REMAINING, (limit - hits).max(0).to_string()),
+        (header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".to_string()),
+    ];
+    if hits > limit {
+        let retry_after = state.clean_limiter.retry_after(now).to_string();
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            limits,
+            [(header::RETRY_AFTER, retry_after)],
+        )
+            .into_response();
+    }
+
+    let Ok(Query(query)) = Query::<CleanQuery>::try_from_uri(request.uri()) else {
+        return (StatusCode::BAD_REQUEST, limits).into_response();
+    };
+    let level = match query.level.as_deref().map(CleaningLevel::parse) {
+        None => CleaningLevel::default(),
+        Some(Some(level)) => level,
+        Some(None) => return (StatusCode::BAD_REQUEST, limits).into_response(),
+    };
+    let is_web_url = query.url.len() <= MAX_URL_LENGTH
+        && Url::parse(&query.url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+    if !is_web_url {
+        return (StatusCode::UNPROCESSABLE_ENTITY, limits).into_response();
+    }
+
+    let (cleaned_url, provider) = match state.rules.sanitize(&query.url, &[], &[], level) {
+        Some((cleaned, provider)) => (cleaned, Some(provider)),
+        None => (query.url.clone(), None),
+    };
+    let response = CleanResponse {
+        changed: provider.is_some(),
+        url: query.url,
+        cleaned_url,
+        provider,
+    };
+    (limits, Json(response)).into_response()
+}
+
+/// IPv6 clients usually own a whole /64, so they share one counter.
+fn rate_key(client: Option<IpAddr>) -> String {
+    match client {
+        Some(IpAddr::V6(ip)) => {
+            let segments = ip.segments();
+            format!(
+                "{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+        Some(ip) => ip.to_string(),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Db;
+    use crate::sanitizer::RuleEngine;
+    use axum::body::Body;
+    use axum::extract::ConnectInfo;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn cleans_anonymously_within_the_rate_limit() {
+        let db = Db::test("web-clean").await;
+        let mut config = Config::load(None);
+        config.public_clean_rate_limit = 3;
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(config));
+        let state = AppState {
+            rules: RuleEngine::new_lazy("").with_embedded_rules(),
+            ..AppState::test(db.clone(), config)
+        };
+        let request = |query: &str, peer: &str| {
+            let mut request = Request::new(Body::empty());
+            *request.uri_mut() = format!("/clean?{}", query).parse().unwrap();
+            let peer: SocketAddr = peer.parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            clean(State(state.clone()), request)
+        };
+
+        let response = request(
+            "url=https%3A%2F%2Fexample.com%2F%3Futm_source%3Dx%26q%3D1",
+            "192.0.2.1:1",
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[&RATE_LIMIT_REMAINING], "2");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["cleaned_url"], "https://example.com/?q=1");
+        assert_eq!(body["changed"], true);
+
+        let status = |response: Response| response.status();
+        assert_eq!(
+            status(request("url=ftp%3A%2F%2Fexample.com%2F", "192.0.2.1:1").await),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            status(request("url=https%3A%2F%2Fa.com%2F&level=max", "192.0.2.1:1").await),
+            StatusCode::BAD_REQUEST
+        );
+        let limited = request("url=https%3A%2F%2Fa.com%2F", "192.0.2.1:2").await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(limited.headers().contains_key(header::RETRY_AFTER));
+        // Other clients have their own budget; nothing was written.
+        assert_eq!(
+            status(request("url=https%3A%2F%2Fa.com%2F", "192.0.2.2:1").await),
+            StatusCode::OK
+        );
+        assert_eq!(db.get_global_stats().await.unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_prefix() {
+        let a: IpAddr = "2001:db8:1:2:aaaa::1".parse().unwrap();
+        let b: IpAddr = "2001:db8:1:2:bbbb::2".parse().unwrap();
+        assert_eq!(rate_key(Some(a)), rate_key(Some(b)));
+        assert_eq!(rate_key(Some(a)), "2001:db8:1:2::/64");
+        assert_eq!(rate_key(None), "unknown");
+    }
+}
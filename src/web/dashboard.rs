@@ -0,0 +1,841 @@
+//! JSON endpoints behind the dashboard session.
+
+use super::auth::{clear_cookie, DashboardUser};
+use super::AppState;
+use crate::db::format_day;
+use crate::exporter;
+use crate::models::{
+    Actor, ChatConfig, CleanedLink, CustomRule, CHAT_MODES, DASHBOARD_WIDGETS, THEMES,
+};
+use crate::profile::{self, ProfileBundle, ProfileError, ProfileSigner};
+use axum::body::Body;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Preferences {
+    pub theme: String,
+    pub hidden_widgets: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PreferencesResponse {
+    #[serde(flatten)]
+    pub preferences: Preferences,
+    /// Every widget the dashboard knows, so the client can list toggles.
+    pub widgets: &'static [&'static str],
+    pub themes: &'static [&'static str],
+}
+
+/// Longest range `/dashboard/stats` serves in one request.
+const MAX_RANGE_DAYS: i64 = 366;
+/// Julian day number of 1970-01-01.
+const UNIX_EPOCH_JULIAN_DAY: i64 = 2_440_588;
+/// Links `/dashboard/chats/{chat_id}/history` returns.
+const CHAT_HISTORY_LIMIT: i64 = 100;
+
+fn internal_error(e: anyhow::Error) -> StatusCode {
+    tracing::error!(error = %e, "Dashboard request failed");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// `GET /dashboard/preferences`: read when the dashboard renders.
+pub async fn get_preferences(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<Json<PreferencesResponse>, StatusCode> {
+    let config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(PreferencesResponse {
+        preferences: Preferences {
+            theme: config.theme,
+            hidden_widgets: config
+                .hidden_widgets
+                .split(',')
+                .filter(|w| !w.is_empty())
+                .map(str::to_string)
+                .collect(),
+        },
+        widgets: DASHBOARD_WIDGETS,
+        themes: THEMES,
+    }))
+}
+
+/// `PUT /dashboard/preferences`: unknown themes or widgets are rejected.
+pub async fn put_preferences(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Json(prefs): Json<Preferences>,
+) -> Result<StatusCode, StatusCode> {
+    if !THEMES.contains(&prefs.theme.as_str())
+        || prefs
+            .hidden_widgets
+            .iter()
+            .any(|w| !DASHBOARD_WIDGETS.contains(&w.as_str()))
+    {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let mut config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    config.user_id = user.user_id;
+    config.theme = prefs.theme;
+    config.hidden_widgets = prefs.hidden_widgets.join(",");
+    state
+        .db
+        .save_user_config(&config, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicPage {
+    pub enabled: bool,
+    /// `/u/{public_token}` while enabled.
+    #[serde(default, skip_deserializing)]
+    pub path: Option<String>,
+}
+
+impl PublicPage {
+    fn new(enabled: bool, token: Option<String>) -> Self {
+        Self {
+            enabled,
+            path: token.filter(|_| enabled).map(|t| format!("/u/{}", t)),
+        }
+    }
+}
+
+/// `GET /dashboard/public-page`: whether the public stats page is on.
+pub async fn get_public_page(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<Json<PublicPage>, StatusCode> {
+    let config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(PublicPage::new(
+        config.has_public_stats(),
+        config.public_token,
+    )))
+}
+
+/// `PUT /dashboard/public-page` with `{"enabled": true}`. Switching the page
+/// off and on again keeps its address.
+pub async fn put_public_page(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Json(page): Json<PublicPage>,
+) -> Result<Json<PublicPage>, StatusCode> {
+    let token = state
+        .db
+        .set_public_stats(user.user_id, page.enabled, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(PublicPage::new(page.enabled, token)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HistorySetting {
+    /// Off, links are cleaned and counted but not kept in the history.
+    pub log_history: bool,
+}
+
+/// `GET /dashboard/privacy`: whether cleaned links are kept.
+pub async fn get_privacy(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<Json<HistorySetting>, StatusCode> {
+    let config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(HistorySetting {
+        log_history: config.keeps_history(),
+    }))
+}
+
+/// `PUT /dashboard/privacy` with `{"log_history": false}`, the dashboard's
+/// side of `/privacy`. Links already kept stay until the history is cleared.
+pub async fn put_privacy(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Json(setting): Json<HistorySetting>,
+) -> Result<Json<HistorySetting>, StatusCode> {
+    let mut config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    config.user_id = user.user_id;
+    config.log_history = i32::from(setting.log_history);
+    state
+        .db
+        .save_user_config(&config, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(setting))
+}
+
+/// Either the last `days` days, or `from`..=`to` as `YYYY-MM-DD` (UTC).
+#[derive(Debug, Deserialize)]
+pub struct RangeQuery {
+    pub days: Option<i64>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl RangeQuery {
+    /// The requested range as inclusive days since the epoch; defaults to
+    /// the last 7 days.
+    pub fn resolve(&self, today: i64) -> Result<(i64, i64), StatusCode> {
+        self.resolve_within(today, MAX_RANGE_DAYS)
+    }
+
+    /// Like [`resolve`](Self::resolve), for endpoints that allow ranges of up
+    /// to `max_days`.
+    pub fn resolve_within(&self, today: i64, max_days: i64) -> Result<(i64, i64), StatusCode> {
+        let (from, to) = match (&self.from, &self.to) {
+            (Some(from), Some(to)) => (
+                parse_day(from).ok_or(StatusCode::BAD_REQUEST)?,
+                parse_day(to).ok_or(StatusCode::BAD_REQUEST)?,
+            ),
+            (None, None) => {
+                // Checked before the arithmetic, which huge values overflow.
+                let days = self.days.unwrap_or(7);
+                if !(1..=max_days).contains(&days) {
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+                (today - days + 1, today)
+            }
+            _ => return Err(StatusCode::BAD_REQUEST),
+        };
+        if from > to || to - from >= max_days {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+        Ok((from, to))
+    }
+}
+
+fn parse_day(value: &str) -> Option<i64> {
+    let mut parts = value.splitn(3, '-');
+    let year = parts.next()?.parse().ok()?;
+    let month = time::Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+    let day = parts.next()?.parse().ok()?;
+    let date = time::Date::from_calendar_date(year, month, day).ok()?;
+    Some(date.to_julian_day() as i64 - UNIX_EPOCH_JULIAN_DAY)
+}
+
+/// Today as days since the epoch (UTC).
+pub(super) fn today() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64 / 86400)
+        .unwrap_or(0)
+}
+
+#[derive(Serialize)]
+pub struct DayCount {
+    pub day: String,
+    pub count: i64,
+}
+
+#[derive(Serialize)]
+pub struct StatsResponse {
+    pub from: String,
+    pub to: String,
+    pub total: i64,
+    /// One entry per day of the range, including days without cleanings.
+    pub series: Vec<DayCount>,
+}
+
+/// `GET /dashboard/stats?days=30` or `?from=2024-01-01&to=2024-03-31`.
+pub async fn get_stats(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Query(range): Query<RangeQuery>,
+) -> Result<Json<StatsResponse>, StatusCode> {
+    let (from, to) = range.resolve(today())?;
+    let series: Vec<DayCount> = state
+        .db
+        .get_stats_by_day(user.user_id, from, to)
+        .await
+        .map_err(internal_error)?
+        .into_iter()
+        .map(|(day, count)| DayCount { day, count })
+        .collect();
+    Ok(Json(StatsResponse {
+        from: format_day(from),
+        to: format_day(to),
+        total: series.iter().map(|d| d.count).sum(),
+        series,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+pub struct AiStatsResponse {
+    /// Whether the user's links go to the AI: on in their settings and
+    /// available on this deployment.
+    pub enabled: bool,
+    /// Cleanings in the user's history that the AI helped with, and their
+    /// share of everything the user cleaned in percent.
+    pub assisted: i64,
+    pub assisted_percent: i64,
+    pub requests: i64,
+    /// Successful requests in percent, `None` before the first request.
+    pub success_rate: Option<i64>,
+    pub avg_latency_ms: i64,
+    pub requests_today: i64,
+    pub tokens_today: i64,
+    /// Requests allowed per day by the user's plan; 0 means unlimited.
+    pub daily_quota: i64,
+}
+
+/// `GET /dashboard/ai-stats`: what `/stats` shows about the AI, over the
+/// user's whole history, plus today's usage against their quota.
+pub async fn get_ai_stats(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<Json<AiStatsResponse>, StatusCode> {
+    let config = state.config.load_full();
+    let user_config = state
+        .db
+        .get_user_config(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let (assisted, requests, successes, avg_latency_ms) = state
+        .db
+        .get_ai_stats(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let (requests_today, tokens_today) = state
+        .db
+        .get_ai_usage_today(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let limits = crate::entitlements::user_limits(&state.db, &config, user.user_id).await;
+    let assisted_percent = match user_config.cleaned_count {
+        0 => 0,
+        cleaned => (assisted * 100 / cleaned).min(100),
+    };
+    Ok(Json(AiStatsResponse {
+        enabled: user_config.is_ai_enabled() && config.ai_available(),
+        assisted,
+        assisted_percent,
+        requests,
+        success_rate: (requests > 0).then(|| successes * 100 / requests),
+        avg_latency_ms,
+        requests_today,
+        tokens_today,
+        daily_quota: limits.ai_daily_quota,
+    }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct HeatmapScope {
+    /// Admin only: aggregate every user's cleanings.
+    #[serde(default)]
+    pub global: bool,
+}
+
+#[derive(Serialize)]
+pub struct HeatmapResponse {
+    pub from: String,
+    pub to: String,
+    pub global: bool,
+    pub total: i64,
+    /// `cells[weekday][hour]` in UTC, Monday first.
+    pub cells: [[i64; 24]; 7],
+}
+
+/// `GET /dashboard/heatmap`: takes the same range parameters as
+/// `/dashboard/stats`, plus `global=true` for the admin.
+pub async fn get_heatmap(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Query(range): Query<RangeQuery>,
+    Query(scope): Query<HeatmapScope>,
+) -> Result<Json<HeatmapResponse>, StatusCode> {
+    let (from, to) = range.resolve(today())?;
+    if scope.global && user.user_id != state.config.load().admin_id {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let cells = state
+        .db
+        .get_activity_heatmap((!scope.global).then_some(user.user_id), from, to)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(HeatmapResponse {
+        from: format_day(from),
+        to: format_day(to),
+        global: scope.global,
+        total: cells.iter().flatten().sum(),
+        cells,
+    }))
+}
+
+/// `GET /dashboard/download-my-data`: the same zip the bot's `/export` sends.
+pub async fn download_my_data(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<impl IntoResponse, StatusCode> {
+    let archive = exporter::export_user_data(&state.db, user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        exporter::archive_name(user.user_id)
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        archive,
+    ))
+}
+
+/// `GET /dashboard/export/events`: the user's cleanings over the same
+/// ranges as `/dashboard/stats`, as JSON Lines.
+pub async fn export_events(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Query(range): Query<RangeQuery>,
+) -> Result<Response, StatusCode> {
+    let (from, to) = range.resolve(today())?;
+    let links = state.db.stream_cleaned_links(Some(user.user_id), from, to);
+    Ok(events_response(from, to, links))
+}
+
+/// A JSON Lines download of `links`, encoded as they are read so large
+/// ranges are never held in memory.
+pub(super) fn events_response(
+    from: i64,
+    to: i64,
+    links: BoxStream<'static, anyhow::Result<crate::models::CleanedLink>>,
+) -> Response {
+    let lines = exporter::json_lines(links)
+        .inspect_err(|e| tracing::error!(error = %e, "Event export failed"));
+    let disposition = format!(
+        "attachment; filename=\"clear_urls_events_{}_{}.jsonl\"",
+        format_day(from),
+        format_day(to)
+    );
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(lines),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileQuery {
+    /// Include the cleaning history, which can make the bundle large.
+    #[serde(default)]
+    pub history: bool,
+}
+
+/// `GET /dashboard/profile`: the user's signed profile bundle, to import on
+/// another instance.
+pub async fn export_profile(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Query(query): Query<ProfileQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let profile = profile::export_profile(&state.db, user.user_id, query.history)
+        .await
+        .map_err(internal_error)?;
+    let signer = ProfileSigner::from_bot_token(&state.config.load().bot_token);
+    let bundle = signer.sign(&profile).map_err(internal_error)?;
+    let disposition = format!(
+        "attachment; filename=\"{}\"",
+        profile::bundle_name(user.user_id)
+    );
+    Ok(([(header::CONTENT_DISPOSITION, disposition)], Json(bundle)))
+}
+
+/// `POST /dashboard/profile`: imports a bundle exported here or on another
+/// instance. 422 when it was altered, 403 when it is someone else's.
+pub async fn import_profile(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Json(bundle): Json<ProfileBundle>,
+) -> Result<Response, StatusCode> {
+    let config = state.config.load_full();
+    let mut trusted_keys = config.trusted_profile_keys.clone();
+    trusted_keys.push(ProfileSigner::from_bot_token(&config.bot_token).public_key());
+    let verified = match bundle.verify(&trusted_keys) {
+        Ok(verified) => verified,
+        Err(e) => {
+            return Ok((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Json(serde_json::json!({ "error": e.to_string() })),
+            )
+                .into_response())
+        }
+    };
+    let limits = crate::entitlements::user_limits(&state.db, &config, user.user_id).await;
+    match profile::import_profile(
+        &state.db,
+        user.user_id,
+        verified,
+        limits.max_custom_rules,
+        Actor::web(user.user_id),
+    )
+    .await
+    {
+        Ok(summary) => Ok(Json(summary).into_response()),
+        Err(e) if e.downcast_ref() == Some(&ProfileError::OtherUser) => Err(StatusCode::FORBIDDEN),
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+/// Whether `user_id` may manage `chat_id`: the member who added the bot
+/// while they still administer the chat, or the admin.
+pub(super) async fn can_manage_chat(
+    state: &AppState,
+    user_id: i64,
+    chat_id: i64,
+) -> Result<bool, StatusCode> {
+    let config = state.config.load_full();
+    if config.admin_id != 0 && user_id == config.admin_id {
+        return Ok(true);
+    }
+    let chat = state
+        .db
+        .get_chat_config(chat_id)
+        .await
+        .map_err(internal_error)?;
+    if chat.is_none_or(|c| c.added_by != user_id) {
+        return Ok(false);
+    }
+    let fetch = || crate::telegram::chat_administrators(&config, chat_id);
+    Ok(state
+        .chat_admins
+        .is_admin(chat_id, user_id, fetch)
+        .await
+        .unwrap_or_else(|e| {
+            tracing::warn!(error = %e, chat_id, "Failed to check chat administrator");
+            false
+        }))
+}
+
+/// `GET /dashboard/chats/{chat_id}`: the page `/settings` links to.
+pub async fn get_chat(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Path(chat_id): Path<i64>,
+) -> Result<Json<ChatConfig>, StatusCode> {
+    if !can_manage_chat(&state, user.user_id, chat_id).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state
+        .db
+        .get_chat_config(chat_id)
+        .await
+        .map_err(internal_error)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Chat settings a `PUT /dashboard/chats/{chat_id}` may change; fields
+/// left out keep their value.
+#[derive(Debug, Default, Deserialize)]
+pub struct ChatSettings {
+    pub enabled: Option<bool>,
+    pub mode: Option<String>,
+    pub ai_enabled: Option<bool>,
+}
+
+/// `PUT /dashboard/chats/{chat_id}`: the dashboard's switches for a managed
+/// chat, like `/mode` and `/groupai` in the chat. Unknown modes are
+/// rejected.
+pub async fn put_chat(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Path(chat_id): Path<i64>,
+    Json(settings): Json<ChatSettings>,
+) -> Result<Json<ChatConfig>, StatusCode> {
+    if !can_manage_chat(&state, user.user_id, chat_id).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if settings
+        .mode
+        .as_deref()
+        .is_some_and(|mode| !CHAT_MODES.contains(&mode))
+    {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let mut chat = state
+        .db
+        .get_chat_config(chat_id)
+        .await
+        .map_err(internal_error)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(enabled) = settings.enabled {
+        chat.enabled = enabled as i32;
+    }
+    if let Some(mode) = settings.mode {
+        chat.mode = mode;
+    }
+    if let Some(ai_enabled) = settings.ai_enabled {
+        chat.ai_enabled = ai_enabled as i32;
+    }
+    state
+        .db
+        .save_chat_config(&chat, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(chat))
+}
+
+/// `GET /dashboard/chats/{chat_id}/history`: the latest links cleaned in
+/// the chat. Senders in privacy mode are never recorded.
+pub async fn get_chat_history(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Path(chat_id): Path<i64>,
+) -> Result<Json<Vec<CleanedLink>>, StatusCode> {
+    if !can_manage_chat(&state, user.user_id, chat_id).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state
+        .db
+        .get_chat_history(chat_id, CHAT_HISTORY_LIMIT)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// `GET /dashboard/chats/{chat_id}/detections`: what the bot found while
+/// the chat was in `dry_run` mode, kept apart from its history.
+pub async fn get_chat_detections(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Path(chat_id): Path<i64>,
+) -> Result<Json<Vec<CleanedLink>>, StatusCode> {
+    if !can_manage_chat(&state, user.user_id, chat_id).await? {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    state
+        .db
+        .get_chat_detections(chat_id, CHAT_HISTORY_LIMIT)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// `GET /dashboard/rules`: the user's custom rules, added with `/addrule`.
+pub async fn get_rules(
+    State(state): State<AppState>,
+    user: DashboardUser,
+) -> Result<Json<Vec<CustomRule>>, StatusCode> {
+    state
+        .db
+        .get_custom_rules(user.user_id)
+        .await
+        .map(Json)
+        .map_err(internal_error)
+}
+
+/// `DELETE /dashboard/rules/{id}`: 404 when the rule is not the user's.
+pub async fn delete_rule(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, StatusCode> {
+    let removed = state
+        .db
+        .delete_custom_rule(user.user_id, id, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    Ok(if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteAccount {
+    /// Must be `true`; guards against a stray request wiping an account.
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// `DELETE /dashboard/account`: schedules `delete_all_user_data` after the
+/// grace period and signs the user out everywhere. Signing in again before
+/// then cancels the deletion.
+pub async fn delete_account(
+    State(state): State<AppState>,
+    user: DashboardUser,
+    Json(request): Json<DeleteAccount>,
+) -> Result<Response, StatusCode> {
+    if !request.confirm {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let grace_days = state.config.load().account_deletion_grace_days.max(0);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    let delete_after = now + grace_days * 86400;
+    state
+        .db
+        .schedule_account_deletion(user.user_id, delete_after, Actor::web(user.user_id))
+        .await
+        .map_err(internal_error)?;
+    state
+        .db
+        .delete_web_sessions_for_user(user.user_id)
+        .await
+        .map_err(internal_error)?;
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::SET_COOKIE, clear_cookie())],
+        Json(serde_json::json!({ "delete_after": delete_after })),
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Db;
+    use std::sync::Arc;
+
+    const OWNER: i64 = 7;
+    const CHAT: i64 = -100;
+
+    /// State with a group added by `OWNER`, whose current administrators
+    /// are `admins`.
+    async fn chat_harness(name: &str, admins: Vec<i64>) -> AppState {
+        let db = Db::test(&format!("dashboard-{}", name)).await;
+        let chat = ChatConfig {
+            chat_id: CHAT,
+            added_by: OWNER,
+            ..Default::default()
+        };
+        db.save_chat_config(&chat, Actor::system()).await.unwrap();
+        let mut config = Config::load(None);
+        config.admin_id = 1;
+        let state = AppState::test(db, Arc::new(arc_swap::ArcSwap::from_pointee(config)));
+        let fetch = || async { Ok::<_, ()>(admins) };
+        state
+            .chat_admins
+            .is_admin(CHAT, OWNER, fetch)
+            .await
+            .unwrap();
+        state
+    }
+
+    async fn put(state: &AppState, settings: ChatSettings) -> Result<Json<ChatConfig>, StatusCode> {
+        let user = DashboardUser { user_id: OWNER };
+        put_chat(State(state.clone()), user, Path(CHAT), Json(settings)).await
+    }
+
+    #[tokio::test]
+    async fn ai_stats_cover_the_whole_history() {
+        let state = chat_harness("ai-stats", vec![OWNER]).await;
+        let db = &state.db;
+        db.increment_cleaned_count(OWNER, 4).await.unwrap();
+        for (url, source) in [
+            ("https://a.com/?utm_source=x", "rules"),
+            ("https://b.com/?fbclid=y", "rules+ai"),
+        ] {
+            db.log_cleaned_link(OWNER, None, None, url, url, "test", source)
+                .await
+                .unwrap();
+        }
+
+        let user = DashboardUser { user_id: OWNER };
+        let Json(stats) = get_ai_stats(State(state.clone()), user).await.unwrap();
+        assert_eq!((stats.assisted, stats.assisted_percent), (1, 25));
+        assert_eq!((stats.requests, stats.success_rate), (0, None));
+        assert_eq!(stats.requests_today, 0);
+    }
+
+    #[tokio::test]
+    async fn owners_change_chat_settings() {
+        let state = chat_harness("put-chat", vec![OWNER]).await;
+        let settings = ChatSettings {
+            ai_enabled: Some(true),
+            mode: Some("dry_run".to_string()),
+            ..Default::default()
+        };
+        let Json(chat) = put(&state, settings).await.unwrap();
+        assert_eq!((chat.ai_enabled, chat.mode.as_str()), (1, "dry_run"));
+        let saved = state.db.get_chat_config(CHAT).await.unwrap().unwrap();
+        assert_eq!((saved.ai_enabled, saved.enabled), (1, chat.enabled));
+
+        let settings = ChatSettings {
+            mode: Some("shout".to_string()),
+            ..Default::default()
+        };
+        let rejected = put(&state, settings).await.err();
+        assert_eq!(rejected, Some(StatusCode::UNPROCESSABLE_ENTITY));
+    }
+
+    #[tokio::test]
+    async fn demoted_owners_cannot_change_chat_settings() {
+        let state = chat_harness("put-chat-demoted", vec![2]).await;
+        let settings = ChatSettings {
+            ai_enabled: Some(true),
+            ..Default::default()
+        };
+        let denied = put(&state, settings).await.err();
+        assert_eq!(denied, Some(StatusCode::FORBIDDEN));
+        let saved = state.db.get_chat_config(CHAT).await.unwrap().unwrap();
+        assert_eq!(saved.ai_enabled, 0);
+    }
+
+    #[test]
+    fn resolves_stats_ranges() {
+        let today = parse_day("2024-03-10").unwrap();
+        assert_eq!(format_day(today), "2024-03-10");
+        let query = |days: Option<i64>, from: Option<&str>, to: Option<&str>| RangeQuery {
+            days,
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+        };
+
+        let (from, to) = query(None, None, None).resolve(today).unwrap();
+        assert_eq!((format_day(from), to), ("2024-03-04".to_string(), today));
+        let (from, _) = query(Some(30), None, None).resolve(today).unwrap();
+        assert_eq!(format_day(from), "2024-02-10");
+        let (from, to) = query(None, Some("2024-02-28"), Some("2024-03-01"))
+            .resolve(today)
+            .unwrap();
+        assert_eq!(to - from, 2);
+
+        for bad in [
+            query(Some(0), None, None),
+            query(Some(400), None, None),
+            query(Some(-1), None, None),
+            query(Some(i64::MIN), None, None),
+            query(Some(i64::MAX), None, None),
+            query(None, Some("2024-03-02"), Some("2024-03-01")),
+            query(None, Some("2024-02-30"), Some("2024-03-01")),
+            query(None, Some("2024-03-01"), None),
+        ] {
+            assert_eq!(bad.resolve(today), Err(StatusCode::BAD_REQUEST));
+        }
+    }
+}
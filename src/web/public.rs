@@ -0,0 +1,260 @@
+//! Pages users opt into sharing, addressed by an unguessable token instead
+//! of a session. They show counts only, never links.
+
+use super::AppState;
+use axum::extract::{Path, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{Html, IntoResponse, Response};
+
+const PUBLIC_STATS_TEMPLATE: &str = include_str!("templates/public_stats.html");
+const BADGE_TEMPLATE: &str = include_str!("templates/badge.svg");
+/// Providers listed on the public page.
+const TOP_PROVIDERS: i64 = 5;
+/// Public pages may be cached by browsers and proxies for this long.
+const PUBLIC_MAX_AGE_SECS: u64 = 300;
+/// Badges are embedded in READMEs whose image proxies cache aggressively
+/// anyway; an hour keeps them reasonably fresh.
+const BADGE_MAX_AGE_SECS: u64 = 3600;
+
+fn lookup_failed(e: anyhow::Error) -> StatusCode {
+    tracing::error!(error = %e, "Failed to load public stats");
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+/// `GET /u/{public_token}`: 404 unless the user switched the page on.
+pub async fn public_stats(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let (total, providers) = state
+        .db
+        .get_public_stats(&token, TOP_PROVIDERS)
+        .await
+        .map_err(lookup_failed)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let providers = if providers.is_empty() {
+        "<p>Nothing yet.</p>".to_string()
+    } else {
+        let items: String = providers
+            .iter()
+            .map(|(name, count)| {
+                format!(
+                    "<li>{} <span>{}</span></li>",
+                    escape_html(name),
+                    format_count(*count)
+                )
+            })
+            .collect();
+        format!("<ol>{}</ol>", items)
+    };
+    let page = render(
+        PUBLIC_STATS_TEMPLATE,
+        &[("total", &format_count(total)), ("providers", &providers)],
+    );
+    Ok((
+        [(
+            header::CACHE_CONTROL,
+            format!("public, max-age={}", PUBLIC_MAX_AGE_SECS),
+        )],
+        Html(page),
+    ))
+}
+
+/// `GET /badge/{public_token}.svg`: a shields-style badge with the user's
+/// cleaned count, for profiles and READMEs. Served for the same opted-in
+/// users as the public page, with an `ETag` so unchanged counts cost a 304.
+pub async fn badge(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let token = file.strip_suffix(".svg").ok_or(StatusCode::NOT_FOUND)?;
+    let (_, total) = state
+        .db
+        .get_public_user(token)
+        .await
+        .map_err(lookup_failed)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let etag = format!("\"{}\"", total);
+    let cache_control = format!("public, max-age={}", BADGE_MAX_AGE_SECS);
+    let fresh = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|tag| tag.trim() == etag));
+    if fresh {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)],
+        )
+            .into_response());
+    }
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml".to_string()),
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        badge_svg("🧹", &format!("{} links cleaned", format_count(total))),
+    )
+        .into_response())
+}
+
+/// Renders the badge with each half sized to its text. Widths are
+/// estimated, like shields.io does, from Verdana 11px.
+fn badge_svg(label: &str, message: &str) -> String {
+    let text_width = |text: &str| -> u32 {
+        text.chars()
+            .map(|c| if c.is_ascii() { 7 } else { 14 })
+            .sum::<u32>()
+            + 10
+    };
+    let (label_width, message_width) = (text_width(label), text_width(message));
+    render(
+        BADGE_TEMPLATE,
+        &[
+            ("width", &(label_width + message_width).to_string()),
+            ("label_width", &label_width.to_string()),
+            ("message_width", &message_width.to_string()),
+            ("label_x", &(label_width / 2).to_string()),
+            ("message_x", &(label_width + message_width / 2).to_string()),
+            ("label", &escape_html(label)),
+            ("message", &escape_html(message)),
+        ],
+    )
+}
+
+/// Fills the `{{name}}` placeholders of `template`. Values are inserted
+/// as given, so callers escape them.
+fn render(template: &str, values: &[(&str, &str)]) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |page, (name, value)| {
+            page.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+pub(super) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// `1234567` as `1,234,567`.
+fn format_count(count: i64) -> String {
+    let digits = count.unsigned_abs().to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    if count < 0 {
+        grouped.insert(0, '-');
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::db::Db;
+    use crate::models::Actor;
+    use std::sync::Arc;
+
+    async fn page(state: &AppState, token: &str) -> Result<String, StatusCode> {
+        let response = public_stats(State(state.clone()), Path(token.to_string()))
+            .await?
+            .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn public_page_is_opt_in_and_shows_no_links() {
+        let db = Db::test("web-public").await;
+        let config = Arc::new(arc_swap::ArcSwap::from_pointee(Config::load(None)));
+        let state = AppState::test(db.clone(), config);
+        for provider in ["amazon", "amazon", "<google>"] {
+            db.log_cleaned_link(
+                7,
+                None,
+                None,
+                "a",
+                "https://secret.example/",
+                provider,
+                "rules",
+            )
+            .await
+            .unwrap();
+        }
+        db.increment_cleaned_count(7, 1_234).await.unwrap();
+
+        let token = db
+            .set_public_stats(7, true, Actor::web(7))
+            .await
+            .unwrap()
+            .unwrap();
+        let html = page(&state, &token).await.unwrap();
+        assert!(html.contains("🧹 1,234"));
+        assert!(html.contains("<li>amazon <span>2</span></li>"));
+        assert!(html.contains("&lt;google&gt;"));
+        assert!(!html.contains("secret.example"));
+
+        // Switching off hides the page; switching on again keeps the link.
+        assert_eq!(
+            db.set_public_stats(7, false, Actor::web(7)).await.unwrap(),
+            Some(token.clone())
+        );
+        assert_eq!(page(&state, &token).await, Err(StatusCode::NOT_FOUND));
+        assert_eq!(page(&state, "unknown").await, Err(StatusCode::NOT_FOUND));
+        let again = db.set_public_stats(7, true, Actor::web(7)).await.unwrap();
+        assert_eq!(again.as_ref(), Some(&token));
+        assert!(db.get_user_config(7).await.unwrap().has_public_stats());
+
+        let badge_for = |file: &str, etag: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(etag) = etag {
+                headers.insert(header::IF_NONE_MATCH, etag.parse().unwrap());
+            }
+            badge(State(state.clone()), Path(file.to_string()), headers)
+        };
+        let response = badge_for(&format!("{}.svg", token), None).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/svg+xml");
+        assert_eq!(response.headers()[header::ETAG], "\"1234\"");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert!(String::from_utf8(body.to_vec())
+            .unwrap()
+            .contains("aria-label=\"🧹: 1,234 links cleaned\""));
+        let cached = badge_for(&format!("{}.svg", token), Some("\"1234\""))
+            .await
+            .unwrap();
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(
+            badge_for(&token, None).await.unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[test]
+    fn formats_counts_and_fills_templates() {
+        assert_eq!(format_count(0), "0");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1_234), "1,234");
+        assert_eq!(format_count(-1_234_567), "-1,234,567");
+        assert_eq!(
+            render("{{a}} and {{b}} and {{a}}", &[("a", "1"), ("b", "<2>")]),
+            "1 and <2> and 1"
+        );
+        assert_eq!(
+            escape_html("<b>\"A&B\"</b>"),
+            "&lt;b&gt;&quot;A&amp;B&quot;&lt;/b&gt;"
+        );
+    }
+}
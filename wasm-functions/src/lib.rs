@@ -1,27 +1,217 @@
-use wasm_bindgen::prelude::*;
+use clear_urls_core::{PassStats, Rules};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
 use url::Url;
+use wasm_bindgen::prelude::*;
+
+thread_local! {
+    /// Rule set loaded by `init_rules`, shared by every call on this thread.
+    static RULES: RefCell<Option<Rules>> = const { RefCell::new(None) };
+    /// Extra parameters stripped by `clean_url`, like the bot's custom rules.
+    static CUSTOM_PARAMS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Hosts `clean_url` leaves untouched.
+    static IGNORED_DOMAINS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Compiles a ClearURLs `data.min.json` document with the same engine the
+/// bot uses and returns the number of providers loaded. Later calls replace
+/// the previous rule set.
+#[wasm_bindgen]
+pub fn init_rules(json: &str) -> Result<usize, JsValue> {
+    let rules = Rules::from_json(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let count = rules.len();
+    RULES.with(|r| *r.borrow_mut() = Some(rules));
+    Ok(count)
+}
+
+/// Replaces the extra tracking parameters removed by `clean_url`. Like the
+/// bot's custom rules, a parameter is removed when its name contains one of
+/// `params` (an array of strings).
+#[wasm_bindgen]
+pub fn set_custom_params(params: JsValue) -> Result<(), JsValue> {
+    let params: Vec<String> = serde_wasm_bindgen::from_value(params)?;
+    CUSTOM_PARAMS.with(|p| *p.borrow_mut() = params);
+    Ok(())
+}
+
+/// Replaces the domains `clean_url` leaves untouched. A URL is skipped when
+/// its host contains one of `domains` (an array of strings).
+#[wasm_bindgen]
+pub fn set_ignored_domains(domains: JsValue) -> Result<(), JsValue> {
+    let domains: Vec<String> = serde_wasm_bindgen::from_value(domains)?;
+    IGNORED_DOMAINS.with(|d| *d.borrow_mut() = domains);
+    Ok(())
+}
+
+/// Masks credentials, IP and e-mail addresses with the same patterns the bot
+/// uses for its logs, so text can be pre-redacted before it leaves the client.
+#[wasm_bindgen]
+pub fn redact_sensitive(text: &str) -> String {
+    clear_urls_core::redact_sensitive(text)
+}
+
+/// Outcome of cleaning one URL, as returned to JavaScript.
+#[derive(Debug, Serialize)]
+struct CleanResult {
+    cleaned: String,
+    changed: bool,
+    removed_params: Vec<String>,
+    /// Provider credited for the cleaning; absent when nothing changed or
+    /// the fixed fallback list was used.
+    provider: Option<String>,
+}
+
+impl CleanResult {
+    fn unchanged(input_url: &str) -> Self {
+        Self {
+            cleaned: input_url.to_string(),
+            changed: false,
+            removed_params: Vec::new(),
+            provider: None,
+        }
+    }
+}
+
+/// Cleans with the rules passed to `init_rules`, falling back to the fixed
+/// tracker list until they are loaded. Custom parameters and ignored domains
+/// apply either way.
+fn clean(input_url: &str) -> CleanResult {
+    let custom = CUSTOM_PARAMS.with(|p| p.borrow().clone());
+    let ignored = IGNORED_DOMAINS.with(|d| d.borrow().clone());
+    RULES.with(|r| match r.borrow().as_ref() {
+        Some(rules) => {
+            let mut stats = PassStats::default();
+            match rules.sanitize_with_stats(input_url, &custom, &ignored, &mut stats) {
+                Some((cleaned, provider)) => CleanResult {
+                    cleaned,
+                    changed: true,
+                    removed_params: stats.removed_params,
+                    provider: Some(provider),
+                },
+                None => CleanResult::unchanged(input_url),
+            }
+        }
+        None => {
+            let is_ignored = Url::parse(input_url)
+                .ok()
+                .and_then(|u| u.host_str().map(|h| ignored.iter().any(|d| h.contains(d))))
+                .unwrap_or(false);
+            if is_ignored {
+                CleanResult::unchanged(input_url)
+            } else {
+                strip_tracking_params(input_url, &custom)
+            }
+        }
+    })
+}
+
+/// Cleans `input_url` with the rules passed to `init_rules`, falling back to
+/// `clean_url_simple` until they are loaded.
+#[wasm_bindgen]
+pub fn clean_url(input_url: &str) -> String {
+    clean(input_url).cleaned
+}
+
+/// Like `clean_url`, but returns `{cleaned, changed, removed_params, provider}`
+/// so front-ends can show what was removed.
+#[wasm_bindgen]
+pub fn clean_url_details(input_url: &str) -> Result<JsValue, JsValue> {
+    to_js(&clean(input_url))
+}
+
+/// Converts `value` to a plain JS object. A missing `provider` becomes
+/// `null`, not `undefined`, so `JSON.stringify` (as in the Workers
+/// endpoint) keeps the key.
+fn to_js<T: Serialize>(value: &T) -> Result<JsValue, JsValue> {
+    Ok(value.serialize(&serde_wasm_bindgen::Serializer::json_compatible())?)
+}
+
+/// Input accepted by `clean_urls`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchInput {
+    Urls(Vec<String>),
+    Text(String),
+}
+
+/// Cleans many URLs in one call. `input` is either an array of URLs or a
+/// string of free text, in which case every URL found in it is cleaned.
+/// Returns an array of `clean_url_details` results in input order.
+#[wasm_bindgen]
+pub fn clean_urls(input: JsValue) -> Result<JsValue, JsValue> {
+    let results = clean_batch(serde_wasm_bindgen::from_value(input)?);
+    to_js(&results)
+}
+
+fn clean_batch(input: BatchInput) -> Vec<CleanResult> {
+    let urls = match input {
+        BatchInput::Urls(urls) => urls,
+        BatchInput::Text(text) => clear_urls_core::find_urls(&text)
+            .map(str::to_string)
+            .collect(),
+    };
+    urls.iter().map(|url| clean(url)).collect()
+}
 
+/// Strips a fixed list of common trackers, without any provider rules.
 #[wasm_bindgen]
 pub fn clean_url_simple(input_url: &str) -> String {
+    strip_tracking_params(input_url, &[]).cleaned
+}
+
+/// `clean_url_simple` with the parameters it removed.
+#[wasm_bindgen]
+pub fn clean_url_simple_details(input_url: &str) -> Result<JsValue, JsValue> {
+    to_js(&strip_tracking_params(input_url, &[]))
+}
+
+/// Removes the fixed tracker list plus any parameter containing one of
+/// `custom_params`.
+fn strip_tracking_params(input_url: &str, custom_params: &[String]) -> CleanResult {
     let mut url = match Url::parse(input_url) {
         Ok(u) => u,
-        Err(_) => return input_url.to_string(),
+        Err(_) => return CleanResult::unchanged(input_url),
     };
 
-    let query_pairs: Vec<(String, String)> = url.query_pairs()
+    let query_pairs: Vec<(String, String)> = url
+        .query_pairs()
         .map(|(k, v)| (k.into_owned(), v.into_owned()))
         .collect();
 
     let mut new_query = Vec::new();
+    let mut removed_params = Vec::new();
     let tracking_params = [
-        "utm_source", "utm_medium", "utm_campaign", "utm_term", "utm_content",
-        "fbclid", "gclid", "msclkid", "mc_eid", "_hsenc", "_hsmi",
-        "gs_lcrp", "oq", "sourceid", "client", "bih", "biw", "ved", "ei",
-        "iflsig", "adgrpid", "nw", "matchtype"
+        "utm_source",
+        "utm_medium",
+        "utm_campaign",
+        "utm_term",
+        "utm_content",
+        "fbclid",
+        "gclid",
+        "msclkid",
+        "mc_eid",
+        "_hsenc",
+        "_hsmi",
+        "gs_lcrp",
+        "oq",
+        "sourceid",
+        "client",
+        "bih",
+        "biw",
+        "ved",
+        "ei",
+        "iflsig",
+        "adgrpid",
+        "nw",
+        "matchtype",
     ];
 
     for (k, v) in query_pairs {
-        if !tracking_params.contains(&k.as_str()) {
+        if tracking_params.contains(&k.as_str())
+            || custom_params.iter().any(|p| k.contains(p.as_str()))
+        {
+            removed_params.push(k);
+        } else {
             new_query.push((k, v));
         }
     }
@@ -29,15 +219,174 @@ pub fn clean_url_simple(input_url: &str) -> String {
     if new_query.is_empty() {
         url.set_query(None);
     } else {
-        let mut query_str = String::new();
-        for (i, (k, v)) in new_query.iter().enumerate() {
-            if i > 0 { query_str.push('&'); }
-            query_str.push_str(k);
-            query_str.push('=');
-            query_str.push_str(v);
+        // Re-encoded, so kept values containing `&`, `=` or `#` stay whole.
+        url.query_pairs_mut().clear().extend_pairs(&new_query);
+    }
+
+    CleanResult {
+        cleaned: url.to_string(),
+        changed: !removed_params.is_empty(),
+        removed_params,
+        provider: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kept_parameters_are_re_encoded() {
+        let result = strip_tracking_params(
+            "https://example.com/?q=a%26b%3Dc%23d&utm_source=x&s=a+b",
+            &[],
+        );
+        assert_eq!(result.cleaned, "https://example.com/?q=a%26b%3Dc%23d&s=a+b");
+        assert_eq!(result.removed_params, ["utm_source"]);
+    }
+
+    const RULES_JSON: &str =
+        r#"{"providers":{"example":{"urlPattern":"^https?://example\\.com","rules":["ref"]}}}"#;
+
+    #[test]
+    fn trackers_and_custom_params_are_stripped() {
+        let result = strip_tracking_params(
+            "https://shop.test/item?id=4&utm_source=x&fbclid=y&aff_id=z",
+            &["aff".to_string()],
+        );
+        assert_eq!(result.cleaned, "https://shop.test/item?id=4");
+        assert!(result.changed);
+        assert_eq!(result.removed_params, ["utm_source", "fbclid", "aff_id"]);
+        assert_eq!(result.provider, None);
+    }
+
+    #[test]
+    fn the_query_is_dropped_when_nothing_is_kept() {
+        assert_eq!(
+            clean_url_simple("https://shop.test/item?utm_source=x#top"),
+            "https://shop.test/item#top"
+        );
+    }
+
+    #[test]
+    fn clean_urls_and_invalid_input_are_unchanged() {
+        for input in ["https://shop.test/item?id=4", "not a url"] {
+            let result = strip_tracking_params(input, &[]);
+            assert_eq!(result.cleaned, input);
+            assert!(!result.changed);
+            assert!(result.removed_params.is_empty());
         }
-        url.set_query(Some(&query_str));
     }
 
-    url.to_string()
+    #[test]
+    fn the_fallback_applies_custom_params_and_ignored_domains() {
+        CUSTOM_PARAMS.with(|p| *p.borrow_mut() = vec!["aff".into()]);
+        IGNORED_DOMAINS.with(|d| *d.borrow_mut() = vec!["keep.test".into()]);
+        assert_eq!(
+            clean_url("https://shop.test/?aff_id=1&id=2"),
+            "https://shop.test/?id=2"
+        );
+        let ignored = "https://www.keep.test/?utm_source=x";
+        assert_eq!(clean_url(ignored), ignored);
+    }
+
+    #[test]
+    fn loaded_rules_credit_their_provider() {
+        assert_eq!(init_rules(RULES_JSON).unwrap(), 1);
+        let result = clean("https://example.com/page?ref=feed&id=1");
+        assert_eq!(result.cleaned, "https://example.com/page?id=1");
+        assert!(result.changed);
+        assert_eq!(result.removed_params, ["ref"]);
+        assert_eq!(result.provider.as_deref(), Some("example"));
+
+        // Links no provider changes are reported as is.
+        let untouched = clean("https://other.test/?ref=feed");
+        assert!(!untouched.changed);
+        assert_eq!(untouched.provider, None);
+    }
+
+    #[test]
+    fn loaded_rules_apply_custom_params_and_ignored_domains() {
+        init_rules(RULES_JSON).unwrap();
+        CUSTOM_PARAMS.with(|p| *p.borrow_mut() = vec!["aff".into()]);
+        let result = clean("https://example.com/page?ref=feed&aff_id=1&id=2");
+        assert_eq!(result.cleaned, "https://example.com/page?id=2");
+        assert_eq!(result.removed_params, ["aff_id", "ref"]);
+
+        IGNORED_DOMAINS.with(|d| *d.borrow_mut() = vec!["example.com".into()]);
+        let ignored = "https://example.com/page?ref=feed&aff_id=1";
+        assert_eq!(clean_url(ignored), ignored);
+    }
+
+    /// The fields of a result; `wasm::details_keep_the_shape_the_worker_serves`
+    /// checks the JS object the Workers endpoint returns.
+    #[test]
+    fn details_serialize_every_field() {
+        let result = strip_tracking_params("https://shop.test/?utm_source=x&id=1", &[]);
+        assert_eq!(
+            serde_json::to_value(&result).unwrap(),
+            serde_json::json!({
+                "cleaned": "https://shop.test/?id=1",
+                "changed": true,
+                "removed_params": ["utm_source"],
+                "provider": null,
+            })
+        );
+    }
+
+    #[test]
+    fn batches_take_a_list_or_free_text() {
+        let results = clean_batch(BatchInput::Urls(vec![
+            "https://a.test/?utm_source=x".into(),
+            "https://b.test/".into(),
+        ]));
+        let cleaned: Vec<_> = results.iter().map(|r| r.cleaned.as_str()).collect();
+        assert_eq!(cleaned, ["https://a.test/", "https://b.test/"]);
+
+        let results = clean_batch(BatchInput::Text(
+            "see https://a.test/?fbclid=1 and https://b.test/?id=2".into(),
+        ));
+        let cleaned: Vec<_> = results.iter().map(|r| r.cleaned.as_str()).collect();
+        assert_eq!(cleaned, ["https://a.test/", "https://b.test/?id=2"]);
+        assert!(clean_batch(BatchInput::Text("no links here".into())).is_empty());
+    }
+
+    #[test]
+    fn batch_input_is_an_array_or_a_string() {
+        let urls: BatchInput = serde_json::from_str(r#"["https://a.test/"]"#).unwrap();
+        assert!(matches!(urls, BatchInput::Urls(u) if u == ["https://a.test/"]));
+        let text: BatchInput = serde_json::from_str(r#""https://a.test/""#).unwrap();
+        assert!(matches!(text, BatchInput::Text(t) if t == "https://a.test/"));
+    }
+
+    #[test]
+    fn redaction_masks_emails() {
+        let redacted = redact_sensitive("write to test@example.com");
+        assert!(redacted.contains("[REDACTED EMAIL]"));
+        assert!(!redacted.contains("test@example.com"));
+    }
+}
+
+/// Runs in a JS engine: `wasm-pack test --node`.
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm {
+    use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    /// The Workers endpoint (`worker/index.js`) returns
+    /// `JSON.stringify(clean_url_details(url))`.
+    #[wasm_bindgen_test]
+    fn details_keep_the_shape_the_worker_serves() {
+        let details = clean_url_details("https://shop.test/?utm_source=x&id=1").unwrap();
+        let json: String = js_sys::JSON::stringify(&details).unwrap().into();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "cleaned": "https://shop.test/?id=1",
+                "changed": true,
+                "removed_params": ["utm_source"],
+                "provider": null,
+            })
+        );
+    }
 }
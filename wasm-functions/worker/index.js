@@ -0,0 +1,50 @@
+// Edge endpoint for the wasm-compiled clear_urls_core engine:
+//   GET /clean?url=<url>  ->  {cleaned, changed, removed_params, provider}
+import init, { init_rules, clean_url_details } from "./pkg/clear_urls_wasm.js";
+import wasmModule from "./pkg/clear_urls_wasm_bg.wasm";
+
+// Rules are fetched once per isolate and kept for its lifetime.
+let ready;
+
+async function setup(env) {
+  await init({ module_or_path: wasmModule });
+  const resp = await fetch(env.CLEARURLS_SOURCE, { cf: { cacheTtl: 3600 } });
+  if (!resp.ok) {
+    throw new Error(`Failed to fetch rules: ${resp.status}`);
+  }
+  init_rules(await resp.text());
+}
+
+function json(body, status = 200) {
+  return new Response(JSON.stringify(body), {
+    status,
+    headers: {
+      "content-type": "application/json",
+      "access-control-allow-origin": "*",
+    },
+  });
+}
+
+export default {
+  async fetch(request, env) {
+    const { pathname, searchParams } = new URL(request.url);
+    if (request.method !== "GET" || pathname !== "/clean") {
+      return json({ error: "not found" }, 404);
+    }
+    const target = searchParams.get("url");
+    if (!target) {
+      return json({ error: "missing url parameter" }, 400);
+    }
+
+    ready ??= setup(env).catch((e) => {
+      ready = undefined;
+      throw e;
+    });
+    try {
+      await ready;
+    } catch (e) {
+      return json({ error: e.message }, 503);
+    }
+    return json(clean_url_details(target));
+  },
+};